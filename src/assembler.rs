@@ -0,0 +1,1981 @@
+// let's implement an assembler real fast.
+
+mod expr;
+mod format;
+mod lexer;
+mod symbols;
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::BufRead,
+    path::{Path, PathBuf},
+    vec,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+
+use self::expr::Expr;
+pub use self::format::format_source;
+use self::lexer::{lex, Token, TokenKind};
+use self::symbols::{SymbolId, Symbols};
+use crate::cpu::{has_code_operand, Opcode};
+use crate::program::{
+    required_features, Arity, DebugInfo, Program, ProgramParts, RecordInfo, Symbol, VariableInfo,
+};
+
+// immediates bigger than this get moved into the constant pool.
+const POOL_SIZE_THRESHOLD: u64 = i32::MAX as u64;
+
+#[derive(Debug, Clone, Default)]
+pub struct AssemblerOptions {
+    // drop labeled blocks that can't be reached from the entry point or an export.
+    pub strip_dead_code: bool,
+    // turn compare-then-jif sequences into a single JEQ, JLT, JZ and so on.
+    pub fuse_branches: bool,
+    // recorded in the debug info so tools can point back at the source.
+    pub source_name: Option<String>,
+    // constants every module starts out with, e.g. `:debug` => 1. The source
+    // can still redefine them, guard defaults with `.ifndef` to avoid that.
+    pub defines: BTreeMap<String, i64>,
+}
+
+#[derive(Clone, Debug)]
+enum ProgramValue {
+    Instruction(Opcode),
+    Value(i64),
+    // `:name value`, where the value can use other constants and labels.
+    Constant(String, Expr),
+    FunctionLabel(String),
+    // `.fn :name args=n rets=n`, right after the label it declares. Until the
+    // `.endfn` every local label belongs to it.
+    Function(Option<Arity>),
+    EndFunction,
+    // a symbolic operand, a label or constant name or arithmetic on them.
+    Label(Expr),
+    Export(String),
+    // `.import :name from "path"`.
+    Import(String, String),
+    // where a linked in module's values start, put there by the linker.
+    Module(String),
+    // words for the data segment, and the label naming their address.
+    Data(String, Vec<i64>),
+    // `.record :name field...`, a record shape and its field names.
+    Record(String, Vec<String>),
+    // conditional assembly, `.ifdef`/`.ifndef`/`.if` up to `.else` or `.endif`.
+    If(Condition),
+    Else,
+    EndIf,
+    // pad with NOPs up to this address.
+    Org(i64),
+    // pad with NOPs up to a multiple of this.
+    Align(i64),
+    // `.var name slot`, a name for a frame slot until the end of the scope.
+    Variable(String, i64),
+    // where a local label scope ends, put there by scope_local_labels.
+    EndScope,
+}
+
+#[derive(Clone, Debug)]
+enum Condition {
+    Defined(String),
+    NotDefined(String),
+    // true when it evaluates to anything but 0.
+    NonZero(Expr),
+}
+
+// parse one lexed line of source into `out`. Tokens are borrowed from the
+// line and only copied when they end up in a value.
+fn parse_line(tokens: &[Token], out: &mut Vec<ProgramValue>) -> Result<()> {
+    // it's a label
+    // we'll outline our grammar here.
+    let mut tokens = tokens
+        .iter()
+        .filter(|token| token.kind != TokenKind::Comment);
+    // we'll skip empty lines
+    let Some(word) = tokens.next() else {
+        return Ok(());
+    };
+    let word = get_word(word)?;
+
+    // we can define constants
+    if is_label(word) {
+        match tokens.next().map(get_word).transpose()? {
+            // `:loop load 0`, a label and the instruction it marks. Constant
+            // values never look like a mnemonic.
+            Some(mnemonic) if Opcode::from_mnemonic(mnemonic).is_some() => {
+                out.push(ProgramValue::FunctionLabel(word.to_string()));
+                return parse_instruction(mnemonic, &mut tokens, out);
+            }
+            Some(argument) => {
+                let constant = Expr::parse(argument).context("Bad constant value")?;
+                out.push(ProgramValue::Constant(word.to_string(), constant));
+            }
+            // A label with no value will demarcate the next instruction address.
+            None => out.push(ProgramValue::FunctionLabel(word.to_string())),
+        }
+        return Ok(());
+    }
+
+    if word == ".fn" {
+        let name = get_word(get_token(&mut tokens)?)?;
+        // the colon is optional here, there's nothing else it could be.
+        let name = match is_label(name) {
+            true => name.to_string(),
+            false => format!(":{name}"),
+        };
+        if is_local_label(&name) || is_numeric_label(&name) {
+            bail!(".fn needs a function name, got {name}")
+        }
+        let mut arity = None;
+        for attribute in tokens {
+            let attribute = get_word(attribute)?;
+            let (key, value) = attribute
+                .split_once('=')
+                .and_then(|(key, value)| Some((key, value.parse::<usize>().ok()?)))
+                .with_context(|| format!("Bad .fn attribute {attribute}"))?;
+            let arity: &mut Arity = arity.get_or_insert_with(Arity::default);
+            match key {
+                "args" => arity.args = value,
+                "rets" => arity.rets = value,
+                _ => bail!("Unknown .fn attribute {key}"),
+            }
+        }
+        out.push(ProgramValue::FunctionLabel(name));
+        out.push(ProgramValue::Function(arity));
+        return Ok(());
+    }
+
+    if word == ".endfn" {
+        out.push(ProgramValue::EndFunction);
+        return Ok(());
+    }
+
+    if word == ".var" {
+        let name = get_word(get_token(&mut tokens)?)?;
+        if is_label(name) || name.parse::<i64>().is_ok() {
+            bail!(".var needs a plain name, got {name}")
+        }
+        let slot = get_word(get_token(&mut tokens)?)?
+            .parse::<i64>()
+            .context(".var needs a slot number")?;
+        // `:.name` works as the slot number too.
+        out.push(ProgramValue::Constant(
+            format!(":.{name}"),
+            Expr::Number(slot),
+        ));
+        out.push(ProgramValue::Variable(name.to_string(), slot));
+        return Ok(());
+    }
+
+    if word == ".export" {
+        let label = get_word(get_token(&mut tokens)?)?;
+        if !is_label(label) {
+            bail!("Can only export labels, got {label}")
+        }
+        out.push(ProgramValue::Export(label.to_string()));
+        return Ok(());
+    }
+
+    if matches!(word, ".string" | ".asciz" | ".lstring") {
+        let label = get_word(get_token(&mut tokens)?)?;
+        if !is_label(label) {
+            bail!("{word} needs a label, got {label}")
+        }
+        let Some(literal) = tokens
+            .next()
+            .filter(|token| token.kind == TokenKind::String)
+        else {
+            bail!("{word} needs a quoted string")
+        };
+        let mut words: Vec<i64> = parse_string_literal(literal.text)?
+            .chars()
+            .map(|c| c as i64)
+            .collect();
+        match word {
+            ".asciz" => words.push(0),
+            ".lstring" => words.insert(0, words.len() as i64),
+            _ => {}
+        }
+        out.push(ProgramValue::Data(label.to_string(), words));
+        return Ok(());
+    }
+
+    if word == ".record" {
+        let label = get_word(get_token(&mut tokens)?)?;
+        if !is_label(label) {
+            bail!(".record needs a label, got {label}")
+        }
+        let mut fields: Vec<String> = vec![];
+        for field in tokens {
+            let field = get_word(field)?;
+            if is_label(field) || field.parse::<i64>().is_ok() {
+                bail!("Bad field name {field} in record {label}")
+            }
+            if fields.iter().any(|seen| seen == field) {
+                bail!("Record {label} has field {field} twice")
+            }
+            fields.push(field.to_string());
+        }
+        out.push(ProgramValue::Record(label.to_string(), fields));
+        return Ok(());
+    }
+
+    if word == ".words" {
+        let label = get_word(get_token(&mut tokens)?)?;
+        if !is_label(label) {
+            bail!(".words needs a label, got {label}")
+        }
+        let words = tokens
+            .map(|word| {
+                get_word(word)?
+                    .parse::<i64>()
+                    .context("Data word was not a number")
+            })
+            .collect::<Result<Vec<i64>>>()?;
+        out.push(ProgramValue::Data(label.to_string(), words));
+        return Ok(());
+    }
+
+    match word {
+        ".ifdef" | ".ifndef" => {
+            let name = get_word(get_token(&mut tokens)?)?;
+            if !is_label(name) {
+                bail!("{word} needs a constant name, got {name}")
+            }
+            let name = name.to_string();
+            out.push(ProgramValue::If(match word {
+                ".ifdef" => Condition::Defined(name),
+                _ => Condition::NotDefined(name),
+            }));
+            return Ok(());
+        }
+        ".if" => {
+            let expr = get_word(get_token(&mut tokens)?)?;
+            let expr = Expr::parse(expr).with_context(|| format!("Bad condition {expr}"))?;
+            out.push(ProgramValue::If(Condition::NonZero(expr)));
+            return Ok(());
+        }
+        ".else" => {
+            out.push(ProgramValue::Else);
+            return Ok(());
+        }
+        ".endif" => {
+            out.push(ProgramValue::EndIf);
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    if word == ".org" || word == ".align" {
+        let argument = get_word(get_token(&mut tokens)?)?
+            .parse::<i64>()
+            .with_context(|| format!("{word} needs a number"))?;
+        out.push(match word {
+            ".org" if argument >= 0 => ProgramValue::Org(argument),
+            ".align" if argument > 0 => ProgramValue::Align(argument),
+            _ => bail!("Bad {word} argument {argument}"),
+        });
+        return Ok(());
+    }
+
+    if word == ".import" {
+        let label = get_word(get_token(&mut tokens)?)?;
+        if !is_label(label) {
+            bail!("Can only import labels, got {label}")
+        }
+        if get_word(get_token(&mut tokens)?)? != "from" {
+            bail!("Expected .import {label} from \"path\"")
+        }
+        let path = get_token(&mut tokens)?;
+        if path.kind != TokenKind::String {
+            bail!("Import path must be quoted, got {}", path.text)
+        }
+        let path = parse_string_literal(path.text)?;
+        out.push(ProgramValue::Import(label.to_string(), path));
+        return Ok(());
+    }
+
+    parse_instruction(word, &mut tokens, out)
+}
+
+fn parse_instruction<'a, Iter>(
+    mnemonic: &str,
+    tokens: &mut Iter,
+    out: &mut Vec<ProgramValue>,
+) -> Result<()>
+where
+    Iter: Iterator<Item = &'a Token<'a>>,
+{
+    let Some(opcode) = Opcode::from_mnemonic(mnemonic) else {
+        bail!("Received invalid instruction {}", mnemonic.to_lowercase())
+    };
+    out.push(ProgramValue::Instruction(opcode));
+    for _ in 0..opcode.operand_count() {
+        out.push(get_labeled_or_unlabled_argument(tokens)?);
+    }
+    Ok(())
+}
+
+fn get_labeled_or_unlabled_argument<'a, Iter>(iterator: &mut Iter) -> Result<ProgramValue>
+where
+    Iter: Iterator<Item = &'a Token<'a>>,
+{
+    let token = get_word(get_token(iterator)?)?;
+    if is_label(token) {
+        let expr = Expr::parse(token).with_context(|| format!("Bad operand {token}"))?;
+        Ok(ProgramValue::Label(expr))
+    } else {
+        Ok(ProgramValue::Value(
+            token.parse::<i64>().context("Not number")?,
+        ))
+    }
+}
+
+fn is_label(string: &str) -> bool {
+    string.starts_with(':')
+}
+
+// a double quoted string with the usual backslash escapes.
+fn parse_string_literal(literal: &str) -> Result<String> {
+    let Some(inner) = literal
+        .strip_prefix('"')
+        .and_then(|literal| literal.strip_suffix('"'))
+    else {
+        bail!("Bad string literal {literal}")
+    };
+    let mut out = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        out.push(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('\\') => '\\',
+            Some('"') => '"',
+            other => bail!("Unknown escape \\{}", other.unwrap_or(' ')),
+        });
+    }
+    Ok(out)
+}
+
+fn is_numeric_label(string: &str) -> bool {
+    string.len() > 1 && string[1..].bytes().all(|byte| byte.is_ascii_digit())
+}
+
+fn is_local_label(string: &str) -> bool {
+    string.starts_with(":.")
+}
+
+// the text of a token that has to be a word, not a string literal.
+fn get_word<'a>(token: &Token<'a>) -> Result<&'a str> {
+    match token.kind {
+        TokenKind::Word => Ok(token.text),
+        _ => bail!(
+            "Unexpected {} at column {}",
+            token.text,
+            token.span.start + 1
+        ),
+    }
+}
+
+fn get_token<'a, Iter>(iterator: &mut Iter) -> Result<&'a Token<'a>>
+where
+    Iter: Iterator<Item = &'a Token<'a>>,
+{
+    match iterator.next() {
+        Some(token) => Ok(token),
+        None => {
+            bail!("No token present when required")
+        }
+    }
+}
+
+pub fn parse_program(program: String, options: &AssemblerOptions) -> Result<Program> {
+    lower(&parse_ir(&program, options)?)
+}
+
+// everything the front end knows about a program, before it's flattened into
+// words. This is what `--emit=json` prints.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ProgramIr {
+    pub file: Option<String>,
+    pub instructions: Vec<IrInstruction>,
+    pub labels: Vec<IrLabel>,
+    pub constants: Vec<IrConstant>,
+    pub data: Vec<IrData>,
+    pub records: Vec<IrRecord>,
+    pub variables: Vec<IrVariable>,
+    pub exports: Vec<String>,
+    // modules linked in after this one, in the order their code was laid out.
+    pub modules: Vec<IrModule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IrModule {
+    pub file: String,
+    // where its code starts.
+    pub address: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IrInstruction {
+    pub address: i64,
+    pub mnemonic: String,
+    pub opcode: Opcode,
+    // LOOP's variable, which comes before its target.
+    pub slot: Option<IrOperand>,
+    pub operand: Option<IrOperand>,
+    pub span: Span,
+}
+
+impl IrInstruction {
+    fn operand_mut(&mut self, slot: bool) -> &mut Option<IrOperand> {
+        match slot {
+            true => &mut self.slot,
+            false => &mut self.operand,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IrOperand {
+    pub value: i64,
+    // set when the operand was written as a label or constant name.
+    pub label: Option<String>,
+    // set when that names a code label. These stay in the code, where
+    // relocation can find them, rather than going in the constant pool.
+    pub code: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IrLabel {
+    pub name: String,
+    pub address: i64,
+    // set for functions declared with `.fn`.
+    pub arity: Option<Arity>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IrConstant {
+    pub name: String,
+    pub value: i64,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IrData {
+    pub name: String,
+    // offset into the data segment.
+    pub address: i64,
+    pub words: Vec<i64>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IrRecord {
+    pub name: String,
+    // constant pool index of the shape. Shapes take the first pool entries.
+    pub shape: i64,
+    pub fields: Vec<String>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IrVariable {
+    pub name: String,
+    pub slot: i64,
+    // the code addresses it has this name for, start..end.
+    pub start: i64,
+    pub end: i64,
+    pub span: Span,
+}
+
+// 1-based line, and the byte columns of the line's code, comments left out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Span {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+type Spanned = (ProgramValue, Span);
+
+pub fn parse_ir(program: &str, options: &AssemblerOptions) -> Result<ProgramIr> {
+    let values = parse_values(program, options)?;
+    reject_imports(&values)?;
+    build_ir(values, options)
+}
+
+// assemble source as it's read, a line at a time, so it never has to be held
+// in memory all at once. Like `parse_program` there's no file to resolve
+// imports against.
+pub fn assemble_reader(reader: impl BufRead, options: &AssemblerOptions) -> Result<Program> {
+    let values = parse_reader(reader, options)?;
+    reject_imports(&values)?;
+    lower(&build_ir(values, options)?)
+}
+
+fn reject_imports(values: &[Spanned]) -> Result<()> {
+    if let Some((_, span)) = values
+        .iter()
+        .find(|(value, _)| matches!(value, ProgramValue::Import(..)))
+    {
+        bail!(
+            "Line {}: imports are only supported when assembling a file",
+            span.line
+        )
+    }
+    Ok(())
+}
+
+// assemble a source file, pulling in anything it imports.
+pub fn assemble_file(path: &Path, options: &AssemblerOptions) -> Result<Program> {
+    lower(&parse_file_ir(path, options)?)
+}
+
+pub fn parse_file_ir(path: &Path, options: &AssemblerOptions) -> Result<ProgramIr> {
+    build_ir(link(path, options)?, options)
+}
+
+fn parse_values(program: &str, options: &AssemblerOptions) -> Result<Vec<Spanned>> {
+    parse_reader(program.as_bytes(), options)
+}
+
+fn parse_reader(mut reader: impl BufRead, options: &AssemblerOptions) -> Result<Vec<Spanned>> {
+    let mut value_stream: Vec<Spanned> = options
+        .defines
+        .iter()
+        .map(|(name, value)| {
+            (
+                ProgramValue::Constant(name.clone(), Expr::Number(*value)),
+                Span::default(),
+            )
+        })
+        .collect();
+    // first grab the lines, reusing one buffer for all of them.
+    let mut parsed = vec![];
+    let mut buffer = String::new();
+    let mut number = 0;
+    loop {
+        buffer.clear();
+        let read = reader
+            .read_line(&mut buffer)
+            .with_context(|| format!("Could not read line {}", number + 1))?;
+        if read == 0 {
+            break;
+        }
+        number += 1;
+        let line = buffer.strip_suffix('\n').unwrap_or(&buffer);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        let tokens = lex(line).with_context(|| format!("Line {number}"))?;
+        // a comment can only be the last token.
+        let code = match tokens.last() {
+            Some(token) if token.kind == TokenKind::Comment => &tokens[..tokens.len() - 1],
+            _ => &tokens[..],
+        };
+        let span = Span {
+            line: number,
+            start: code.first().map_or(0, |token| token.span.start),
+            end: code.last().map_or(0, |token| token.span.end),
+        };
+        parse_line(&tokens, &mut parsed).with_context(|| format!("Line {number}"))?;
+        value_stream.extend(parsed.drain(..).map(|value| (value, span)));
+    }
+    let value_stream = resolve_conditionals(value_stream)?;
+    resolve_numeric_labels(scope_local_labels(value_stream)?)
+}
+
+// drop everything inside conditional blocks that aren't taken. Conditions can
+// only see the constants and labels defined above them, and labels have no
+// value yet so they only work with `.ifdef`.
+fn resolve_conditionals(values: Vec<Spanned>) -> Result<Vec<Spanned>> {
+    struct Block {
+        taken: bool,
+        // whether the enclosing block is taken.
+        parent: bool,
+        in_else: bool,
+    }
+
+    // most programs, and nearly every generated one, have none.
+    let conditional = |value: &ProgramValue| {
+        matches!(
+            value,
+            ProgramValue::If(_) | ProgramValue::Else | ProgramValue::EndIf
+        )
+    };
+    if !values.iter().any(|(value, _)| conditional(value)) {
+        return Ok(values);
+    }
+
+    let mut known: HashMap<String, Option<i64>> = HashMap::new();
+    let mut blocks: Vec<Block> = vec![];
+    let mut out = vec![];
+    for (value, span) in values.into_iter() {
+        let active = blocks.last().is_none_or(|block| block.taken);
+        match value {
+            ProgramValue::If(condition) => {
+                let taken = active
+                    && match &condition {
+                        Condition::Defined(name) => known.contains_key(name),
+                        Condition::NotDefined(name) => !known.contains_key(name),
+                        Condition::NonZero(expr) => {
+                            let lookup = |name: &str| known.get(name).copied().flatten();
+                            expr.evaluate(&lookup)
+                                .with_context(|| format!("Line {}", span.line))?
+                                != 0
+                        }
+                    };
+                blocks.push(Block {
+                    taken,
+                    parent: active,
+                    in_else: false,
+                });
+            }
+            ProgramValue::Else => {
+                let Some(block) = blocks.last_mut() else {
+                    bail!("Line {}: .else without .if", span.line)
+                };
+                if block.in_else {
+                    bail!("Line {}: second .else for the same .if", span.line)
+                }
+                block.taken = block.parent && !block.taken;
+                block.in_else = true;
+            }
+            ProgramValue::EndIf => {
+                if blocks.pop().is_none() {
+                    bail!("Line {}: .endif without .if", span.line)
+                }
+            }
+            _ if !active => {}
+            value => {
+                match &value {
+                    ProgramValue::Constant(name, expr) => {
+                        // anything using a label can't have a value yet, and
+                        // there's no point building the error saying so.
+                        let lookup = |name: &str| known.get(name).copied().flatten();
+                        let value = match expr.names().into_iter().all(|n| lookup(n).is_some()) {
+                            true => expr.evaluate(&lookup).ok(),
+                            false => None,
+                        };
+                        known.insert(name.clone(), value);
+                    }
+                    ProgramValue::FunctionLabel(name) | ProgramValue::Data(name, _) => {
+                        known.insert(name.clone(), None);
+                    }
+                    _ => {}
+                }
+                out.push((value, span));
+            }
+        }
+    }
+    if !blocks.is_empty() {
+        bail!("Missing .endif")
+    }
+    Ok(out)
+}
+
+// `:1` can be defined any number of times. `:1f` refers to the next one after
+// the reference and `:1b` to the closest one before it. Each definition gets a
+// unique `:1@n` name so the rest of the assembler sees plain labels.
+fn resolve_numeric_labels(mut values: Vec<Spanned>) -> Result<Vec<Spanned>> {
+    let mut definitions: HashMap<String, Vec<usize>> = HashMap::new();
+    for (index, (value, _)) in values.iter_mut().enumerate() {
+        if let ProgramValue::FunctionLabel(name) = value {
+            if is_numeric_label(name) {
+                let seen = definitions.entry(name.clone()).or_default();
+                seen.push(index);
+                *name = format!("{name}@{}", seen.len() - 1);
+            }
+        }
+    }
+
+    for (index, (value, span)) in values.iter_mut().enumerate() {
+        let (ProgramValue::Label(expr) | ProgramValue::Constant(_, expr)) = value else {
+            continue;
+        };
+        expr.visit_names(&mut |name| {
+            let (label, forward) = match name.strip_suffix('f') {
+                Some(label) => (label, true),
+                None => match name.strip_suffix('b') {
+                    Some(label) => (label, false),
+                    None => return Ok(()),
+                },
+            };
+            if !is_numeric_label(label) {
+                return Ok(());
+            }
+            let seen = definitions
+                .get(label)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            // how many definitions come before this reference.
+            let before = seen.partition_point(|definition| *definition < index);
+            let target = match forward {
+                true if before < seen.len() => before,
+                false if before > 0 => before - 1,
+                _ => bail!(
+                    "Line {}: no {label} {} this reference",
+                    span.line,
+                    if forward { "after" } else { "before" }
+                ),
+            };
+            *name = format!("{label}@{target}");
+            Ok(())
+        })?;
+    }
+    Ok(values)
+}
+
+// labels written `:.name` belong to the function label above them, so every
+// function can have its own `:.loop`. They become `:function.name`. Anything
+// before the first function label is scoped to the entry point and keeps its
+// name. Inside `.fn`/`.endfn` the scope is the declared function, whatever
+// other labels it has.
+fn scope_local_labels(values: Vec<Spanned>) -> Result<Vec<Spanned>> {
+    let mut scope = String::new();
+    let mut in_function = false;
+    let qualify = |name: String, scope: &str| match is_local_label(&name) {
+        true => format!("{scope}{}", &name[1..]),
+        false => name,
+    };
+    let mut out = vec![];
+    for (value, span) in values.into_iter() {
+        let value = match value {
+            ProgramValue::Function(arity) => {
+                if in_function {
+                    bail!("Line {}: .fn inside another function", span.line)
+                }
+                in_function = true;
+                ProgramValue::Function(arity)
+            }
+            ProgramValue::EndFunction => {
+                if !in_function {
+                    bail!("Line {}: .endfn without .fn", span.line)
+                }
+                in_function = false;
+                scope.clear();
+                out.push((ProgramValue::EndScope, span));
+                ProgramValue::EndFunction
+            }
+            ProgramValue::FunctionLabel(name)
+                if !in_function && !is_local_label(&name) && !is_numeric_label(&name) =>
+            {
+                scope = name.clone();
+                out.push((ProgramValue::EndScope, span));
+                ProgramValue::FunctionLabel(name)
+            }
+            ProgramValue::FunctionLabel(name) => ProgramValue::FunctionLabel(qualify(name, &scope)),
+            ProgramValue::Constant(name, mut expr) => {
+                let _ = expr.visit_names(&mut |name| {
+                    *name = qualify(std::mem::take(name), &scope);
+                    Ok(())
+                });
+                ProgramValue::Constant(qualify(name, &scope), expr)
+            }
+            ProgramValue::Data(name, words) => ProgramValue::Data(qualify(name, &scope), words),
+            ProgramValue::Label(mut expr) => {
+                let _ = expr.visit_names(&mut |name| {
+                    *name = qualify(std::mem::take(name), &scope);
+                    Ok(())
+                });
+                ProgramValue::Label(expr)
+            }
+            value => value,
+        };
+        out.push((value, span));
+    }
+    if in_function {
+        bail!("Missing .endfn")
+    }
+    if let Some((_, span)) = out.last() {
+        let span = *span;
+        out.push((ProgramValue::EndScope, span));
+    }
+    Ok(out)
+}
+
+// an operand that names something, filled in once every name has a value.
+struct Fixup {
+    instruction: usize,
+    // LOOP's slot rather than its target.
+    slot: bool,
+    expr: Expr,
+}
+
+fn build_ir(value_stream: Vec<Spanned>, options: &AssemblerOptions) -> Result<ProgramIr> {
+    let mut ir = ProgramIr {
+        file: options.source_name.clone(),
+        ..Default::default()
+    };
+
+    // the first pass gathers every name the source defines. Constants can
+    // refer to labels, so they're only evaluated once every label has an
+    // address.
+    let mut symbols = Symbols::default();
+    let mut definitions: Vec<(SymbolId, Expr, Span)> = vec![];
+    let mut exports = HashSet::new();
+    let mut data_size = 0;
+    let mut after_constant_remapping = vec![];
+    for (value, span) in value_stream.into_iter() {
+        match value {
+            ProgramValue::Constant(name, expr) => {
+                let id = symbols.define(&name, span)?;
+                definitions.push((id, expr, span));
+            }
+            ProgramValue::Data(name, words) => {
+                let id = symbols.define(&name, span)?;
+                symbols.set_value(id, data_size);
+                ir.data.push(IrData {
+                    name,
+                    address: data_size,
+                    words,
+                    span,
+                });
+                data_size += ir.data.last().unwrap().words.len() as i64;
+            }
+            ProgramValue::Record(name, fields) => {
+                // the shape is the record's name, its fields are offsets
+                // named `:record.field`.
+                let shape = ir.records.len() as i64;
+                let id = symbols.define(&name, span)?;
+                symbols.set_value(id, shape);
+                for (offset, field) in fields.iter().enumerate() {
+                    let id = symbols.define(&format!("{name}.{field}"), span)?;
+                    symbols.set_value(id, offset as i64);
+                }
+                ir.records.push(IrRecord {
+                    name,
+                    shape,
+                    fields,
+                    span,
+                });
+            }
+            ProgramValue::Export(name) => {
+                if exports.insert(name.clone()) {
+                    ir.exports.push(name);
+                }
+            }
+            value => {
+                if let ProgramValue::FunctionLabel(name) = &value {
+                    let id = symbols.define(name, span)?;
+                    symbols.mark_code(id);
+                }
+                after_constant_remapping.push((value, span))
+            }
+        }
+    }
+
+    if options.strip_dead_code {
+        // labels only a constant mentions still have to be kept around.
+        let mut roots = exports.clone();
+        for (_, expr, _) in definitions.iter() {
+            roots.extend(expr.names().into_iter().map(str::to_string));
+        }
+        after_constant_remapping = strip_dead_code(after_constant_remapping, &roots);
+    }
+    if options.fuse_branches {
+        after_constant_remapping = fuse_branches(after_constant_remapping);
+    }
+
+    // the second pass gives every label its address and hangs operands off
+    // the instruction before them. Operands that name something are left for
+    // the backpatching pass at the end.
+    let mut fixups = vec![];
+    let mut instruction_number = 0;
+    let mut open_variables = vec![];
+    for (value, span) in after_constant_remapping.into_iter() {
+        match value {
+            ProgramValue::Module(file) => ir.modules.push(IrModule {
+                file,
+                address: instruction_number,
+            }),
+            ProgramValue::Org(address) if address < instruction_number => bail!(
+                "Line {}: .org {address} but we're already at {instruction_number}",
+                span.line
+            ),
+            ProgramValue::Org(_) | ProgramValue::Align(_) => {
+                let target = match value {
+                    ProgramValue::Org(address) => address,
+                    ProgramValue::Align(alignment) => {
+                        let remainder = instruction_number % alignment;
+                        instruction_number + (alignment - remainder) % alignment
+                    }
+                    _ => instruction_number,
+                };
+                while instruction_number < target {
+                    ir.instructions.push(IrInstruction {
+                        address: instruction_number,
+                        mnemonic: "nop".to_string(),
+                        opcode: Opcode::Nop,
+                        slot: None,
+                        operand: None,
+                        span,
+                    });
+                    instruction_number += 1;
+                }
+            }
+            ProgramValue::FunctionLabel(label) => {
+                let id = symbols.intern(&label);
+                symbols.set_value(id, instruction_number);
+                ir.labels.push(IrLabel {
+                    name: label,
+                    address: instruction_number,
+                    arity: None,
+                    span,
+                });
+            }
+            // always straight after the function's label.
+            ProgramValue::Function(arity) => {
+                if let Some(label) = ir.labels.last_mut() {
+                    label.arity = arity;
+                }
+            }
+            ProgramValue::EndFunction => {}
+            ProgramValue::Variable(name, slot) => open_variables.push(IrVariable {
+                name,
+                slot,
+                start: instruction_number,
+                end: instruction_number,
+                span,
+            }),
+            ProgramValue::EndScope => {
+                for mut variable in open_variables.drain(..) {
+                    variable.end = instruction_number;
+                    // a function stripped as dead code leaves nothing to name.
+                    if variable.start < variable.end {
+                        ir.variables.push(variable);
+                    }
+                }
+            }
+            ProgramValue::Instruction(opcode) => {
+                ir.instructions.push(IrInstruction {
+                    address: instruction_number,
+                    mnemonic: opcode.name().to_string(),
+                    opcode,
+                    slot: None,
+                    operand: None,
+                    span,
+                });
+                instruction_number += 1;
+            }
+            ProgramValue::Value(value) => {
+                let (index, slot) = operand_target(&ir, instruction_number, span)?;
+                *ir.instructions[index].operand_mut(slot) = Some(IrOperand {
+                    value,
+                    label: None,
+                    code: false,
+                });
+                instruction_number += 1;
+            }
+            ProgramValue::Label(expr) => {
+                let (instruction, slot) = operand_target(&ir, instruction_number, span)?;
+                fixups.push(Fixup {
+                    instruction,
+                    slot,
+                    expr,
+                });
+                instruction_number += 1;
+            }
+            value => bail!("Invalid value leaked through {value:?}"),
+        }
+    }
+
+    // everything has been defined by now, so a name that hasn't is a typo.
+    let uses = definitions
+        .iter()
+        .map(|(_, expr, span)| (expr, span.line))
+        .chain(
+            fixups
+                .iter()
+                .map(|fixup| (&fixup.expr, ir.instructions[fixup.instruction].span.line)),
+        );
+    for (expr, line) in uses {
+        if let Some(name) = expr
+            .names()
+            .into_iter()
+            .find(|name| !symbols.is_defined(name))
+        {
+            bail!("Line {line}: undefined symbol {name}")
+        }
+    }
+
+    // a define being overridden by the source is the only redefinition
+    // allowed, and the source's value wins.
+    let mut definition_of = vec![None; symbols.len()];
+    for (index, (id, _, _)) in definitions.iter().enumerate() {
+        definition_of[id.index()] = Some(index);
+    }
+    for (id, _, span) in definitions.iter() {
+        let value =
+            evaluate_constant(*id, &definitions, &definition_of, &mut symbols, &mut vec![])?;
+        ir.constants.push(IrConstant {
+            name: symbols.name(*id).to_string(),
+            value,
+            span: *span,
+        });
+    }
+
+    // constants that are just another name for a label stand for a code
+    // address too.
+    loop {
+        let mut changed = false;
+        for (id, expr, _) in definitions.iter() {
+            let Expr::Name(target) = expr else {
+                continue;
+            };
+            if !symbols.is_code(*id) && symbols.get(target).is_some_and(|t| symbols.is_code(t)) {
+                symbols.mark_code(*id);
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    // the backpatching pass.
+    for Fixup {
+        instruction,
+        slot,
+        expr,
+    } in fixups
+    {
+        let instruction = &mut ir.instructions[instruction];
+        let value = expr
+            .evaluate(&|name| symbols.value_of(name))
+            .with_context(|| format!("Line {}", instruction.span.line))?;
+        // LOOP's slot is a plain number, like a LOAD's.
+        match slot {
+            true => check_value_operand(instruction, &expr, &symbols)?,
+            false => check_operand_kind(instruction, &expr, &symbols)?,
+        }
+        let code = expr
+            .names()
+            .iter()
+            .any(|name| symbols.get(name).is_some_and(|id| symbols.is_code(id)));
+        *instruction.operand_mut(slot) = Some(IrOperand {
+            value,
+            label: Some(expr.to_string()),
+            code,
+        });
+    }
+    Ok(ir)
+}
+
+// the instruction an operand at `address` belongs to, and whether it's the
+// slot. Only LOOP has a word before its last operand.
+fn operand_target(ir: &ProgramIr, address: i64, span: Span) -> Result<(usize, bool)> {
+    let Some(instruction) = ir.instructions.last() else {
+        bail!("Operand before any instruction on line {}", span.line)
+    };
+    let position = (address - instruction.address) as usize;
+    let count = instruction.opcode.operand_count();
+    Ok((ir.instructions.len() - 1, position < count))
+}
+
+// jumps and calls have to go to a code label, and instructions that take a
+// plain number can't be given one, so a mixed up name is caught here rather
+// than jumping somewhere nonsensical. `push` takes either, closures need it.
+fn check_operand_kind(instruction: &IrInstruction, expr: &Expr, symbols: &Symbols) -> Result<()> {
+    let line = instruction.span.line;
+    let mnemonic = &instruction.mnemonic;
+    match instruction.opcode {
+        opcode if has_code_operand(opcode) => {
+            let is_code = |name: &&str| symbols.get(name).is_some_and(|id| symbols.is_code(id));
+            if !expr.names().iter().any(is_code) {
+                bail!("Line {line}: {mnemonic} needs a code label, but {expr} is a constant")
+            }
+        }
+        Opcode::Push => {}
+        _ => check_value_operand(instruction, expr, symbols)?,
+    }
+    Ok(())
+}
+
+fn check_value_operand(instruction: &IrInstruction, expr: &Expr, symbols: &Symbols) -> Result<()> {
+    if let Expr::Name(name) = expr {
+        if symbols.get(name).is_some_and(|id| symbols.is_code(id)) {
+            let line = instruction.span.line;
+            let mnemonic = &instruction.mnemonic;
+            bail!("Line {line}: {mnemonic} needs a value, but {name} is a code label")
+        }
+    }
+    Ok(())
+}
+
+// evaluate a constant after everything it depends on, remembering the results
+// in `symbols`. `path` is the chain of constants being evaluated, so a
+// constant that ends up depending on itself can be reported.
+fn evaluate_constant(
+    id: SymbolId,
+    definitions: &[(SymbolId, Expr, Span)],
+    definition_of: &[Option<usize>],
+    symbols: &mut Symbols,
+    path: &mut Vec<SymbolId>,
+) -> Result<i64> {
+    if let Some(value) = symbols.value(id) {
+        return Ok(value);
+    }
+    // only constants are evaluated, everything else has a value already.
+    let (_, expr, span) = &definitions[definition_of[id.index()].unwrap()];
+    if let Some(start) = path.iter().position(|seen| *seen == id) {
+        let chain: Vec<&str> = path[start..].iter().map(|id| symbols.name(*id)).collect();
+        bail!(
+            "Line {}: circular constant definition {} -> {}",
+            span.line,
+            chain.join(" -> "),
+            symbols.name(id)
+        )
+    }
+    path.push(id);
+    // anything that isn't a constant is a label or already known, undefined
+    // names were reported before evaluating anything.
+    for dependency in expr.names() {
+        let Some(dependency) = symbols.get(dependency) else {
+            continue;
+        };
+        if definition_of[dependency.index()].is_some() {
+            evaluate_constant(dependency, definitions, definition_of, symbols, path)?;
+        }
+    }
+    path.pop();
+    let value = expr
+        .evaluate(&|name| symbols.value_of(name))
+        .with_context(|| format!("Line {}", span.line))?;
+    symbols.set_value(id, value);
+    Ok(value)
+}
+
+// flatten the ir into words.
+pub fn lower(ir: &ProgramIr) -> Result<Program> {
+    // PUSHC is the same width as PUSH so pooling doesn't move any addresses.
+    let shapes = ir
+        .records
+        .iter()
+        .map(|record| record.fields.len() as i64)
+        .collect();
+    let (instructions, constants) = pool_constants(&ir.instructions, shapes);
+
+    let mut code = vec![];
+    for (opcode, operand) in instructions.into_iter() {
+        code.push(opcode.value());
+        code.extend(operand);
+    }
+    let symbols = ir
+        .labels
+        .iter()
+        .map(|label| Symbol {
+            name: label.name.clone(),
+            address: label.address,
+            arity: label.arity,
+        })
+        .collect();
+    // line numbers only cover the root module, the spans of anything linked in
+    // point into other files.
+    let root_end = ir.modules.first().map_or(i64::MAX, |module| module.address);
+    let debug_info = DebugInfo {
+        file: ir.file.clone(),
+        lines: ir
+            .instructions
+            .iter()
+            .filter(|instruction| instruction.address < root_end)
+            .map(|instruction| (instruction.address, instruction.span.line))
+            .collect(),
+        records: ir
+            .records
+            .iter()
+            .map(|record| RecordInfo {
+                name: record.name.clone(),
+                shape: record.shape,
+                fields: record.fields.clone(),
+            })
+            .collect(),
+        variables: ir
+            .variables
+            .iter()
+            .map(|variable| VariableInfo {
+                name: variable.name.clone(),
+                slot: variable.slot,
+                start: variable.start,
+                end: variable.end,
+            })
+            .collect(),
+    };
+    let mut parts = ProgramParts {
+        code,
+        constants,
+        data: ir.data.iter().flat_map(|data| data.words.clone()).collect(),
+        symbols,
+        debug_info,
+        ..Default::default()
+    };
+    parts.features = required_features(&parts);
+    Program::new(parts)
+}
+
+// the compare and JIF sequences compilers and people write, and the one
+// instruction each does the same job as. The result is never worse, so the
+// longest match wins. Anything between them, a label included, breaks the
+// sequence up, so nothing can jump into the middle of one.
+fn fuse_branches(values: Vec<Spanned>) -> Vec<Spanned> {
+    use Opcode::{Iseq, Isge, Iszero, Jeq, Jge, Jif, Jlt, Jne, Jnz, Jz, Not, Push};
+    use ProgramValue::{Instruction as I, Value as V};
+    let mut out: Vec<Spanned> = vec![];
+    let mut index = 0;
+    while index < values.len() {
+        let window: Vec<&ProgramValue> = values[index..].iter().take(5).map(|(v, _)| v).collect();
+        let fused = match window.as_slice() {
+            [I(Push), V(0), I(Iseq), I(Not), I(Jif), ..] => Some((5, Jnz)),
+            [I(Push), V(0), I(Iseq), I(Jif), ..] => Some((4, Jz)),
+            [I(Iseq), I(Not), I(Jif), ..] => Some((3, Jne)),
+            [I(Isge), I(Not), I(Jif), ..] => Some((3, Jlt)),
+            [I(Iseq), I(Jif), ..] => Some((2, Jeq)),
+            [I(Isge), I(Jif), ..] => Some((2, Jge)),
+            [I(Not | Iszero), I(Jif), ..] => Some((2, Jz)),
+            _ => None,
+        };
+        match fused {
+            // the JIF's operand follows, and becomes the fused jump's.
+            Some((count, opcode)) => {
+                out.push((I(opcode), values[index].1));
+                index += count;
+            }
+            None => {
+                out.push(values[index].clone());
+                index += 1;
+            }
+        }
+    }
+    out
+}
+
+// split the stream into blocks, each starting at a label, and keep only the
+// blocks reachable from the entry point or an exported label. A block is
+// reachable if something reachable names its label (jumps, calls, pushed
+// addresses) or if the block before it is reachable and falls through.
+fn strip_dead_code(values: Vec<Spanned>, exports: &HashSet<String>) -> Vec<Spanned> {
+    let mut blocks: Vec<Vec<Spanned>> = vec![vec![]];
+    for value in values.into_iter() {
+        if let ProgramValue::FunctionLabel(_) = value.0 {
+            blocks.push(vec![]);
+        }
+        blocks.last_mut().unwrap().push(value);
+    }
+
+    let mut block_by_label = HashMap::new();
+    for (index, block) in blocks.iter().enumerate() {
+        if let Some((ProgramValue::FunctionLabel(label), _)) = block.first() {
+            block_by_label.insert(label.clone(), index);
+        }
+    }
+
+    let mut reachable = vec![false; blocks.len()];
+    let mut worklist = vec![0];
+    for export in exports.iter() {
+        if let Some(index) = block_by_label.get(export) {
+            worklist.push(*index);
+        }
+    }
+
+    while let Some(index) = worklist.pop() {
+        if reachable[index] {
+            continue;
+        }
+        reachable[index] = true;
+
+        let mut last_instruction = None;
+        for (value, _) in blocks[index].iter() {
+            match value {
+                ProgramValue::Instruction(instruction) => last_instruction = Some(*instruction),
+                ProgramValue::Label(expr) => {
+                    for name in expr.names() {
+                        if let Some(target) = block_by_label.get(name) {
+                            worklist.push(*target);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let falls_through = !matches!(
+            last_instruction,
+            Some(Opcode::Jmp | Opcode::Ret | Opcode::Iret | Opcode::Halt)
+        );
+        if falls_through && index + 1 < blocks.len() {
+            worklist.push(index + 1);
+        }
+    }
+
+    // module markers and layout directives have to survive so addresses stay
+    // attributed to the right file and padded the way the source asked, and
+    // scope ends so a dead function's variables don't run on into the next.
+    blocks
+        .into_iter()
+        .zip(reachable)
+        .flat_map(|(block, reachable)| {
+            block.into_iter().filter(move |(value, _)| {
+                reachable
+                    || matches!(
+                        value,
+                        ProgramValue::Module(_)
+                            | ProgramValue::Org(_)
+                            | ProgramValue::Align(_)
+                            | ProgramValue::EndScope
+                    )
+            })
+        })
+        .collect()
+}
+
+struct Module {
+    path: PathBuf,
+    values: Vec<Spanned>,
+    exports: HashSet<String>,
+}
+
+// load the root module and everything it imports, rename each module's private
+// names so they can't collide, and lay the modules out one after another with
+// the root first so the entry point stays at address 0. Only the root module's
+// exports survive, so dead code stripping can drop unused library functions.
+fn link(root: &Path, options: &AssemblerOptions) -> Result<Vec<Spanned>> {
+    let mut modules: Vec<Module> = vec![];
+    let mut index_by_path: HashMap<PathBuf, usize> = HashMap::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(path) = pending.pop() {
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("Could not find module {}", path.display()))?;
+        if index_by_path.contains_key(&canonical) {
+            continue;
+        }
+        tracing::debug!(module = %path.display(), "loading");
+        let source = std::fs::read_to_string(&path)
+            .with_context(|| format!("Could not load module {}", path.display()))?;
+        let values = parse_values(&source, options)
+            .with_context(|| format!("In module {}", path.display()))?;
+
+        let mut exports = HashSet::new();
+        for (value, _) in values.iter() {
+            match value {
+                ProgramValue::Export(name) => {
+                    exports.insert(name.clone());
+                }
+                ProgramValue::Import(_, import) => {
+                    let directory = path.parent().unwrap_or(Path::new(""));
+                    pending.push(directory.join(import));
+                }
+                _ => {}
+            }
+        }
+        index_by_path.insert(canonical, modules.len());
+        modules.push(Module {
+            path,
+            values,
+            exports,
+        });
+    }
+
+    let mut exported_by: HashMap<&str, &Path> = HashMap::new();
+    for module in modules.iter() {
+        for name in module.exports.iter() {
+            if let Some(other) = exported_by.insert(name, &module.path) {
+                bail!(
+                    "{name} is exported by both {} and {}",
+                    other.display(),
+                    module.path.display()
+                )
+            }
+        }
+    }
+
+    // what each module can refer to: its own names, and what it imports.
+    let mut scopes = vec![];
+    for module in modules.iter() {
+        let directory = module.path.parent().unwrap_or(Path::new(""));
+        let mut imports = HashSet::new();
+        let mut local = HashSet::new();
+        for (value, span) in module.values.iter() {
+            match value {
+                ProgramValue::Import(name, import) => {
+                    let target = &modules[index_by_path[&directory.join(import).canonicalize()?]];
+                    if !target.exports.contains(name) {
+                        bail!(
+                            "{} line {}: {} doesn't export {name}",
+                            module.path.display(),
+                            span.line,
+                            target.path.display()
+                        )
+                    }
+                    imports.insert(name.clone());
+                    // an imported record brings its field names along.
+                    for (value, _) in target.values.iter() {
+                        if let ProgramValue::Record(record, fields) = value {
+                            if record == name {
+                                imports.extend(fields.iter().map(|f| format!("{name}.{f}")));
+                            }
+                        }
+                    }
+                }
+                ProgramValue::FunctionLabel(name)
+                | ProgramValue::Constant(name, _)
+                | ProgramValue::Data(name, _) => {
+                    local.insert(name.clone());
+                }
+                ProgramValue::Record(name, fields) => {
+                    let fields = fields.iter().map(|field| format!("{name}.{field}"));
+                    // and so does an exported one.
+                    if module.exports.contains(name) {
+                        imports.extend(fields.clone());
+                    }
+                    local.insert(name.clone());
+                    local.extend(fields);
+                }
+                _ => {}
+            }
+        }
+        scopes.push((imports, local));
+    }
+
+    // the values are moved over as they are, only names private to a
+    // module other than the root get renamed.
+    let mut linked = vec![];
+    let mut defined_in: HashMap<String, usize> = HashMap::new();
+    for (index, (module, (imports, local))) in modules.into_iter().zip(scopes).enumerate() {
+        let stem = module
+            .path
+            .file_stem()
+            .map_or("module".into(), |stem| stem.to_string_lossy().into_owned());
+        let rename = |name: &mut String| -> Result<()> {
+            if module.exports.contains(name) || imports.contains(name) {
+                Ok(())
+            } else if !local.contains(name) {
+                bail!("{} uses undeclared {name}", module.path.display())
+            } else if index == 0 {
+                Ok(())
+            } else {
+                *name = format!(":{stem}/{}", &name[1..]);
+                Ok(())
+            }
+        };
+
+        if index > 0 {
+            linked.push((
+                ProgramValue::Module(module.path.display().to_string()),
+                Span::default(),
+            ));
+        }
+        for (mut value, span) in module.values {
+            match &mut value {
+                ProgramValue::Import(..) => continue,
+                ProgramValue::Export(_) if index > 0 => continue,
+                ProgramValue::FunctionLabel(name)
+                | ProgramValue::Data(name, _)
+                | ProgramValue::Record(name, _) => rename(name)?,
+                ProgramValue::Constant(name, expr) => {
+                    expr.visit_names(&mut |name| rename(name))?;
+                    rename(name)?
+                }
+                ProgramValue::Label(expr) => expr.visit_names(&mut |name| rename(name))?,
+                _ => {}
+            }
+            if let ProgramValue::FunctionLabel(name)
+            | ProgramValue::Constant(name, _)
+            | ProgramValue::Data(name, _)
+            | ProgramValue::Record(name, _) = &value
+            {
+                if *defined_in.entry(name.clone()).or_insert(index) != index {
+                    bail!("{name} is defined in more than one module")
+                }
+            }
+            linked.push((value, span));
+        }
+    }
+    Ok(linked)
+}
+
+// `constants` is what the pool starts out with, which pooled immediates are
+// never shared with.
+fn pool_constants(
+    instructions: &[IrInstruction],
+    mut constants: Vec<i64>,
+) -> (Vec<(Opcode, Vec<i64>)>, Vec<i64>) {
+    // PUSHC is no shorter than PUSH, so only immediates too big for a
+    // smaller encoding are worth a pool word.
+    let pooled = |instruction: &IrInstruction| match (instruction.opcode, &instruction.operand) {
+        (Opcode::Push, Some(operand))
+            if !operand.code && operand.value.unsigned_abs() > POOL_SIZE_THRESHOLD =>
+        {
+            Some(operand.value)
+        }
+        _ => None,
+    };
+
+    let mut pool_indices = HashMap::new();
+    let mut out = vec![];
+    for instruction in instructions.iter() {
+        match pooled(instruction) {
+            Some(immediate) => {
+                let index = *pool_indices.entry(immediate).or_insert_with(|| {
+                    constants.push(immediate);
+                    constants.len() as i64 - 1
+                });
+                out.push((Opcode::Pushc, vec![index]));
+            }
+            _ => {
+                let operands = instruction.slot.iter().chain(&instruction.operand);
+                out.push((
+                    instruction.opcode,
+                    operands.map(|operand| operand.value).collect(),
+                ))
+            }
+        }
+    }
+    (out, constants)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::{
+        CALL, DLOAD, DUP, HALT, ISGE, JIF, JMP, JNE, JZ, MUL, NOP, POP, PRNCHR, PUSH, PUSHC, RET,
+        RGET, RNEW, SUB,
+    };
+    use crate::program::{FEATURE_CONSTANT_POOL, FEATURE_DATA, FEATURE_RECORDS};
+
+    #[test]
+    fn assembles_from_a_reader() {
+        let source = "push 2\r\n:loop\npush 3\nmul\nhalt";
+        let options = AssemblerOptions::default();
+        let streamed = assemble_reader(source.as_bytes(), &options).unwrap();
+        let parsed = parse_program(source.to_string(), &options).unwrap();
+        assert_eq!(parsed.code(), streamed.code());
+        let error = assemble_reader("push 1\nfrob".as_bytes(), &options).unwrap_err();
+        assert_eq!("Line 2", error.to_string());
+    }
+
+    #[test]
+    fn pools_large_immediates() {
+        let program = parse_program(
+            "push 9999999999\npush 1\nhalt".to_string(),
+            &AssemblerOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(vec![PUSHC, 0, PUSH, 1, HALT], program.code());
+        assert_eq!(vec![9999999999], program.constants());
+        assert_eq!(vec![FEATURE_CONSTANT_POOL], program.features());
+    }
+
+    #[test]
+    fn pools_only_large_values() {
+        // reuse alone doesn't make PUSHC any shorter.
+        let program = parse_program(
+            "push 7\npush 7\npush 7\npush 7\nhalt".to_string(),
+            &AssemblerOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            vec![PUSH, 7, PUSH, 7, PUSH, 7, PUSH, 7, HALT],
+            program.code()
+        );
+        assert!(program.constants().is_empty());
+
+        // code addresses stay in the code, where relocation can see them.
+        let program = parse_program(
+            ":big 9999999999\npush :big\n:f\npush :f+9999999999\nhalt".to_string(),
+            &AssemblerOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(vec![PUSHC, 0, PUSH, 10000000001, HALT], program.code());
+        assert_eq!(vec![9999999999], program.constants());
+    }
+
+    #[test]
+    fn strips_unreferenced_functions() {
+        let source = "push 1\ncall :used\nhalt\n:unused\npush 2\nret\n:used\nret";
+        let options = AssemblerOptions {
+            strip_dead_code: true,
+            ..Default::default()
+        };
+        let program = parse_program(source.to_string(), &options).unwrap();
+        assert_eq!(vec![PUSH, 1, CALL, 5, HALT, RET], program.code());
+    }
+
+    #[test]
+    fn fuses_branches() {
+        let source = "push 1\npush 2\niseq\nnot\njif :end\npush 0\npush 0\niseq\njif :end\n\
+                      push 3\nisge\n:end\njif :end\nhalt";
+        let options = AssemblerOptions {
+            fuse_branches: true,
+            ..Default::default()
+        };
+        let program = parse_program(source.to_string(), &options).unwrap();
+        assert_eq!(
+            vec![PUSH, 1, PUSH, 2, JNE, 13, PUSH, 0, JZ, 13, PUSH, 3, ISGE, JIF, 13, HALT],
+            program.code()
+        );
+    }
+
+    #[test]
+    fn keeps_exported_and_fallthrough_blocks() {
+        let source =
+            ".export :lib\npush 1\njif :skip\n:inner\npop\n:skip\nhalt\n:lib\nret\n:dead\nret";
+        let options = AssemblerOptions {
+            strip_dead_code: true,
+            ..Default::default()
+        };
+        let program = parse_program(source.to_string(), &options).unwrap();
+        assert_eq!(vec![PUSH, 1, JIF, 5, POP, HALT, RET], program.code());
+    }
+
+    #[test]
+    fn labels_and_constants_are_not_interchangeable() {
+        let options = AssemblerOptions::default();
+        let error = parse_ir(":limit 10\n:loop\njmp :limit", &options).unwrap_err();
+        assert_eq!(
+            "Line 3: jmp needs a code label, but :limit is a constant",
+            error.to_string()
+        );
+        let error = parse_ir(":loop\nload :loop", &options).unwrap_err();
+        assert_eq!(
+            "Line 2: load needs a value, but :loop is a code label",
+            error.to_string()
+        );
+        let error = parse_ir(":count 0\n:loop\nloop 0 :count", &options).unwrap_err();
+        assert_eq!(
+            "Line 3: loop needs a code label, but :count is a constant",
+            error.to_string()
+        );
+        let error = parse_ir(":loop\nloop :loop :loop", &options).unwrap_err();
+        assert_eq!(
+            "Line 2: loop needs a value, but :loop is a code label",
+            error.to_string()
+        );
+        // aliases of labels are addresses too, and push takes anything.
+        let source = ":main\n:entry :main\npush :main\npush 1\njif :entry\ncall :entry+2\nhalt";
+        assert!(parse_ir(source, &options).is_ok());
+    }
+
+    #[test]
+    fn label_and_instruction_on_one_line() {
+        let source = "push 3\n:loop push 1\nsub\ndup\njif :loop\n:two 2\nhalt";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        assert_eq!(
+            vec![PUSH, 3, PUSH, 1, SUB, DUP, JIF, 2, HALT],
+            program.code()
+        );
+    }
+
+    #[test]
+    fn lexes_tabs_and_trailing_comments() {
+        let source = "push\t1;; one\n.string :s \"a ;; b\" ;; text\nhalt";
+        let ir = parse_ir(source, &AssemblerOptions::default()).unwrap();
+        assert_eq!(
+            Span {
+                line: 1,
+                start: 0,
+                end: 6
+            },
+            ir.instructions[0].span
+        );
+        assert_eq!(vec![97, 32, 59, 59, 32, 98], ir.data[0].words);
+        assert!(parse_ir(".string :s \"open", &AssemblerOptions::default()).is_err());
+    }
+
+    #[test]
+    fn ir_keeps_labels_and_spans() {
+        let source = ":a 3\n  push :a\n:f\n  call :f";
+        let ir = parse_ir(source, &AssemblerOptions::default()).unwrap();
+        assert_eq!(
+            Span {
+                line: 2,
+                start: 2,
+                end: 9
+            },
+            ir.instructions[0].span
+        );
+        assert_eq!(
+            Some(IrOperand {
+                value: 3,
+                label: Some(":a".to_string()),
+                code: false,
+            }),
+            ir.instructions[0].operand
+        );
+        assert_eq!(2, ir.labels[0].address);
+        assert_eq!(":a", ir.constants[0].name);
+        assert_eq!("call", ir.instructions[1].mnemonic);
+    }
+
+    fn write_modules(name: &str, modules: &[(&str, &str)]) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("biteycode-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        for (file, source) in modules.iter() {
+            std::fs::write(directory.join(file), source).unwrap();
+        }
+        directory
+    }
+
+    #[test]
+    fn links_imported_modules() {
+        let directory = write_modules(
+            "link",
+            &[
+                (
+                    "main.bvm",
+                    ".import :double from \"lib.bvm\"\n:loop\npush 2\ncall :double\nhalt",
+                ),
+                (
+                    "lib.bvm",
+                    ".export :double\n:double\njmp :loop\n:loop\npush 2\nmul\nret",
+                ),
+            ],
+        );
+        let program =
+            assemble_file(&directory.join("main.bvm"), &AssemblerOptions::default()).unwrap();
+        assert_eq!(
+            vec![PUSH, 2, CALL, 5, HALT, JMP, 7, PUSH, 2, MUL, RET],
+            program.code()
+        );
+        let names: Vec<&str> = program.symbols().iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(vec![":loop", ":double", ":lib/loop"], names);
+        // only the root module has line numbers.
+        let lines: Vec<i64> = program.debug_info().lines.keys().copied().collect();
+        assert_eq!(vec![0, 2, 4], lines);
+    }
+
+    #[test]
+    fn import_needs_an_export() {
+        let directory = write_modules(
+            "export",
+            &[
+                (
+                    "main.bvm",
+                    ".import :hidden from \"lib.bvm\"\ncall :hidden\nhalt",
+                ),
+                ("lib.bvm", ":hidden\nret"),
+            ],
+        );
+        let err =
+            assemble_file(&directory.join("main.bvm"), &AssemblerOptions::default()).unwrap_err();
+        assert!(format!("{err:#}").contains("doesn't export :hidden"));
+        assert!(parse_ir(".import :a from \"lib.bvm\"", &AssemblerOptions::default()).is_err());
+    }
+
+    #[test]
+    fn local_labels_are_scoped_per_function() {
+        let source = ":a\n:.loop\njmp :.loop\n:b\n:.loop\njmp :.loop";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        assert_eq!(vec![JMP, 0, JMP, 2], program.code());
+        let names: Vec<&str> = program.symbols().iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(vec![":a", ":a.loop", ":b", ":b.loop"], names);
+    }
+
+    #[test]
+    fn function_directives() {
+        // the inner label doesn't start a new scope inside the .fn.
+        let source = ".fn max args=2 rets=1\n:inner\n:.end\nret\n.endfn\n:after\nhalt";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        let symbols: Vec<(&str, Option<Arity>)> = program
+            .symbols()
+            .iter()
+            .map(|s| (s.name.as_str(), s.arity))
+            .collect();
+        assert_eq!(
+            vec![
+                (":max", Some(Arity { args: 2, rets: 1 })),
+                (":inner", None),
+                (":max.end", None),
+                (":after", None),
+            ],
+            symbols
+        );
+
+        for bad in [
+            ".fn :a\n.fn :b\n.endfn\n.endfn",
+            ".endfn",
+            ".fn :a\nret",
+            ".fn :a args=x\n.endfn",
+            ".fn :a locals=1\n.endfn",
+        ] {
+            assert!(parse_program(bad.to_string(), &AssemblerOptions::default()).is_err());
+        }
+    }
+
+    #[test]
+    fn numeric_labels() {
+        let source = ":1\njmp :1f\n:1\njmp :1b\njmp :1f\n:1\nhalt";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        assert_eq!(vec![JMP, 2, JMP, 2, JMP, 6, HALT], program.code());
+        assert!(parse_program("jmp :2b".to_string(), &AssemblerOptions::default()).is_err());
+    }
+
+    #[test]
+    fn label_arithmetic() {
+        let source = "push :end-:table\njmp :table+1\n:table\nhalt\nhalt\n:end";
+        let ir = parse_ir(source, &AssemblerOptions::default()).unwrap();
+        assert_eq!(
+            Some(IrOperand {
+                value: 2,
+                label: Some(":end-:table".to_string()),
+                code: true,
+            }),
+            ir.instructions[0].operand
+        );
+        assert_eq!(
+            Some(5),
+            ir.instructions[1].operand.as_ref().map(|o| o.value)
+        );
+    }
+
+    #[test]
+    fn org_and_align_pad_with_nops() {
+        let source = "push 1\n.align 4\n:table\nhalt\n.org 7\nhalt";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        assert_eq!(
+            vec![PUSH, 1, NOP, NOP, HALT, NOP, NOP, HALT],
+            program.code()
+        );
+        assert_eq!(
+            Some(":table"),
+            program.symbol_at(4).map(|s| s.name.as_str())
+        );
+        assert!(parse_program(
+            "halt\nhalt\n.org 1".to_string(),
+            &AssemblerOptions::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn string_directives() {
+        let source = ".words :nums 7 -1\n.asciz :hi \"hi \\\"x\\\"\\n\"\n.lstring :ok \"ok\"\npush :hi\ndload\nprnchr\npush :ok\nhalt";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        assert_eq!(
+            vec![7, -1, 104, 105, 32, 34, 120, 34, 10, 0, 2, 111, 107],
+            program.data()
+        );
+        assert_eq!(vec![PUSH, 2, DLOAD, PRNCHR, PUSH, 10, HALT], program.code());
+        assert_eq!(vec![FEATURE_DATA], program.features());
+        assert!(parse_program(
+            ".string :bad \"\\q\"".to_string(),
+            &AssemblerOptions::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn conditional_assembly() {
+        let source = ".ifdef :debug\npush 1\n.if :level-2\npush 2\n.else\npush 3\n.endif\n.else\npush 4\n.endif\nhalt";
+        let assemble = |defines: &[(&str, i64)]| {
+            let options = AssemblerOptions {
+                defines: defines
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), *value))
+                    .collect(),
+                ..Default::default()
+            };
+            parse_program(source.to_string(), &options).map(|p| p.code().to_vec())
+        };
+        assert_eq!(vec![PUSH, 4, HALT], assemble(&[]).unwrap());
+        assert_eq!(
+            vec![PUSH, 1, PUSH, 3, HALT],
+            assemble(&[(":debug", 1), (":level", 2)]).unwrap()
+        );
+        assert_eq!(
+            vec![PUSH, 1, PUSH, 2, HALT],
+            assemble(&[(":debug", 1), (":level", 5)]).unwrap()
+        );
+        // :level isn't defined, so the inner .if can't be evaluated.
+        assert!(assemble(&[(":debug", 1)]).is_err());
+        assert!(parse_program(".if 1\nhalt".to_string(), &AssemblerOptions::default()).is_err());
+    }
+
+    #[test]
+    fn constants_can_use_constants() {
+        // used before it's defined, and depending on a label.
+        let source = "push :area\npush :size\nhalt\n:area :width*:width\n:width 8\n:size :end-:start\n:start\nhalt\n:end";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        assert_eq!(vec![PUSH, 64, PUSH, 1, HALT, HALT], program.code());
+
+        let err = parse_program(
+            ":a :b+1\n:b :c\n:c :a\nhalt".to_string(),
+            &AssemblerOptions::default(),
+        )
+        .unwrap_err();
+        assert!(format!("{err:#}").contains(":a -> :b -> :c -> :a"));
+    }
+
+    #[test]
+    fn symbol_diagnostics() {
+        let options = AssemblerOptions::default();
+        let error = |source: &str| parse_ir(source, &options).unwrap_err().to_string();
+        assert_eq!(
+            "Line 3: undefined symbol :lop",
+            error(":loop\npush 1\njif :lop")
+        );
+        assert_eq!("Line 1: undefined symbol :b", error(":a :b+1\nhalt"));
+        assert_eq!(
+            "Line 3: :x is defined twice, first on line 1",
+            error(":x\nhalt\n:x 4")
+        );
+        assert_eq!(
+            "Line 2: :point.x is defined twice, first on line 1",
+            error(":point.x 1\n.record :point x")
+        );
+        assert_eq!(
+            "Line 1: circular constant definition :a -> :a",
+            error(":a :a\nhalt")
+        );
+        // defines can still be overridden.
+        let options = AssemblerOptions {
+            defines: [(":debug".to_string(), 1)].into_iter().collect(),
+            ..Default::default()
+        };
+        let program = parse_program(":debug 0\npush :debug".to_string(), &options).unwrap();
+        assert_eq!(vec![PUSH, 0], program.code());
+    }
+
+    #[test]
+    fn records() {
+        // the shapes come first in the pool, ahead of pooled immediates.
+        let source = "push 9999999999\n.record :point x y\n.record :unit\nrnew :point\nrget :point.y\nrnew :unit\nhalt";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        assert_eq!(vec![2, 0, 9999999999], program.constants());
+        assert_eq!(
+            vec![PUSHC, 2, RNEW, 0, RGET, 1, RNEW, 1, HALT],
+            program.code()
+        );
+        let records = &program.debug_info().records;
+        assert_eq!((":point", 0), (records[0].name.as_str(), records[0].shape));
+        assert_eq!(vec!["x", "y"], records[0].fields);
+        assert!(program.features().contains(&FEATURE_RECORDS.to_string()));
+
+        for bad in [".record :p x x", ".record :p :x", ".record p x"] {
+            assert!(parse_program(bad.to_string(), &AssemblerOptions::default()).is_err());
+        }
+    }
+
+    #[test]
+    fn records_source_lines() {
+        let options = AssemblerOptions {
+            source_name: Some("lines.bc".to_string()),
+            ..Default::default()
+        };
+        let program = parse_program("push 1\n\n;; hi\nhalt".to_string(), &options).unwrap();
+        let debug_info = program.debug_info();
+        assert_eq!(Some("lines.bc"), debug_info.file.as_deref());
+        assert_eq!(
+            vec![(&0, &1), (&2, &4)],
+            debug_info.lines.iter().collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,174 @@
+// operand expressions like `:table+2` or `:end-:start`. Tokens can't contain
+// spaces, so neither can expressions.
+
+use std::fmt;
+
+use anyhow::{bail, Result};
+
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum Expr {
+    Number(i64),
+    Name(String),
+    Binary(Box<Expr>, Operator, Box<Expr>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+}
+
+impl Operator {
+    fn from_char(c: char) -> Option<Self> {
+        match c {
+            '+' => Some(Self::Add),
+            '-' => Some(Self::Subtract),
+            '*' => Some(Self::Multiply),
+            _ => None,
+        }
+    }
+
+    fn symbol(self) -> char {
+        match self {
+            Self::Add => '+',
+            Self::Subtract => '-',
+            Self::Multiply => '*',
+        }
+    }
+}
+
+impl Expr {
+    // sums of products of names and numbers, evaluated left to right.
+    pub(super) fn parse(text: &str) -> Result<Self> {
+        let mut terms = vec![];
+        let mut operators = vec![];
+        let mut start = 0;
+        for (index, c) in text.char_indices() {
+            // a leading minus belongs to the number.
+            if index == start {
+                continue;
+            }
+            if let Some(operator) = Operator::from_char(c) {
+                terms.push(Self::parse_term(&text[start..index])?);
+                operators.push(operator);
+                start = index + 1;
+            }
+        }
+        terms.push(Self::parse_term(&text[start..])?);
+
+        // fold the products first, then the sums.
+        let mut terms = terms.into_iter();
+        let mut sums = vec![terms.next().unwrap()];
+        let mut sum_operators = vec![];
+        for (operator, term) in operators.into_iter().zip(terms) {
+            match operator {
+                Operator::Multiply => {
+                    let left = sums.pop().unwrap();
+                    sums.push(Self::Binary(Box::new(left), operator, Box::new(term)));
+                }
+                operator => {
+                    sum_operators.push(operator);
+                    sums.push(term);
+                }
+            }
+        }
+        let mut sums = sums.into_iter();
+        let mut expr = sums.next().unwrap();
+        for (operator, term) in sum_operators.into_iter().zip(sums) {
+            expr = Self::Binary(Box::new(expr), operator, Box::new(term));
+        }
+        Ok(expr)
+    }
+
+    fn parse_term(text: &str) -> Result<Self> {
+        if text.starts_with(':') && text.len() > 1 {
+            return Ok(Self::Name(text.to_string()));
+        }
+        match text.parse::<i64>() {
+            Ok(number) => Ok(Self::Number(number)),
+            Err(_) => bail!("Expected a label or a number, got {text:?}"),
+        }
+    }
+
+    // call `f` on every name, so passes can rename them in place.
+    pub(super) fn visit_names(
+        &mut self,
+        f: &mut impl FnMut(&mut String) -> Result<()>,
+    ) -> Result<()> {
+        match self {
+            Self::Number(_) => Ok(()),
+            Self::Name(name) => f(name),
+            Self::Binary(left, _, right) => {
+                left.visit_names(f)?;
+                right.visit_names(f)
+            }
+        }
+    }
+
+    pub(super) fn names(&self) -> Vec<&str> {
+        match self {
+            Self::Number(_) => vec![],
+            Self::Name(name) => vec![name.as_str()],
+            Self::Binary(left, _, right) => {
+                let mut names = left.names();
+                names.extend(right.names());
+                names
+            }
+        }
+    }
+
+    pub(super) fn evaluate(&self, lookup: &impl Fn(&str) -> Option<i64>) -> Result<i64> {
+        match self {
+            Self::Number(number) => Ok(*number),
+            Self::Name(name) => match lookup(name) {
+                Some(value) => Ok(value),
+                None => bail!("Used undeclared constant {name}"),
+            },
+            Self::Binary(left, operator, right) => {
+                let (left, right) = (left.evaluate(lookup)?, right.evaluate(lookup)?);
+                let result = match operator {
+                    Operator::Add => left.checked_add(right),
+                    Operator::Subtract => left.checked_sub(right),
+                    Operator::Multiply => left.checked_mul(right),
+                };
+                match result {
+                    Some(result) => Ok(result),
+                    None => bail!("{self} overflows"),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Number(number) => write!(f, "{number}"),
+            Self::Name(name) => write!(f, "{name}"),
+            Self::Binary(left, operator, right) => {
+                write!(f, "{left}{}{right}", operator.symbol())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn precedence_and_display() {
+        let expr = Expr::parse(":a+2*:b-1").unwrap();
+        let lookup = |name: &str| match name {
+            ":a" => Some(10),
+            ":b" => Some(3),
+            _ => None,
+        };
+        assert_eq!(15, expr.evaluate(&lookup).unwrap());
+        assert_eq!(":a+2*:b-1", expr.to_string());
+        assert_eq!(vec![":a", ":b"], expr.names());
+        assert!(Expr::parse(":a+").is_err());
+        assert!(Expr::parse(":c-1").unwrap().evaluate(&lookup).is_err());
+    }
+}
@@ -0,0 +1,145 @@
+// the canonical layout for assembly source, what `biteycode fmt` writes.
+// Labels, constants and directives start at column 0, instructions are
+// indented with their mnemonic lowercased and operands lined up, and trailing
+// comments line up after that. Whole line comments take the indentation of
+// the code under them, and runs of blank lines become one.
+
+use anyhow::{Context, Result};
+
+use super::lexer::{lex, Token, TokenKind};
+use super::parse_line;
+use crate::cpu::Opcode;
+
+const INDENT: &str = "    ";
+// wide enough for the longest mnemonic, `callclos`.
+const MNEMONIC_WIDTH: usize = 8;
+// trailing comments start here unless the code runs past it.
+const COMMENT_COLUMN: usize = 28;
+
+pub fn format_source(source: &str) -> Result<String> {
+    // every line has to parse, there's no canonical layout for garbage.
+    let mut parsed = vec![];
+    let mut lines: Vec<(String, Option<&str>)> = vec![];
+    for (index, line) in source.lines().enumerate() {
+        let tokens = lex(line)
+            .and_then(|tokens| {
+                parse_line(&tokens, &mut parsed)?;
+                Ok(tokens)
+            })
+            .with_context(|| format!("Line {}", index + 1))?;
+        parsed.clear();
+        lines.push(format_line(&tokens));
+    }
+
+    let mut out = String::new();
+    let mut blank = true;
+    for (index, (code, comment)) in lines.iter().enumerate() {
+        let line = match (code.is_empty(), comment) {
+            (true, None) => {
+                // no blank lines at the top, and only one in a row.
+                if !blank {
+                    out.push('\n');
+                }
+                blank = true;
+                continue;
+            }
+            (true, Some(comment)) => {
+                let indent = lines[index..]
+                    .iter()
+                    .find(|(code, _)| !code.is_empty())
+                    .map_or("", |(code, _)| match code.starts_with(INDENT) {
+                        true => INDENT,
+                        false => "",
+                    });
+                format!("{indent}{comment}")
+            }
+            (false, None) => code.clone(),
+            (false, Some(comment)) => {
+                // a label split off an instruction is on a line of its own.
+                let last_line = code.rsplit('\n').next().unwrap_or(code);
+                let padding = COMMENT_COLUMN.saturating_sub(last_line.len()).max(1);
+                format!("{code}{:padding$}{comment}", "")
+            }
+        };
+        out.push_str(&line);
+        out.push('\n');
+        blank = false;
+    }
+    // and none at the bottom.
+    while out.ends_with("\n\n") {
+        out.pop();
+    }
+    Ok(out)
+}
+
+// the code part of a line laid out, and its comment if it has one.
+fn format_line<'a>(tokens: &[Token<'a>]) -> (String, Option<&'a str>) {
+    let comment = tokens
+        .last()
+        .filter(|token| token.kind == TokenKind::Comment)
+        .map(|token| token.text);
+    let mut words = tokens
+        .iter()
+        .filter(|token| token.kind != TokenKind::Comment)
+        .map(|token| token.text);
+    let Some(first) = words.next() else {
+        return (String::new(), comment);
+    };
+    let code = match Opcode::from_mnemonic(first) {
+        Some(_) => format_instruction(first, words.next()),
+        // `:loop load 0` puts the label on a line of its own.
+        None if first.starts_with(':') => match words.next() {
+            Some(mnemonic) if Opcode::from_mnemonic(mnemonic).is_some() => {
+                format!("{first}\n{}", format_instruction(mnemonic, words.next()))
+            }
+            second => std::iter::once(first)
+                .chain(second)
+                .chain(words)
+                .collect::<Vec<&str>>()
+                .join(" "),
+        },
+        // string literals come through whole, exactly as written.
+        None => std::iter::once(first)
+            .chain(words)
+            .collect::<Vec<&str>>()
+            .join(" "),
+    };
+    (code, comment)
+}
+
+fn format_instruction(mnemonic: &str, operand: Option<&str>) -> String {
+    let mnemonic = mnemonic.to_lowercase();
+    match operand {
+        Some(operand) => format!("{INDENT}{mnemonic:<MNEMONIC_WIDTH$} {operand}"),
+        None => format!("{INDENT}{mnemonic}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn canonical_layout() {
+        let source = "\n;; registers\n:a 0\nPUSH  6 ;; six\n\n\n  ;; the max\n:max\nSTORE :a\n  Callclos\n.string :s   \"a  ;; b\"\n\n";
+        let expected = ";; registers\n:a 0\n    push     6              ;; six\n\n;; the max\n:max\n    store    :a\n    callclos\n.string :s \"a  ;; b\"\n";
+        let formatted = format_source(source).unwrap();
+        assert_eq!(expected, formatted);
+        assert_eq!(formatted, format_source(&formatted).unwrap());
+    }
+
+    #[test]
+    fn splits_labels_off_instructions() {
+        let formatted = format_source(":loop LOAD 0 ;; top\n:two 2").unwrap();
+        assert_eq!(
+            ":loop\n    load     0              ;; top\n:two 2\n",
+            formatted
+        );
+    }
+
+    #[test]
+    fn refuses_bad_source() {
+        let err = format_source("push 1\nfrobnicate").unwrap_err();
+        assert_eq!("Line 2", err.to_string());
+    }
+}
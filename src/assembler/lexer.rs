@@ -0,0 +1,98 @@
+// splits a line of source into tokens, each knowing the bytes of the line it
+// came from. Words run up to whitespace, string literals up to their closing
+// quote whatever's inside, and a `;;` outside a string comments out the rest
+// of the line, even straight after a word.
+
+use std::ops::Range;
+
+use anyhow::{bail, Result};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum TokenKind {
+    // mnemonics, labels, numbers, directives and expressions.
+    Word,
+    // a double quoted literal, quotes and escapes still in it.
+    String,
+    // from `;;` to the end of the line.
+    Comment,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+    // byte offsets into the line.
+    pub span: Range<usize>,
+}
+
+pub(super) fn lex(line: &str) -> Result<Vec<Token<'_>>> {
+    let mut tokens = vec![];
+    let mut chars = line.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let (kind, end) = if line[start..].starts_with(";;") {
+            (TokenKind::Comment, line.trim_end().len())
+        } else if c == '"' {
+            chars.next();
+            let mut escaped = false;
+            let end = loop {
+                match chars.next() {
+                    Some((index, '"')) if !escaped => break index + 1,
+                    Some((_, '\\')) if !escaped => escaped = true,
+                    Some(_) => escaped = false,
+                    None => bail!("Unterminated string literal at column {}", start + 1),
+                }
+            };
+            (TokenKind::String, end)
+        } else {
+            let end = line[start..]
+                .find(|c: char| c.is_whitespace())
+                .map_or(line.len(), |length| start + length);
+            // a comment can start right after a word.
+            let end = match line[start..end].find(";;") {
+                Some(comment) => start + comment,
+                None => end,
+            };
+            (TokenKind::Word, end)
+        };
+        tokens.push(Token {
+            kind,
+            text: &line[start..end],
+            span: start..end,
+        });
+        while chars.next_if(|(index, _)| *index < end).is_some() {}
+        if kind == TokenKind::Comment {
+            break;
+        }
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tokens_and_spans() {
+        let tokens = lex("  .string :s \"a \\\" ;; b\"\tpush;; done ").unwrap();
+        let kinds: Vec<(TokenKind, &str, Range<usize>)> = tokens
+            .into_iter()
+            .map(|token| (token.kind, token.text, token.span))
+            .collect();
+        assert_eq!(
+            vec![
+                (TokenKind::Word, ".string", 2..9),
+                (TokenKind::Word, ":s", 10..12),
+                (TokenKind::String, "\"a \\\" ;; b\"", 13..24),
+                (TokenKind::Word, "push", 25..29),
+                (TokenKind::Comment, ";; done", 29..36),
+            ],
+            kinds
+        );
+        assert!(lex("").unwrap().is_empty());
+        assert!(lex(".string :s \"open").is_err());
+    }
+}
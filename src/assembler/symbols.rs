@@ -0,0 +1,136 @@
+// every name the source defines or uses, interned once so the passes after
+// parsing can hand around small ids instead of cloning and hashing strings.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+
+use super::Span;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(super) struct SymbolId(u32);
+
+impl SymbolId {
+    // for tables indexed by symbol, see `Symbols::len`.
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    name: String,
+    // None for a name that's only been used so far.
+    defined_at: Option<Span>,
+    value: Option<i64>,
+    // labels, and constants that are just another name for one.
+    code: bool,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Symbols {
+    ids: HashMap<String, SymbolId>,
+    entries: Vec<Entry>,
+}
+
+impl Symbols {
+    pub fn intern(&mut self, name: &str) -> SymbolId {
+        if let Some(id) = self.ids.get(name) {
+            return *id;
+        }
+        let id = SymbolId(self.entries.len() as u32);
+        self.ids.insert(name.to_string(), id);
+        self.entries.push(Entry {
+            name: name.to_string(),
+            defined_at: None,
+            value: None,
+            code: false,
+        });
+        id
+    }
+
+    pub fn get(&self, name: &str) -> Option<SymbolId> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // defines from the options have no line and are the only thing that can
+    // be defined again, by the source.
+    pub fn define(&mut self, name: &str, span: Span) -> Result<SymbolId> {
+        let id = self.intern(name);
+        let entry = &mut self.entries[id.index()];
+        match entry.defined_at.replace(span) {
+            Some(previous) if previous.line != 0 => bail!(
+                "Line {}: {name} is defined twice, first on line {}",
+                span.line,
+                previous.line
+            ),
+            _ => Ok(id),
+        }
+    }
+
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.get(name)
+            .is_some_and(|id| self.entries[id.index()].defined_at.is_some())
+    }
+
+    pub fn name(&self, id: SymbolId) -> &str {
+        &self.entries[id.index()].name
+    }
+
+    pub fn value(&self, id: SymbolId) -> Option<i64> {
+        self.entries[id.index()].value
+    }
+
+    // for `Expr::evaluate`.
+    pub fn value_of(&self, name: &str) -> Option<i64> {
+        self.get(name).and_then(|id| self.value(id))
+    }
+
+    pub fn set_value(&mut self, id: SymbolId, value: i64) {
+        self.entries[id.index()].value = Some(value);
+    }
+
+    pub fn is_code(&self, id: SymbolId) -> bool {
+        self.entries[id.index()].code
+    }
+
+    pub fn mark_code(&mut self, id: SymbolId) {
+        self.entries[id.index()].code = true;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn interns_once() {
+        let mut symbols = Symbols::default();
+        let start = symbols.intern(":start");
+        assert_eq!(start, symbols.intern(":start"));
+        assert_ne!(start, symbols.intern(":end"));
+        assert_eq!(":start", symbols.name(start));
+        assert!(!symbols.is_defined(":start"));
+    }
+
+    #[test]
+    fn redefinition() {
+        let line = |line| Span {
+            line,
+            ..Default::default()
+        };
+        let mut symbols = Symbols::default();
+        symbols.define(":debug", Span::default()).unwrap();
+        // the source can override a define, but only once.
+        symbols.define(":debug", line(3)).unwrap();
+        let err = symbols.define(":debug", line(5)).unwrap_err();
+        assert_eq!(
+            "Line 5: :debug is defined twice, first on line 3",
+            err.to_string()
+        );
+    }
+}
@@ -0,0 +1,144 @@
+// when a breakpoint should actually stop the machine, e.g. `slot 2 == 0`,
+// `depth > 10` or `hits == 10000`. Checked each time execution reaches the
+// breakpoint's address, before the instruction there runs.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Error, Result};
+
+use crate::cpu::Cpu;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quantity {
+    // a variable in the current frame, 0 if it's never been stored to.
+    Slot(i64),
+    // how many values are on the stack.
+    Depth,
+    // the value on top of the stack. Nothing matches an empty stack.
+    Top,
+    // how many times execution has reached the breakpoint, this time included.
+    Hits,
+    // instructions executed so far.
+    Steps,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Condition {
+    pub quantity: Quantity,
+    pub comparison: Comparison,
+    pub value: i64,
+}
+
+impl Condition {
+    pub fn holds(&self, cpu: &Cpu, hits: u64) -> bool {
+        let actual = match self.quantity {
+            Quantity::Slot(id) => cpu.variable(id),
+            Quantity::Depth => cpu.stack().len() as i64,
+            Quantity::Top => match cpu.stack().last() {
+                Some(top) => *top,
+                None => return false,
+            },
+            Quantity::Hits => hits as i64,
+            Quantity::Steps => cpu.steps() as i64,
+        };
+        match self.comparison {
+            Comparison::Eq => actual == self.value,
+            Comparison::Ne => actual != self.value,
+            Comparison::Lt => actual < self.value,
+            Comparison::Le => actual <= self.value,
+            Comparison::Gt => actual > self.value,
+            Comparison::Ge => actual >= self.value,
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = Error;
+
+    fn from_str(condition: &str) -> Result<Self> {
+        let words: Vec<&str> = condition.split_whitespace().collect();
+        let (quantity, rest) = match words.as_slice() {
+            ["slot", id, rest @ ..] => {
+                let id = id
+                    .parse()
+                    .with_context(|| format!("{id:?} isn't a slot number"))?;
+                (Quantity::Slot(id), rest)
+            }
+            ["depth", rest @ ..] => (Quantity::Depth, rest),
+            ["top", rest @ ..] => (Quantity::Top, rest),
+            ["hits", rest @ ..] => (Quantity::Hits, rest),
+            ["steps", rest @ ..] => (Quantity::Steps, rest),
+            _ => bail!("Expected slot N, depth, top, hits or steps at the start of {condition:?}"),
+        };
+        let [comparison, value] = rest else {
+            bail!("Expected a comparison and a number after the quantity in {condition:?}")
+        };
+        let comparison = match *comparison {
+            "==" => Comparison::Eq,
+            "!=" => Comparison::Ne,
+            "<" => Comparison::Lt,
+            "<=" => Comparison::Le,
+            ">" => Comparison::Gt,
+            ">=" => Comparison::Ge,
+            other => bail!("Unknown comparison {other:?} in {condition:?}"),
+        };
+        let value = value
+            .parse()
+            .with_context(|| format!("{value:?} isn't a number"))?;
+        Ok(Self {
+            quantity,
+            comparison,
+            value,
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.quantity {
+            Quantity::Slot(id) => write!(f, "slot {id}")?,
+            Quantity::Depth => write!(f, "depth")?,
+            Quantity::Top => write!(f, "top")?,
+            Quantity::Hits => write!(f, "hits")?,
+            Quantity::Steps => write!(f, "steps")?,
+        }
+        let comparison = match self.comparison {
+            Comparison::Eq => "==",
+            Comparison::Ne => "!=",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+        };
+        write!(f, " {comparison} {}", self.value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_and_prints() {
+        for condition in ["slot 2 == 0", "depth > 10", "top <= -3", "hits == 10000"] {
+            assert_eq!(
+                condition,
+                condition.parse::<Condition>().unwrap().to_string()
+            );
+        }
+        assert!("slot x == 1".parse::<Condition>().is_err());
+        assert!("depth =~ 1".parse::<Condition>().is_err());
+        assert!("depth".parse::<Condition>().is_err());
+    }
+}
@@ -0,0 +1,915 @@
+// the on-disk container for assembled programs.
+//
+// layout:
+//   magic         4 bytes, "BITE"
+//   major version u16, big endian
+//   minor version u16, big endian
+//   flags         u32, big endian, only present from version 3
+//   section count u32
+//   sections      [kind u32, byte length u64, payload...]
+//
+// version 1 sections were [kind u32, word count u64, words i64...], and only
+// code and constants.
+//
+// everything after the flags is big endian unless FLAG_LITTLE_ENDIAN is set.
+// with FLAG_ZSTD set, everything after the flags is a zstd frame holding the
+// section count and sections.
+//
+// minor versions only ever add sections, so a newer minor loads fine and any
+// sections we don't know are skipped. anything the vm actually has to support
+// goes in the feature section instead.
+//
+// code, constant and data payloads are just i64 words. the symbol payload is a u32
+// count followed by [address i64, name length u32, utf-8 name] entries, then a
+// u32 count of [symbol index u32, args u32, rets u32] arities, which older
+// files don't have.
+// the debug payload is the source file name (u32 length, utf-8, empty for
+// none), then a u32 count of [address i64, line u32] entries, then a u32
+// count of [name, shape i64, u32 field count, field names...] records, then a
+// u32 count of [name, slot i64, start i64, end i64] variables.
+// strings are a u32 length and utf-8. older files stop after the lines or the
+// records. the feature
+// payload is a u32 count of [name length u32, utf-8 name] entries.
+// the checksum payload, from minor version 1, is a u32 CRC-32 of the code
+// payload followed by the data payload, as they are in the file.
+// the signature payload is a 64 byte ed25519 signature over every section
+// before it, kinds and lengths included, as they are in the (decompressed)
+// file. it's always the last section.
+
+use std::{
+    io::{Read, Write},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::hexbc;
+use crate::program::{Arity, DebugInfo, Program, ProgramParts, RecordInfo, Symbol, VariableInfo};
+
+pub const MAGIC: &[u8; 4] = b"BITE";
+pub const MAJOR_VERSION: u16 = 3;
+pub const MINOR_VERSION: u16 = 1;
+
+const FLAG_ZSTD: u32 = 1;
+const FLAG_LITTLE_ENDIAN: u32 = 2;
+
+const CODE_SECTION: u32 = 1;
+const CONSTANT_SECTION: u32 = 2;
+const SYMBOL_SECTION: u32 = 3;
+const DEBUG_SECTION: u32 = 4;
+const FEATURE_SECTION: u32 = 5;
+const DATA_SECTION: u32 = 6;
+const CHECKSUM_SECTION: u32 = 7;
+const SIGNATURE_SECTION: u32 = 8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Endian {
+    #[default]
+    Big,
+    Little,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EncodeOptions {
+    // needs the `zstd` feature.
+    pub compress: bool,
+    pub endian: Endian,
+    // an ed25519 private key in PEM, as `openssl genpkey -algorithm ed25519`
+    // writes it, to sign the program with. Needs the `crypto` feature.
+    pub signing_key: Option<String>,
+}
+
+// files ending in .hexbc get the text format, everything else is binary.
+pub fn is_hex_path(filename: impl AsRef<Path>) -> bool {
+    filename.as_ref().extension() == Some(hexbc::EXTENSION.as_ref())
+}
+
+pub fn emit_bytecode(
+    filename: impl AsRef<Path>,
+    program: &Program,
+    options: &EncodeOptions,
+) -> Result<()> {
+    let encoded = match is_hex_path(&filename) {
+        true if options.compress => bail!("The .hexbc format can't be compressed"),
+        true if options.signing_key.is_some() => bail!("The .hexbc format can't be signed"),
+        true => hexbc::encode(program).into_bytes(),
+        false => encode(program, options)?,
+    };
+    let mut file = std::fs::File::create(filename).context("Unable to create outfile")?;
+    file.write_all(&encoded)
+        .context("Could not write bytecode")?;
+    file.flush().context("Could not flush file")?;
+    Ok(())
+}
+
+pub fn load_bytecode(filename: impl AsRef<Path>) -> Result<Program> {
+    if is_hex_path(&filename) {
+        let file = std::fs::read_to_string(filename).context("Could not open file")?;
+        return hexbc::decode(&file);
+    }
+    let file = read_file(filename.as_ref())?;
+    decode(&file)
+}
+
+// the same, failing unless the program was signed by the private half of
+// `public_key`, an ed25519 public key in PEM.
+pub fn load_signed_bytecode(filename: impl AsRef<Path>, public_key: &str) -> Result<Program> {
+    if is_hex_path(&filename) {
+        bail!("The .hexbc format can't be signed, so there's no signature to check")
+    }
+    let file = read_file(filename.as_ref())?;
+    decode_signed(&file, Some(public_key))
+}
+
+// big generated programs get decoded straight out of the page cache, without
+// reading the whole file into a buffer first.
+#[cfg(feature = "mmap")]
+fn read_file(path: &Path) -> Result<memmap2::Mmap> {
+    let file = std::fs::File::open(path).context("Could not open file")?;
+    // SAFETY: decode copies everything it keeps out of the mapping before
+    // it's dropped. Another process truncating the file while we read it is
+    // the usual risk with mapping files, and one we take for the speed.
+    unsafe { memmap2::Mmap::map(&file) }.context("Could not map file")
+}
+
+#[cfg(not(feature = "mmap"))]
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path).context("Could not open file")
+}
+
+// whether a file is binary bytecode, reading no more of it than the magic.
+pub fn has_magic(filename: impl AsRef<Path>) -> Result<bool> {
+    let mut start = vec![];
+    std::fs::File::open(filename)
+        .context("Could not open file")?
+        .take(MAGIC.len() as u64)
+        .read_to_end(&mut start)
+        .context("Could not read file")?;
+    Ok(start == MAGIC)
+}
+
+pub fn encode(program: &Program, options: &EncodeOptions) -> Result<Vec<u8>> {
+    let mut out = vec![];
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&MAJOR_VERSION.to_be_bytes());
+    out.extend_from_slice(&MINOR_VERSION.to_be_bytes());
+    let mut flags = 0;
+    if options.compress {
+        flags |= FLAG_ZSTD;
+    }
+    if options.endian == Endian::Little {
+        flags |= FLAG_LITTLE_ENDIAN;
+    }
+    out.extend_from_slice(&flags.to_be_bytes());
+
+    let body = encode_sections(program, options)?;
+    match options.compress {
+        true => out.extend_from_slice(&compress(&body)?),
+        false => out.extend_from_slice(&body),
+    }
+    Ok(out)
+}
+
+#[cfg(feature = "zstd")]
+fn compress(bytes: &[u8]) -> Result<Vec<u8>> {
+    zstd::encode_all(bytes, 0).context("Could not compress bytecode")
+}
+
+#[cfg(not(feature = "zstd"))]
+fn compress(_bytes: &[u8]) -> Result<Vec<u8>> {
+    bail!("Compression needs biteycode built with the zstd feature")
+}
+
+#[cfg(feature = "zstd")]
+fn decompress(bytes: &[u8]) -> Result<Vec<u8>> {
+    zstd::decode_all(bytes).context("Could not decompress bytecode")
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress(_bytes: &[u8]) -> Result<Vec<u8>> {
+    bail!("This bytecode is compressed, which needs biteycode built with the zstd feature")
+}
+
+fn encode_sections(program: &Program, options: &EncodeOptions) -> Result<Vec<u8>> {
+    let endian = options.endian;
+    let code = encode_words(program.code(), endian);
+    let data = encode_words(program.data(), endian);
+    let mut checksum = Writer::new(endian);
+    checksum.write_u32(crc32(&[&code, &data]));
+    let sections = [
+        (CODE_SECTION, code),
+        (CONSTANT_SECTION, encode_words(program.constants(), endian)),
+        (DATA_SECTION, data),
+        (SYMBOL_SECTION, encode_symbols(program.symbols(), endian)),
+        (
+            DEBUG_SECTION,
+            encode_debug_info(program.debug_info(), endian),
+        ),
+        (FEATURE_SECTION, encode_features(program.features(), endian)),
+        (CHECKSUM_SECTION, checksum.bytes),
+    ];
+    let signed = options.signing_key.is_some();
+    let mut out = Writer::new(endian);
+    out.write_u32(sections.len() as u32 + signed as u32);
+    for (kind, payload) in sections {
+        out.write_u32(kind);
+        out.write_u64(payload.len() as u64);
+        out.bytes.extend_from_slice(&payload);
+    }
+    if let Some(key) = &options.signing_key {
+        let signature = sign(&out.bytes[4..], key)?;
+        out.write_u32(SIGNATURE_SECTION);
+        out.write_u64(signature.len() as u64);
+        out.bytes.extend_from_slice(&signature);
+    }
+    Ok(out.bytes)
+}
+
+#[cfg(feature = "crypto")]
+fn sign(message: &[u8], private_key: &str) -> Result<Vec<u8>> {
+    let key = ed25519_compact::KeyPair::from_pem(private_key)
+        .context("Could not read the signing key, it should be an ed25519 private key in PEM")?;
+    Ok(key.sk.sign(message, None).to_vec())
+}
+
+#[cfg(not(feature = "crypto"))]
+fn sign(_message: &[u8], _private_key: &str) -> Result<Vec<u8>> {
+    bail!("Signing needs biteycode built with the crypto feature")
+}
+
+#[cfg(feature = "crypto")]
+fn verify(message: &[u8], signature: &[u8], public_key: &str) -> Result<()> {
+    let key = ed25519_compact::PublicKey::from_pem(public_key)
+        .context("Could not read the public key, it should be an ed25519 public key in PEM")?;
+    let signature =
+        ed25519_compact::Signature::from_slice(signature).context("Bad signature section")?;
+    if key.verify(message, &signature).is_err() {
+        bail!("Bytecode signature doesn't match the public key, it was changed after signing or signed with another key")
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "crypto"))]
+fn verify(_message: &[u8], _signature: &[u8], _public_key: &str) -> Result<()> {
+    bail!("Checking signatures needs biteycode built with the crypto feature")
+}
+
+fn encode_words(words: &[i64], endian: Endian) -> Vec<u8> {
+    let mut out = Writer::new(endian);
+    for word in words.iter() {
+        out.write_i64(*word);
+    }
+    out.bytes
+}
+
+fn encode_symbols(symbols: &[Symbol], endian: Endian) -> Vec<u8> {
+    let mut out = Writer::new(endian);
+    out.write_u32(symbols.len() as u32);
+    for symbol in symbols.iter() {
+        out.write_i64(symbol.address);
+        out.write_string(&symbol.name);
+    }
+    let arities: Vec<(usize, Arity)> = symbols
+        .iter()
+        .enumerate()
+        .filter_map(|(index, symbol)| Some((index, symbol.arity?)))
+        .collect();
+    out.write_u32(arities.len() as u32);
+    for (index, arity) in arities.iter() {
+        out.write_u32(*index as u32);
+        out.write_u32(arity.args as u32);
+        out.write_u32(arity.rets as u32);
+    }
+    out.bytes
+}
+
+fn encode_debug_info(debug_info: &DebugInfo, endian: Endian) -> Vec<u8> {
+    let mut out = Writer::new(endian);
+    out.write_string(debug_info.file.as_deref().unwrap_or_default());
+    out.write_u32(debug_info.lines.len() as u32);
+    for (address, line) in debug_info.lines.iter() {
+        out.write_i64(*address);
+        out.write_u32(*line as u32);
+    }
+    out.write_u32(debug_info.records.len() as u32);
+    for record in debug_info.records.iter() {
+        out.write_string(&record.name);
+        out.write_i64(record.shape);
+        out.write_u32(record.fields.len() as u32);
+        for field in record.fields.iter() {
+            out.write_string(field);
+        }
+    }
+    out.write_u32(debug_info.variables.len() as u32);
+    for variable in debug_info.variables.iter() {
+        out.write_string(&variable.name);
+        out.write_i64(variable.slot);
+        out.write_i64(variable.start);
+        out.write_i64(variable.end);
+    }
+    out.bytes
+}
+
+fn encode_features(features: &[String], endian: Endian) -> Vec<u8> {
+    let mut out = Writer::new(endian);
+    out.write_u32(features.len() as u32);
+    for feature in features.iter() {
+        out.write_string(feature);
+    }
+    out.bytes
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+    endian: Endian,
+}
+
+impl Writer {
+    fn new(endian: Endian) -> Self {
+        Self {
+            bytes: vec![],
+            endian,
+        }
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        match self.endian {
+            Endian::Big => self.bytes.extend_from_slice(&value.to_be_bytes()),
+            Endian::Little => self.bytes.extend_from_slice(&value.to_le_bytes()),
+        }
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        match self.endian {
+            Endian::Big => self.bytes.extend_from_slice(&value.to_be_bytes()),
+            Endian::Little => self.bytes.extend_from_slice(&value.to_le_bytes()),
+        }
+    }
+
+    fn write_i64(&mut self, value: i64) {
+        self.write_u64(value as u64);
+    }
+
+    fn write_string(&mut self, string: &str) {
+        self.write_u32(string.len() as u32);
+        self.bytes.extend_from_slice(string.as_bytes());
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Program> {
+    decode_signed(bytes, None)
+}
+
+// checking the signature against `public_key` when there is one.
+fn decode_signed(bytes: &[u8], public_key: Option<&str>) -> Result<Program> {
+    if bytes.is_empty() {
+        bail!("Bytecode file is empty")
+    }
+    // the header is always big endian.
+    let mut reader = Reader::new(bytes, Endian::Big);
+    if reader.take(4)? != MAGIC {
+        bail!("Not a bytecode file, bad magic")
+    }
+    let major = reader.read_u16()?;
+    let _minor = reader.read_u16()?;
+    let flags = match major {
+        1 => return decode_word_counts(reader.take(bytes.len() - reader.position)?, public_key),
+        2 => 0,
+        MAJOR_VERSION => reader.read_u32()?,
+        major => bail!("Unsupported bytecode version {major}"),
+    };
+
+    let endian = match flags & FLAG_LITTLE_ENDIAN {
+        0 => Endian::Big,
+        _ => Endian::Little,
+    };
+    let rest = reader.take(bytes.len() - reader.position)?;
+    if flags & FLAG_ZSTD != 0 {
+        decode_sections(&decompress(rest)?, endian, public_key)
+    } else {
+        decode_sections(rest, endian, public_key)
+    }
+}
+
+fn decode_sections(bytes: &[u8], endian: Endian, public_key: Option<&str>) -> Result<Program> {
+    let mut reader = Reader::new(bytes, endian);
+    let mut parts = ProgramParts::default();
+    let (mut code, mut data) = (&[][..], &[][..]);
+    let mut checksum = None;
+    // the signature, and the bytes it covers.
+    let mut signature = None;
+    let section_count = reader.read_u32()?;
+    for _ in 0..section_count {
+        if signature.is_some() {
+            bail!("Bytecode has sections after its signature, which the signature doesn't cover")
+        }
+        let start = reader.position;
+        let kind = reader.read_u32()?;
+        let length = reader.read_u64()?;
+        let left = bytes.len() - reader.position;
+        if length > left as u64 {
+            bail!(
+                "Bytecode is truncated, the {} section is {length} bytes but only {left} are left",
+                section_name(kind)
+            )
+        }
+        let payload = reader.take(length as usize)?;
+        let mut section = Reader::new(payload, endian);
+        match kind {
+            CODE_SECTION => {
+                code = payload;
+                parts.code = section.read_words("code")?
+            }
+            CONSTANT_SECTION => parts.constants = section.read_words("constant")?,
+            DATA_SECTION => {
+                data = payload;
+                parts.data = section.read_words("data")?
+            }
+            CHECKSUM_SECTION => checksum = Some(section.read_u32()?),
+            SIGNATURE_SECTION => signature = Some((payload, &bytes[4..start])),
+            SYMBOL_SECTION => parts.symbols = section.read_symbols()?,
+            DEBUG_SECTION => parts.debug_info = section.read_debug_info()?,
+            FEATURE_SECTION => parts.features = section.read_features()?,
+            // written by something newer than us, and nothing we need.
+            kind => tracing::debug!("Skipping unknown section kind {kind}"),
+        }
+    }
+    if let Some(public_key) = public_key {
+        let Some((signature, signed)) = signature else {
+            bail!("Bytecode isn't signed")
+        };
+        verify(signed, signature, public_key)?;
+    }
+    // files from before checksums don't have one.
+    if let Some(expected) = checksum {
+        let actual = crc32(&[code, data]);
+        if actual != expected {
+            bail!("Bytecode checksum mismatch, expected {expected:08x} but the code and data come to {actual:08x}")
+        }
+    }
+    Program::new(parts)
+}
+
+// version 1, where a section's length was a count of words.
+fn decode_word_counts(bytes: &[u8], public_key: Option<&str>) -> Result<Program> {
+    if public_key.is_some() {
+        bail!("Bytecode isn't signed")
+    }
+    let mut reader = Reader::new(bytes, Endian::Big);
+    let mut parts = ProgramParts::default();
+    let section_count = reader.read_u32()?;
+    for _ in 0..section_count {
+        let kind = reader.read_u32()?;
+        let word_count = reader.read_u64()?;
+        let left = bytes.len() - reader.position;
+        let length = match word_count.checked_mul(8) {
+            Some(length) if length <= left as u64 => length as usize,
+            _ => bail!(
+                "Bytecode is truncated, the {} section is {word_count} words but only {left} bytes are left",
+                section_name(kind)
+            ),
+        };
+        let mut section = Reader::new(reader.take(length)?, Endian::Big);
+        match kind {
+            CODE_SECTION => parts.code = section.read_words("code")?,
+            CONSTANT_SECTION => parts.constants = section.read_words("constant")?,
+            kind => bail!("Unknown section kind {kind}"),
+        }
+    }
+    Program::new(parts)
+}
+
+// CRC-32 as zip and png use it, over the chunks one after another.
+fn crc32(chunks: &[&[u8]]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut index = 0;
+        while index < 256 {
+            let mut crc = index as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = match crc & 1 {
+                    1 => (crc >> 1) ^ 0xedb8_8320,
+                    _ => crc >> 1,
+                };
+                bit += 1;
+            }
+            table[index] = crc;
+            index += 1;
+        }
+        table
+    };
+    let mut crc = !0u32;
+    for byte in chunks.iter().flat_map(|chunk| chunk.iter()) {
+        crc = TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+fn section_name(kind: u32) -> String {
+    match kind {
+        CODE_SECTION => "code".to_string(),
+        CONSTANT_SECTION => "constant".to_string(),
+        SYMBOL_SECTION => "symbol".to_string(),
+        DEBUG_SECTION => "debug".to_string(),
+        FEATURE_SECTION => "feature".to_string(),
+        DATA_SECTION => "data".to_string(),
+        CHECKSUM_SECTION => "checksum".to_string(),
+        SIGNATURE_SECTION => "signature".to_string(),
+        kind => format!("unknown kind {kind}"),
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    endian: Endian,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8], endian: Endian) -> Self {
+        Self {
+            bytes,
+            position: 0,
+            endian,
+        }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8]> {
+        let end = self.position.checked_add(count);
+        let Some(slice) = end.and_then(|end| self.bytes.get(self.position..end)) else {
+            bail!("Unexpected end of bytecode at byte {}", self.position)
+        };
+        self.position += count;
+        Ok(slice)
+    }
+
+    fn read_u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?.try_into()?;
+        Ok(match self.endian {
+            Endian::Big => u16::from_be_bytes(bytes),
+            Endian::Little => u16::from_le_bytes(bytes),
+        })
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?.try_into()?;
+        Ok(match self.endian {
+            Endian::Big => u32::from_be_bytes(bytes),
+            Endian::Little => u32::from_le_bytes(bytes),
+        })
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        let bytes = self.take(8)?.try_into()?;
+        Ok(match self.endian {
+            Endian::Big => u64::from_be_bytes(bytes),
+            Endian::Little => u64::from_le_bytes(bytes),
+        })
+    }
+
+    fn read_i64(&mut self) -> Result<i64> {
+        Ok(self.read_u64()? as i64)
+    }
+
+    // the rest of the bytes as words, all at once since code sections can be
+    // millions of them.
+    fn read_words(&mut self, section: &str) -> Result<Vec<i64>> {
+        let words = self.take(self.bytes.len() - self.position)?.chunks_exact(8);
+        if !words.remainder().is_empty() {
+            bail!(
+                "The {section} section is {} bytes, which leaves {} after the last whole 8 byte word",
+                self.bytes.len(),
+                words.remainder().len()
+            )
+        }
+        let read: fn([u8; 8]) -> i64 = match self.endian {
+            Endian::Big => i64::from_be_bytes,
+            Endian::Little => i64::from_le_bytes,
+        };
+        Ok(words.map(|word| read(word.try_into().unwrap())).collect())
+    }
+
+    fn read_symbols(&mut self) -> Result<Vec<Symbol>> {
+        let count = self.read_u32()?;
+        let mut symbols = vec![];
+        for _ in 0..count {
+            let address = self.read_i64()?;
+            let name = self.read_string().context("Bad symbol name")?;
+            symbols.push(Symbol {
+                name,
+                address,
+                arity: None,
+            });
+        }
+        if self.position == self.bytes.len() {
+            return Ok(symbols);
+        }
+        let count = self.read_u32()?;
+        for _ in 0..count {
+            let index = self.read_u32()? as usize;
+            let args = self.read_u32()? as usize;
+            let rets = self.read_u32()? as usize;
+            let Some(symbol) = symbols.get_mut(index) else {
+                bail!(
+                    "Arity for symbol {index}, but there are only {}",
+                    symbols.len()
+                )
+            };
+            symbol.arity = Some(Arity { args, rets });
+        }
+        Ok(symbols)
+    }
+
+    fn read_string(&mut self) -> Result<String> {
+        let length = self.read_u32()?;
+        let string = std::str::from_utf8(self.take(length as usize)?).context("Not utf-8")?;
+        Ok(string.to_string())
+    }
+
+    fn read_debug_info(&mut self) -> Result<DebugInfo> {
+        let file = self.read_string().context("Bad source file name")?;
+        let mut debug_info = DebugInfo {
+            file: (!file.is_empty()).then_some(file),
+            ..Default::default()
+        };
+        let count = self.read_u32()?;
+        for _ in 0..count {
+            let address = self.read_i64()?;
+            let line = self.read_u32()?;
+            debug_info.lines.insert(address, line as usize);
+        }
+        // records came later, so they may not be there.
+        if self.position == self.bytes.len() {
+            return Ok(debug_info);
+        }
+        let count = self.read_u32()?;
+        for _ in 0..count {
+            let name = self.read_string().context("Bad record name")?;
+            let shape = self.read_i64()?;
+            let field_count = self.read_u32()?;
+            let fields = (0..field_count)
+                .map(|_| self.read_string().context("Bad field name"))
+                .collect::<Result<Vec<String>>>()?;
+            debug_info.records.push(RecordInfo {
+                name,
+                shape,
+                fields,
+            });
+        }
+        // and variables later still.
+        if self.position == self.bytes.len() {
+            return Ok(debug_info);
+        }
+        let count = self.read_u32()?;
+        for _ in 0..count {
+            let name = self.read_string().context("Bad variable name")?;
+            debug_info.variables.push(VariableInfo {
+                name,
+                slot: self.read_i64()?,
+                start: self.read_i64()?,
+                end: self.read_i64()?,
+            });
+        }
+        Ok(debug_info)
+    }
+
+    fn read_features(&mut self) -> Result<Vec<String>> {
+        let count = self.read_u32()?;
+        let mut features = vec![];
+        for _ in 0..count {
+            features.push(self.read_string().context("Bad feature name")?);
+        }
+        Ok(features)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let program = Program::new(ProgramParts {
+            code: vec![25, 0, 3],
+            constants: vec![i64::MAX],
+            data: vec![104, 105],
+            symbols: vec![Symbol {
+                name: ":main".to_string(),
+                address: 0,
+                arity: Some(Arity { args: 2, rets: 1 }),
+            }],
+            debug_info: DebugInfo {
+                file: Some("main.bc".to_string()),
+                lines: [(0, 1), (2, 2)].into(),
+                records: vec![RecordInfo {
+                    name: ":point".to_string(),
+                    shape: 0,
+                    fields: vec!["x".to_string(), "y".to_string()],
+                }],
+                variables: vec![VariableInfo {
+                    name: "total".to_string(),
+                    slot: 2,
+                    start: 0,
+                    end: 3,
+                }],
+            },
+            features: vec!["constant-pool".to_string(), "data".to_string()],
+        })
+        .unwrap();
+        let encoded = encode(&program, &EncodeOptions::default()).unwrap();
+        assert_eq!(program, decode(&encoded).unwrap());
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let program = Program::from_code(vec![1, 2, 1, 3, 4, 3]).unwrap();
+        let path = std::env::temp_dir().join(format!("biteycode-{}.bc", std::process::id()));
+        emit_bytecode(&path, &program, &EncodeOptions::default()).unwrap();
+        assert!(has_magic(&path).unwrap());
+        let loaded = load_bytecode(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(program, loaded.unwrap());
+    }
+
+    #[test]
+    fn little_endian_round_trip() {
+        let program = Program::from_code(vec![1, -2, 3]).unwrap();
+        let options = EncodeOptions {
+            endian: Endian::Little,
+            ..Default::default()
+        };
+        let encoded = encode(&program, &options).unwrap();
+        // the header stays big endian, the words after it don't.
+        assert_eq!(&encoded[..8], b"BITE\x00\x03\x00\x01");
+        assert_eq!(&encoded[12..16], &7u32.to_le_bytes());
+        assert_eq!(program, decode(&encoded).unwrap());
+    }
+
+    #[test]
+    fn reads_version_one() {
+        // lengths were word counts back then.
+        let mut bytes = b"BITE\x00\x01\x00\x00\x00\x00\x00\x02".to_vec();
+        bytes.extend_from_slice(&CODE_SECTION.to_be_bytes());
+        bytes.extend_from_slice(&2u64.to_be_bytes());
+        bytes.extend_from_slice(&25i64.to_be_bytes());
+        bytes.extend_from_slice(&0i64.to_be_bytes());
+        bytes.extend_from_slice(&CONSTANT_SECTION.to_be_bytes());
+        bytes.extend_from_slice(&1u64.to_be_bytes());
+        bytes.extend_from_slice(&i64::MAX.to_be_bytes());
+        let program = decode(&bytes).unwrap();
+        assert_eq!(vec![25, 0], program.code());
+        assert_eq!(vec![i64::MAX], program.constants());
+
+        bytes[16..24].copy_from_slice(&3u64.to_be_bytes());
+        assert!(decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn reads_version_two() {
+        // byte lengths, but no flags word yet.
+        let mut bytes = b"BITE\x00\x02\x00\x00\x00\x00\x00\x01".to_vec();
+        bytes.extend_from_slice(&CODE_SECTION.to_be_bytes());
+        bytes.extend_from_slice(&8u64.to_be_bytes());
+        bytes.extend_from_slice(&3i64.to_be_bytes());
+        assert_eq!(vec![3], decode(&bytes).unwrap().code());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn compressed_round_trip() {
+        let program =
+            Program::from_code([1, 0].repeat(500).into_iter().chain([3]).collect()).unwrap();
+        let options = EncodeOptions {
+            compress: true,
+            ..Default::default()
+        };
+        let encoded = encode(&program, &options).unwrap();
+        assert!(encoded.len() < encode(&program, &EncodeOptions::default()).unwrap().len());
+        assert_eq!(program, decode(&encoded).unwrap());
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    #[test]
+    fn compression_needs_the_feature() {
+        let program = Program::from_code(vec![3]).unwrap();
+        let options = EncodeOptions {
+            compress: true,
+            ..Default::default()
+        };
+        assert!(encode(&program, &options).is_err());
+    }
+
+    #[cfg(feature = "crypto")]
+    #[test]
+    fn signed_round_trip() {
+        use ed25519_compact::{KeyPair, Seed};
+        // kind, length and signature.
+        const SIGNATURE_RECORD: usize = 4 + 8 + 64;
+
+        let ours = KeyPair::from_seed(Seed::new([7; 32]));
+        let theirs = KeyPair::from_seed(Seed::new([8; 32]));
+        let program = Program::from_code(vec![1, 2, 1, 3, 4, 3]).unwrap();
+        let options = EncodeOptions {
+            signing_key: Some(ours.sk.to_pem()),
+            ..Default::default()
+        };
+        let encoded = encode(&program, &options).unwrap();
+        assert_eq!(
+            program,
+            decode_signed(&encoded, Some(&ours.pk.to_pem())).unwrap()
+        );
+        // anyone can still run it without checking.
+        assert_eq!(program, decode(&encoded).unwrap());
+
+        let err = decode_signed(&encoded, Some(&theirs.pk.to_pem())).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Bytecode signature doesn't match"));
+        let unsigned = encode(&program, &EncodeOptions::default()).unwrap();
+        let err = decode_signed(&unsigned, Some(&ours.pk.to_pem())).unwrap_err();
+        assert_eq!("Bytecode isn't signed", err.to_string());
+        // the checksum is signed too, so a changed one fails the signature first.
+        let mut tampered = encoded.clone();
+        let checksum = tampered.len() - SIGNATURE_RECORD - 1;
+        tampered[checksum] ^= 1;
+        let err = decode_signed(&tampered, Some(&ours.pk.to_pem())).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Bytecode signature doesn't match"));
+    }
+
+    #[cfg(not(feature = "crypto"))]
+    #[test]
+    fn signing_needs_the_feature() {
+        let program = Program::from_code(vec![3]).unwrap();
+        let options = EncodeOptions {
+            signing_key: Some(String::new()),
+            ..Default::default()
+        };
+        assert!(encode(&program, &options).is_err());
+    }
+
+    #[test]
+    fn skips_unknown_sections() {
+        let mut bytes = b"BITE\x00\x03\x00\x07\x00\x00\x00\x00".to_vec();
+        bytes.extend_from_slice(&2u32.to_be_bytes());
+        bytes.extend_from_slice(&99u32.to_be_bytes());
+        bytes.extend_from_slice(&3u64.to_be_bytes());
+        bytes.extend_from_slice(b"???");
+        bytes.extend_from_slice(&CODE_SECTION.to_be_bytes());
+        bytes.extend_from_slice(&8u64.to_be_bytes());
+        bytes.extend_from_slice(&3i64.to_be_bytes());
+        assert_eq!(vec![3], decode(&bytes).unwrap().code());
+    }
+
+    #[test]
+    fn truncated() {
+        assert_eq!(
+            "Bytecode file is empty",
+            decode(b"").unwrap_err().to_string()
+        );
+        let program = Program::from_code(vec![1, 2, 1, 3, 4, 3]).unwrap();
+        let encoded = encode(&program, &EncodeOptions::default()).unwrap();
+        // the header, then the code section's kind and length, then 20 of
+        // its 48 bytes.
+        let err = decode(&encoded[..48]).unwrap_err();
+        assert_eq!(
+            "Bytecode is truncated, the code section is 48 bytes but only 20 are left",
+            err.to_string()
+        );
+
+        // a section that's all there, but isn't whole words.
+        let mut bytes = b"BITE\x00\x03\x00\x00\x00\x00\x00\x00".to_vec();
+        bytes.extend_from_slice(&1u32.to_be_bytes());
+        bytes.extend_from_slice(&CODE_SECTION.to_be_bytes());
+        bytes.extend_from_slice(&11u64.to_be_bytes());
+        bytes.extend_from_slice(&[0; 11]);
+        assert_eq!(
+            "The code section is 11 bytes, which leaves 3 after the last whole 8 byte word",
+            decode(&bytes).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn checksum() {
+        // the standard check value.
+        assert_eq!(0xcbf4_3926, crc32(&[b"12345", b"6789"]));
+
+        let program = Program::from_code(vec![1, 2, 1, 3, 4, 3]).unwrap();
+        let mut encoded = encode(&program, &EncodeOptions::default()).unwrap();
+        // the last byte of the second push's operand, 3 becomes 7.
+        encoded[28 + 31] ^= 4;
+        let err = decode(&encoded).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Bytecode checksum mismatch, expected"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn bad_magic() {
+        assert!(decode(b"NOPE\x00\x01\x00\x00").is_err());
+    }
+}
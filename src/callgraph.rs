@@ -0,0 +1,134 @@
+// static call graph. a function is the entry point or anything a CALL
+// targets, and it runs until the next function starts.
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use anyhow::Result;
+
+use crate::cpu::Opcode;
+use crate::disassembler::decode;
+use crate::program::Program;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub start: usize,
+    pub instruction_count: usize,
+    // start addresses of the functions called directly, in call order.
+    pub calls: Vec<usize>,
+    // CALLCLOS sites, we can't know where these go statically.
+    pub indirect_calls: usize,
+}
+
+pub fn call_graph(program: &Program) -> Result<Vec<Function>> {
+    let instructions = decode(program.code())?;
+
+    let mut starts = BTreeSet::from([0]);
+    for instruction in instructions.iter() {
+        if instruction.opcode == Opcode::Call {
+            if let Some(target) = instruction.operand.and_then(|t| usize::try_from(t).ok()) {
+                starts.insert(target);
+            }
+        }
+    }
+
+    let mut functions: Vec<Function> = starts
+        .iter()
+        .map(|start| Function {
+            name: program.function_name(*start),
+            start: *start,
+            instruction_count: 0,
+            calls: vec![],
+            indirect_calls: 0,
+        })
+        .collect();
+
+    for instruction in instructions.iter() {
+        // the last function starting at or before this instruction owns it.
+        let index = functions.partition_point(|f| f.start <= instruction.address) - 1;
+        let function = &mut functions[index];
+        function.instruction_count += 1;
+        match instruction.opcode {
+            Opcode::Call => {
+                if let Some(target) = instruction.operand.and_then(|t| usize::try_from(t).ok()) {
+                    if !function.calls.contains(&target) {
+                        function.calls.push(target);
+                    }
+                }
+            }
+            Opcode::Callclos => function.indirect_calls += 1,
+            _ => {}
+        }
+    }
+    Ok(functions)
+}
+
+pub fn to_text(functions: &[Function]) -> String {
+    let mut out = String::new();
+    for function in functions.iter() {
+        let _ = writeln!(
+            out,
+            "{} @{} ({} instructions)",
+            function.name, function.start, function.instruction_count
+        );
+        for target in function.calls.iter() {
+            let name = functions
+                .iter()
+                .find(|f| f.start == *target)
+                .map_or("<unknown>", |f| f.name.as_str());
+            let _ = writeln!(out, "    calls {name}");
+        }
+        if function.indirect_calls > 0 {
+            let _ = writeln!(out, "    {} indirect call(s)", function.indirect_calls);
+        }
+    }
+    out
+}
+
+pub fn to_dot(functions: &[Function]) -> String {
+    let mut out = String::new();
+    out.push_str("digraph calls {\n");
+    out.push_str("    node [shape=box fontname=\"monospace\"];\n");
+    for function in functions.iter() {
+        let _ = writeln!(
+            out,
+            "    f{} [label=\"{}\\n{} instructions\"];",
+            function.start, function.name, function.instruction_count
+        );
+    }
+    if functions.iter().any(|f| f.indirect_calls > 0) {
+        out.push_str("    indirect [label=\"<indirect>\" style=dashed];\n");
+    }
+    for function in functions.iter() {
+        for target in function.calls.iter() {
+            let _ = writeln!(out, "    f{} -> f{target};", function.start);
+        }
+        if function.indirect_calls > 0 {
+            let _ = writeln!(out, "    f{} -> indirect [style=dashed];", function.start);
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::{parse_program, AssemblerOptions};
+
+    #[test]
+    fn finds_direct_and_indirect_calls() {
+        let source =
+            "call :a\nhalt\n:a\ncall :b\ncall :b\nret\n:b\npush :a\nmkclos 0\ncallclos\nret";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        let functions = call_graph(&program).unwrap();
+
+        let names: Vec<&str> = functions.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(vec!["<entry>", ":a", ":b"], names);
+        assert_eq!(vec![3], functions[0].calls);
+        assert_eq!(vec![8], functions[1].calls);
+        assert_eq!(3, functions[1].instruction_count);
+        assert_eq!(1, functions[2].indirect_calls);
+    }
+}
@@ -0,0 +1,191 @@
+// control flow graph recovery, mostly so we can look at programs in graphviz.
+
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::Result;
+
+use crate::cpu::{has_code_operand, is_branch, Opcode};
+use crate::disassembler::{decode, Instruction};
+use crate::program::Program;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EdgeKind {
+    Jump,
+    BranchTaken,
+    Fallthrough,
+    Call,
+}
+
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub instructions: Vec<Instruction>,
+    pub successors: Vec<(usize, EdgeKind)>,
+}
+
+fn jump_target(instruction: &Instruction) -> Option<usize> {
+    match has_code_operand(instruction.opcode) {
+        true => instruction
+            .operand
+            .and_then(|target| usize::try_from(target).ok()),
+        false => None,
+    }
+}
+
+pub fn basic_blocks(program: &Program) -> Result<Vec<BasicBlock>> {
+    let instructions = decode(program.code())?;
+    let boundaries: BTreeSet<usize> = instructions.iter().map(|i| i.address).collect();
+
+    // a block starts at the entry point, at every label, at every branch
+    // target, and right after anything that doesn't fall through.
+    let mut leaders = BTreeSet::from([0]);
+    for symbol in program.symbols().iter() {
+        if let Ok(address) = usize::try_from(symbol.address) {
+            leaders.insert(address);
+        }
+    }
+    for instruction in instructions.iter() {
+        if let Some(target) = jump_target(instruction) {
+            leaders.insert(target);
+        }
+        if matches!(
+            instruction.opcode,
+            Opcode::Jmp | Opcode::Ret | Opcode::Iret | Opcode::Halt
+        ) || is_branch(instruction.opcode)
+        {
+            leaders.insert(instruction.next_address());
+        }
+    }
+    // targets that land in the middle of an instruction aren't blocks.
+    leaders.retain(|leader| boundaries.contains(leader));
+
+    let mut blocks: Vec<BasicBlock> = vec![];
+    for instruction in instructions.into_iter() {
+        if leaders.contains(&instruction.address) || blocks.is_empty() {
+            blocks.push(BasicBlock {
+                start: instruction.address,
+                instructions: vec![],
+                successors: vec![],
+            });
+        }
+        blocks.last_mut().unwrap().instructions.push(instruction);
+    }
+
+    let starts: BTreeSet<usize> = blocks.iter().map(|block| block.start).collect();
+    for block in blocks.iter_mut() {
+        let mut successors = vec![];
+        for instruction in block.instructions.iter() {
+            if instruction.opcode == Opcode::Call {
+                if let Some(target) = jump_target(instruction).filter(|t| starts.contains(t)) {
+                    successors.push((target, EdgeKind::Call));
+                }
+            }
+        }
+
+        let last = block.instructions.last().unwrap();
+        let next = last.next_address();
+        match last.opcode {
+            Opcode::Jmp => {
+                if let Some(target) = jump_target(last).filter(|t| starts.contains(t)) {
+                    successors.push((target, EdgeKind::Jump));
+                }
+            }
+            opcode if is_branch(opcode) => {
+                if let Some(target) = jump_target(last).filter(|t| starts.contains(t)) {
+                    successors.push((target, EdgeKind::BranchTaken));
+                }
+                if starts.contains(&next) {
+                    successors.push((next, EdgeKind::Fallthrough));
+                }
+            }
+            Opcode::Ret | Opcode::Iret | Opcode::Halt => {}
+            _ => {
+                if starts.contains(&next) {
+                    successors.push((next, EdgeKind::Fallthrough));
+                }
+            }
+        }
+        block.successors = successors;
+    }
+    Ok(blocks)
+}
+
+pub fn to_dot(program: &Program) -> Result<String> {
+    let blocks = basic_blocks(program)?;
+    let names: HashMap<i64, &str> = program
+        .symbols()
+        .iter()
+        .map(|symbol| (symbol.address, symbol.name.as_str()))
+        .collect();
+
+    let mut out = String::new();
+    out.push_str("digraph cfg {\n");
+    out.push_str("    node [shape=box fontname=\"monospace\"];\n");
+    for block in blocks.iter() {
+        let mut label = String::new();
+        if let Some(name) = names.get(&(block.start as i64)) {
+            label.push_str(&format!("{name}\\l"));
+        }
+        for instruction in block.instructions.iter() {
+            let rendered = match (jump_target(instruction), instruction.operand) {
+                (Some(_), Some(operand)) if names.contains_key(&operand) => {
+                    instruction.with_target(names[&operand])
+                }
+                _ => instruction.to_string(),
+            };
+            label.push_str(&format!("{:>4}: {}\\l", instruction.address, rendered));
+        }
+        out.push_str(&format!(
+            "    b{} [label=\"{}\"];\n",
+            block.start,
+            label.replace('"', "\\\"")
+        ));
+    }
+    for block in blocks.iter() {
+        for (target, kind) in block.successors.iter() {
+            let attributes = match kind {
+                EdgeKind::Jump | EdgeKind::Fallthrough => "",
+                EdgeKind::BranchTaken => " [label=\"true\"]",
+                EdgeKind::Call => " [style=dashed label=\"call\"]",
+            };
+            out.push_str(&format!("    b{} -> b{target}{attributes};\n", block.start));
+        }
+    }
+    out.push_str("}\n");
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::{parse_program, AssemblerOptions};
+
+    fn blocks_for(source: &str) -> Vec<BasicBlock> {
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        basic_blocks(&program).unwrap()
+    }
+
+    #[test]
+    fn splits_on_branches() {
+        let blocks = blocks_for("push 1\njif :yes\npush 2\nhalt\n:yes\npush 3\nhalt");
+        let starts: Vec<usize> = blocks.iter().map(|block| block.start).collect();
+        assert_eq!(vec![0, 4, 7], starts);
+        assert_eq!(
+            vec![(7, EdgeKind::BranchTaken), (4, EdgeKind::Fallthrough)],
+            blocks[0].successors
+        );
+        assert!(blocks[1].successors.is_empty());
+    }
+
+    #[test]
+    fn dot_uses_symbol_names() {
+        let program = parse_program(
+            "call :f\nhalt\n:f\nret".to_string(),
+            &AssemblerOptions::default(),
+        )
+        .unwrap();
+        let dot = to_dot(&program).unwrap();
+        assert!(dot.contains("call :f"));
+        assert!(dot.contains("b0 -> b3 [style=dashed label=\"call\"];"));
+    }
+}
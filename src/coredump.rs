@@ -0,0 +1,478 @@
+// everything the cpu knew when a run failed, saved as a `.bcore` file so the
+// failure can be looked at after the process is gone. The program travels
+// with the dump, so it can be read without the original bytecode. Two dumps
+// can be diffed to see what a stretch of the run changed.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::callgraph::{call_graph, Function};
+use crate::cpu::{heap_objects, RECORD_TAG};
+use crate::disassembler::{decode, Instruction};
+use crate::program::Program;
+
+// instructions of disassembly to show on each side of the faulting one.
+const CONTEXT: usize = 5;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CoreDump {
+    // the error run() failed with, causes included.
+    pub error: String,
+    // address of the instruction that was executing.
+    pub address: usize,
+    pub instruction_pointer: usize,
+    pub stack: Vec<i64>,
+    // outermost first.
+    pub frames: Vec<FrameDump>,
+    pub heap: Vec<i64>,
+    // what the MAP_TAG objects on the heap index into.
+    #[serde(default)]
+    pub maps: Vec<BTreeMap<i64, i64>>,
+    // addresses of the last instructions executed, oldest first. Empty unless
+    // the cpu was keeping a trace.
+    pub trace: Vec<usize>,
+    pub steps: u64,
+    pub cycles: u64,
+    pub program: Program,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrameDump {
+    pub return_address: usize,
+    pub variables: BTreeMap<i64, i64>,
+}
+
+impl CoreDump {
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json)
+            .with_context(|| format!("Could not write core dump {}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read core dump {}", path.display()))?;
+        serde_json::from_str(&json).context("Not a core dump")
+    }
+}
+
+// one word that differs between two dumps, None on the side it isn't in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    // counting from the bottom of the stack.
+    Stack {
+        index: usize,
+        before: Option<i64>,
+        after: Option<i64>,
+    },
+    // frames count outermost first, unlike to_text() which starts from the
+    // innermost, so the same frame has the same number on both sides. Frame
+    // 0 is the root frame, whose variables are what a program has for globals.
+    Variable {
+        frame: usize,
+        slot: i64,
+        before: Option<i64>,
+        after: Option<i64>,
+    },
+    Heap {
+        address: usize,
+        before: Option<i64>,
+        after: Option<i64>,
+    },
+    Map {
+        index: usize,
+        key: i64,
+        before: Option<i64>,
+        after: Option<i64>,
+    },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let word = |value: &Option<i64>| value.map_or("-".to_string(), |value| value.to_string());
+        match self {
+            Change::Stack {
+                index,
+                before,
+                after,
+            } => write!(f, "stack[{index}]: {} -> {}", word(before), word(after)),
+            Change::Variable {
+                frame: 0,
+                slot,
+                before,
+                after,
+            } => write!(f, "global {slot}: {} -> {}", word(before), word(after)),
+            Change::Variable {
+                frame,
+                slot,
+                before,
+                after,
+            } => write!(
+                f,
+                "frame at depth {frame} slot {slot}: {} -> {}",
+                word(before),
+                word(after)
+            ),
+            Change::Heap {
+                address,
+                before,
+                after,
+            } => write!(f, "heap[{address}]: {} -> {}", word(before), word(after)),
+            Change::Map {
+                index,
+                key,
+                before,
+                after,
+            } => write!(
+                f,
+                "map #{index}[{key}]: {} -> {}",
+                word(before),
+                word(after)
+            ),
+        }
+    }
+}
+
+// every stack entry, frame variable, heap word and map entry that isn't the
+// same in `after` as in `before`, in that order.
+pub fn diff(before: &CoreDump, after: &CoreDump) -> Vec<Change> {
+    let mut out = vec![];
+    let indexed =
+        |words: &[i64]| -> BTreeMap<usize, i64> { words.iter().copied().enumerate().collect() };
+    for (index, before, after) in changes(indexed(&before.stack), indexed(&after.stack)) {
+        out.push(Change::Stack {
+            index,
+            before,
+            after,
+        });
+    }
+    let variables = |dump: &CoreDump| -> BTreeMap<(usize, i64), i64> {
+        let frames = dump.frames.iter().enumerate();
+        frames
+            .flat_map(|(frame, dump)| dump.variables.iter().map(move |(k, v)| ((frame, *k), *v)))
+            .collect()
+    };
+    for ((frame, slot), before, after) in changes(variables(before), variables(after)) {
+        out.push(Change::Variable {
+            frame,
+            slot,
+            before,
+            after,
+        });
+    }
+    for (address, before, after) in changes(indexed(&before.heap), indexed(&after.heap)) {
+        out.push(Change::Heap {
+            address,
+            before,
+            after,
+        });
+    }
+    let entries = |dump: &CoreDump| -> BTreeMap<(usize, i64), i64> {
+        let maps = dump.maps.iter().enumerate();
+        maps.flat_map(|(index, map)| map.iter().map(move |(k, v)| ((index, *k), *v)))
+            .collect()
+    };
+    for ((index, key), before, after) in changes(entries(before), entries(after)) {
+        out.push(Change::Map {
+            index,
+            key,
+            before,
+            after,
+        });
+    }
+    out
+}
+
+// keys whose values differ, in key order.
+fn changes<K: Ord + Copy>(
+    before: BTreeMap<K, i64>,
+    after: BTreeMap<K, i64>,
+) -> Vec<(K, Option<i64>, Option<i64>)> {
+    let keys: BTreeSet<K> = before.keys().chain(after.keys()).copied().collect();
+    keys.into_iter()
+        .map(|key| (key, before.get(&key).copied(), after.get(&key).copied()))
+        .filter(|(_, before, after)| before != after)
+        .collect()
+}
+
+// the diff a line per change, after where each dump had got to.
+pub fn diff_to_text(before: &CoreDump, after: &CoreDump) -> String {
+    let mut out = format!(
+        "ip: {} -> {}, steps: {} -> {}\n",
+        before.instruction_pointer, after.instruction_pointer, before.steps, after.steps
+    );
+    let changes = diff(before, after);
+    if changes.is_empty() {
+        out.push_str("no changes\n");
+    }
+    for change in changes {
+        let _ = writeln!(out, "{change}");
+    }
+    out
+}
+
+// everything in the dump, laid out for a person: the error, the call stack
+// with source lines where there's debug info, the code around the fault and
+// the machine state.
+pub fn to_text(dump: &CoreDump) -> Result<String> {
+    let program = &dump.program;
+    let instructions = decode(program.code())?;
+    let functions = call_graph(program)?;
+
+    let mut out = String::new();
+    let _ = writeln!(out, "error: {}", dump.error);
+    let _ = writeln!(
+        out,
+        "at {} {}",
+        dump.address,
+        locate(program, &functions, dump.address)
+    );
+    let _ = writeln!(out, "after {} steps, {} cycles", dump.steps, dump.cycles);
+
+    let _ = writeln!(out, "\ncall stack, innermost first:");
+    out.push_str(&backtrace(dump)?);
+
+    let _ = writeln!(out, "\ncode:");
+    let index = instructions.partition_point(|i| i.address < dump.address);
+    let start = index.saturating_sub(CONTEXT);
+    let end = (index + CONTEXT + 1).min(instructions.len());
+    for instruction in instructions[start..end].iter() {
+        if let Some(symbol) = program.symbol_at(instruction.address as i64) {
+            let _ = writeln!(out, "         {}", symbol.name);
+        }
+        let marker = if instruction.address == dump.address {
+            "=>"
+        } else {
+            "  "
+        };
+        let _ = writeln!(out, "  {marker} {:>4}: {instruction}", instruction.address);
+    }
+
+    let _ = writeln!(out, "\nstack: {:?}", dump.stack);
+    let addresses = frame_addresses(dump, &instructions);
+    for (number, (frame, address)) in dump.frames.iter().rev().zip(addresses).enumerate() {
+        // named the way the code the frame is in names them.
+        let variables = program.describe_variables(address as i64, &frame.variables);
+        let _ = writeln!(
+            out,
+            "frame #{number} returns to {}, variables {}",
+            frame.return_address,
+            match variables.is_empty() {
+                true => "none".to_string(),
+                false => variables.join(", "),
+            }
+        );
+    }
+    if !dump.heap.is_empty() {
+        let _ = writeln!(out, "heap: {:?}", dump.heap);
+    }
+    for (address, record) in records(dump) {
+        let _ = writeln!(out, "record at {address}: {record}");
+    }
+    for (index, map) in dump.maps.iter().enumerate() {
+        let _ = writeln!(out, "map #{index}: {map:?}");
+    }
+    if !dump.trace.is_empty() {
+        let _ = writeln!(out, "\nlast {} instructions:", dump.trace.len());
+        for address in dump.trace.iter() {
+            let _ = match instructions.iter().find(|i| i.address == *address) {
+                Some(instruction) => writeln!(out, "  {address:>6}: {instruction}"),
+                None => writeln!(out, "  {address:>6}: ???"),
+            };
+        }
+    }
+    Ok(out)
+}
+
+// `function+offset (file:line)` for a code address.
+fn locate(program: &Program, functions: &[Function], address: usize) -> String {
+    let function = functions.iter().rev().find(|f| f.start <= address);
+    let mut out = match function {
+        Some(f) if f.start == address => f.name.clone(),
+        Some(f) => format!("{}+{}", f.name, address - f.start),
+        None => format!("<{address}>"),
+    };
+    if let Some(line) = program.debug_info().lines.get(&(address as i64)) {
+        let file = program.debug_info().file.as_deref().unwrap_or("<source>");
+        let _ = write!(out, " ({file}:{line})");
+    }
+    out
+}
+
+// a line per frame, innermost first, starting from the dump's address.
+pub fn backtrace(dump: &CoreDump) -> Result<String> {
+    let program = &dump.program;
+    let instructions = decode(program.code())?;
+    let functions = call_graph(program)?;
+    let mut out = String::new();
+    for (number, address) in frame_addresses(dump, &instructions).into_iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "  #{number} {address:>6} {}",
+            locate(program, &functions, address)
+        );
+    }
+    Ok(out)
+}
+
+// where each frame had got to, innermost first. Each frame above the root was
+// entered by the instruction just before its return address, which is where
+// its caller is sitting.
+fn frame_addresses(dump: &CoreDump, instructions: &[Instruction]) -> Vec<usize> {
+    let mut addresses = vec![];
+    let mut address = dump.address;
+    for frame in dump.frames.iter().rev() {
+        addresses.push(address);
+        address = instructions
+            .iter()
+            .find(|i| i.next_address() == frame.return_address)
+            .map_or(frame.return_address, |i| i.address);
+    }
+    addresses
+}
+
+// the records on the heap as `:point { x: 1, y: 2 }`, falling back to field
+// offsets for shapes the debug info doesn't name.
+fn records(dump: &CoreDump) -> Vec<(usize, String)> {
+    let mut out = vec![];
+    for (address, object) in heap_objects(&dump.heap, dump.program.constants()) {
+        if object[0] != RECORD_TAG {
+            continue;
+        }
+        let info = dump
+            .program
+            .debug_info()
+            .records
+            .iter()
+            .find(|record| record.shape == object[1]);
+        let fields: Vec<String> = object[2..]
+            .iter()
+            .enumerate()
+            .map(
+                |(offset, value)| match info.and_then(|record| record.fields.get(offset)) {
+                    Some(field) => format!("{field}: {value}"),
+                    None => format!("{offset}: {value}"),
+                },
+            )
+            .collect();
+        let name = info.map_or(format!("<shape {}>", object[1]), |record| {
+            record.name.clone()
+        });
+        out.push((address, format!("{name} {{ {} }}", fields.join(", "))));
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::{parse_program, AssemblerOptions};
+    use crate::cpu::{Cpu, HALT, PUSH};
+
+    #[test]
+    fn save_and_load() {
+        let dump = CoreDump {
+            error: "Division by zero at address 4".to_string(),
+            address: 4,
+            instruction_pointer: 5,
+            stack: vec![1],
+            frames: vec![FrameDump {
+                return_address: 0,
+                variables: BTreeMap::from([(0, 7)]),
+            }],
+            heap: vec![],
+            maps: vec![],
+            trace: vec![0, 2],
+            steps: 2,
+            cycles: 2,
+            program: Program::from_code(vec![PUSH, 1, HALT]).unwrap(),
+        };
+        let path = std::env::temp_dir().join(format!("coredump-{}.bcore", std::process::id()));
+        dump.save(&path).unwrap();
+        let loaded = CoreDump::load(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(dump, loaded.unwrap());
+    }
+
+    #[test]
+    fn diffs() {
+        let source = "push 1\nstore 0\npush 7\npush 5\nstore 0\nmnew\npush 3\npush 4\nmset\nhalt";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(program);
+        cpu.add_breakpoint(4, None);
+        cpu.run_to_break().unwrap();
+        let before = cpu.state_dump();
+        cpu.run().unwrap();
+        let after = cpu.state_dump();
+
+        assert!(diff(&before, &before).is_empty());
+        assert_eq!(
+            Change::Variable {
+                frame: 0,
+                slot: 0,
+                before: Some(1),
+                after: Some(5)
+            },
+            diff(&before, &after)[1]
+        );
+        assert_eq!(
+            "ip: 4 -> 17, steps: 2 -> 10\n\
+             stack[0]: - -> 7\n\
+             global 0: 1 -> 5\n\
+             heap[0]: - -> 3\n\
+             heap[1]: - -> 0\n\
+             map #0[3]: - -> 4\n",
+            diff_to_text(&before, &after)
+        );
+    }
+
+    #[test]
+    fn text_has_the_call_stack() {
+        let source =
+            "push 3\ncall :outer\nhalt\n:outer\npush 0\ncall :inner\nret\n:inner\ndiv\nret";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(program);
+        let err = cpu.run().unwrap_err();
+        let text = to_text(&cpu.core_dump(&err)).unwrap();
+
+        let stack: Vec<&str> = text
+            .lines()
+            .filter(|line| line.trim_start().starts_with('#'))
+            .map(|line| line.split_whitespace().nth(2).unwrap())
+            .collect();
+        assert_eq!(vec![":inner", ":outer+2", "<entry>+2"], stack);
+        assert!(text.contains("=>   10: div"));
+    }
+
+    #[test]
+    fn text_names_variables() {
+        let source = "push 7\nstore 0\npush 0\ncall :ratio\nhalt\n\
+                      .fn :ratio\n.var divisor 0\nstore :.divisor\npush 1\n\
+                      load :.divisor\ndiv\nret\n.endfn";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(program);
+        let err = cpu.run().unwrap_err();
+        let text = to_text(&cpu.core_dump(&err)).unwrap();
+        assert!(text.contains("frame #0 returns to 8, variables divisor=0 (slot 0)\n"));
+        // the caller's slot 0 isn't the divisor.
+        assert!(text.contains("frame #1 returns to 0, variables 0 = 7\n"));
+    }
+
+    #[test]
+    fn text_names_record_fields() {
+        let source = ".record :point x y\nrnew :point\ndup\npush 4\nrset :point.y\nrget 2";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(program);
+        let err = cpu.run().unwrap_err();
+        let text = to_text(&cpu.core_dump(&err)).unwrap();
+        assert!(text.contains("record at 0: :point { x: 0, y: 4 }"));
+    }
+}
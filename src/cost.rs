@@ -0,0 +1,138 @@
+// how many "cycles" each instruction takes, for people who want to talk
+// about performance without a real pipeline to measure.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::cpu::{
+    Opcode, CALL, CALLCLOS, DIV, DLOAD, FXDIV, FXMUL, HALT, HLOAD, HSTORE, JEQ, JGE, JIF, JLT, JMP,
+    JNE, JNZ, JZ, LOAD, LOOP, MKCLOS, MUL, RET, STORE, STRCAT, STRNEW,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CostModel {
+    default_cost: u64,
+    costs: HashMap<i64, u64>,
+}
+
+impl Default for CostModel {
+    // loosely shaped like a simple in-order cpu: memory and control flow cost
+    // more than stack shuffling, division is slow.
+    fn default() -> Self {
+        let costs = HashMap::from([
+            (HALT, 1),
+            (MUL, 3),
+            (DIV, 10),
+            (FXMUL, 4),
+            (FXDIV, 12),
+            (JMP, 2),
+            (JIF, 2),
+            (JZ, 2),
+            (JNZ, 2),
+            (JEQ, 2),
+            (JNE, 2),
+            (JLT, 2),
+            (JGE, 2),
+            // a load, a store and a branch.
+            (LOOP, 3),
+            (LOAD, 2),
+            (DLOAD, 2),
+            (STORE, 2),
+            (HLOAD, 2),
+            (HSTORE, 2),
+            (CALL, 5),
+            (RET, 5),
+            (MKCLOS, 4),
+            (CALLCLOS, 6),
+            (STRNEW, 4),
+            (STRCAT, 4),
+        ]);
+        Self {
+            default_cost: 1,
+            costs,
+        }
+    }
+}
+
+impl CostModel {
+    // everything costs the same.
+    pub fn uniform(cost: u64) -> Self {
+        Self {
+            default_cost: cost,
+            costs: HashMap::new(),
+        }
+    }
+
+    pub fn cost(&self, opcode: i64) -> u64 {
+        self.costs
+            .get(&opcode)
+            .copied()
+            .unwrap_or(self.default_cost)
+    }
+
+    pub fn set(&mut self, opcode: i64, cost: u64) {
+        self.costs.insert(opcode, cost);
+    }
+
+    // starts from the defaults, e.g.
+    //
+    //   default = 1
+    //   [costs]
+    //   mul = 3
+    //   div = 20
+    pub fn from_toml(source: &str) -> Result<Self> {
+        let table: toml::Table = source.parse().context("Cost table is not valid toml")?;
+        let mut model = Self::default();
+        for (key, value) in table.iter() {
+            match (key.as_str(), value) {
+                ("default", toml::Value::Integer(cost)) => {
+                    model.default_cost = to_cost(key, *cost)?
+                }
+                ("costs", toml::Value::Table(costs)) => {
+                    for (mnemonic, cost) in costs.iter() {
+                        let Some(opcode) = Opcode::from_mnemonic(mnemonic) else {
+                            bail!("Unknown instruction {mnemonic} in cost table")
+                        };
+                        let Some(cost) = cost.as_integer() else {
+                            bail!("Cost for {mnemonic} is not an integer")
+                        };
+                        model.set(opcode.value(), to_cost(mnemonic, cost)?);
+                    }
+                }
+                (key, _) => bail!("Unexpected key {key} in cost table"),
+            }
+        }
+        Ok(model)
+    }
+
+    pub fn from_toml_file(path: impl AsRef<Path>) -> Result<Self> {
+        let source = std::fs::read_to_string(path).context("Could not read cost table")?;
+        Self::from_toml(&source)
+    }
+}
+
+fn to_cost(name: &str, cost: i64) -> Result<u64> {
+    u64::try_from(cost).with_context(|| format!("Cost for {name} can't be negative"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::ADD;
+
+    #[test]
+    fn toml_overrides_defaults() {
+        let model = CostModel::from_toml("default = 2\n[costs]\ndiv = 40\n").unwrap();
+        assert_eq!(40, model.cost(DIV));
+        assert_eq!(3, model.cost(MUL));
+        assert_eq!(2, model.cost(ADD));
+    }
+
+    #[test]
+    fn toml_rejects_unknown_instructions() {
+        assert!(CostModel::from_toml("[costs]\nfrobnicate = 1\n").is_err());
+        assert!(CostModel::from_toml("[costs]\nadd = -1\n").is_err());
+    }
+}
@@ -1,555 +1,4722 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::{self, Write};
+use std::io::{self, Write as _};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Context, Result};
+use serde::{Serialize, Serializer};
 
-pub const PUSH: i64 = 1;
-pub const HALT: i64 = 3;
-pub const ADD: i64 = 4;
-pub const SUB: i64 = 5;
-pub const MUL: i64 = 6;
-pub const DIV: i64 = 7;
-pub const NOT: i64 = 8;
-pub const AND: i64 = 9;
-pub const OR: i64 = 10;
-pub const POP: i64 = 11;
-pub const DUP: i64 = 12;
-pub const ISEQ: i64 = 13;
-pub const ISGT: i64 = 14;
-pub const ISGE: i64 = 15;
-pub const JMP: i64 = 16;
-pub const JIF: i64 = 17;
-pub const LOAD: i64 = 18;
-pub const STORE: i64 = 19;
-pub const CALL: i64 = 20;
-pub const RET: i64 = 21;
-pub const PRNSTK: i64 = 22;
+use crate::breakpoint::Condition;
+use crate::coredump::{CoreDump, FrameDump};
+use crate::cost::CostModel;
+use crate::disassembler::decode_at;
+use crate::host::{Binding, HostFunction};
+use crate::profiler::Profiler;
+use crate::program::{
+    Program, FEATURE_CLOSURES, FEATURE_FIXED_POINT, FEATURE_MAPS, FEATURE_MEMORY, FEATURE_RECORDS,
+    FEATURE_STRINGS,
+};
+
+// every instruction, described once. Each entry is the constant for the word
+// it's encoded as and that word, the Opcode variant, then the mnemonic, how
+// many inline operands follow it, its stack effect, what it should do (see
+// Semantics), category, the Cpu method that runs it, and what it does in
+// words for `--explain`, where `{a}`, `{b}` and `{c}` are the values it pops,
+// deepest first, `{n}` is its last operand and `{s}` the variable slot before
+// it, for LOOP.
+macro_rules! instructions {
+    ($(
+        $name:ident = $value:literal, $variant:ident, $mnemonic:literal, $operands:literal,
+        $effect:ident $(($pops:literal, $pushes:literal))?,
+        $semantics:ident $(($reference:path))?, $category:ident, $execute:ident,
+        $summary:literal;
+    )*) => {
+        // an instruction the vm knows. The words are part of the bytecode
+        // format, so never renumber one.
+        #[repr(i64)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub enum Opcode {
+            $($variant = $value),*
+        }
+
+        // the words, for writing code by hand.
+        $(pub const $name: i64 = Opcode::$variant as i64;)*
+
+        impl TryFrom<i64> for Opcode {
+            type Error = anyhow::Error;
+
+            fn try_from(value: i64) -> Result<Self> {
+                match value {
+                    $($value => Ok(Opcode::$variant),)*
+                    _ => bail!("Unknown opcode {value}"),
+                }
+            }
+        }
+
+        // in opcode order, starting from 1.
+        pub static INSTRUCTIONS: &[InstructionInfo] = &[$(InstructionInfo {
+            opcode: Opcode::$variant,
+            mnemonic: $mnemonic,
+            operands: $operands,
+            effect: StackEffect::$effect $(($pops, $pushes))?,
+            semantics: Semantics::$semantics $(($reference))?,
+            category: Category::$category,
+            summary: $summary,
+            execute: Cpu::$execute,
+        }),*];
+    };
+}
+
+instructions! {
+    PUSH = 1, Push, "push", 1, Fixed(0, 1), Pure(reference::push), Stack, op_push, "push {n}";
+    NOP = 2, Nop, "nop", 0, Fixed(0, 0), Pure(reference::nothing), Control, op_nop, "do nothing";
+    HALT = 3, Halt, "halt", 0, Fixed(0, 0), Pure(reference::nothing), Control, op_halt,
+        "stop the machine";
+    ADD = 4, Add, "add", 0, Fixed(2, 1), Pure(reference::add), Arithmetic, op_binary, "{a} + {b}";
+    SUB = 5, Sub, "sub", 0, Fixed(2, 1), Pure(reference::sub), Arithmetic, op_binary, "{a} - {b}";
+    MUL = 6, Mul, "mul", 0, Fixed(2, 1), Pure(reference::mul), Arithmetic, op_binary, "{a} * {b}";
+    DIV = 7, Div, "div", 0, Fixed(2, 1), Pure(reference::div), Arithmetic, op_binary, "{a} / {b}";
+    NOT = 8, Not, "not", 0, Fixed(1, 1), Pure(reference::not), Logic, op_not, "not {a}";
+    AND = 9, And, "and", 0, Fixed(2, 1), Pure(reference::and), Logic, op_binary, "{a} and {b}";
+    OR = 10, Or, "or", 0, Fixed(2, 1), Pure(reference::or), Logic, op_binary, "{a} or {b}";
+    POP = 11, Pop, "pop", 0, Fixed(1, 0), Pure(reference::nothing), Stack, op_pop, "throw away {a}";
+    DUP = 12, Dup, "dup", 0, Fixed(1, 2), Pure(reference::dup), Stack, op_dup, "copy {a}";
+    ISEQ = 13, Iseq, "iseq", 0, Fixed(2, 1), Pure(reference::iseq), Comparison, op_binary,
+        "{a} == {b}";
+    ISGT = 14, Isgt, "isgt", 0, Fixed(2, 1), Pure(reference::isgt), Comparison, op_binary,
+        "{a} > {b}";
+    ISGE = 15, Isge, "isge", 0, Fixed(2, 1), Pure(reference::isge), Comparison, op_binary,
+        "{a} >= {b}";
+    JMP = 16, Jmp, "jmp", 1, Fixed(0, 0), Branch(reference::always), Control, op_jmp, "jump to {n}";
+    JIF = 17, Jif, "jif", 1, Fixed(1, 0), Branch(reference::nonzero), Control, op_jif,
+        "jump to {n} if {a} isn't 0";
+    LOAD = 18, Load, "load", 1, Fixed(0, 1), Stateful, Variables, op_load, "read variable {n}";
+    STORE = 19, Store, "store", 1, Fixed(1, 0), Stateful, Variables, op_store,
+        "write {a} to variable {n}";
+    // the stack effect of a call is up to the function being called.
+    CALL = 20, Call, "call", 1, Varies, Stateful, Control, op_call, "call the function at {n}";
+    RET = 21, Ret, "ret", 0, Fixed(0, 0), Stateful, Control, op_ret, "return to the caller";
+    PRNSTK = 22, Prnstk, "prnstk", 0, Fixed(0, 0), Stateful, Output, op_prnstk, "print the stack";
+    // pops the captures, then the function address.
+    MKCLOS = 23, Mkclos, "mkclos", 1, Captures, Stateful, Closures, op_mkclos,
+        "close over {n} values";
+    CALLCLOS = 24, Callclos, "callclos", 0, Varies, Stateful, Closures, op_callclos,
+        "call the closure on top of the stack";
+    PUSHC = 25, Pushc, "pushc", 1, Fixed(0, 1), Stateful, Stack, op_pushc, "push constant {n}";
+    DLOAD = 26, Dload, "dload", 0, Fixed(1, 1), Stateful, Data, op_dload, "read data word {a}";
+    PRNCHR = 27, Prnchr, "prnchr", 0, Fixed(1, 0), Stateful, Output, op_prnchr,
+        "print character {a}";
+    // 32.32 fixed point multiply and divide.
+    FXMUL = 28, Fxmul, "fxmul", 0, Fixed(2, 1), Pure(reference::fxmul), FixedPoint, op_binary,
+        "{a} * {b} in fixed point";
+    FXDIV = 29, Fxdiv, "fxdiv", 0, Fixed(2, 1), Pure(reference::fxdiv), FixedPoint, op_binary,
+        "{a} / {b} in fixed point";
+    // heap strings.
+    STRNEW = 30, Strnew, "strnew", 0, Fixed(1, 1), Stateful, Strings, op_strnew,
+        "make a string from data at {a}";
+    STRLEN = 31, Strlen, "strlen", 0, Fixed(1, 1), Stateful, Strings, op_strlen,
+        "length of string {a}";
+    STRCAT = 32, Strcat, "strcat", 0, Fixed(2, 1), Stateful, Strings, op_strcat,
+        "join strings {a} and {b}";
+    STRCMP = 33, Strcmp, "strcmp", 0, Fixed(2, 1), Stateful, Strings, op_strcmp,
+        "compare strings {a} and {b}";
+    STRGET = 34, Strget, "strget", 0, Fixed(2, 1), Stateful, Strings, op_strget,
+        "character {b} of string {a}";
+    PRNSTR = 35, Prnstr, "prnstr", 0, Fixed(1, 0), Stateful, Strings, op_prnstr, "print string {a}";
+    // heap maps from words to words.
+    MNEW = 36, Mnew, "mnew", 0, Fixed(0, 1), Stateful, Maps, op_mnew, "make an empty map";
+    MGET = 37, Mget, "mget", 0, Fixed(2, 1), Stateful, Maps, op_mget, "look up {b} in map {a}";
+    MSET = 38, Mset, "mset", 0, Fixed(3, 0), Stateful, Maps, op_mset, "set {b} to {c} in map {a}";
+    MDEL = 39, Mdel, "mdel", 0, Fixed(2, 0), Stateful, Maps, op_mdel, "remove {b} from map {a}";
+    MLEN = 40, Mlen, "mlen", 0, Fixed(1, 1), Stateful, Maps, op_mlen,
+        "count the entries in map {a}";
+    MHAS = 41, Mhas, "mhas", 0, Fixed(2, 1), Stateful, Maps, op_mhas, "map {a} has {b}";
+    // fixed-shape records, described by a field count in the constant pool.
+    RNEW = 42, Rnew, "rnew", 1, Fixed(0, 1), Stateful, Records, op_rnew,
+        "make a record of shape {n}";
+    RGET = 43, Rget, "rget", 1, Fixed(1, 1), Stateful, Records, op_rget,
+        "read field {n} of record {a}";
+    RSET = 44, Rset, "rset", 1, Fixed(2, 0), Stateful, Records, op_rset,
+        "set field {n} of record {a} to {b}";
+    // hand control back to whoever called run_to_break().
+    BRK = 45, Brk, "brk", 0, Fixed(0, 0), Stateful, Debug, op_brk, "stop for the debugger";
+    // raw heap words by address, or a device if one is mapped there.
+    HLOAD = 46, Hload, "hload", 0, Fixed(1, 1), Stateful, Memory, op_hload, "read heap word {a}";
+    HSTORE = 47, Hstore, "hstore", 0, Fixed(2, 0), Stateful, Memory, op_hstore,
+        "write {b} to heap word {a}";
+    // jump to the handler in the interrupt vector, and come back with IRET.
+    INT = 48, Int, "int", 1, Varies, Stateful, Interrupts, op_int, "raise interrupt {n}";
+    IRET = 49, Iret, "iret", 0, Fixed(0, 0), Stateful, Interrupts, op_iret,
+        "return from an interrupt handler";
+    // a rust function bound with Cpu::bind(), which decides the stack effect.
+    SYSCALL = 50, Syscall, "syscall", 1, Varies, Stateful, Host, op_syscall,
+        "call host function {n}";
+    // fused tests, for what would otherwise be `push 0` `iseq` `jif`.
+    ISZERO = 51, Iszero, "iszero", 0, Fixed(1, 1), Pure(reference::iszero), Comparison, op_iszero,
+        "{a} == 0";
+    JZ = 52, Jz, "jz", 1, Fixed(1, 0), Branch(reference::zero), Control, op_jif,
+        "jump to {n} if {a} is 0";
+    // the same as JIF, for symmetry with JZ.
+    JNZ = 53, Jnz, "jnz", 1, Fixed(1, 0), Branch(reference::nonzero), Control, op_jif,
+        "jump to {n} if {a} isn't 0";
+    // compare and branch in one, for loop conditions.
+    JEQ = 54, Jeq, "jeq", 1, Fixed(2, 0), Branch(reference::eq), Control, op_jcmp,
+        "jump to {n} if {a} == {b}";
+    JNE = 55, Jne, "jne", 1, Fixed(2, 0), Branch(reference::ne), Control, op_jcmp,
+        "jump to {n} if {a} != {b}";
+    JLT = 56, Jlt, "jlt", 1, Fixed(2, 0), Branch(reference::lt), Control, op_jcmp,
+        "jump to {n} if {a} < {b}";
+    JGE = 57, Jge, "jge", 1, Fixed(2, 0), Branch(reference::ge), Control, op_jcmp,
+        "jump to {n} if {a} >= {b}";
+    // counted loops, the slot then the target.
+    LOOP = 58, Loop, "loop", 2, Fixed(0, 0), Stateful, Control, op_loop,
+        "count variable {s} down and jump to {n} while it's above 0";
+}
+
+pub struct InstructionInfo {
+    pub opcode: Opcode,
+    pub mnemonic: &'static str,
+    // inline operand words after the opcode.
+    pub operands: usize,
+    pub effect: StackEffect,
+    pub semantics: Semantics,
+    pub category: Category,
+    // see `describe_instruction`.
+    pub summary: &'static str,
+    execute: fn(&mut Cpu, Opcode) -> Result<()>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StackEffect {
+    // pops this many, then pushes this many.
+    Fixed(usize, usize),
+    // MKCLOS, which pops as many captures as its operand says and the
+    // function address under them.
+    Captures,
+    // up to the function being called.
+    Varies,
+}
+
+// what an instruction should do, which the tests run every instruction
+// against boundary operands to check.
+#[derive(Debug, Clone, Copy)]
+pub enum Semantics {
+    // pushes what the function gives for the words it pops, deepest first,
+    // and its operand, or traps where that's None.
+    Pure(fn(&[i64], i64) -> Option<Vec<i64>>),
+    // jumps to its operand when the function says so for the words it pops.
+    Branch(fn(&[i64]) -> bool),
+    // depends on variables, the heap, the host or the call stack, so only its
+    // stack effect is checked.
+    Stateful,
+}
+
+// the reference for Semantics::Pure and Semantics::Branch, where `x` is what
+// the instruction pops, deepest first, and `n` its last operand.
+mod reference {
+    use super::FIXED_POINT_BITS;
+
+    type Pushes = Option<Vec<i64>>;
+
+    // None where it doesn't fit in a word, which traps with Overflow::Trap.
+    fn narrow(wide: i128) -> Pushes {
+        i64::try_from(wide).ok().map(|word| vec![word])
+    }
+
+    fn flag(condition: bool) -> Pushes {
+        Some(vec![condition as i64])
+    }
+
+    pub fn push(_: &[i64], n: i64) -> Pushes {
+        Some(vec![n])
+    }
+
+    pub fn nothing(_: &[i64], _: i64) -> Pushes {
+        Some(vec![])
+    }
+
+    pub fn dup(x: &[i64], _: i64) -> Pushes {
+        Some(vec![x[0], x[0]])
+    }
+
+    pub fn add(x: &[i64], _: i64) -> Pushes {
+        narrow(x[0] as i128 + x[1] as i128)
+    }
+
+    pub fn sub(x: &[i64], _: i64) -> Pushes {
+        narrow(x[0] as i128 - x[1] as i128)
+    }
+
+    pub fn mul(x: &[i64], _: i64) -> Pushes {
+        narrow(x[0] as i128 * x[1] as i128)
+    }
+
+    // truncates toward zero.
+    pub fn div(x: &[i64], _: i64) -> Pushes {
+        match x[1] {
+            0 => None,
+            divisor => narrow(x[0] as i128 / divisor as i128),
+        }
+    }
+
+    // rounds toward negative infinity.
+    pub fn fxmul(x: &[i64], _: i64) -> Pushes {
+        narrow((x[0] as i128 * x[1] as i128) >> FIXED_POINT_BITS)
+    }
+
+    // truncates toward zero, like DIV.
+    pub fn fxdiv(x: &[i64], _: i64) -> Pushes {
+        match x[1] {
+            0 => None,
+            divisor => narrow(((x[0] as i128) << FIXED_POINT_BITS) / divisor as i128),
+        }
+    }
+
+    // anything but 0 is true.
+    pub fn not(x: &[i64], _: i64) -> Pushes {
+        flag(x[0] == 0)
+    }
+
+    pub fn and(x: &[i64], _: i64) -> Pushes {
+        flag(x[0] != 0 && x[1] != 0)
+    }
+
+    pub fn or(x: &[i64], _: i64) -> Pushes {
+        flag(x[0] != 0 || x[1] != 0)
+    }
+
+    pub fn iseq(x: &[i64], _: i64) -> Pushes {
+        flag(x[0] == x[1])
+    }
+
+    pub fn isgt(x: &[i64], _: i64) -> Pushes {
+        flag(x[0] > x[1])
+    }
+
+    pub fn isge(x: &[i64], _: i64) -> Pushes {
+        flag(x[0] >= x[1])
+    }
+
+    pub fn iszero(x: &[i64], _: i64) -> Pushes {
+        flag(x[0] == 0)
+    }
+
+    pub fn always(_: &[i64]) -> bool {
+        true
+    }
+
+    pub fn nonzero(x: &[i64]) -> bool {
+        x[0] != 0
+    }
+
+    pub fn zero(x: &[i64]) -> bool {
+        x[0] == 0
+    }
+
+    pub fn eq(x: &[i64]) -> bool {
+        x[0] == x[1]
+    }
+
+    pub fn ne(x: &[i64]) -> bool {
+        x[0] != x[1]
+    }
+
+    pub fn lt(x: &[i64]) -> bool {
+        x[0] < x[1]
+    }
+
+    pub fn ge(x: &[i64]) -> bool {
+        x[0] >= x[1]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Category {
+    Stack,
+    Arithmetic,
+    FixedPoint,
+    Logic,
+    Comparison,
+    Control,
+    Variables,
+    Closures,
+    Data,
+    Output,
+    Strings,
+    Maps,
+    Records,
+    Debug,
+    Memory,
+    Interrupts,
+    Host,
+}
+
+impl Category {
+    // the vm feature a program using an instruction from here needs.
+    pub fn feature(self) -> Option<&'static str> {
+        let feature = match self {
+            Self::Closures => FEATURE_CLOSURES,
+            Self::FixedPoint => FEATURE_FIXED_POINT,
+            Self::Strings => FEATURE_STRINGS,
+            Self::Maps => FEATURE_MAPS,
+            Self::Records => FEATURE_RECORDS,
+            Self::Memory => FEATURE_MEMORY,
+            _ => return None,
+        };
+        Some(feature)
+    }
+}
+
+// the table entry for an opcode, None if there's no such instruction.
+pub fn instruction(opcode: i64) -> Option<&'static InstructionInfo> {
+    let index = usize::try_from(opcode.checked_sub(1)?).ok()?;
+    INSTRUCTIONS.get(index)
+}
+
+// jumps that only happen if a test passes, and fall through otherwise.
+pub fn is_branch(opcode: Opcode) -> bool {
+    use Opcode::*;
+    matches!(opcode, Jif | Jz | Jnz | Jeq | Jne | Jlt | Jge | Loop)
+}
+
+// instructions whose last operand is a code address to go to.
+pub fn has_code_operand(opcode: Opcode) -> bool {
+    matches!(opcode, Opcode::Jmp | Opcode::Call) || is_branch(opcode)
+}
+
+// fraction bits in a fixed point word, so 1.0 is `1 << FIXED_POINT_BITS`.
+pub const FIXED_POINT_BITS: u32 = 32;
+
+// mnemonic and number of inline operands for each instruction.
+pub fn instruction_info(opcode: i64) -> Option<(&'static str, usize)> {
+    instruction(opcode).map(|info| (info.mnemonic, info.operands))
+}
+
+// how many values an instruction pops and then pushes. None for the calls
+// and INT, where that's up to the function being called.
+pub fn stack_effect(opcode: Opcode, operand: Option<i64>) -> Option<(usize, usize)> {
+    match opcode.info().effect {
+        StackEffect::Fixed(pops, pushes) => Some((pops, pushes)),
+        StackEffect::Captures => Some((usize::try_from(operand?).ok()? + 1, 1)),
+        StackEffect::Varies => None,
+    }
+}
+
+// an instruction's summary with the placeholders filled in, by values when
+// running or by names when disassembling, shared by `run --explain` and
+// `disasm --explain`.
+pub fn describe_instruction(opcode: Opcode, operands: &[String], popped: &[String]) -> String {
+    let mut summary = opcode.info().summary.to_string();
+    if let [slot, _] = operands {
+        summary = summary.replace("{s}", slot);
+    }
+    if let Some(operand) = operands.last() {
+        summary = summary.replace("{n}", operand);
+    }
+    for (name, value) in ["{a}", "{b}", "{c}"].iter().zip(popped) {
+        summary = summary.replace(name, value);
+    }
+    summary
+}
+
+// the one way in for tools that need to go between opcodes, mnemonics and
+// operand counts.
+impl Opcode {
+    // every instruction, in opcode order.
+    pub fn all() -> impl Iterator<Item = Opcode> {
+        INSTRUCTIONS.iter().map(|info| info.opcode)
+    }
+
+    // case insensitive, `PUSH` and `push` are the same instruction.
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Opcode> {
+        Self::all().find(|opcode| opcode.name().eq_ignore_ascii_case(mnemonic))
+    }
+
+    // the word it's encoded as.
+    pub fn value(self) -> i64 {
+        self as i64
+    }
+
+    // its table entry.
+    pub fn info(self) -> &'static InstructionInfo {
+        &INSTRUCTIONS[self as usize - 1]
+    }
+
+    // the lowercase mnemonic.
+    pub fn name(self) -> &'static str {
+        self.info().mnemonic
+    }
+
+    // how many inline operand words follow it.
+    pub fn operand_count(self) -> usize {
+        self.info().operands
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// as the word it's encoded as, the same as in the bytecode.
+impl Serialize for Opcode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_i64(self.value())
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Opcode {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(u.choose(INSTRUCTIONS)?.opcode)
+    }
+}
+
+// "1", "1 and 2", "1, 2 and 3".
+fn join_words(words: &[String]) -> String {
+    match words {
+        [] => String::new(),
+        [word] => word.clone(),
+        [rest @ .., last] => format!("{} and {last}", rest.join(", ")),
+    }
+}
 
 const TRUE: i64 = 1;
 const FALSE: i64 = 0;
 
-#[derive(Debug, Clone)]
+// every heap object starts with a tag word so we can tell what we're pointing at.
+pub(crate) const CLOSURE_TAG: i64 = 1;
+// strings are [STRING_TAG, length, chars...].
+pub(crate) const STRING_TAG: i64 = 2;
+// maps are [MAP_TAG, index into Cpu::maps]. The entries live outside the heap
+// so they can grow, but still count two words each against the heap limit.
+pub(crate) const MAP_TAG: i64 = 3;
+// records are [RECORD_TAG, shape, fields...], the shape being the constant
+// pool index holding the field count.
+pub(crate) const RECORD_TAG: i64 = 4;
+
+// where `address` in `old` ends up in `new`: the same distance past the
+// closest label before it, if `new` has that label. Anything before the first
+// label is measured from the entry point.
+fn relocate(old: &Program, new: &Program, address: usize) -> Option<usize> {
+    let label = old
+        .symbols()
+        .iter()
+        .filter(|symbol| usize::try_from(symbol.address).is_ok_and(|start| start <= address))
+        .max_by_key(|symbol| symbol.address);
+    let relocated = match label {
+        Some(label) => {
+            let offset = address - label.address as usize;
+            let start = new.symbols().iter().find(|s| s.name == label.name)?.address;
+            usize::try_from(start).ok()? + offset
+        }
+        None => address,
+    };
+    // just past the end is where a program with an implicit halt finishes.
+    (relocated <= new.code().len()).then_some(relocated)
+}
+
+// where record `shape` in `old`'s constant pool is in `new`'s: the record of
+// the same name, or the same index for records without one, so long as it
+// has as many fields.
+fn relocate_shape(old: &Program, new: &Program, shape: i64) -> Option<i64> {
+    let fields = |program: &Program, shape: i64| {
+        program
+            .constants()
+            .get(usize::try_from(shape).ok()?)
+            .copied()
+    };
+    let mut named = old.debug_info().records.iter();
+    let relocated = match named.find(|record| record.shape == shape) {
+        Some(record) => {
+            let mut records = new.debug_info().records.iter();
+            records.find(|new| new.name == record.name)?.shape
+        }
+        None => shape,
+    };
+    (fields(new, relocated)? == fields(old, shape)?).then_some(relocated)
+}
+
+// every object on the heap with its address, in order. Walking stops at
+// anything that doesn't look like a heap object, which HSTORE can leave
+// behind. Record sizes come from the loaded program's constant pool, which
+// replace_program() keeps their shapes pointing into.
+pub(crate) fn heap_objects<'a>(heap: &'a [i64], constants: &[i64]) -> Vec<(usize, &'a [i64])> {
+    let word = |index: usize| heap.get(index).copied();
+    let mut objects = vec![];
+    let mut address = 0;
+    while let Some(tag) = word(address) {
+        let size = match tag {
+            CLOSURE_TAG => word(address + 2).and_then(|captures| captures.checked_add(3)),
+            STRING_TAG => word(address + 1).and_then(|length| length.checked_add(2)),
+            MAP_TAG => Some(2),
+            RECORD_TAG => word(address + 1)
+                .and_then(|shape| constants.get(usize::try_from(shape).ok()?))
+                .and_then(|fields| fields.checked_add(2)),
+            _ => None,
+        };
+        let Some(size) = size.and_then(|size| usize::try_from(size).ok()) else {
+            break;
+        };
+        let Some(object) = address
+            .checked_add(size)
+            .and_then(|end| heap.get(address..end))
+        else {
+            break;
+        };
+        objects.push((address, object));
+        address += size;
+    }
+    objects
+}
+
+// variables below this live in a frame's slot array, which is what programs
+// written by hand or by a compiler almost always use.
+const FRAME_SLOTS: i64 = 64;
+
+// calls deep a program can go even with no frame limit set.
+pub const MAX_CALL_DEPTH: usize = 100_000;
+
+#[derive(Clone)]
 struct Frame {
+    // variables 0..FRAME_SLOTS, indexed directly. None until stored to.
+    slots: Vec<Option<i64>>,
+    // anything else, negative ids included.
     variables: HashMap<i64, i64>,
-    return_address: usize,
+    // where the function it's for starts, 0 for the root frame.
+    entry: usize,
 }
 
 impl Frame {
-    fn new(return_address: usize) -> Self {
+    fn new(entry: usize) -> Self {
         Self {
+            slots: vec![],
             variables: HashMap::new(),
-            return_address,
+            entry,
         }
     }
 
     // I hate that it gets something by default.
-    // my vm will not.
+    // my vm will not, with strict loads.
     fn get(&self, key: i64) -> i64 {
-        match self.variables.get(&key) {
-            Some(val) => *val,
-            None => 0,
+        self.try_get(key).unwrap_or(0)
+    }
+
+    // None if it's never been stored to.
+    fn try_get(&self, key: i64) -> Option<i64> {
+        if (0..FRAME_SLOTS).contains(&key) {
+            return self.slots.get(key as usize).copied().flatten();
         }
+        self.variables.get(&key).copied()
     }
 
     fn set(&mut self, key: i64, value: i64) {
+        if (0..FRAME_SLOTS).contains(&key) {
+            let index = key as usize;
+            if index >= self.slots.len() {
+                self.slots.resize(index + 1, None);
+            }
+            self.slots[index] = Some(value);
+            return;
+        }
         self.variables.insert(key, value);
     }
+
+    // every variable that's been stored to, by id.
+    fn variables(&self) -> BTreeMap<i64, i64> {
+        let slots = self
+            .slots
+            .iter()
+            .enumerate()
+            .filter_map(|(index, value)| value.map(|value| (index as i64, value)));
+        slots
+            .chain(self.variables.iter().map(|(k, v)| (*k, *v)))
+            .collect()
+    }
+}
+
+// how to get back out of a frame. These are kept on a stack of their own
+// rather than in the frames, so nothing a program does to its variables can
+// send a RET somewhere else.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Return {
+    address: usize,
+    // for an interrupt handler, how tall the stack was when it was
+    // interrupted. IRET puts it back that way.
+    interrupted_stack: Option<usize>,
+    // the words below this belong to interrupted code, so nothing in the
+    // frame may pop them. 0 outside any interrupt handler.
+    stack_floor: usize,
 }
 
 pub struct Cpu {
-    program: Vec<i64>,
+    // shared with forks, copied on write.
+    program: Arc<Program>,
     frames: Vec<Frame>,
+    // one for each frame but the root, in the same order.
+    returns: Vec<Return>,
     instruction_pointer: usize,
     stack: Vec<i64>,
+    // the lowest the stack has been during the current instruction, and the
+    // words it had there, top first. A trap handler skipping the instruction
+    // gets them put back.
+    step_low_water: usize,
+    step_popped: Vec<i64>,
+    heap: Vec<i64>,
+    maps: Vec<BTreeMap<i64, i64>>,
+    // entries across all maps.
+    map_entries: usize,
     halted: bool,
+    profiler: Option<Profiler>,
+    cost_model: CostModel,
+    cycles: u64,
+    steps: u64,
+    max_steps: Option<u64>,
+    timeout: Option<Duration>,
+    // addresses of the last few instructions executed, oldest first.
+    trace: VecDeque<usize>,
+    trace_length: usize,
+    limits: MemoryLimits,
+    memory_stats: MemoryStats,
+    trap_handler: Option<TrapHandler>,
+    // address of the instruction being executed.
+    current_address: usize,
+    implicit_halt: bool,
+    // LOAD of a variable that was never stored to fails instead of giving 0.
+    strict_loads: bool,
+    // where PRNSTK and PRNCHR write, stdout unless told otherwise.
+    output: Box<dyn io::Write + Send>,
+    stack_dump_format: StackDumpFormat,
+    overflow: Overflow,
+    nondeterminism: Nondeterminism,
+    host_inputs: Vec<HostInput>,
+    // write a line to `output` saying what each instruction did.
+    explain: bool,
+    // where run() streams execution events, if anywhere.
+    events: Option<Box<dyn io::Write + Send>>,
+    // set by BRK, run_to_break() returns once the instruction is done.
+    at_breakpoint: bool,
+    // patch() and replace_program() refuse to touch a loaded program.
+    write_protect_code: bool,
+    // interrupt number to handler address.
+    interrupt_vectors: BTreeMap<usize, usize>,
+    // raised by the host and not handled yet, oldest first.
+    pending_interrupts: VecDeque<usize>,
+    // heap address ranges HLOAD and HSTORE hand to a device instead.
+    devices: Vec<(Range<i64>, Box<dyn Device>)>,
+    // SYSCALL n calls the nth.
+    host_functions: Vec<Binding>,
+    // by address, checked before the instruction there runs.
+    breakpoints: BTreeMap<usize, Breakpoint>,
+    // where run_to_break() last stopped for one of `breakpoints`, so carrying
+    // on doesn't stop there again straight away.
+    stopped_at: Option<usize>,
+    // the step count run_slice() hands control back at.
+    slice_end: Option<u64>,
+    // set from anywhere to stop at the next instruction boundary.
+    cancelled: Arc<AtomicBool>,
+    // frames pushed for calls and interrupts.
+    calls: u64,
+    // time spent in run_to_break().
+    elapsed: Duration,
 }
 
-impl Cpu {
-    pub fn new() -> Self {
-        Self {
-            stack: vec![],
-            instruction_pointer: 0,
-            halted: false,
-            program: vec![],
-            frames: vec![Frame::new(0)],
-        }
+#[derive(Debug, Clone)]
+struct Breakpoint {
+    condition: Option<Condition>,
+    hits: u64,
+}
+
+// a read-only look at one call frame, from Cpu::frames().
+pub struct FrameView<'a> {
+    frame: &'a Frame,
+    // None for the root frame.
+    returns: Option<&'a Return>,
+}
+
+impl FrameView<'_> {
+    // 0 for the root frame, which never returns anywhere.
+    pub fn return_address(&self) -> usize {
+        self.returns.map_or(0, |returns| returns.address)
     }
 
-    pub fn load_program(&mut self, program: Vec<i64>) {
-        self.program = program;
+    // 0 if it's never been stored to, the same as LOAD would see.
+    pub fn variable(&self, id: i64) -> i64 {
+        self.frame.get(id)
     }
 
-    pub fn step(&mut self, instruction: i64) -> Result<()> {
-        if self.halted {
-            // Probably better to develop our own error type.
-            bail!("Processing instruction while halted")
-        }
+    // every variable that's been stored to, by id.
+    pub fn variables(&self) -> BTreeMap<i64, i64> {
+        self.frame.variables()
+    }
 
-        match instruction {
-            HALT => {
-                self.halted = true;
-            }
-            PUSH => {
-                // get immediate value
-                let next_word = self.get_next_word()?;
-                self.stack.push(next_word);
-            }
-            ADD | SUB | MUL | DIV | AND | OR | ISEQ | ISGT | ISGE => {
-                let val = self.binary_op(instruction)?;
-                self.push_stack(val);
-            }
-            NOT => {
-                let val = self.pop_stack()?;
-                if Self::i64_to_bool(val) {
-                    self.push_stack(0);
-                } else {
-                    self.push_stack(1);
-                }
-            }
-            POP => {
-                let _ = self.pop_stack()?;
-            }
-            DUP => {
-                let val = self.pop_stack()?;
-                // we can just copy because it's a i64.
-                let copied = val;
-                self.push_stack(val);
-                self.push_stack(copied);
-            }
-            JMP => {
-                let target_address = self.get_next_word()?;
-                // we should really trap if the number is negative.
-                self.instruction_pointer = target_address as usize;
-            }
-            JIF => {
-                let conditional_val = self.pop_stack()?;
-                let target_address = self.get_next_word()?;
-                if Self::i64_to_bool(conditional_val) {
-                    self.instruction_pointer = target_address as usize;
-                }
-            }
-            LOAD => {
-                let variable_identifier = self.get_next_word()?;
-                let val = self.get_current_frame().get(variable_identifier);
-                self.push_stack(val);
-            }
-            STORE => {
-                let variable_identifier = self.get_next_word()?;
-                let val = self.pop_stack()?;
-                self.get_current_frame().set(variable_identifier, val);
-            }
-            CALL => {
-                let target_address = self.get_next_word()?;
-                self.frames.push(Frame::new(self.instruction_pointer));
-                self.instruction_pointer = target_address as usize;
-            }
-            RET => {
-                let target_address = self.get_current_frame().return_address;
-                self.frames.pop();
-                self.instruction_pointer = target_address;
-            }
-            PRNSTK => {
-                println!("{:?}", self.get_current_frame());
-                println!("{:?}", self.stack);
-            }
-            instruction => {
-                bail!("Received invalid instruction {instruction}")
-            }
-        }
+    // whether this frame is an interrupt handler's.
+    pub fn is_interrupt(&self) -> bool {
+        self.returns
+            .is_some_and(|returns| returns.interrupted_stack.is_some())
+    }
+}
+
+impl fmt::Debug for FrameView<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Frame")
+            .field("variables", &self.variables())
+            .field("return_address", &self.return_address())
+            .finish()
+    }
+}
+
+// the machine state at one point in a run, for going back to later.
+#[derive(Clone)]
+pub struct Snapshot {
+    frames: Vec<Frame>,
+    returns: Vec<Return>,
+    instruction_pointer: usize,
+    stack: Vec<i64>,
+    heap: Vec<i64>,
+    maps: Vec<BTreeMap<i64, i64>>,
+    map_entries: usize,
+    halted: bool,
+    steps: u64,
+    cycles: u64,
+}
+
+// why run_to_break() stopped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Halted,
+    // a BRK at this address ran, or execution reached a breakpoint added
+    // with add_breakpoint() at it. Either way run_to_break() carries on.
+    Breakpoint(usize),
+    // run_slice() used up its steps. Calling it again carries on.
+    Yielded,
+    // a CancelHandle was used. Nothing more runs until it's reset.
+    Cancelled,
+}
+
+// stops a Cpu from another thread, see Cpu::cancel_handle().
+#[derive(Debug, Clone)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    // the cpu stops before its next instruction.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    // let the cpu carry on from where it stopped.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+// what run() fails with when it's cancelled, so embedders can downcast and
+// tell it apart from the program going wrong.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cancelled {
+    pub address: usize,
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cancelled at address {}", self.address)
+    }
+}
+
+impl std::error::Error for Cancelled {}
 
+// how a program ended, from run(). The machine is left as it was, so there's
+// nothing to pop to find out. The counts are since the cpu was created.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RunOutcome {
+    // the value on top of the stack, if there's anything on it.
+    pub exit_value: Option<i64>,
+    pub steps: u64,
+    pub stack_size: usize,
+    pub max_stack_depth: usize,
+    pub max_frame_depth: usize,
+    // functions, closures and interrupt handlers entered.
+    pub calls: u64,
+    // time spent executing, not counting any time stopped at breakpoints.
+    pub elapsed: Duration,
+}
+
+// what ADD, SUB, MUL, DIV, FXMUL and FXDIV do when the result doesn't fit in a word.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Overflow {
+    // raise Trap::Overflow, which aborts unless a handler says otherwise.
+    #[default]
+    Trap,
+    // two's complement wraparound.
+    Wrap,
+    // clamp to i64::MIN or i64::MAX.
+    Saturate,
+}
+
+// the instructions Cpu::arithmetic works out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ArithmeticOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Fxmul,
+    Fxdiv,
+}
+
+// what to do about instructions whose result comes from the host rather
+// than the program: SYSCALL, and HLOAD from a device.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Nondeterminism {
+    #[default]
+    Allow,
+    // keep every word the host hands back, see Cpu::host_inputs().
+    Record,
+    // fail the instruction, so a run that halts is reproducible.
+    Forbid,
+}
+
+// words the host handed the program, kept under Nondeterminism::Record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostInput {
+    // the instruction count when it happened, counting from 1.
+    pub step: u64,
+    pub address: usize,
+    // e.g. "syscall sqrt" or "device read at 4096".
+    pub source: String,
+    pub words: Vec<i64>,
+}
+
+// a writer that can still be read after handing it to a cpu with
+// set_output(), every clone sharing the one buffer.
+#[derive(Clone, Default)]
+pub(crate) struct SharedOutput(pub(crate) Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
         Ok(())
     }
+}
 
-    fn get_current_frame(&mut self) -> &mut Frame {
-        // there will always be one frame.
-        self.frames.last_mut().unwrap()
+// how PRNSTK writes the machine state.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum StackDumpFormat {
+    // the current frame and the stack, Debug formatted on a line each.
+    #[default]
+    Text,
+    // one JSON object per dump, for tools and tests to parse.
+    JsonLines,
+    // the block Cpu's Display writes.
+    Pretty,
+}
+
+// runtime faults a trap handler gets a say in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trap {
+    DivisionByZero,
+    // only raised with Overflow::Trap.
+    Overflow,
+    StackUnderflow,
+    InvalidJump { target: i64 },
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trap::DivisionByZero => write!(f, "Division by zero"),
+            Trap::Overflow => write!(f, "Arithmetic overflow"),
+            Trap::StackUnderflow => write!(f, "Tried to pop empty stack"),
+            Trap::InvalidJump { target } => write!(f, "Jump to invalid address {target}"),
+        }
     }
+}
 
-    fn binary_op(&mut self, instruction: i64) -> Result<i64> {
-        // remember it's reverse polish.
-        let right = self.pop_stack()?;
-        let left = self.pop_stack()?;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrapAction {
+    // use this value instead: the result of a division, the popped value, or
+    // the address to jump to.
+    Substitute(i64),
+    // abandon the faulting instruction and carry on with the next one.
+    Skip,
+    // stop running with an error.
+    Abort,
+}
 
-        let val = match instruction {
-            ADD => left + right,
-            SUB => left - right,
-            MUL => left * right,
-            DIV => left / right,
-            ISEQ => {
-                if left == right {
-                    TRUE
-                } else {
-                    FALSE
-                }
-            }
-            ISGT => {
-                if left > right {
-                    TRUE
-                } else {
-                    FALSE
-                }
-            }
-            ISGE => {
-                if left >= right {
-                    TRUE
-                } else {
-                    FALSE
-                }
-            }
-            AND | OR => {
-                let left = Self::i64_to_bool(left);
-                let right = Self::i64_to_bool(right);
-                match instruction {
-                    AND => {
-                        if left && right {
-                            1
-                        } else {
-                            0
-                        }
-                    }
-                    OR => {
-                        if left || right {
-                            1
-                        } else {
-                            0
-                        }
-                    }
-                    instruction => {
-                        bail!("Received invalid instruction {instruction}")
-                    }
+pub type TrapHandler = Box<dyn FnMut(Trap, usize) -> TrapAction + Send>;
+
+// a peripheral programs talk to with HLOAD and HSTORE, like a keyboard buffer
+// or an LED matrix. `offset` counts from the start of the range it's mapped at.
+// It moves between threads with the Cpu, so it has to be Send.
+pub trait Device: Send {
+    fn read(&mut self, offset: usize) -> Result<i64>;
+    fn write(&mut self, offset: usize, value: i64) -> Result<()>;
+}
+
+// what a bad jump aborts with, so embedders can downcast and tell it apart.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InvalidJumpTarget {
+    pub from: usize,
+    pub to: i64,
+}
+
+impl fmt::Display for InvalidJumpTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid jump target {} from address {}",
+            self.to, self.from
+        )
+    }
+}
+
+impl std::error::Error for InvalidJumpTarget {}
+
+// unwinds out of the current instruction when a handler asks to skip it.
+#[derive(Debug)]
+struct SkipInstruction;
+
+impl fmt::Display for SkipInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Instruction skipped by trap handler")
+    }
+}
+
+impl std::error::Error for SkipInstruction {}
+
+// caps on how much memory a program may use, None means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MemoryLimits {
+    pub stack_words: Option<usize>,
+    pub frames: Option<usize>,
+    pub heap_words: Option<usize>,
+}
+
+// high water marks since the cpu was created.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryStats {
+    pub peak_stack_words: usize,
+    pub peak_frames: usize,
+    pub peak_heap_words: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CpuBuilder {
+    cost_model: CostModel,
+    max_steps: Option<u64>,
+    timeout: Option<Duration>,
+    trace_length: usize,
+    limits: MemoryLimits,
+    implicit_halt: bool,
+    strict_loads: bool,
+    stack_dump_format: StackDumpFormat,
+    overflow: Overflow,
+    nondeterminism: Nondeterminism,
+    explain: bool,
+    write_protect_code: bool,
+}
+
+impl CpuBuilder {
+    pub fn cost_model(mut self, cost_model: CostModel) -> Self {
+        self.cost_model = cost_model;
+        self
+    }
+
+    // override a single instruction's cost on top of the current model.
+    pub fn cost(mut self, opcode: i64, cost: u64) -> Self {
+        self.cost_model.set(opcode, cost);
+        self
+    }
+
+    // run() gives up after executing this many instructions.
+    pub fn max_steps(mut self, max_steps: u64) -> Self {
+        self.max_steps = Some(max_steps);
+        self
+    }
+
+    // run() gives up after this much wall-clock time.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    // remember the last `length` executed instructions for error reports.
+    pub fn trace_length(mut self, length: usize) -> Self {
+        self.trace_length = length;
+        self
+    }
+
+    pub fn memory_limits(mut self, limits: MemoryLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // treat running off the end of the program as a HALT instead of an error.
+    pub fn implicit_halt(mut self, implicit_halt: bool) -> Self {
+        self.implicit_halt = implicit_halt;
+        self
+    }
+
+    // make LOAD of a variable that was never stored to an error rather
+    // than 0.
+    pub fn strict_loads(mut self, strict_loads: bool) -> Self {
+        self.strict_loads = strict_loads;
+        self
+    }
+
+    pub fn stack_dump_format(mut self, format: StackDumpFormat) -> Self {
+        self.stack_dump_format = format;
+        self
+    }
+
+    pub fn overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    pub fn nondeterminism(mut self, nondeterminism: Nondeterminism) -> Self {
+        self.nondeterminism = nondeterminism;
+        self
+    }
+
+    // describe every instruction as it runs, e.g. "ISGT popped 4 and 6,
+    // pushed 1 because 6 > 4".
+    pub fn explain(mut self, explain: bool) -> Self {
+        self.explain = explain;
+        self
+    }
+
+    // forbid patch() and replace_program() once a program is loaded.
+    pub fn write_protect_code(mut self, write_protect_code: bool) -> Self {
+        self.write_protect_code = write_protect_code;
+        self
+    }
+
+    pub fn build(self) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.cost_model = self.cost_model;
+        cpu.max_steps = self.max_steps;
+        cpu.timeout = self.timeout;
+        cpu.trace_length = self.trace_length;
+        cpu.limits = self.limits;
+        cpu.implicit_halt = self.implicit_halt;
+        cpu.strict_loads = self.strict_loads;
+        cpu.stack_dump_format = self.stack_dump_format;
+        cpu.overflow = self.overflow;
+        cpu.nondeterminism = self.nondeterminism;
+        cpu.explain = self.explain;
+        cpu.write_protect_code = self.write_protect_code;
+        cpu
+    }
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cpu {
+    pub fn new() -> Self {
+        Self {
+            stack: vec![],
+            step_low_water: 0,
+            step_popped: vec![],
+            heap: vec![],
+            maps: vec![],
+            map_entries: 0,
+            instruction_pointer: 0,
+            halted: false,
+            profiler: None,
+            cost_model: CostModel::default(),
+            cycles: 0,
+            steps: 0,
+            max_steps: None,
+            timeout: None,
+            trace: VecDeque::new(),
+            trace_length: 0,
+            limits: MemoryLimits::default(),
+            // the root frame is there from the start.
+            memory_stats: MemoryStats {
+                peak_stack_words: 0,
+                peak_frames: 1,
+                peak_heap_words: 0,
+            },
+            trap_handler: None,
+            current_address: 0,
+            implicit_halt: false,
+            strict_loads: false,
+            output: Box::new(io::stdout()),
+            stack_dump_format: StackDumpFormat::default(),
+            overflow: Overflow::default(),
+            nondeterminism: Nondeterminism::default(),
+            host_inputs: vec![],
+            explain: false,
+            events: None,
+            at_breakpoint: false,
+            write_protect_code: false,
+            interrupt_vectors: BTreeMap::new(),
+            pending_interrupts: VecDeque::new(),
+            devices: vec![],
+            host_functions: vec![],
+            breakpoints: BTreeMap::new(),
+            stopped_at: None,
+            slice_end: None,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            calls: 0,
+            elapsed: Duration::ZERO,
+            program: Arc::default(),
+            frames: vec![Frame::new(0)],
+            returns: vec![],
+        }
+    }
+
+    pub fn builder() -> CpuBuilder {
+        CpuBuilder::default()
+    }
+
+    // total cost of every instruction executed so far, per the cost model.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // number of instructions executed so far.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.memory_stats
+    }
+
+    // everything the host has handed the program so far, oldest first.
+    // Empty unless built with Nondeterminism::Record.
+    pub fn host_inputs(&self) -> &[HostInput] {
+        &self.host_inputs
+    }
+
+    // called with the fault and the faulting address whenever a trap fires.
+    pub fn set_trap_handler(
+        &mut self,
+        handler: impl FnMut(Trap, usize) -> TrapAction + Send + 'static,
+    ) {
+        self.trap_handler = Some(Box::new(handler));
+    }
+
+    // stream a JSON line for every step, push, pop, call, return and store,
+    // for visualizers to follow a run with.
+    pub fn set_event_sink(&mut self, events: impl io::Write + Send + 'static) {
+        self.events = Some(Box::new(events));
+    }
+
+    // send everything the program prints here instead of stdout.
+    pub fn set_output(&mut self, output: impl io::Write + Send + 'static) {
+        self.output = Box::new(output);
+    }
+
+    pub fn load_program(&mut self, program: Program) {
+        self.program = Arc::new(program);
+    }
+
+    // swap in new code without losing the stack, frames or heap. With
+    // `remap`, the instruction pointer, return addresses and closures are
+    // moved to the same distance past the same label in the new program.
+    // Returns the addresses that couldn't be, which are left as they were.
+    // Records on the heap always move to their shape in the new constant
+    // pool, and it's an error if one isn't there.
+    pub fn replace_program(&mut self, program: Program, remap: bool) -> Result<Vec<usize>> {
+        if self.write_protect_code {
+            bail!("Code is write protected")
+        }
+        // the heap can only be walked with the pool that made it.
+        let objects = heap_objects(&self.heap, self.program.constants());
+        let mut shapes = vec![];
+        for (address, object) in objects.iter().filter(|(_, object)| object[0] == RECORD_TAG) {
+            let shape = object[1];
+            let Some(relocated) = relocate_shape(&self.program, &program, shape) else {
+                bail!("Record at {address} has shape {shape}, which isn't in the new program")
+            };
+            shapes.push((address + 1, relocated));
+        }
+        let closures: Vec<usize> = objects
+            .iter()
+            .filter(|(_, object)| object[0] == CLOSURE_TAG)
+            .map(|(address, _)| address + 1)
+            .collect();
+
+        let old = std::mem::replace(&mut self.program, Arc::new(program));
+        for (index, shape) in shapes {
+            self.heap[index] = shape;
+        }
+        if !remap {
+            return Ok(vec![]);
+        }
+        let mut unmapped = vec![];
+        let mut remap_address = |address: usize| match relocate(&old, &self.program, address) {
+            Some(address) => address,
+            None => {
+                unmapped.push(address);
+                address
+            }
+        };
+        self.instruction_pointer = remap_address(self.instruction_pointer);
+        for returns in self.returns.iter_mut() {
+            returns.address = remap_address(returns.address);
+        }
+        for index in closures {
+            if let Ok(function) = usize::try_from(self.heap[index]) {
+                self.heap[index] = remap_address(function) as i64;
+            }
+        }
+        Ok(unmapped)
+    }
+
+    // overwrite code words starting at `address`, e.g. to drop a BRK in or
+    // hot fix an instruction. The result has to be a valid program.
+    pub fn patch(&mut self, address: usize, words: &[i64]) -> Result<()> {
+        if self.write_protect_code {
+            bail!("Code is write protected")
+        }
+        let length = self.program.code().len();
+        let Some(end) = address
+            .checked_add(words.len())
+            .filter(|end| *end <= length)
+        else {
+            bail!(
+                "Patch of {} words at {address} runs past the end of the code at {length}",
+                words.len()
+            )
+        };
+        let mut parts = Program::clone(&self.program).into_parts();
+        parts.code[address..end].copy_from_slice(words);
+        let program = Program::new(parts).context("Patched code isn't a valid program")?;
+        self.program = Arc::new(program);
+        Ok(())
+    }
+
+    // let a halted cpu carry on, e.g. once more code has been loaded after it.
+    pub fn resume(&mut self) {
+        self.halted = false;
+    }
+
+    // the loaded program.
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    pub fn stack(&self) -> &[i64] {
+        &self.stack
+    }
+
+    // the top of the stack, left where it is.
+    pub fn peek(&self) -> Option<i64> {
+        self.stack.last().copied()
+    }
+
+    // the call frames, the root one first and the current one last.
+    pub fn frames(&self) -> impl Iterator<Item = FrameView<'_>> {
+        let returns = std::iter::once(None).chain(self.returns.iter().map(Some));
+        self.frames
+            .iter()
+            .zip(returns)
+            .map(|(frame, returns)| FrameView { frame, returns })
+    }
+
+    // the current frame's variables that have been stored to, by id.
+    pub fn variables(&self) -> BTreeMap<i64, i64> {
+        self.frames.last().unwrap().variables()
+    }
+
+    // where interrupt `number` goes, whether raised by the host or INT.
+    pub fn set_interrupt_vector(&mut self, number: usize, handler: usize) {
+        self.interrupt_vectors.insert(number, handler);
+    }
+
+    // handle interrupt `number` at the next instruction boundary, once any
+    // handler already running has returned.
+    pub fn raise_interrupt(&mut self, number: usize) -> Result<()> {
+        if !self.interrupt_vectors.contains_key(&number) {
+            bail!("No handler for interrupt {number}")
+        }
+        self.pending_interrupts.push_back(number);
+        Ok(())
+    }
+
+    fn in_interrupt(&self) -> bool {
+        self.returns
+            .iter()
+            .any(|returns| returns.interrupted_stack.is_some())
+    }
+
+    // call the handler like a function, coming back to `return_address`.
+    fn enter_interrupt(&mut self, number: usize, return_address: usize) -> Result<()> {
+        let Some(handler) = self.interrupt_vectors.get(&number).copied() else {
+            bail!("No handler for interrupt {number}")
+        };
+        let returns = Return {
+            address: return_address,
+            interrupted_stack: Some(self.stack.len()),
+            stack_floor: self.stack.len(),
+        };
+        self.push_frame(Frame::new(handler), returns)?;
+        self.instruction_pointer = handler;
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.enter(handler);
+        }
+        Ok(())
+    }
+
+    // route HLOAD and HSTORE for `range` to `device`. The heap can't grow
+    // into a device, so put it past anywhere the program will allocate, or
+    // at negative addresses.
+    pub fn map_device(&mut self, range: Range<i64>, device: impl Device + 'static) -> Result<()> {
+        if range.is_empty() {
+            bail!("Device range {range:?} is empty")
+        }
+        if range.start < self.heap.len() as i64 && range.end > 0 {
+            bail!(
+                "Device range {range:?} overlaps the heap, which is {} words",
+                self.heap.len()
+            )
+        }
+        if let Some((other, _)) = self
+            .devices
+            .iter()
+            .find(|(other, _)| range.start < other.end && other.start < range.end)
+        {
+            bail!("Device range {range:?} overlaps the device at {other:?}")
+        }
+        self.devices.push((range, Box::new(device)));
+        Ok(())
+    }
+
+    // let programs call `function` with `SYSCALL n`, where n is the number
+    // returned. Binding a name again replaces the function and keeps the
+    // number. See host.rs for the types it can take and return.
+    pub fn bind<Args>(&mut self, name: &str, function: impl HostFunction<Args>) -> i64 {
+        self.bind_dynamic(Binding::new(name, function))
+    }
+
+    // bind() for a function whose types aren't known until runtime.
+    pub(crate) fn bind_dynamic(&mut self, binding: Binding) -> i64 {
+        match self
+            .host_functions
+            .iter()
+            .position(|b| b.name == binding.name)
+        {
+            Some(number) => {
+                self.host_functions[number] = binding;
+                number as i64
+            }
+            None => {
+                self.host_functions.push(binding);
+                self.host_functions.len() as i64 - 1
+            }
+        }
+    }
+
+    // `:name` for each bound function's SYSCALL number, for
+    // AssemblerOptions::defines so sources can write `syscall :sqrt`.
+    pub fn host_function_defines(&self) -> BTreeMap<String, i64> {
+        self.host_functions
+            .iter()
+            .enumerate()
+            .map(|(number, binding)| (format!(":{}", binding.name), number as i64))
+            .collect()
+    }
+
+    // the device mapped at `address` and how far into its range that is.
+    fn device_at(&mut self, address: i64) -> Option<(&mut Box<dyn Device>, usize)> {
+        self.devices
+            .iter_mut()
+            .find(|(range, _)| range.contains(&address))
+            .map(|(range, device)| (device, (address - range.start) as usize))
+    }
+
+    fn heap_load(&mut self, address: i64) -> Result<i64> {
+        if self.device_at(address).is_some() {
+            let source = |_: &Self| format!("device read at {address}");
+            self.check_deterministic(source)?;
+            let (device, offset) = self.device_at(address).unwrap();
+            let value = device
+                .read(offset)
+                .with_context(|| format!("Device read at {address} failed"))?;
+            self.record_host_input(source, &[value]);
+            return Ok(value);
+        }
+        match usize::try_from(address).ok().and_then(|a| self.heap.get(a)) {
+            Some(value) => Ok(*value),
+            None => bail!("Heap address {address} is out of bounds"),
+        }
+    }
+
+    // `source` names what the host was asked for, only when it's needed.
+    fn check_deterministic(&self, source: impl Fn(&Self) -> String) -> Result<()> {
+        if self.nondeterminism == Nondeterminism::Forbid {
+            bail!(
+                "The result of {} comes from the host, which isn't allowed when running deterministically",
+                source(self)
+            )
+        }
+        Ok(())
+    }
+
+    fn record_host_input(&mut self, source: impl Fn(&Self) -> String, words: &[i64]) {
+        if self.nondeterminism == Nondeterminism::Record {
+            self.host_inputs.push(HostInput {
+                step: self.steps,
+                address: self.current_address,
+                source: source(self),
+                words: words.to_vec(),
+            });
+        }
+    }
+
+    fn heap_store(&mut self, address: i64, value: i64) -> Result<()> {
+        if let Some((device, offset)) = self.device_at(address) {
+            return device
+                .write(offset, value)
+                .with_context(|| format!("Device write at {address} failed"));
+        }
+        match usize::try_from(address)
+            .ok()
+            .and_then(|a| self.heap.get_mut(a))
+        {
+            Some(word) => *word = value,
+            None => bail!("Heap address {address} is out of bounds"),
+        }
+        Ok(())
+    }
+
+    // a variable in the current frame, 0 if it's never been stored to, the
+    // same as LOAD would see.
+    pub fn variable(&self, id: i64) -> i64 {
+        self.frames.last().unwrap().get(id)
+    }
+
+    // stop run_to_break() before the instruction at `address` runs, but only
+    // when `condition` holds if there is one. Replaces any breakpoint already
+    // there.
+    pub fn add_breakpoint(&mut self, address: usize, condition: Option<Condition>) {
+        self.breakpoints
+            .insert(address, Breakpoint { condition, hits: 0 });
+    }
+
+    // false if there wasn't one there.
+    pub fn remove_breakpoint(&mut self, address: usize) -> bool {
+        self.breakpoints.remove(&address).is_some()
+    }
+
+    // whether to stop before the instruction at the instruction pointer.
+    fn breakpoint_reached(&mut self) -> bool {
+        let address = self.instruction_pointer;
+        if self.stopped_at.take() == Some(address) {
+            return false;
+        }
+        let Some(breakpoint) = self.breakpoints.get_mut(&address) else {
+            return false;
+        };
+        breakpoint.hits += 1;
+        let (condition, hits) = (breakpoint.condition, breakpoint.hits);
+        if !condition.is_none_or(|condition| condition.holds(self, hits)) {
+            return false;
+        }
+        self.stopped_at = Some(address);
+        true
+    }
+
+    pub fn ip(&self) -> usize {
+        self.instruction_pointer
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            frames: self.frames.clone(),
+            returns: self.returns.clone(),
+            instruction_pointer: self.instruction_pointer,
+            stack: self.stack.clone(),
+            heap: self.heap.clone(),
+            maps: self.maps.clone(),
+            map_entries: self.map_entries,
+            halted: self.halted,
+            steps: self.steps,
+            cycles: self.cycles,
+        }
+    }
+
+    // an independent copy of the machine as it is now, sharing the program
+    // and host functions, to run on from here separately. Mapped devices,
+    // the trap handler and the event stream stay with this one, and the fork
+    // prints to stdout until it's given set_output().
+    pub fn fork(&self) -> Cpu {
+        let mut fork = Cpu {
+            program: self.program.clone(),
+            profiler: self.profiler.clone(),
+            cost_model: self.cost_model.clone(),
+            max_steps: self.max_steps,
+            timeout: self.timeout,
+            trace: self.trace.clone(),
+            trace_length: self.trace_length,
+            limits: self.limits,
+            memory_stats: self.memory_stats,
+            current_address: self.current_address,
+            implicit_halt: self.implicit_halt,
+            strict_loads: self.strict_loads,
+            stack_dump_format: self.stack_dump_format,
+            overflow: self.overflow,
+            nondeterminism: self.nondeterminism,
+            host_inputs: self.host_inputs.clone(),
+            explain: self.explain,
+            at_breakpoint: self.at_breakpoint,
+            write_protect_code: self.write_protect_code,
+            interrupt_vectors: self.interrupt_vectors.clone(),
+            pending_interrupts: self.pending_interrupts.clone(),
+            host_functions: self.host_functions.clone(),
+            breakpoints: self.breakpoints.clone(),
+            stopped_at: self.stopped_at,
+            slice_end: self.slice_end,
+            calls: self.calls,
+            elapsed: self.elapsed,
+            ..Cpu::new()
+        };
+        fork.restore(self.snapshot());
+        fork
+    }
+
+    // go back to an earlier snapshot. The program and settings stay as they are.
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.frames = snapshot.frames;
+        self.returns = snapshot.returns;
+        self.instruction_pointer = snapshot.instruction_pointer;
+        self.stack = snapshot.stack;
+        self.heap = snapshot.heap;
+        self.maps = snapshot.maps;
+        self.map_entries = snapshot.map_entries;
+        self.halted = snapshot.halted;
+        self.steps = snapshot.steps;
+        self.cycles = snapshot.cycles;
+    }
+
+    pub fn enable_profiling(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
+
+    pub fn profiler(&mut self) -> Option<&mut Profiler> {
+        self.profiler.as_mut()
+    }
+
+    pub fn step(&mut self, instruction: i64) -> Result<()> {
+        if self.halted {
+            // Probably better to develop our own error type.
+            bail!("Processing instruction while halted")
+        }
+        self.cycles += self.cost_model.cost(instruction);
+
+        let Ok(opcode) = Opcode::try_from(instruction) else {
+            bail!("Received invalid instruction {instruction}")
+        };
+        self.step_low_water = self.stack.len();
+        self.step_popped.clear();
+        (opcode.info().execute)(self, opcode)
+    }
+
+    // back to the stack the current instruction started with.
+    fn unwind_step(&mut self) {
+        self.stack.truncate(self.step_low_water);
+        self.stack.extend(self.step_popped.drain(..).rev());
+    }
+
+    fn dump_stack(&mut self) -> io::Result<()> {
+        // the last return is the current frame's, unless it's the root.
+        let frame = FrameView {
+            frame: self.frames.last().unwrap(),
+            returns: self.returns.last(),
+        };
+        match self.stack_dump_format {
+            StackDumpFormat::Text => {
+                writeln!(self.output, "{frame:?}")?;
+                // and again by name, if the program gave them any.
+                let address = self.current_address as i64;
+                let variables = frame.variables();
+                if variables
+                    .keys()
+                    .any(|slot| self.program.variable_name(address, *slot).is_some())
+                {
+                    let named = self.program.describe_variables(address, &variables);
+                    writeln!(self.output, "{}", named.join(", "))?;
                 }
+                writeln!(self.output, "{:?}", self.stack)
             }
-            instruction => {
-                bail!("Received invalid instruction {instruction}")
+            StackDumpFormat::Pretty => {
+                let dump = self.dump();
+                write!(self.output, "{dump}")
+            }
+            StackDumpFormat::JsonLines => {
+                let variables = frame.variables();
+                let address = self.current_address as i64;
+                let names: BTreeMap<i64, &str> = variables
+                    .keys()
+                    .filter_map(|slot| Some((*slot, self.program.variable_name(address, *slot)?)))
+                    .collect();
+                let mut dump = serde_json::json!({
+                    "address": self.current_address,
+                    "depth": self.frames.len(),
+                    "return_address": frame.return_address(),
+                    "variables": variables,
+                    "stack": self.stack,
+                });
+                if !names.is_empty() {
+                    dump["names"] = serde_json::json!(names);
+                }
+                writeln!(self.output, "{dump}")
             }
+        }
+    }
+
+    fn get_current_frame(&mut self) -> &mut Frame {
+        // there will always be one frame.
+        self.frames.last_mut().unwrap()
+    }
+
+    // closures are laid out as [CLOSURE_TAG, function address, capture count, captures...]
+    fn alloc_closure(&mut self, function_address: i64, captured: Vec<i64>) -> Result<i64> {
+        let address = self.alloc(3 + captured.len())?;
+        let start = address as usize;
+        self.heap[start] = CLOSURE_TAG;
+        self.heap[start + 1] = function_address;
+        self.heap[start + 2] = captured.len() as i64;
+        self.heap[start + 3..].copy_from_slice(&captured);
+        Ok(address)
+    }
+
+    fn alloc_string(&mut self, chars: &[i64]) -> Result<i64> {
+        let address = self.alloc(2 + chars.len())?;
+        let start = address as usize;
+        self.heap[start] = STRING_TAG;
+        self.heap[start + 1] = chars.len() as i64;
+        self.heap[start + 2..].copy_from_slice(chars);
+        Ok(address)
+    }
+
+    fn get_string(&self, address: i64) -> Result<&[i64]> {
+        if address < 0 || self.heap.get(address as usize) != Some(&STRING_TAG) {
+            bail!("Value {address} is not a string")
+        }
+        // the header can be forged, so the length isn't trusted either.
+        let start = address as usize;
+        let chars = self
+            .heap
+            .get(start + 1)
+            .and_then(|length| usize::try_from(*length).ok())
+            .and_then(|length| self.heap.get(start + 2..(start + 2).checked_add(length)?));
+        match chars {
+            Some(chars) => Ok(chars),
+            None => bail!("Value {address} is not a string"),
+        }
+    }
+
+    fn map_index(&self, address: i64) -> Result<usize> {
+        if address < 0 || self.heap.get(address as usize) != Some(&MAP_TAG) {
+            bail!("Value {address} is not a map")
+        }
+        // always in range once it's checked, so callers can index maps with it.
+        let index = self.heap.get(address as usize + 1).copied().unwrap_or(-1);
+        match usize::try_from(index) {
+            Ok(index) if index < self.maps.len() => Ok(index),
+            _ => bail!("Value {address} is not a map"),
+        }
+    }
+
+    fn get_map(&self, address: i64) -> Result<&BTreeMap<i64, i64>> {
+        let index = self.map_index(address)?;
+        self.maps
+            .get(index)
+            .with_context(|| format!("Value {address} is not a map"))
+    }
+
+    // the heap index of a record's field.
+    fn record_field(&self, address: i64, field: i64) -> Result<usize> {
+        if address < 0 || self.heap.get(address as usize) != Some(&RECORD_TAG) {
+            bail!("Value {address} is not a record")
+        }
+        // the shape can be forged, or from the pool of a replaced program.
+        let start = address as usize;
+        let shape = self.heap.get(start + 1).copied().unwrap_or(-1);
+        let Some(field_count) = usize::try_from(shape)
+            .ok()
+            .and_then(|shape| self.program.constants().get(shape))
+            .copied()
+        else {
+            bail!("Record at {address} has shape {shape}, which isn't in the constant pool")
         };
-        Ok(val)
+        if field < 0 || field >= field_count {
+            bail!("Field {field} is out of bounds for a record of {field_count}")
+        }
+        let slot = start + 2 + field as usize;
+        if slot >= self.heap.len() {
+            bail!("Record at {address} runs past the end of the heap")
+        }
+        Ok(slot)
+    }
+
+    // heap words in use, counting each map entry as a key and a value.
+    fn heap_words(&self) -> usize {
+        self.heap.len() + 2 * self.map_entries
+    }
+
+    // make room for one more map entry.
+    fn grow_maps(&mut self) -> Result<()> {
+        if let Some(max) = self.limits.heap_words {
+            if self.heap_words() + 2 > max {
+                bail!("Heap limit of {max} words exceeded")
+            }
+        }
+        self.map_entries += 1;
+        // counted as two words, the same as against the heap limit.
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.allocation(2);
+        }
+        self.memory_stats.peak_heap_words =
+            self.memory_stats.peak_heap_words.max(self.heap_words());
+        Ok(())
+    }
+
+    // bump allocate `words` zeroed heap words and return the address of the first.
+    fn alloc(&mut self, words: usize) -> Result<i64> {
+        let address = self.heap.len();
+        if let Some(max) = self.limits.heap_words {
+            if self.heap_words() + words > max {
+                bail!("Heap limit of {max} words exceeded")
+            }
+        }
+        let end = (address + words) as i64;
+        if let Some((range, _)) = self
+            .devices
+            .iter()
+            .find(|(range, _)| range.start < end && range.end > address as i64)
+        {
+            bail!("Heap ran into the device mapped at {range:?}")
+        }
+        self.heap.resize(address + words, 0);
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.allocation(words);
+        }
+        self.memory_stats.peak_heap_words =
+            self.memory_stats.peak_heap_words.max(self.heap_words());
+        Ok(address as i64)
+    }
+
+    fn push_frame(&mut self, frame: Frame, returns: Return) -> Result<()> {
+        if let Some(max) = self.limits.frames {
+            if self.frames.len() >= max {
+                bail!("Frame limit of {max} exceeded")
+            }
+        }
+        // with no limit set, runaway recursion still stops here rather than
+        // when the host runs out of memory.
+        if self.returns.len() >= MAX_CALL_DEPTH {
+            bail!("Call stack overflow, more than {MAX_CALL_DEPTH} calls deep")
+        }
+        self.frames.push(frame);
+        self.returns.push(returns);
+        self.calls += 1;
+        self.memory_stats.peak_frames = self.memory_stats.peak_frames.max(self.frames.len());
+        Ok(())
+    }
+
+    fn get_closure(&self, address: i64) -> Result<(i64, Vec<i64>)> {
+        if address < 0 || self.heap.get(address as usize) != Some(&CLOSURE_TAG) {
+            bail!("Value {address} is not a closure")
+        }
+        // the header can be forged, so the capture count isn't trusted either.
+        let start = address as usize;
+        let closure = self
+            .heap
+            .get(start + 2)
+            .and_then(|count| usize::try_from(*count).ok())
+            .and_then(|count| self.heap.get(start + 3..(start + 3).checked_add(count)?));
+        match closure {
+            Some(captured) => Ok((self.heap[start + 1], captured.to_vec())),
+            None => bail!("Value {address} is not a closure"),
+        }
+    }
+
+    // hand a fault to the trap handler. Abort (or no handler) becomes an error,
+    // otherwise the caller decides what substituting or skipping means.
+    fn trap(&mut self, trap: Trap) -> Result<TrapAction> {
+        let address = self.current_address;
+        let action = match self.trap_handler.as_mut() {
+            Some(handler) => handler(trap, address),
+            None => TrapAction::Abort,
+        };
+        match (action, trap) {
+            (TrapAction::Abort, Trap::InvalidJump { target }) => Err(InvalidJumpTarget {
+                from: address,
+                to: target,
+            }
+            .into()),
+            (TrapAction::Abort, trap) => bail!("{trap} at address {address}"),
+            (action, _) => Ok(action),
+        }
+    }
+
+    // None means the handler chose to skip the jump. It gets one go at
+    // substituting a target, and a second bad one is an error rather than
+    // trapping again, which could go on forever.
+    fn check_jump(&mut self, target: i64) -> Result<Option<usize>> {
+        let code_len = self.program.code().len();
+        let valid = |target: i64| usize::try_from(target).ok().filter(|t| *t < code_len);
+        if let Some(target) = valid(target) {
+            return Ok(Some(target));
+        }
+        match self.trap(Trap::InvalidJump { target })? {
+            TrapAction::Substitute(value) => match valid(value) {
+                Some(target) => Ok(Some(target)),
+                None => Err(InvalidJumpTarget {
+                    from: self.current_address,
+                    to: value,
+                }
+                .into()),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    // ADD, SUB, MUL, DIV and the fixed point versions, by anything but zero.
+    // Everything is worked out in 128 bits, where none of these can overflow,
+    // and then narrowed the configured way.
+    fn arithmetic(&mut self, op: ArithmeticOp, left: i64, right: i64) -> Result<i64> {
+        let (left, right) = (left as i128, right as i128);
+        let wide = match op {
+            ArithmeticOp::Add => left + right,
+            ArithmeticOp::Sub => left - right,
+            ArithmeticOp::Mul => left * right,
+            ArithmeticOp::Div => left / right,
+            // rounds toward negative infinity.
+            ArithmeticOp::Fxmul => (left * right) >> FIXED_POINT_BITS,
+            // rounds toward zero, like DIV.
+            ArithmeticOp::Fxdiv => (left << FIXED_POINT_BITS) / right,
+        };
+        match (i64::try_from(wide), self.overflow) {
+            (Ok(value), _) => Ok(value),
+            (Err(_), Overflow::Wrap) => Ok(wide as i64),
+            (Err(_), Overflow::Saturate) if wide < 0 => Ok(i64::MIN),
+            (Err(_), Overflow::Saturate) => Ok(i64::MAX),
+            (Err(_), Overflow::Trap) => match self.trap(Trap::Overflow)? {
+                TrapAction::Substitute(value) => Ok(value),
+                _ => Err(SkipInstruction.into()),
+            },
+        }
+    }
+
+    fn binary_op(&mut self, instruction: Opcode) -> Result<i64> {
+        // remember it's reverse polish.
+        let right = self.pop_stack()?;
+        let left = self.pop_stack()?;
+
+        let val = match instruction {
+            Opcode::Add => self.arithmetic(ArithmeticOp::Add, left, right)?,
+            Opcode::Sub => self.arithmetic(ArithmeticOp::Sub, left, right)?,
+            Opcode::Mul => self.arithmetic(ArithmeticOp::Mul, left, right)?,
+            Opcode::Fxmul => self.arithmetic(ArithmeticOp::Fxmul, left, right)?,
+            Opcode::Div => self.division(ArithmeticOp::Div, left, right)?,
+            Opcode::Fxdiv => self.division(ArithmeticOp::Fxdiv, left, right)?,
+            Opcode::Iseq => {
+                if left == right {
+                    TRUE
+                } else {
+                    FALSE
+                }
+            }
+            Opcode::Isgt => {
+                if left > right {
+                    TRUE
+                } else {
+                    FALSE
+                }
+            }
+            Opcode::Isge => {
+                if left >= right {
+                    TRUE
+                } else {
+                    FALSE
+                }
+            }
+            Opcode::And => {
+                if Self::i64_to_bool(left) && Self::i64_to_bool(right) {
+                    1
+                } else {
+                    0
+                }
+            }
+            Opcode::Or => {
+                if Self::i64_to_bool(left) || Self::i64_to_bool(right) {
+                    1
+                } else {
+                    0
+                }
+            }
+            // the table never sends these here. They're named, not caught with
+            // a wildcard, so a new instruction has to be placed on one side.
+            Opcode::Push
+            | Opcode::Nop
+            | Opcode::Halt
+            | Opcode::Not
+            | Opcode::Pop
+            | Opcode::Dup
+            | Opcode::Jmp
+            | Opcode::Jif
+            | Opcode::Load
+            | Opcode::Store
+            | Opcode::Call
+            | Opcode::Ret
+            | Opcode::Prnstk
+            | Opcode::Mkclos
+            | Opcode::Callclos
+            | Opcode::Pushc
+            | Opcode::Dload
+            | Opcode::Prnchr
+            | Opcode::Strnew
+            | Opcode::Strlen
+            | Opcode::Strcat
+            | Opcode::Strcmp
+            | Opcode::Strget
+            | Opcode::Prnstr
+            | Opcode::Mnew
+            | Opcode::Mget
+            | Opcode::Mset
+            | Opcode::Mdel
+            | Opcode::Mlen
+            | Opcode::Mhas
+            | Opcode::Rnew
+            | Opcode::Rget
+            | Opcode::Rset
+            | Opcode::Brk
+            | Opcode::Hload
+            | Opcode::Hstore
+            | Opcode::Int
+            | Opcode::Iret
+            | Opcode::Syscall
+            | Opcode::Iszero
+            | Opcode::Jz
+            | Opcode::Jnz
+            | Opcode::Jeq
+            | Opcode::Jne
+            | Opcode::Jlt
+            | Opcode::Jge
+            | Opcode::Loop => {
+                bail!("Received invalid instruction {instruction}")
+            }
+        };
+        Ok(val)
+    }
+
+    // DIV and FXDIV, which ask the trap handler what to do about zero.
+    fn division(&mut self, op: ArithmeticOp, left: i64, right: i64) -> Result<i64> {
+        if right == 0 {
+            match self.trap(Trap::DivisionByZero)? {
+                TrapAction::Substitute(value) => Ok(value),
+                _ => Err(SkipInstruction.into()),
+            }
+        } else {
+            self.arithmetic(op, left, right)
+        }
+    }
+
+    fn i64_to_bool(val: i64) -> bool {
+        val != 0
+    }
+
+    fn push_stack(&mut self, val: i64) -> Result<()> {
+        if let Some(max) = self.limits.stack_words {
+            if self.stack.len() >= max {
+                bail!("Stack limit of {max} words exceeded")
+            }
+        }
+        self.stack.push(val);
+        self.memory_stats.peak_stack_words =
+            self.memory_stats.peak_stack_words.max(self.stack.len());
+        Ok(())
+    }
+
+    fn pop_stack(&mut self) -> Result<i64> {
+        if !self.stack.is_empty() && self.stack.len() <= self.stack_floor() {
+            bail!("Interrupt handler tried to pop the interrupted code's stack")
+        }
+        match self.stack.pop() {
+            Some(val) => {
+                // words it pushed itself don't need putting back.
+                if self.stack.len() < self.step_low_water {
+                    self.step_low_water = self.stack.len();
+                    self.step_popped.push(val);
+                }
+                Ok(val)
+            }
+            None => match self.trap(Trap::StackUnderflow)? {
+                TrapAction::Substitute(value) => Ok(value),
+                _ => Err(SkipInstruction.into()),
+            },
+        }
+    }
+
+    fn stack_floor(&self) -> usize {
+        self.returns.last().map_or(0, |returns| returns.stack_floor)
+    }
+
+    // put a value on the stack, like an argument before run().
+    pub fn push(&mut self, value: i64) -> Result<()> {
+        self.push_stack(value)
+    }
+
+    pub fn get_latest_return_value(&mut self) -> Result<i64> {
+        self.stack.pop().context("Tried to pop empty stack")
+    }
+
+    fn get_next_word(&mut self) -> Result<i64> {
+        let word = self.program.code().get(self.instruction_pointer).copied();
+        self.instruction_pointer += 1;
+        match word {
+            Some(word) => Ok(word),
+            None => bail!("Program tried to load out of bounds word."),
+        }
+    }
+
+    // run to the end, carrying straight on past any BRK.
+    pub fn run(&mut self) -> Result<RunOutcome> {
+        loop {
+            match self.run_to_break()? {
+                Outcome::Halted => return Ok(self.outcome()),
+                Outcome::Breakpoint(address) => {
+                    tracing::debug!(address, "passed a breakpoint");
+                }
+                // only run_slice() yields.
+                Outcome::Yielded => {}
+                Outcome::Cancelled => {
+                    return Err(Cancelled {
+                        address: self.instruction_pointer,
+                    }
+                    .into())
+                }
+            }
+        }
+    }
+
+    // a handle that stops this cpu at the next instruction boundary, from
+    // any thread. Every handle controls the same cpu.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle(self.cancelled.clone())
+    }
+
+    // how the run has gone so far, what run() returns once it halts.
+    pub fn outcome(&self) -> RunOutcome {
+        RunOutcome {
+            exit_value: self.stack.last().copied(),
+            steps: self.steps,
+            stack_size: self.stack.len(),
+            max_stack_depth: self.memory_stats.peak_stack_words,
+            max_frame_depth: self.memory_stats.peak_frames,
+            calls: self.calls,
+            elapsed: self.elapsed,
+        }
+    }
+
+    // run_to_break(), but giving up control after at most `steps`
+    // instructions, for running lots of programs a bit at a time.
+    pub fn run_slice(&mut self, steps: u64) -> Result<Outcome> {
+        self.slice_end = Some(self.steps.saturating_add(steps));
+        let outcome = self.run_to_break();
+        self.slice_end = None;
+        outcome
+    }
+
+    // run until the program halts or executes a BRK. After a breakpoint,
+    // calling this again continues from the instruction after it.
+    pub fn run_to_break(&mut self) -> Result<Outcome> {
+        let started = Instant::now();
+        let outcome = self.execute();
+        self.elapsed += started.elapsed();
+        outcome
+    }
+
+    fn execute(&mut self) -> Result<Outcome> {
+        if self.program.code().is_empty() {
+            self.halted = true;
+            bail!("Loaded empty program")
+        }
+
+        let _span = tracing::info_span!("run").entered();
+        tracing::info!(words = self.program.code().len(), "starting");
+        let started = Instant::now();
+        loop {
+            if self.halted {
+                break;
+            }
+            if std::mem::take(&mut self.at_breakpoint) {
+                tracing::info!(address = self.current_address, "breakpoint");
+                return Ok(Outcome::Breakpoint(self.current_address));
+            }
+            if self.slice_end.is_some_and(|end| self.steps >= end) {
+                return Ok(Outcome::Yielded);
+            }
+            if self.cancelled.load(Ordering::Relaxed) {
+                tracing::info!(address = self.instruction_pointer, "cancelled");
+                return Ok(Outcome::Cancelled);
+            }
+            if !self.pending_interrupts.is_empty() && !self.in_interrupt() {
+                let number = self.pending_interrupts.pop_front().unwrap();
+                tracing::debug!(number, "interrupt");
+                self.enter_interrupt(number, self.instruction_pointer)?;
+            }
+            if !self.breakpoints.is_empty() && self.breakpoint_reached() {
+                tracing::info!(address = self.instruction_pointer, "breakpoint");
+                return Ok(Outcome::Breakpoint(self.instruction_pointer));
+            }
+
+            if self.instruction_pointer == self.program.code().len() {
+                if self.implicit_halt {
+                    self.halted = true;
+                    break;
+                }
+                bail!(
+                    "Ran off the end of the program at address {} (missing HALT?)",
+                    self.instruction_pointer
+                )
+            }
+
+            if let Some(max_steps) = self.max_steps {
+                if self.steps >= max_steps {
+                    bail!(
+                        "Step limit of {max_steps} reached\n{}",
+                        self.describe_state()
+                    )
+                }
+            }
+            // checking the clock every instruction is expensive, so don't.
+            if let Some(timeout) = self.timeout {
+                if self.steps.is_multiple_of(1024) && started.elapsed() >= timeout {
+                    bail!("Timed out after {timeout:?}\n{}", self.describe_state())
+                }
+            }
+
+            if let Some(profiler) = self.profiler.as_mut() {
+                profiler.instruction();
+            }
+            if self.trace_length > 0 {
+                if self.trace.len() == self.trace_length {
+                    self.trace.pop_front();
+                }
+                self.trace.push_back(self.instruction_pointer);
+            }
+            self.steps += 1;
+            self.current_address = self.instruction_pointer;
+            let instruction = self.get_next_word()?;
+            // step() says what's wrong if it isn't one.
+            let opcode = Opcode::try_from(instruction).ok();
+            tracing::trace!(
+                address = self.current_address,
+                instruction = opcode.map_or("???", Opcode::name),
+                stack = ?self.stack,
+            );
+            // what's about to be popped, deepest first.
+            let popped = match (opcode, self.explain || self.events.is_some()) {
+                (Some(opcode), true) => {
+                    let operand = self.operand_at(self.current_address, opcode);
+                    let pops = stack_effect(opcode, operand).map_or(0, |(pops, _)| pops);
+                    Some((
+                        opcode,
+                        self.stack[self.stack.len().saturating_sub(pops)..].to_vec(),
+                    ))
+                }
+                _ => None,
+            };
+            match self.step(instruction) {
+                Ok(()) => {
+                    if let Some((opcode, popped)) = popped {
+                        if self.explain {
+                            self.explain_step(opcode, &popped)?;
+                        }
+                        if self.events.is_some() {
+                            self.emit_events(opcode, &popped)?;
+                        }
+                    }
+                }
+                Err(err) if err.is::<SkipInstruction>() => {
+                    self.unwind_step();
+                    let operand_count = opcode.map_or(0, Opcode::operand_count);
+                    self.instruction_pointer = self.current_address + 1 + operand_count;
+                }
+                Err(err) => {
+                    let err = err.context(self.fault_location(instruction));
+                    return Err(err.context("Unable to execute program."));
+                }
+            }
+        }
+        if let Some(events) = self.events.as_mut() {
+            events.flush().context("Could not write events")?;
+        }
+        tracing::info!(steps = self.steps, cycles = self.cycles, "halted");
+        Ok(Outcome::Halted)
+    }
+
+    // the inline words after the opcode at `address`.
+    fn operands_at(&self, address: usize, opcode: Opcode) -> &[i64] {
+        let count = opcode.operand_count();
+        let code = self.program.code();
+        code.get(address + 1..address + 1 + count)
+            .unwrap_or_default()
+    }
+
+    fn operand_at(&self, address: usize, opcode: Opcode) -> Option<i64> {
+        self.operands_at(address, opcode).last().copied()
+    }
+
+    // one line for `run --explain` about the instruction that just ran.
+    fn explain_step(&mut self, opcode: Opcode, popped: &[i64]) -> Result<()> {
+        use Opcode::{Iseq, Isge, Isgt, Iszero};
+        let address = self.current_address;
+        let operand = self.operand_at(address, opcode);
+        let mut line = opcode.name().to_uppercase();
+        if !popped.is_empty() {
+            // in the order they came off, top first.
+            let values: Vec<String> = popped.iter().rev().map(i64::to_string).collect();
+            line.push_str(&format!(" popped {}", join_words(&values)));
+        }
+        let pushes = stack_effect(opcode, operand).map_or(0, |(_, pushes)| pushes);
+        let pushed = &self.stack[self.stack.len().saturating_sub(pushes)..];
+        if !pushed.is_empty() {
+            let values: Vec<String> = pushed.iter().map(i64::to_string).collect();
+            let separator = if popped.is_empty() { " " } else { ", " };
+            line.push_str(&format!("{separator}pushed {}", join_words(&values)));
+        }
+        let next = address + 1 + opcode.operand_count();
+        if !self.halted && self.instruction_pointer != next {
+            line.push_str(&format!(" and went to {}", self.instruction_pointer));
+        }
+        let operands: Vec<String> = self
+            .operands_at(address, opcode)
+            .iter()
+            .map(i64::to_string)
+            .collect();
+        let popped: Vec<String> = popped.iter().map(i64::to_string).collect();
+        let summary = describe_instruction(opcode, &operands, &popped);
+        // comparisons say why they came out the way they did.
+        match (opcode, pushed) {
+            (Iseq | Isgt | Isge | Iszero, [TRUE]) => line.push_str(&format!(" because {summary}")),
+            (Iseq | Isgt | Isge | Iszero, _) => line.push_str(&format!(" because not {summary}")),
+            _ => line.push_str(&format!(" ({summary})")),
+        }
+        writeln!(self.output, "{line}").context("Could not write explanation")?;
+        Ok(())
+    }
+
+    // the JSON lines for the instruction that just ran: a step, then what it
+    // popped (top first) and pushed, then any call, return or store.
+    fn emit_events(&mut self, opcode: Opcode, popped: &[i64]) -> Result<()> {
+        let address = self.current_address;
+        let operand = self.operand_at(address, opcode);
+        let mut events = vec![serde_json::json!({
+            "event": "step",
+            "step": self.steps,
+            "address": address,
+            "instruction": opcode.name(),
+            "operand": operand,
+        })];
+        for value in popped.iter().rev() {
+            events.push(serde_json::json!({"event": "pop", "value": value}));
+        }
+        let pushes = stack_effect(opcode, operand).map_or(0, |(_, pushes)| pushes);
+        for value in self.stack[self.stack.len().saturating_sub(pushes)..].iter() {
+            events.push(serde_json::json!({"event": "push", "value": value}));
+        }
+        match (opcode, operand) {
+            (Opcode::Call | Opcode::Callclos, _) => events.push(serde_json::json!({
+                "event": "call",
+                "from": address,
+                "to": self.instruction_pointer,
+            })),
+            (Opcode::Ret, _) => events.push(serde_json::json!({
+                "event": "ret",
+                "from": address,
+                "to": self.instruction_pointer,
+            })),
+            (Opcode::Store, Some(variable)) => events.push(serde_json::json!({
+                "event": "store",
+                "variable": variable,
+                "value": popped.first(),
+            })),
+            (Opcode::Loop, _) => {
+                let variable = self.operands_at(address, opcode)[0];
+                events.push(serde_json::json!({
+                    "event": "store",
+                    "variable": variable,
+                    "value": self.frames.last().unwrap().get(variable),
+                }))
+            }
+            _ => {}
+        }
+        if let Some(sink) = self.events.as_mut() {
+            for event in events {
+                writeln!(sink, "{event}").context("Could not write events")?;
+            }
+        }
+        Ok(())
+    }
+
+    // a snapshot of the machine for offline inspection, normally taken right
+    // after run() fails with `error`.
+    pub fn core_dump(&self, error: &anyhow::Error) -> CoreDump {
+        CoreDump {
+            error: format!("{error:#}"),
+            ..self.state_dump()
+        }
+    }
+
+    // the same for a machine that hasn't failed, e.g. to coredump::diff()
+    // against a later one.
+    pub fn state_dump(&self) -> CoreDump {
+        CoreDump {
+            error: String::new(),
+            address: self.current_address,
+            instruction_pointer: self.instruction_pointer,
+            stack: self.stack.clone(),
+            frames: self
+                .frames()
+                .map(|frame| FrameDump {
+                    return_address: frame.return_address(),
+                    variables: frame.variables(),
+                })
+                .collect(),
+            heap: self.heap.clone(),
+            maps: self.maps.clone(),
+            trace: self.trace.iter().copied().collect(),
+            steps: self.steps,
+            cycles: self.cycles,
+            program: Program::clone(&self.program),
+        }
+    }
+
+    // where we are, what's on the stack, and how we got here.
+    // `add at address 4 (sum.asm:3)`, for the instruction that just failed.
+    fn fault_location(&self, opcode: i64) -> String {
+        let address = self.current_address;
+        let mnemonic = instruction_info(opcode).map_or("???", |(mnemonic, _)| mnemonic);
+        let mut out = format!("{mnemonic} at address {address}");
+        let debug_info = self.program.debug_info();
+        if let Some(line) = debug_info.lines.get(&(address as i64)) {
+            let file = debug_info.file.as_deref().unwrap_or("<source>");
+            let _ = write!(out, " ({file}:{line})");
+        }
+        out
+    }
+
+    fn describe_state(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "ip: {}", self.instruction_pointer);
+        let _ = write!(out, "stack: {:?}", self.stack);
+        if !self.trace.is_empty() {
+            let _ = write!(out, "\nlast {} instructions:", self.trace.len());
+        }
+        for address in self.trace.iter() {
+            let _ = match decode_at(self.program.code(), *address) {
+                Ok(instruction) => write!(out, "\n{address:>6}: {instruction}"),
+                Err(_) => write!(out, "\n{address:>6}: ???"),
+            };
+        }
+        out
+    }
+
+    // the machine state as a readable block, the same as `{cpu}`.
+    pub fn dump(&self) -> String {
+        self.to_string()
+    }
+}
+
+// what each instruction does, called through its entry in INSTRUCTIONS with
+// the opcode that got it there. Operands are read from the instruction
+// stream as they're needed.
+impl Cpu {
+    fn op_nop(&mut self, _: Opcode) -> Result<()> {
+        Ok(())
+    }
+
+    fn op_brk(&mut self, _: Opcode) -> Result<()> {
+        self.at_breakpoint = true;
+        Ok(())
+    }
+
+    fn op_halt(&mut self, _: Opcode) -> Result<()> {
+        self.halted = true;
+        Ok(())
+    }
+
+    fn op_push(&mut self, _: Opcode) -> Result<()> {
+        // get immediate value
+        let next_word = self.get_next_word()?;
+        self.push_stack(next_word)
+    }
+
+    fn op_pushc(&mut self, _: Opcode) -> Result<()> {
+        let index = self.get_next_word()?;
+        let Some(constant) = usize::try_from(index)
+            .ok()
+            .and_then(|index| self.program.constants().get(index))
+        else {
+            bail!("Constant pool index {index} out of bounds")
+        };
+        self.push_stack(*constant)
+    }
+
+    fn op_binary(&mut self, opcode: Opcode) -> Result<()> {
+        let val = self.binary_op(opcode)?;
+        self.push_stack(val)
+    }
+
+    fn op_jcmp(&mut self, opcode: Opcode) -> Result<()> {
+        let b = self.pop_stack()?;
+        let a = self.pop_stack()?;
+        let target_address = self.get_next_word()?;
+        if Self::branch_condition(opcode)?(&[a, b]) {
+            if let Some(target) = self.check_jump(target_address)? {
+                self.instruction_pointer = target;
+            }
+        }
+        Ok(())
+    }
+
+    // when a conditional jump is taken, which the table keeps alongside
+    // everything else about the instruction.
+    fn branch_condition(opcode: Opcode) -> Result<fn(&[i64]) -> bool> {
+        match opcode.info().semantics {
+            Semantics::Branch(taken) => Ok(taken),
+            _ => bail!("{opcode} isn't a branch"),
+        }
+    }
+
+    fn op_iszero(&mut self, _: Opcode) -> Result<()> {
+        let val = self.pop_stack()?;
+        self.push_stack((val == 0) as i64)
+    }
+
+    fn op_not(&mut self, _: Opcode) -> Result<()> {
+        let val = self.pop_stack()?;
+        if Self::i64_to_bool(val) {
+            self.push_stack(0)
+        } else {
+            self.push_stack(1)
+        }
+    }
+
+    fn op_pop(&mut self, _: Opcode) -> Result<()> {
+        let _ = self.pop_stack()?;
+        Ok(())
+    }
+
+    fn op_dup(&mut self, _: Opcode) -> Result<()> {
+        let val = self.pop_stack()?;
+        // we can just copy because it's a i64.
+        let copied = val;
+        self.push_stack(val)?;
+        self.push_stack(copied)
+    }
+
+    fn op_jmp(&mut self, _: Opcode) -> Result<()> {
+        let target_address = self.get_next_word()?;
+        if let Some(target) = self.check_jump(target_address)? {
+            self.instruction_pointer = target;
+        }
+        Ok(())
+    }
+
+    // JIF and JNZ jump on anything but 0, JZ only on 0.
+    fn op_jif(&mut self, opcode: Opcode) -> Result<()> {
+        let conditional_val = self.pop_stack()?;
+        let target_address = self.get_next_word()?;
+        if Self::branch_condition(opcode)?(&[conditional_val]) {
+            if let Some(target) = self.check_jump(target_address)? {
+                self.instruction_pointer = target;
+            }
+        }
+        Ok(())
+    }
+
+    fn op_load(&mut self, _: Opcode) -> Result<()> {
+        let variable_identifier = self.get_next_word()?;
+        let val = self.load_variable(variable_identifier)?;
+        self.push_stack(val)
+    }
+
+    // a variable in the current frame, which is 0 until it's stored to unless
+    // loads are strict.
+    fn load_variable(&self, variable_identifier: i64) -> Result<i64> {
+        let frame = self.frames.last().unwrap();
+        match (frame.try_get(variable_identifier), self.strict_loads) {
+            (Some(val), _) => Ok(val),
+            (None, false) => Ok(0),
+            (None, true) => {
+                let address = self.current_address as i64;
+                let variable = match self.program.variable_name(address, variable_identifier) {
+                    Some(name) => format!("{name} (slot {variable_identifier})"),
+                    None => format!("variable {variable_identifier}"),
+                };
+                bail!(
+                    "Loaded {variable} before it was stored to, in {}",
+                    self.program.function_name(frame.entry)
+                )
+            }
+        }
+    }
+
+    fn op_loop(&mut self, _: Opcode) -> Result<()> {
+        let variable_identifier = self.get_next_word()?;
+        let target_address = self.get_next_word()?;
+        let val = self.load_variable(variable_identifier)?.wrapping_sub(1);
+        self.get_current_frame().set(variable_identifier, val);
+        if val > 0 {
+            if let Some(target) = self.check_jump(target_address)? {
+                self.instruction_pointer = target;
+            }
+        }
+        Ok(())
+    }
+
+    fn op_dload(&mut self, _: Opcode) -> Result<()> {
+        let address = self.pop_stack()?;
+        let Some(word) = usize::try_from(address)
+            .ok()
+            .and_then(|address| self.program.data().get(address))
+        else {
+            bail!("Data address {address} is out of bounds")
+        };
+        self.push_stack(*word)
+    }
+
+    fn op_store(&mut self, _: Opcode) -> Result<()> {
+        let variable_identifier = self.get_next_word()?;
+        let val = self.pop_stack()?;
+        self.get_current_frame().set(variable_identifier, val);
+        Ok(())
+    }
+
+    fn op_call(&mut self, _: Opcode) -> Result<()> {
+        let target_address = self.get_next_word()?;
+        let Some(target) = self.check_jump(target_address)? else {
+            return Ok(());
+        };
+        let returns = Return {
+            address: self.instruction_pointer,
+            interrupted_stack: None,
+            stack_floor: self.stack_floor(),
+        };
+        self.push_frame(Frame::new(target), returns)?;
+        self.instruction_pointer = target;
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.enter(self.instruction_pointer);
+        }
+        Ok(())
+    }
+
+    // drop the current frame and go back to where it was entered from.
+    fn pop_frame(&mut self) -> Result<()> {
+        let Some(returns) = self.returns.pop() else {
+            bail!("Call stack underflow")
+        };
+        self.frames.pop();
+        if returns.address > self.program.code().len() {
+            bail!("Return address {} is outside the program", returns.address)
+        }
+        self.instruction_pointer = returns.address;
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.exit();
+        }
+        Ok(())
+    }
+
+    fn op_ret(&mut self, _: Opcode) -> Result<()> {
+        // returning from main ends the program, and keeps the root
+        // frame around so there's always a current frame.
+        let Some(returns) = self.returns.last() else {
+            self.halted = true;
+            return Ok(());
+        };
+        if returns.interrupted_stack.is_some() {
+            bail!("RET in an interrupt handler, which has to return with IRET")
+        }
+        self.pop_frame()
+    }
+
+    fn op_int(&mut self, _: Opcode) -> Result<()> {
+        let number = self.get_next_word()?;
+        let Ok(number) = usize::try_from(number) else {
+            bail!("No handler for interrupt {number}")
+        };
+        self.enter_interrupt(number, self.instruction_pointer)
+    }
+
+    fn op_iret(&mut self, _: Opcode) -> Result<()> {
+        let Some(height) = self.returns.last().and_then(|r| r.interrupted_stack) else {
+            bail!("IRET outside an interrupt handler")
+        };
+        if self.stack.len() < height {
+            bail!(
+                "IRET with {} words on the stack, but the interrupted code had {height}",
+                self.stack.len()
+            )
+        }
+        // whatever the handler left behind goes, the interrupted code
+        // gets its stack back as it was.
+        self.stack.truncate(height);
+        self.pop_frame()
+    }
+
+    fn op_syscall(&mut self, _: Opcode) -> Result<()> {
+        let number = self.get_next_word()?;
+        let Some(index) = usize::try_from(number)
+            .ok()
+            .filter(|number| *number < self.host_functions.len())
+        else {
+            bail!("No host function bound as {number}")
+        };
+        let source = |cpu: &Self| format!("syscall {}", cpu.host_functions[index].name);
+        self.check_deterministic(source)?;
+        // an interrupt handler can't pass the interrupted code's words.
+        let available = self.stack.len() - self.stack_floor();
+        let binding = &mut self.host_functions[index];
+        if binding.args > available {
+            bail!(
+                "{} takes {} argument{} but the stack only has {available}",
+                binding.name,
+                binding.args,
+                if binding.args == 1 { "" } else { "s" },
+            )
+        }
+        let args = self.stack.split_off(self.stack.len() - binding.args);
+        let results = (binding.call.lock().unwrap_or_else(PoisonError::into_inner))(&args)
+            .with_context(|| format!("Host function {} failed", binding.name))?;
+        self.record_host_input(source, &results);
+        for value in results {
+            self.push_stack(value)?;
+        }
+        Ok(())
+    }
+
+    fn op_mkclos(&mut self, _: Opcode) -> Result<()> {
+        // captured values are on top of the stack, the function address is under them.
+        let capture_count = self.get_next_word()?;
+        if capture_count < 0 {
+            bail!("Closure capture count was negative: {capture_count}")
+        }
+        let mut captured = vec![];
+        for _ in 0..capture_count {
+            captured.push(self.pop_stack()?);
+        }
+        captured.reverse();
+        let function_address = self.pop_stack()?;
+        let closure = self.alloc_closure(function_address, captured)?;
+        self.push_stack(closure)
+    }
+
+    fn op_callclos(&mut self, _: Opcode) -> Result<()> {
+        let closure = self.pop_stack()?;
+        let (function_address, captured) = self.get_closure(closure)?;
+        let Some(target) = self.check_jump(function_address)? else {
+            return Ok(());
+        };
+        let mut frame = Frame::new(target);
+        // captured values become the first slots of the new frame.
+        for (slot, value) in captured.into_iter().enumerate() {
+            frame.set(slot as i64, value);
+        }
+        let returns = Return {
+            address: self.instruction_pointer,
+            interrupted_stack: None,
+            stack_floor: self.stack_floor(),
+        };
+        self.push_frame(frame, returns)?;
+        self.instruction_pointer = target;
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.enter(self.instruction_pointer);
+        }
+        Ok(())
+    }
+
+    fn op_strnew(&mut self, _: Opcode) -> Result<()> {
+        // copies a length prefixed string, as laid down by `.lstring`,
+        // out of the data segment.
+        let address = self.pop_stack()?;
+        let data = self.program.data();
+        let chars = usize::try_from(address)
+            .ok()
+            .and_then(|start| {
+                let length = usize::try_from(*data.get(start)?).ok()?;
+                data.get(start + 1..start + 1 + length)
+            })
+            .map(<[i64]>::to_vec);
+        let Some(chars) = chars else {
+            bail!("No length prefixed string at data address {address}")
+        };
+        let string = self.alloc_string(&chars)?;
+        self.push_stack(string)
+    }
+
+    fn op_strlen(&mut self, _: Opcode) -> Result<()> {
+        let string = self.pop_stack()?;
+        let length = self.get_string(string)?.len() as i64;
+        self.push_stack(length)
+    }
+
+    fn op_strcat(&mut self, _: Opcode) -> Result<()> {
+        let right = self.pop_stack()?;
+        let left = self.pop_stack()?;
+        let mut chars = self.get_string(left)?.to_vec();
+        chars.extend_from_slice(self.get_string(right)?);
+        let string = self.alloc_string(&chars)?;
+        self.push_stack(string)
+    }
+
+    fn op_strcmp(&mut self, _: Opcode) -> Result<()> {
+        let right = self.pop_stack()?;
+        let left = self.pop_stack()?;
+        let ordering = self.get_string(left)?.cmp(self.get_string(right)?);
+        self.push_stack(ordering as i64)
+    }
+
+    fn op_strget(&mut self, _: Opcode) -> Result<()> {
+        let index = self.pop_stack()?;
+        let string = self.pop_stack()?;
+        let chars = self.get_string(string)?;
+        let Some(c) = usize::try_from(index)
+            .ok()
+            .and_then(|index| chars.get(index))
+        else {
+            bail!(
+                "Index {index} is out of bounds for a string of {}",
+                chars.len()
+            )
+        };
+        self.push_stack(*c)
+    }
+
+    fn op_prnstr(&mut self, _: Opcode) -> Result<()> {
+        let string = self.pop_stack()?;
+        let text = self
+            .get_string(string)?
+            .iter()
+            .map(|code| u32::try_from(*code).ok().and_then(char::from_u32))
+            .collect::<Option<String>>();
+        let Some(text) = text else {
+            bail!("String at {string} holds something that isn't a character")
+        };
+        write!(self.output, "{text}").context("Could not write string")
+    }
+
+    fn op_mnew(&mut self, _: Opcode) -> Result<()> {
+        let address = self.alloc(2)?;
+        self.heap[address as usize] = MAP_TAG;
+        self.heap[address as usize + 1] = self.maps.len() as i64;
+        self.maps.push(BTreeMap::new());
+        self.push_stack(address)
+    }
+
+    fn op_mget(&mut self, _: Opcode) -> Result<()> {
+        let key = self.pop_stack()?;
+        let map = self.pop_stack()?;
+        let Some(value) = self.get_map(map)?.get(&key).copied() else {
+            bail!("Key {key} is not in the map at {map}")
+        };
+        self.push_stack(value)
+    }
+
+    fn op_mset(&mut self, _: Opcode) -> Result<()> {
+        let value = self.pop_stack()?;
+        let key = self.pop_stack()?;
+        let map = self.pop_stack()?;
+        let index = self.map_index(map)?;
+        if !self.maps[index].contains_key(&key) {
+            self.grow_maps()?;
+        }
+        self.maps[index].insert(key, value);
+        Ok(())
+    }
+
+    fn op_mdel(&mut self, _: Opcode) -> Result<()> {
+        let key = self.pop_stack()?;
+        let map = self.pop_stack()?;
+        let index = self.map_index(map)?;
+        if self.maps[index].remove(&key).is_some() {
+            self.map_entries -= 1;
+        }
+        Ok(())
+    }
+
+    fn op_mlen(&mut self, _: Opcode) -> Result<()> {
+        let map = self.pop_stack()?;
+        let length = self.get_map(map)?.len() as i64;
+        self.push_stack(length)
+    }
+
+    fn op_mhas(&mut self, _: Opcode) -> Result<()> {
+        let key = self.pop_stack()?;
+        let map = self.pop_stack()?;
+        let has = match self.get_map(map)?.contains_key(&key) {
+            true => TRUE,
+            false => FALSE,
+        };
+        self.push_stack(has)
+    }
+
+    fn op_rnew(&mut self, _: Opcode) -> Result<()> {
+        let shape = self.get_next_word()?;
+        let Some(field_count) = usize::try_from(shape)
+            .ok()
+            .and_then(|shape| self.program.constants().get(shape))
+            .and_then(|count| usize::try_from(*count).ok())
+        else {
+            bail!("Constant {shape} is not a record shape")
+        };
+        let address = self.alloc(2 + field_count)?;
+        self.heap[address as usize] = RECORD_TAG;
+        self.heap[address as usize + 1] = shape;
+        self.push_stack(address)
+    }
+
+    fn op_rget(&mut self, _: Opcode) -> Result<()> {
+        let field = self.get_next_word()?;
+        let record = self.pop_stack()?;
+        let slot = self.record_field(record, field)?;
+        self.push_stack(self.heap[slot])
+    }
+
+    fn op_rset(&mut self, _: Opcode) -> Result<()> {
+        let field = self.get_next_word()?;
+        let value = self.pop_stack()?;
+        let record = self.pop_stack()?;
+        let slot = self.record_field(record, field)?;
+        self.heap[slot] = value;
+        Ok(())
+    }
+
+    fn op_hload(&mut self, _: Opcode) -> Result<()> {
+        let address = self.pop_stack()?;
+        let value = self.heap_load(address)?;
+        self.push_stack(value)
+    }
+
+    fn op_hstore(&mut self, _: Opcode) -> Result<()> {
+        let value = self.pop_stack()?;
+        let address = self.pop_stack()?;
+        self.heap_store(address, value)
+    }
+
+    fn op_prnstk(&mut self, _: Opcode) -> Result<()> {
+        self.dump_stack().context("Could not write stack dump")
+    }
+
+    fn op_prnchr(&mut self, _: Opcode) -> Result<()> {
+        let code = self.pop_stack()?;
+        let Some(c) = u32::try_from(code).ok().and_then(char::from_u32) else {
+            bail!("{code} is not a character")
+        };
+        write!(self.output, "{c}").context("Could not write character")
+    }
+}
+
+// the instruction pointer and what's there, the stack top first, and the
+// current frame's variables.
+impl fmt::Display for Cpu {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ip = self.instruction_pointer;
+        match decode_at(self.program.code(), ip) {
+            Ok(instruction) => write!(f, "ip: {ip}: {instruction}")?,
+            Err(_) if ip == self.program.code().len() => write!(f, "ip: {ip}: end of program")?,
+            Err(_) => write!(f, "ip: {ip}: ???")?,
+        }
+        match self.program.symbol_at(ip as i64) {
+            Some(symbol) => writeln!(f, " ({})", symbol.name)?,
+            None => match self.program.symbol_before(ip as i64) {
+                Some(symbol) if ip < self.program.code().len() => {
+                    writeln!(f, " (in {})", symbol.name)?
+                }
+                _ => writeln!(f)?,
+            },
+        }
+        if self.stack.is_empty() {
+            writeln!(f, "stack: empty")?;
+        } else {
+            writeln!(f, "stack, top first:")?;
+            for value in self.stack.iter().rev() {
+                writeln!(f, "    {value}")?;
+            }
+        }
+        let variables = self
+            .program
+            .describe_variables(ip as i64, &self.variables());
+        match variables.is_empty() {
+            true => writeln!(f, "variables: none"),
+            false => writeln!(f, "variables: {}", variables.join(", ")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::{parse_program, AssemblerOptions};
+    use crate::program::ProgramParts;
+    #[test]
+    fn add_two() {
+        let program = vec![PUSH, 42, PUSH, 42, ADD, HALT];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        let val = cpu.pop_stack().unwrap();
+        assert_eq!(84, val);
+    }
+
+    #[test]
+    fn sub_two() {
+        let program = vec![PUSH, 42, PUSH, 42, SUB, HALT];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        let val = cpu.pop_stack().unwrap();
+        assert_eq!(0, val);
+    }
+
+    #[test]
+    fn mul_two() {
+        let program = vec![PUSH, 42, PUSH, 42, MUL, HALT];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        let val = cpu.pop_stack().unwrap();
+        assert_eq!(1764, val);
+    }
+
+    #[test]
+    fn div_two() {
+        let program = vec![PUSH, 4, PUSH, 2, DIV, HALT];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        let val = cpu.pop_stack().unwrap();
+        assert_eq!(2, val);
+    }
+
+    #[test]
+    fn not() {
+        let program = vec![PUSH, 1, NOT, HALT];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        let val = cpu.pop_stack().unwrap();
+        assert_eq!(0, val);
+    }
+
+    #[test]
+    fn and() {
+        let program = vec![PUSH, 1, PUSH, 2, AND, HALT];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        let val = cpu.pop_stack().unwrap();
+        assert_eq!(1, val);
+    }
+
+    #[test]
+    fn or() {
+        let program = vec![PUSH, 1, PUSH, 0, OR, HALT];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        let val = cpu.pop_stack().unwrap();
+        assert_eq!(1, val);
+    }
+
+    #[test]
+    fn dup() {
+        let program = vec![PUSH, 1, DUP, ADD, HALT];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        let val = cpu.pop_stack().unwrap();
+        assert_eq!(2, val);
+    }
+
+    #[test]
+    fn is_eq() {
+        let program = vec![PUSH, 1, PUSH, 1, ISEQ, HALT];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        let val = cpu.pop_stack().unwrap();
+        assert_eq!(1, val);
+    }
+
+    #[test]
+    fn is_gt() {
+        let program = vec![PUSH, 2, PUSH, 1, ISGT, HALT];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        let val = cpu.pop_stack().unwrap();
+        assert_eq!(1, val);
+    }
+
+    #[test]
+    fn is_gte() {
+        let program = vec![PUSH, 2, PUSH, 1, ISGE, HALT];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        let val = cpu.pop_stack().unwrap();
+        assert_eq!(1, val);
+
+        let program = vec![PUSH, 1, PUSH, 1, ISGE, HALT];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        let val = cpu.pop_stack().unwrap();
+        assert_eq!(1, val);
+    }
+
+    #[test]
+    fn jmp() {
+        let program = vec![JMP, 5, PUSH, 420, HALT, JMP, 2];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+    }
+
+    #[test]
+    fn jif() {
+        let program = vec![PUSH, 1, JIF, 5, POP, PUSH, 0, JIF, 4, PUSH, 420, HALT];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        let val = cpu.pop_stack().unwrap();
+        assert_eq!(420, val)
+    }
+
+    #[test]
+    fn fused_tests() {
+        // counts 3 down to 0, leaving whether it got there.
+        let program = vec![
+            PUSH, 3, DUP, JZ, 11, PUSH, 1, SUB, JMP, 2, HALT, ISZERO, PUSH, 0, JNZ, 10, HALT,
+        ];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        // and JNZ didn't jump back to the first HALT on 0.
+        assert_eq!(vec![1], cpu.stack());
+        assert_eq!(17, cpu.ip());
+    }
+
+    #[test]
+    fn compare_and_branch() {
+        for (opcode, a, b, taken) in [
+            (JEQ, 3, 3, true),
+            (JEQ, 3, 4, false),
+            (JNE, 3, 4, true),
+            (JLT, -1, 0, true),
+            (JLT, 2, 2, false),
+            (JGE, 2, 2, true),
+            (JGE, 1, 2, false),
+        ] {
+            let program = vec![PUSH, a, PUSH, b, opcode, 9, PUSH, 0, HALT, PUSH, 1, HALT];
+            let mut cpu = Cpu::new();
+            cpu.load_program(Program::from_code(program).unwrap());
+            cpu.run().unwrap();
+            assert_eq!(vec![taken as i64], cpu.stack(), "{opcode} {a} {b}");
+        }
+    }
+
+    #[test]
+    fn counted_loop() {
+        let source = "push 3\nstore 0\npush 0\n:top\nload 0\nadd\nloop 0 :top\nhalt";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        assert_eq!(
+            vec![PUSH, 3, STORE, 0, PUSH, 0, LOAD, 0, ADD, LOOP, 0, 6, HALT],
+            program.code()
+        );
+        let mut cpu = Cpu::new();
+        cpu.load_program(program);
+        cpu.run().unwrap();
+        assert_eq!(vec![6], cpu.stack());
+        assert_eq!(Some(0), cpu.frames.last().unwrap().try_get(0));
+    }
+
+    #[test]
+    fn load() {
+        let program = vec![LOAD, 0, HALT];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        let val = cpu.pop_stack().unwrap();
+        assert_eq!(0, val)
+    }
+
+    #[test]
+    fn store() {
+        let program = vec![PUSH, 42, STORE, 0, HALT];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        let val = cpu.get_current_frame().get(0);
+        assert_eq!(42, val)
+    }
+
+    #[test]
+    fn load_and_store() {
+        let program = vec![PUSH, 42, STORE, 0, LOAD, 0, HALT];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        let val = cpu.pop_stack().unwrap();
+        assert_eq!(42, val)
+    }
+
+    #[test]
+    fn variables_outside_the_slots() {
+        let program = vec![
+            PUSH, 1, STORE, 3, PUSH, 2, STORE, 1000, PUSH, 3, STORE, -1, LOAD, 3, LOAD, 1000, LOAD,
+            -1, LOAD, 2, HALT,
+        ];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        assert_eq!(vec![1, 2, 3, 0], cpu.stack);
+        assert_eq!(
+            BTreeMap::from([(-1, 3), (3, 1), (1000, 2)]),
+            cpu.frames[0].variables()
+        );
+    }
+
+    #[test]
+    fn bigger_program() {
+        // we're just really checking if the program halts.
+        let program = vec![
+            // Init a with "6"
+            PUSH, 6, STORE, 0, // Init b with "4"
+            PUSH, 4, STORE, 1, // Load a and b into the stack
+            LOAD, 0, // Stack contains a
+            LOAD, 1,    // Stack contains a, b
+            ISGT, // Stack contains a > b
+            JIF, 21, // This is the "else" path
+            LOAD, 1, // Stack contains b
+            STORE, 2, // Set c to the stack head, meaning c = b
+            JMP, 25, // This is the "if" path, and this is the address 21
+            LOAD, 0, // Stack contains a
+            STORE, 2, // Set c to the stack head, meaning c = a
+            // Done; this is address 25
+            HALT,
+        ];
+
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        let val = cpu.get_current_frame().get(2);
+        assert_eq!(val, 6);
+    }
+
+    #[test]
+    fn add_multiply_without_multiplying() {
+        let program = vec![
+            // Init a with "6"
+            PUSH, 6, STORE, 0, // Init b with "4"
+            PUSH, 4, STORE, 1, // Init total to 0
+            PUSH, 0, STORE, 2, // While part
+            // Here is address 12
+            LOAD, 1, // Stack contains b
+            PUSH, 1,    // Stack contains b, 1
+            ISGE, // Stack contains b >= 1
+            NOT,  // Stack contains b < 1
+            JIF, 36, // 36 is the address of the HALT label
+            // Inner loop part
+            LOAD, 0, // Stack contains a
+            LOAD, 2,   // Stack contains a, total
+            ADD, // Stack contains a + total
+            STORE, 2, // Save in total, meaning total = a + total
+            LOAD, 1, // Stack contains b
+            PUSH, 1,   // Stack contains b, 1
+            SUB, // Stack contains b - 1
+            STORE, 1, // Save in b, meaning b = b - 1
+            JMP, 12, // Go back to the start of the loop
+            HALT,
+        ];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        let val = cpu.get_current_frame().get(2);
+        assert_eq!(24, val);
+    }
+
+    #[test]
+    fn funcall_no_args_return() {
+        let program = vec![CALL, 3, HALT, RET];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        assert!(cpu.stack.is_empty());
+    }
+
+    #[test]
+    fn funcall_returns_no_arguments_int_return() {
+        let program = vec![CALL, 3, HALT, PUSH, 7, RET];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        let val = cpu.pop_stack().unwrap();
+        assert_eq!(7, val)
+    }
+
+    #[test]
+    fn doubles_given_argument() {
+        let program = vec![PUSH, 3, CALL, 5, HALT, PUSH, 2, MUL, RET];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        let val = cpu.pop_stack().unwrap();
+        assert_eq!(6, val)
+    }
+
+    #[test]
+    fn maximum() {
+        let program = vec![
+            PUSH, 6, // Push the first argument
+            PUSH, 4, // Push the second argument
+            CALL, 7,    // Call "max"
+            HALT, // Here is address 7, the start of "max" function
+            STORE, 1, // Store b in local variable 1; the stack now contains [a]
+            STORE, 0, // Store a in local variable 0; the stack is now empty
+            LOAD, 0, // The stack now contains [a]
+            LOAD, 1,    // The stack now contains [a, b]
+            ISGE, // The stack now contains [a > b]
+            JIF, 21, // If the top of the stack is true (a > b), jump to the "if" path
+            LOAD, 1,   // "else" path: load b on the stack
+            RET, // Here is address 23
+            LOAD, 0, // "if" path: load a on the stack
+            RET,
+        ];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        let val = cpu.pop_stack().unwrap();
+        assert_eq!(6, val)
+    }
+
+    #[test]
+    fn push_constant() {
+        let program = Program::new(ProgramParts {
+            code: vec![PUSHC, 1, HALT],
+            constants: vec![1, i64::MAX],
+            ..Default::default()
+        })
+        .unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(program);
+        cpu.run().unwrap();
+        let val = cpu.pop_stack().unwrap();
+        assert_eq!(i64::MAX, val)
+    }
+
+    #[test]
+    fn load_data() {
+        let program = Program::new(ProgramParts {
+            code: vec![PUSH, 1, DLOAD, HALT],
+            data: vec![104, 105],
+            ..Default::default()
+        })
+        .unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(program);
+        cpu.run().unwrap();
+        assert_eq!(105, cpu.pop_stack().unwrap());
+
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(vec![PUSH, 0, DLOAD, HALT]).unwrap());
+        assert!(cpu.run().is_err());
+    }
+
+    #[test]
+    fn heap_strings() {
+        // "ab" at data address 0 and "c" at 3.
+        let data = vec![2, 97, 98, 1, 99];
+        let run = |code: Vec<i64>| {
+            let output = SharedOutput::default();
+            let mut cpu = Cpu::new();
+            cpu.set_output(output.clone());
+            cpu.load_program(
+                Program::new(ProgramParts {
+                    code,
+                    data: data.clone(),
+                    ..Default::default()
+                })
+                .unwrap(),
+            );
+            let result = cpu.run().map(|_| cpu.stack.clone());
+            let printed = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+            (result, printed)
+        };
+
+        let (stack, printed) = run(vec![
+            PUSH, 0, STRNEW, PUSH, 3, STRNEW, STRCAT, STORE, 0, LOAD, 0, PRNSTR, LOAD, 0, STRLEN,
+            LOAD, 0, PUSH, 2, STRGET, HALT,
+        ]);
+        assert_eq!(vec![3, 99], stack.unwrap());
+        assert_eq!("abc", printed);
+
+        let (stack, _) = run(vec![
+            PUSH, 0, STRNEW, PUSH, 3, STRNEW, STRCMP, PUSH, 3, STRNEW, PUSH, 0, STRNEW, STRCMP,
+            PUSH, 0, STRNEW, DUP, STRCMP, HALT,
+        ]);
+        assert_eq!(vec![-1, 1, 0], stack.unwrap());
+
+        assert!(run(vec![PUSH, 0, STRNEW, PUSH, 2, STRGET, HALT]).0.is_err());
+        assert!(run(vec![PUSH, 1, STRNEW, HALT]).0.is_err());
+        assert!(run(vec![PUSH, 0, STRLEN, HALT]).0.is_err());
+
+        // a closure capturing 2 and -1 looks like a string header at 3.
+        let forged = [PUSH, 0, PUSH, STRING_TAG, PUSH, -1, MKCLOS, 2, POP];
+        for rest in [
+            vec![PUSH, 3, STRLEN],
+            vec![PUSH, 3, PUSH, 0, STRGET],
+            vec![PUSH, 3, PUSH, 3, STRCAT],
+            vec![PUSH, 3, PRNSTR],
+        ] {
+            let code = [&forged[..], &rest, &[HALT]].concat();
+            let err = format!("{:#}", run(code).0.unwrap_err());
+            assert!(err.contains("Value 3 is not a string"), "{err}");
+        }
+    }
+
+    #[test]
+    fn maps() {
+        let program = vec![
+            MNEW, STORE, 0, // m = {}
+            LOAD, 0, PUSH, 5, PUSH, 50, MSET, // m[5] = 50
+            LOAD, 0, PUSH, 6, PUSH, 60, MSET, // m[6] = 60
+            LOAD, 0, PUSH, 5, PUSH, 55, MSET, // m[5] = 55
+            LOAD, 0, PUSH, 6, MDEL, // del m[6]
+            LOAD, 0, PUSH, 5, MGET, LOAD, 0, MLEN, LOAD, 0, PUSH, 6, MHAS, HALT,
+        ];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        assert_eq!(vec![55, 1, FALSE], cpu.stack);
+        assert_eq!(6, cpu.memory_stats().peak_heap_words);
+
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(vec![MNEW, PUSH, 1, MGET, HALT]).unwrap());
+        assert!(format!("{:#}", cpu.run().unwrap_err()).contains("Key 1 is not in the map"));
+
+        // each entry counts two words against the heap limit.
+        let limits = MemoryLimits {
+            heap_words: Some(5),
+            ..Default::default()
+        };
+        let mut cpu = Cpu::builder().memory_limits(limits).build();
+        cpu.load_program(
+            Program::from_code(vec![
+                MNEW, DUP, PUSH, 1, PUSH, 1, MSET, PUSH, 2, PUSH, 2, MSET, HALT,
+            ])
+            .unwrap(),
+        );
+        assert!(format!("{:#}", cpu.run().unwrap_err()).contains("Heap limit of 5 words"));
+
+        // a map header pointing past the maps there are, or before them.
+        for index in [99, -1] {
+            for rest in [
+                vec![PUSH, 0, MLEN],
+                vec![PUSH, 0, PUSH, 1, MGET],
+                vec![PUSH, 0, PUSH, 1, PUSH, 2, MSET],
+                vec![PUSH, 0, PUSH, 1, MDEL],
+            ] {
+                let code = [
+                    &[MNEW, POP, PUSH, 1, PUSH, index, HSTORE],
+                    &rest[..],
+                    &[HALT],
+                ]
+                .concat();
+                let mut cpu = Cpu::new();
+                cpu.load_program(Program::from_code(code).unwrap());
+                let err = format!("{:#}", cpu.run().unwrap_err());
+                assert!(err.contains("Value 0 is not a map"), "{err}");
+            }
+        }
+    }
+
+    #[test]
+    fn records() {
+        let run_records = |code: Vec<i64>| {
+            let program = Program::new(ProgramParts {
+                code,
+                constants: vec![2],
+                ..Default::default()
+            })
+            .unwrap();
+            let mut cpu = Cpu::new();
+            cpu.load_program(program);
+            let result = cpu.run();
+            (result, cpu.stack)
+        };
+        let (result, stack) = run_records(vec![
+            RNEW, 0, STORE, 0, // p = {0, 0}
+            LOAD, 0, PUSH, 7, RSET, 1, // p.1 = 7
+            LOAD, 0, RGET, 0, LOAD, 0, RGET, 1, HALT,
+        ]);
+        result.unwrap();
+        assert_eq!(vec![0, 7], stack);
+
+        let (result, _) = run_records(vec![RNEW, 0, RGET, 2, HALT]);
+        assert!(format!("{:#}", result.unwrap_err()).contains("Field 2 is out of bounds"));
+        let (result, _) = run_records(vec![MNEW, RGET, 0, HALT]);
+        assert!(format!("{:#}", result.unwrap_err()).contains("is not a record"));
+
+        // a shape written over with one that isn't in the pool, or with one
+        // saying there are more fields than the heap has.
+        for (shape, field, message) in [
+            (99, 0, "has shape 99, which isn't in the constant pool"),
+            (1, 50, "Record at 0 runs past the end of the heap"),
+        ] {
+            let program = Program::new(ProgramParts {
+                code: vec![
+                    RNEW, 0, DUP, PUSH, 1, PUSH, shape, HSTORE, RGET, field, HALT,
+                ],
+                constants: vec![2, 100],
+                ..Default::default()
+            })
+            .unwrap();
+            let mut cpu = Cpu::new();
+            cpu.load_program(program);
+            let err = format!("{:#}", cpu.run().unwrap_err());
+            assert!(err.contains(message), "{err}");
+        }
+
+        // replace_program() won't lose a record's shape.
+        let mut cpu = Cpu::new();
+        cpu.load_program(
+            Program::new(ProgramParts {
+                code: vec![RNEW, 0, BRK, RGET, 0, HALT],
+                constants: vec![2],
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        assert_eq!(Outcome::Breakpoint(2), cpu.run_to_break().unwrap());
+        let replacement = Program::from_code(vec![NOP, NOP, NOP, RGET, 0, HALT]).unwrap();
+        let err = cpu.replace_program(replacement, false).unwrap_err();
+        assert_eq!(
+            "Record at 0 has shape 0, which isn't in the new program",
+            err.to_string()
+        );
+        assert_eq!(Outcome::Halted, cpu.run_to_break().unwrap());
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(vec![PUSH, 1, BRK, PUSH, 2, BRK, ADD, HALT]).unwrap());
+        assert_eq!(Outcome::Breakpoint(2), cpu.run_to_break().unwrap());
+        assert_eq!(vec![1], cpu.stack());
+        assert_eq!(Outcome::Breakpoint(5), cpu.run_to_break().unwrap());
+        assert_eq!(Outcome::Halted, cpu.run_to_break().unwrap());
+        assert_eq!(vec![3], cpu.stack());
+
+        // run() doesn't stop for them.
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(vec![PUSH, 1, BRK, HALT]).unwrap());
+        cpu.run().unwrap();
+        assert_eq!(vec![1], cpu.stack());
+    }
+
+    #[test]
+    fn conditional_breakpoints() {
+        // counts slot 0 up from 0 to 5.
+        let program = Program::from_code(vec![
+            LOAD, 0, PUSH, 1, ADD, DUP, STORE, 0, PUSH, 5, ISGE, NOT, JIF, 0, HALT,
+        ])
+        .unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(program.clone());
+        cpu.add_breakpoint(0, Some("slot 0 == 3".parse().unwrap()));
+        assert_eq!(Outcome::Breakpoint(0), cpu.run_to_break().unwrap());
+        assert_eq!(3, cpu.variable(0));
+        assert_eq!(Outcome::Halted, cpu.run_to_break().unwrap());
+
+        let mut cpu = Cpu::new();
+        cpu.load_program(program);
+        cpu.add_breakpoint(2, Some("hits == 2".parse().unwrap()));
+        cpu.add_breakpoint(14, None);
+        assert_eq!(Outcome::Breakpoint(2), cpu.run_to_break().unwrap());
+        assert_eq!(vec![1], cpu.stack());
+        assert_eq!(Outcome::Breakpoint(14), cpu.run_to_break().unwrap());
+        assert!(cpu.remove_breakpoint(14));
+        assert_eq!(Outcome::Halted, cpu.run_to_break().unwrap());
+    }
+
+    #[test]
+    fn replaces_program_keeping_state() {
+        let assemble =
+            |source: &str| parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(assemble(
+            "push 5\npush :f\nmkclos 0\ncall :f\nhalt\n:f\nbrk\npush 1\nadd\nret",
+        ));
+        assert_eq!(Outcome::Breakpoint(9), cpu.run_to_break().unwrap());
+
+        // :f now adds 10, and has moved along two words.
+        let unmapped = cpu
+            .replace_program(
+            assemble(
+                "push 5\npush :f\nmkclos 0\ncall :f\nnop\nnop\nhalt\n:f\nbrk\npush 10\nadd\nret",
+            ),
+            true,
+        )
+            .unwrap();
+        assert!(unmapped.is_empty());
+        assert_eq!(12, cpu.ip());
+        // the closure points at the new :f.
+        assert_eq!(11, cpu.heap[1]);
+        assert_eq!(Outcome::Halted, cpu.run_to_break().unwrap());
+        // and returned to just after the call, now the NOPs.
+        assert_eq!(11, cpu.steps());
+        assert_eq!(vec![5, 10], cpu.stack());
+    }
+
+    #[test]
+    fn replace_program_moves_record_shapes() {
+        let assemble =
+            |source: &str| parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        let first = ".record :a x\n.record :b x y\nrnew :b\npush :f\nmkclos 0\nbrk\nhalt\n:f\nret";
+        let second =
+            ".record :b x y\n.record :a x\nrnew :b\npush :f\nmkclos 0\nbrk\nnop\nhalt\n:f\nret";
+        let mut cpu = Cpu::new();
+        cpu.load_program(assemble(first));
+        cpu.run_to_break().unwrap();
+        assert_eq!(1, cpu.heap[1]);
+
+        cpu.replace_program(assemble(second), true).unwrap();
+        assert_eq!(0, cpu.heap[1]);
+        assert_eq!(9, cpu.heap[5]);
+        // which the next swap needs, to find the closure after the record.
+        cpu.replace_program(assemble(first), true).unwrap();
+        assert_eq!(1, cpu.heap[1]);
+        assert_eq!(8, cpu.heap[5]);
+
+        // a record the new program has with a different number of fields.
+        let err = cpu
+            .replace_program(assemble(".record :b x\nhalt"), true)
+            .unwrap_err();
+        assert_eq!(
+            "Record at 0 has shape 1, which isn't in the new program",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn patches_code() {
+        let program = Program::from_code(vec![PUSH, 1, PUSH, 2, ADD, HALT]).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(program.clone());
+        cpu.patch(4, &[MUL]).unwrap();
+        assert!(cpu.patch(5, &[HALT, HALT]).is_err());
+        // leaves PUSH without its operand.
+        assert!(cpu.patch(5, &[PUSH]).is_err());
+        cpu.run().unwrap();
+        assert_eq!(vec![2], cpu.stack());
+
+        let mut cpu = Cpu::builder().write_protect_code(true).build();
+        cpu.load_program(program.clone());
+        let err = cpu.patch(4, &[MUL]).unwrap_err();
+        assert_eq!("Code is write protected", err.to_string());
+        assert!(cpu.replace_program(program, false).is_err());
+    }
+
+    // remembers what was written, and reads back ten times the offset.
+    #[derive(Clone, Default)]
+    struct Lights(std::sync::Arc<std::sync::Mutex<Vec<(usize, i64)>>>);
+
+    impl Device for Lights {
+        fn read(&mut self, offset: usize) -> Result<i64> {
+            Ok(offset as i64 * 10)
+        }
+
+        fn write(&mut self, offset: usize, value: i64) -> Result<()> {
+            self.0.lock().unwrap().push((offset, value));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn memory_mapped_devices() {
+        let lights = Lights::default();
+        let mut cpu = Cpu::new();
+        cpu.map_device(1000..1004, lights.clone()).unwrap();
+        assert!(cpu.map_device(1003..1010, Lights::default()).is_err());
+        cpu.load_program(
+            Program::from_code(vec![
+                PUSH, 1002, PUSH, 7, HSTORE, PUSH, 1003, HLOAD, // device
+                MNEW, HLOAD, // the map's tag, off the real heap
+                HALT,
+            ])
+            .unwrap(),
+        );
+        cpu.run().unwrap();
+        assert_eq!(vec![(2, 7)], *lights.0.lock().unwrap());
+        assert_eq!(vec![30, MAP_TAG], cpu.stack());
+
+        let mut cpu = Cpu::new();
+        cpu.map_device(0..2, Lights::default()).unwrap();
+        cpu.load_program(Program::from_code(vec![MNEW, HALT]).unwrap());
+        let err = format!("{:#}", cpu.run().unwrap_err());
+        assert!(err.contains("Heap ran into the device mapped at 0..2"));
+
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(vec![PUSH, 5, HLOAD, HALT]).unwrap());
+        let err = format!("{:#}", cpu.run().unwrap_err());
+        assert!(err.contains("Heap address 5 is out of bounds"));
+    }
+
+    #[test]
+    fn hstore_over_object_headers() {
+        // HSTORE can write any heap word, headers included, so whatever reads
+        // an object has to cope with one that's been written over.
+        let cases: [(&[i64], &str); 5] = [
+            (
+                &[
+                    PUSH,
+                    0,
+                    MKCLOS,
+                    0,
+                    DUP,
+                    PUSH,
+                    2,
+                    ADD,
+                    PUSH,
+                    i64::MAX,
+                    HSTORE,
+                    CALLCLOS,
+                ],
+                "is not a closure",
+            ),
+            (
+                &[
+                    PUSH,
+                    0,
+                    STRNEW,
+                    DUP,
+                    PUSH,
+                    1,
+                    ADD,
+                    PUSH,
+                    i64::MAX,
+                    HSTORE,
+                    STRLEN,
+                ],
+                "is not a string",
+            ),
+            (
+                &[MNEW, DUP, PUSH, 1, ADD, PUSH, i64::MIN, HSTORE, MLEN],
+                "is not a map",
+            ),
+            (
+                &[RNEW, 0, DUP, PUSH, 1, ADD, PUSH, i64::MAX, HSTORE, RGET, 0],
+                "isn't in the constant pool",
+            ),
+            // a map retagged as a record, with no room for its field.
+            (
+                &[MNEW, DUP, PUSH, RECORD_TAG, HSTORE, RGET, 0],
+                "runs past the end of the heap",
+            ),
+        ];
+        for (code, message) in cases {
+            let program = Program::new(ProgramParts {
+                code: [code, &[HALT]].concat(),
+                constants: vec![2],
+                data: vec![1, 97],
+                ..Default::default()
+            })
+            .unwrap();
+            let mut cpu = Cpu::new();
+            cpu.load_program(program.clone());
+            let err = cpu.run().unwrap_err();
+            assert!(format!("{err:#}").contains(message), "{err:#}");
+            // and so do the things that walk the whole heap.
+            cpu.core_dump(&err);
+            cpu.replace_program(program, true).unwrap();
+        }
+    }
+
+    #[test]
+    fn fork() {
+        // stores a syscall's result in a variable, then prints it.
+        let program =
+            Program::from_code(vec![PUSH, 5, SYSCALL, 0, STORE, 0, LOAD, 0, HALT]).unwrap();
+        let calls = Arc::new(std::sync::Mutex::new(0));
+        let mut cpu = Cpu::new();
+        let counter = calls.clone();
+        cpu.bind("bump", move |x: i64| {
+            *counter.lock().unwrap() += 1;
+            x + 1
+        });
+        cpu.load_program(program);
+        cpu.run_slice(1).unwrap();
+
+        let mut fork = cpu.fork();
+        assert!(Arc::ptr_eq(&cpu.program, &fork.program));
+        assert_eq!(cpu.steps(), fork.steps());
+        // patching the fork's code leaves ours alone.
+        fork.patch(6, &[PUSH]).unwrap();
+        fork.run().unwrap();
+        cpu.run().unwrap();
+        assert_eq!(vec![6], cpu.stack());
+        assert_eq!(vec![0], fork.stack());
+        assert_eq!(LOAD, cpu.program().code()[6]);
+        // both called the one function.
+        assert_eq!(2, *calls.lock().unwrap());
+        assert_eq!(5, fork.steps());
+    }
+
+    #[test]
+    fn nondeterminism() {
+        let program =
+            Program::from_code(vec![PUSH, 1001, HLOAD, SYSCALL, 0, PUSH, 3, ADD, HALT]).unwrap();
+        let machine = |nondeterminism| {
+            let mut cpu = Cpu::builder().nondeterminism(nondeterminism).build();
+            cpu.map_device(1000..1004, Lights::default()).unwrap();
+            cpu.bind("double", |x: i64| x * 2);
+            cpu.load_program(program.clone());
+            cpu
+        };
+
+        let mut cpu = machine(Nondeterminism::Allow);
+        cpu.run().unwrap();
+        assert_eq!(vec![23], cpu.stack());
+        assert!(cpu.host_inputs().is_empty());
+
+        let mut cpu = machine(Nondeterminism::Record);
+        cpu.run().unwrap();
+        let input = |step, address, source: &str, words| HostInput {
+            step,
+            address,
+            source: source.to_string(),
+            words,
+        };
+        assert_eq!(
+            vec![
+                input(2, 2, "device read at 1001", vec![10]),
+                input(3, 3, "syscall double", vec![20]),
+            ],
+            cpu.host_inputs()
+        );
+
+        let mut cpu = machine(Nondeterminism::Forbid);
+        let err = format!("{:#}", cpu.run().unwrap_err());
+        assert!(err.contains("The result of device read at 1001 comes from the host"));
+        let mut cpu = machine(Nondeterminism::Forbid);
+        cpu.load_program(Program::from_code(vec![PUSH, 1, SYSCALL, 0, HALT]).unwrap());
+        let err = format!("{:#}", cpu.run().unwrap_err());
+        assert!(err.contains("The result of syscall double comes from the host"));
+    }
+
+    #[test]
+    fn interrupts() {
+        // a loop that never ends by itself, and a handler at 5.
+        let program =
+            Program::from_code(vec![PUSH, 1, POP, JMP, 0, PUSH, 9, PUSH, 9, IRET]).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(program);
+        cpu.set_interrupt_vector(1, 5);
+        cpu.add_breakpoint(0, Some("hits == 3".parse().unwrap()));
+        assert!(cpu.raise_interrupt(7).is_err());
+        cpu.raise_interrupt(1).unwrap();
+        assert_eq!(Outcome::Breakpoint(0), cpu.run_to_break().unwrap());
+        // went through the handler first, and IRET dropped what it left.
+        assert_eq!(9, cpu.steps());
+        assert!(cpu.stack().is_empty());
+        assert_eq!(1, cpu.frames.len());
+
+        // INT from the program itself.
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(vec![INT, 2, HALT, PUSH, 42, IRET]).unwrap());
+        cpu.set_interrupt_vector(2, 3);
+        cpu.run().unwrap();
+        assert!(cpu.stack().is_empty());
+        assert_eq!(4, cpu.steps());
+
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(vec![IRET]).unwrap());
+        let err = format!("{:#}", cpu.run().unwrap_err());
+        assert!(err.contains("IRET outside an interrupt handler"));
+    }
+
+    #[test]
+    fn handler_cant_pop_the_interrupted_stack() {
+        // stopped just before ADD, with the handler at 6 popping one word too many.
+        let program =
+            Program::from_code(vec![PUSH, 1, PUSH, 2, ADD, HALT, PUSH, 9, POP, POP, IRET]).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(program);
+        cpu.set_interrupt_vector(1, 6);
+        cpu.add_breakpoint(4, None);
+        assert_eq!(Outcome::Breakpoint(4), cpu.run_to_break().unwrap());
+        cpu.raise_interrupt(1).unwrap();
+        let err = format!("{:#}", cpu.run().unwrap_err());
+        assert!(err.contains("Interrupt handler tried to pop the interrupted code's stack"));
+        assert_eq!(&[1, 2], cpu.stack());
+
+        // nor can a function it calls.
+        let program = Program::from_code(vec![PUSH, 1, HALT, CALL, 6, IRET, POP, RET]).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(program);
+        cpu.set_interrupt_vector(1, 3);
+        cpu.add_breakpoint(2, None);
+        assert_eq!(Outcome::Breakpoint(2), cpu.run_to_break().unwrap());
+        cpu.raise_interrupt(1).unwrap();
+        assert!(cpu.run().is_err());
+        assert_eq!(&[1], cpu.stack());
+    }
+
+    #[test]
+    fn inspects_without_changing_anything() {
+        let mut cpu = Cpu::new();
+        cpu.load_program(
+            Program::from_code(vec![PUSH, 4, STORE, 1, PUSH, 7, CALL, 9, HALT, BRK, RET]).unwrap(),
+        );
+        assert_eq!(Outcome::Breakpoint(9), cpu.run_to_break().unwrap());
+        assert_eq!(10, cpu.ip());
+        assert_eq!(Some(7), cpu.peek());
+        assert_eq!(Some(7), cpu.peek());
+        let frames: Vec<FrameView> = cpu.frames().collect();
+        assert_eq!(2, frames.len());
+        assert_eq!(4, frames[0].variable(1));
+        assert_eq!(8, frames[1].return_address());
+        assert!(frames[1].variables().is_empty());
+        assert!(!frames[1].is_interrupt());
+    }
+
+    #[test]
+    fn displays_state() {
+        let program = parse_program(
+            "push 4\nstore 0\npush 1\npush 2\nbrk\n:sum\nadd\nhalt".to_string(),
+            &AssemblerOptions::default(),
+        )
+        .unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(program);
+        cpu.run_to_break().unwrap();
+        assert_eq!(
+            "ip: 9: add (:sum)\nstack, top first:\n    2\n    1\nvariables: 0 = 4\n",
+            cpu.dump()
+        );
+        cpu.run().unwrap();
+        assert_eq!(
+            "ip: 11: end of program\nstack, top first:\n    3\nvariables: 0 = 4\n",
+            cpu.to_string()
+        );
+    }
+
+    #[test]
+    fn names_variables() {
+        let source = "push 6\npush 4\ncall :mul\nhalt\n\
+                      .fn :mul args=2 rets=1\n.var a 0\n.var b 1\n.var total 2\n\
+                      store :.b\nstore :.a\npush 0\nstore :.total\n\
+                      :.loop\nload :.total\nload :.a\nadd\nstore :.total\nbrk\nprnstk\n\
+                      load :.b\npush 1\nsub\ndup\nstore :.b\njif :.loop\n\
+                      load :.total\nret\n.endfn";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        let output = SharedOutput::default();
+        let mut cpu = Cpu::new();
+        cpu.set_output(output.clone());
+        cpu.load_program(program);
+        cpu.run_to_break().unwrap();
+        assert_eq!(
+            "ip: 23: prnstk (in :mul.loop)\nstack: empty\n\
+             variables: a=6 (slot 0), b=4 (slot 1), total=6 (slot 2)\n",
+            cpu.to_string()
+        );
+        cpu.run_to_break().unwrap();
+        let printed = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            "a=6 (slot 0), b=4 (slot 1), total=6 (slot 2)",
+            printed.lines().nth(1).unwrap()
+        );
+        // the names stop at the end of the function.
+        cpu.run().unwrap();
+        assert_eq!(Some(24), cpu.peek());
+        assert_eq!(None, cpu.program().variable_name(3, 2));
+    }
+
+    #[test]
+    fn strict_loads() {
+        let source = "push 1\nstore 0\ncall :f\nhalt\n\
+                      .fn :f\n.var count 1\nload 0\nload :.count\nret\n.endfn";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(program.clone());
+        cpu.run().unwrap();
+        assert_eq!(vec![0, 0], cpu.stack());
+
+        let mut cpu = Cpu::builder().strict_loads(true).build();
+        cpu.load_program(program);
+        let err = cpu.run().unwrap_err();
+        assert_eq!(
+            "Loaded variable 0 before it was stored to, in :f",
+            err.root_cause().to_string()
+        );
+
+        let source = "push 2\nstore 0\n.var total 1\nload :.total\nhalt";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        let mut cpu = Cpu::builder().strict_loads(true).build();
+        cpu.load_program(program);
+        let err = cpu.run().unwrap_err();
+        assert_eq!(
+            "Loaded total (slot 1) before it was stored to, in <entry>",
+            err.root_cause().to_string()
+        );
+    }
+
+    #[test]
+    fn return_stack_is_checked() {
+        let run = |source: &str| {
+            let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+            let mut cpu = Cpu::new();
+            cpu.set_interrupt_vector(1, 3);
+            cpu.load_program(program);
+            cpu.run()
+                .map(|_| ())
+                .map_err(|e| e.root_cause().to_string())
+        };
+        assert_eq!(
+            Err("Call stack overflow, more than 100000 calls deep".to_string()),
+            run(":f\ncall :f")
+        );
+        assert_eq!(
+            Err("RET in an interrupt handler, which has to return with IRET".to_string()),
+            run("int 1\nhalt\n:handler\nret")
+        );
+        assert_eq!(
+            Err("IRET outside an interrupt handler".to_string()),
+            run("call :f\nhalt\n:f\niret")
+        );
+        assert_eq!(Ok(()), run("int 1\nhalt\n:handler\niret"));
+    }
+
+    #[test]
+    fn errors_say_where() {
+        let options = AssemblerOptions {
+            source_name: Some("sum.asm".to_string()),
+            ..Default::default()
+        };
+        let program = parse_program("push 1\n\nadd\nhalt".to_string(), &options).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(program);
+        let err = format!("{:#}", cpu.run().unwrap_err());
+        assert!(err.contains("add at address 2 (sum.asm:3): "), "{err}");
+
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(vec![POP]).unwrap());
+        let err = format!("{:#}", cpu.run().unwrap_err());
+        assert!(err.contains("pop at address 0: "), "{err}");
+    }
+
+    #[test]
+    fn opcode_table() {
+        let push = Opcode::from_mnemonic("PUSH").unwrap();
+        assert_eq!(PUSH, push.value());
+        assert_eq!("push", push.name());
+        assert_eq!(1, push.operand_count());
+        assert_eq!(Some(push), Opcode::try_from(PUSH).ok());
+        assert!(Opcode::try_from(-1).is_err());
+        assert_eq!(None, Opcode::from_mnemonic("frobnicate"));
+        // the words are the bytecode format, so they can't move.
+        assert_eq!(1, Opcode::Push as i64);
+        assert_eq!(58, Opcode::Loop as i64);
+        assert_eq!("1", serde_json::to_string(&Opcode::Push).unwrap());
+        // every opcode round trips through its mnemonic and its word.
+        for opcode in Opcode::all() {
+            assert_eq!(Some(opcode), Opcode::from_mnemonic(opcode.name()));
+            assert_eq!(opcode, Opcode::try_from(opcode.value()).unwrap());
+        }
+    }
+
+    #[test]
+    fn cpu_is_send() {
+        fn send<T: Send>() {}
+        send::<Cpu>();
+    }
+
+    #[test]
+    fn instruction_table() {
+        // lookups index by opcode, so the table has to stay in order.
+        for (index, info) in INSTRUCTIONS.iter().enumerate() {
+            assert_eq!(index as i64 + 1, info.opcode.value());
+        }
+        assert_eq!(Category::Maps, instruction(MSET).unwrap().category);
+        assert_eq!(Some((3, 0)), stack_effect(Opcode::Mset, None));
+        assert!(instruction(0).is_none());
+        assert!(instruction(INSTRUCTIONS.len() as i64 + 1).is_none());
+    }
+
+    #[test]
+    fn run_outcome() {
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(vec![PUSH, 1, PUSH, 2, HALT]).unwrap());
+        let outcome = cpu.run().unwrap();
+        assert_eq!(
+            RunOutcome {
+                exit_value: Some(2),
+                steps: 3,
+                stack_size: 2,
+                max_stack_depth: 2,
+                max_frame_depth: 1,
+                calls: 0,
+                elapsed: outcome.elapsed,
+            },
+            outcome
+        );
+        // nothing was taken off to find out.
+        assert_eq!(vec![1, 2], cpu.stack());
+
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(vec![HALT]).unwrap());
+        assert_eq!(None, cpu.run().unwrap().exit_value);
+
+        // three calls deep at the bottom of the recursion.
+        let mut cpu = Cpu::new();
+        cpu.load_program(
+            Program::from_code(vec![
+                PUSH, 3, CALL, 5, HALT, // main
+                DUP, JIF, 9, RET, PUSH, 1, SUB, CALL, 5, RET, // countdown
+            ])
+            .unwrap(),
+        );
+        let outcome = cpu.run().unwrap();
+        assert_eq!(4, outcome.calls);
+        assert_eq!(5, outcome.max_frame_depth);
+        assert_eq!(2, outcome.max_stack_depth);
+    }
+
+    #[test]
+    fn explains_each_instruction() {
+        let output = SharedOutput::default();
+        let mut cpu = Cpu::builder().explain(true).build();
+        cpu.set_output(output.clone());
+        cpu.load_program(
+            Program::from_code(vec![
+                PUSH, 6, PUSH, 4, ISGT, JIF, 9, NOP, NOP, PUSH, 4, PUSH, 6, ISGT, HALT,
+            ])
+            .unwrap(),
+        );
+        cpu.run().unwrap();
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            vec![
+                "PUSH pushed 6 (push 6)",
+                "PUSH pushed 4 (push 4)",
+                "ISGT popped 4 and 6, pushed 1 because 6 > 4",
+                "JIF popped 1 and went to 9 (jump to 9 if 1 isn't 0)",
+                "PUSH pushed 4 (push 4)",
+                "PUSH pushed 6 (push 6)",
+                "ISGT popped 6 and 4, pushed 0 because not 4 > 6",
+                "HALT (stop the machine)",
+            ],
+            lines
+        );
+    }
+
+    #[test]
+    fn streams_events() {
+        let events = SharedOutput::default();
+        let mut cpu = Cpu::new();
+        cpu.set_event_sink(events.clone());
+        cpu.load_program(
+            Program::from_code(vec![PUSH, 3, CALL, 5, HALT, DUP, STORE, 0, RET]).unwrap(),
+        );
+        cpu.run().unwrap();
+
+        let events = String::from_utf8(events.0.lock().unwrap().clone()).unwrap();
+        let events: Vec<serde_json::Value> = events
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let kinds: Vec<&str> = events
+            .iter()
+            .map(|e| e["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                "step", "push", "step", "call", "step", "pop", "push", "push", "step", "pop",
+                "store", "step", "ret", "step",
+            ],
+            kinds
+        );
+        assert_eq!(
+            serde_json::json!({"event": "step", "step": 1, "address": 0, "instruction": "push", "operand": 3}),
+            events[0]
+        );
+        assert_eq!(
+            serde_json::json!({"event": "store", "variable": 0, "value": 3}),
+            events[10]
+        );
+        assert_eq!(
+            serde_json::json!({"event": "ret", "from": 8, "to": 4}),
+            events[12]
+        );
+    }
+
+    #[test]
+    fn prints_to_the_configured_output() {
+        let output = SharedOutput::default();
+        let mut cpu = Cpu::builder()
+            .stack_dump_format(StackDumpFormat::JsonLines)
+            .build();
+        cpu.set_output(output.clone());
+        cpu.load_program(
+            Program::from_code(vec![
+                PUSH, 7, STORE, 0, PUSH, 104, PRNCHR, PUSH, 1, PRNSTK, HALT,
+            ])
+            .unwrap(),
+        );
+        cpu.run().unwrap();
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let (printed, dump) = output.split_at(1);
+        assert_eq!("h", printed);
+        let dump: serde_json::Value = serde_json::from_str(dump).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "address": 9,
+                "depth": 1,
+                "return_address": 0,
+                "variables": {"0": 7},
+                "stack": [1],
+            }),
+            dump
+        );
     }
 
-    fn i64_to_bool(val: i64) -> bool {
-        val != 0
+    #[test]
+    fn push_constant_out_of_bounds() {
+        // caught when the program is built, before it gets anywhere near a cpu.
+        assert!(Program::from_code(vec![PUSHC, 1, HALT]).is_err());
     }
 
-    fn push_stack(&mut self, val: i64) {
-        self.stack.push(val)
+    #[test]
+    fn counts_cycles() {
+        let program = vec![PUSH, 4, PUSH, 2, DIV, HALT];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program.clone()).unwrap());
+        cpu.run().unwrap();
+        assert_eq!(13, cpu.cycles());
+
+        let mut cpu = Cpu::builder()
+            .cost_model(CostModel::uniform(2))
+            .cost(DIV, 7)
+            .build();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        assert_eq!(13, cpu.cycles());
     }
 
-    fn pop_stack(&mut self) -> Result<i64> {
-        match self.stack.pop() {
-            Some(val) => Ok(val),
-            None => bail!("Tried to pop empty stack."),
-        }
+    #[test]
+    fn stops_at_step_limit() {
+        let program = vec![JMP, 0];
+        let mut cpu = Cpu::builder().max_steps(100).trace_length(2).build();
+        cpu.load_program(Program::from_code(program).unwrap());
+        let err = cpu.run().unwrap_err().to_string();
+        assert_eq!(100, cpu.steps());
+        assert!(err.contains("Step limit of 100 reached"));
+        assert!(err.contains("     0: jmp 0\n     0: jmp 0"));
     }
 
-    pub fn get_latest_return_value(&mut self) -> Result<i64> {
-        self.pop_stack()
+    #[test]
+    fn stops_at_timeout() {
+        let program = vec![JMP, 0];
+        let mut cpu = Cpu::builder().timeout(Duration::from_millis(10)).build();
+        cpu.load_program(Program::from_code(program).unwrap());
+        let err = cpu.run().unwrap_err().to_string();
+        assert!(err.starts_with("Timed out"));
     }
 
-    fn get_next_word(&mut self) -> Result<i64> {
-        let word = self.program.get(self.instruction_pointer).copied();
-        self.instruction_pointer += 1;
-        match word {
-            Some(word) => Ok(word),
-            None => bail!("Program tried to load out of bounds word."),
-        }
+    #[test]
+    fn tracks_peak_memory() {
+        let program = vec![
+            PUSH, 1, PUSH, 2, PUSH, 3, POP, POP, CALL, 12, HALT, HALT, PUSH, 9, MKCLOS, 0, RET,
+        ];
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(program).unwrap());
+        cpu.run().unwrap();
+        let stats = cpu.memory_stats();
+        assert_eq!(3, stats.peak_stack_words);
+        assert_eq!(2, stats.peak_frames);
+        assert_eq!(3, stats.peak_heap_words);
     }
 
-    pub fn run(&mut self) -> Result<()> {
-        if self.program.is_empty() {
-            self.halted = true;
-            bail!("Loaded empty program")
-        }
+    #[test]
+    fn enforces_memory_limits() {
+        let limits = MemoryLimits {
+            stack_words: Some(2),
+            ..Default::default()
+        };
+        let mut cpu = Cpu::builder().memory_limits(limits).build();
+        cpu.load_program(Program::from_code(vec![PUSH, 1, PUSH, 2, PUSH, 3, HALT]).unwrap());
+        let err = cpu.run().unwrap_err();
+        assert!(format!("{err:#}").contains("Stack limit of 2 words exceeded"));
 
-        loop {
-            if self.halted {
-                break;
-            }
+        let limits = MemoryLimits {
+            frames: Some(3),
+            ..Default::default()
+        };
+        let mut cpu = Cpu::builder().memory_limits(limits).build();
+        cpu.load_program(Program::from_code(vec![CALL, 0]).unwrap());
+        let err = cpu.run().unwrap_err();
+        assert!(format!("{err:#}").contains("Frame limit of 3 exceeded"));
 
-            let instruction = self.get_next_word()?;
-            self.step(instruction)
-                .context("Unable to execute program.")?;
-        }
-        Ok(())
+        let limits = MemoryLimits {
+            heap_words: Some(4),
+            ..Default::default()
+        };
+        let mut cpu = Cpu::builder().memory_limits(limits).build();
+        cpu.load_program(
+            Program::from_code(vec![PUSH, 0, PUSH, 1, PUSH, 2, MKCLOS, 2, HALT]).unwrap(),
+        );
+        let err = cpu.run().unwrap_err();
+        assert!(format!("{err:#}").contains("Heap limit of 4 words exceeded"));
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
     #[test]
-    fn add_two() {
-        let program = vec![PUSH, 42, PUSH, 42, ADD, HALT];
+    fn ret_from_main_halts() {
+        let program = vec![PUSH, 7, RET, PUSH, 8, HALT];
         let mut cpu = Cpu::new();
-        cpu.load_program(program);
+        cpu.load_program(Program::from_code(program).unwrap());
         cpu.run().unwrap();
-        let val = cpu.pop_stack().unwrap();
-        assert_eq!(84, val);
+        assert_eq!(vec![7], cpu.stack);
+        assert_eq!(1, cpu.frames.len());
+        assert!(cpu.halted);
     }
 
     #[test]
-    fn sub_two() {
-        let program = vec![PUSH, 42, PUSH, 42, SUB, HALT];
+    fn running_off_the_end() {
         let mut cpu = Cpu::new();
-        cpu.load_program(program);
+        cpu.load_program(Program::from_code(vec![PUSH, 1]).unwrap());
+        let err = cpu.run().unwrap_err();
+        assert!(err.to_string().contains("Ran off the end of the program"));
+
+        let mut cpu = Cpu::builder().implicit_halt(true).build();
+        cpu.load_program(Program::from_code(vec![PUSH, 1]).unwrap());
         cpu.run().unwrap();
-        let val = cpu.pop_stack().unwrap();
-        assert_eq!(0, val);
+        assert_eq!(vec![1], cpu.stack);
     }
 
     #[test]
-    fn mul_two() {
-        let program = vec![PUSH, 42, PUSH, 42, MUL, HALT];
+    fn division_by_zero_traps() {
         let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(vec![PUSH, 1, PUSH, 0, DIV, HALT]).unwrap());
+        let err = cpu.run().unwrap_err();
+        assert!(format!("{err:#}").contains("Division by zero at address 4"));
+    }
+
+    const BOUNDARIES: [i64; 5] = [0, 1, -1, i64::MIN, i64::MAX];
+
+    // every way of picking `count` words from BOUNDARIES.
+    fn boundary_words(count: usize) -> Vec<Vec<i64>> {
+        (0..count).fold(vec![vec![]], |picks, _| {
+            picks
+                .iter()
+                .flat_map(|pick| {
+                    BOUNDARIES
+                        .iter()
+                        .map(|word| [pick.as_slice(), &[*word]].concat())
+                })
+                .collect()
+        })
+    }
+
+    // runs the instruction on `pops`, pushed deepest first, with every
+    // operand set to `operand`. None if that isn't a valid program, like
+    // PUSHC with a constant that isn't in the pool.
+    fn run_instruction(
+        info: &InstructionInfo,
+        pops: &[i64],
+        operand: i64,
+    ) -> Option<(Cpu, Result<()>)> {
+        let mut code: Vec<i64> = pops.iter().flat_map(|word| [PUSH, *word]).collect();
+        code.push(info.opcode.value());
+        code.extend(std::iter::repeat_n(operand, info.operands));
+        code.push(HALT);
+        let program = Program::new(ProgramParts {
+            code,
+            constants: vec![0, 1],
+            ..Default::default()
+        })
+        .ok()?;
+        let mut cpu = Cpu::builder().max_steps(1000).build();
+        cpu.set_output(io::sink());
         cpu.load_program(program);
-        cpu.run().unwrap();
-        let val = cpu.pop_stack().unwrap();
-        assert_eq!(1764, val);
+        let result = cpu.run().map(|_| ());
+        Some((cpu, result))
     }
 
     #[test]
-    fn div_two() {
-        let program = vec![PUSH, 4, PUSH, 2, DIV, HALT];
-        let mut cpu = Cpu::new();
-        cpu.load_program(program);
-        cpu.run().unwrap();
-        let val = cpu.pop_stack().unwrap();
-        assert_eq!(2, val);
+    fn every_instruction_at_the_boundaries() {
+        for info in INSTRUCTIONS {
+            let name = info.mnemonic;
+            let (pops, pushes) = match info.effect {
+                StackEffect::Fixed(pops, pushes) => (pops, Some(pushes)),
+                _ => (0, None),
+            };
+            let operands = match info.operands {
+                0 => vec![0],
+                _ => BOUNDARIES.to_vec(),
+            };
+            for words in boundary_words(pops) {
+                match info.semantics {
+                    Semantics::Pure(reference) => {
+                        for operand in operands.iter() {
+                            let (cpu, result) = run_instruction(info, &words, *operand).unwrap();
+                            let case = format!("{name} on {words:?} with {operand}");
+                            match reference(&words, *operand) {
+                                Some(expected) => {
+                                    assert_eq!(Some(expected.len()), pushes, "{case}");
+                                    assert!(result.is_ok(), "{case}: {result:?}");
+                                    assert_eq!(expected, cpu.stack(), "{case}");
+                                }
+                                None => assert!(result.is_err(), "{case} should trap"),
+                            }
+                        }
+                    }
+                    Semantics::Branch(taken) => {
+                        assert_eq!(Some(0), pushes, "{name}");
+                        // falls through to push 0, or jumps to push 1.
+                        let mut code: Vec<i64> =
+                            words.iter().flat_map(|word| [PUSH, *word]).collect();
+                        let target = code.len() as i64 + 5;
+                        code.extend([info.opcode.value(), target, PUSH, 0, HALT, PUSH, 1, HALT]);
+                        let mut cpu = Cpu::new();
+                        cpu.load_program(Program::from_code(code).unwrap());
+                        cpu.run().unwrap();
+                        assert_eq!(
+                            vec![taken(&words) as i64],
+                            cpu.stack(),
+                            "{name} on {words:?}"
+                        );
+                    }
+                    Semantics::Stateful => {
+                        for operand in operands.iter() {
+                            // most of these fail on made up handles and
+                            // addresses, but they mustn't panic, and what
+                            // succeeds has to leave the stack it says.
+                            let Some((cpu, result)) = run_instruction(info, &words, *operand)
+                            else {
+                                continue;
+                            };
+                            if let (Ok(()), Some(pushes)) = (result, pushes) {
+                                assert_eq!(
+                                    pushes,
+                                    cpu.stack().len(),
+                                    "{name} on {words:?} with {operand}"
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn run_with_overflow(overflow: Overflow, code: Vec<i64>) -> Result<i64> {
+        let mut cpu = Cpu::builder().overflow(overflow).build();
+        cpu.load_program(Program::from_code(code).unwrap());
+        cpu.run()?;
+        cpu.pop_stack()
     }
 
     #[test]
-    fn not() {
-        let program = vec![PUSH, 1, NOT, HALT];
+    fn overflow_traps() {
+        let err = run_with_overflow(Overflow::Trap, vec![PUSH, i64::MAX, PUSH, 1, ADD, HALT])
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("Arithmetic overflow at address 4"));
+        assert!(
+            run_with_overflow(Overflow::Trap, vec![PUSH, i64::MIN, PUSH, -1, DIV, HALT]).is_err()
+        );
+        assert_eq!(
+            3,
+            run_with_overflow(Overflow::Trap, vec![PUSH, 1, PUSH, 2, ADD, HALT]).unwrap()
+        );
+
         let mut cpu = Cpu::new();
-        cpu.load_program(program);
+        cpu.set_trap_handler(|_, _| TrapAction::Substitute(-7));
+        cpu.load_program(Program::from_code(vec![PUSH, i64::MIN, PUSH, 1, SUB, HALT]).unwrap());
         cpu.run().unwrap();
-        let val = cpu.pop_stack().unwrap();
-        assert_eq!(0, val);
+        assert_eq!(vec![-7], cpu.stack);
     }
 
     #[test]
-    fn and() {
-        let program = vec![PUSH, 1, PUSH, 2, AND, HALT];
-        let mut cpu = Cpu::new();
-        cpu.load_program(program);
-        cpu.run().unwrap();
-        let val = cpu.pop_stack().unwrap();
-        assert_eq!(1, val);
+    fn overflow_wraps() {
+        let run = |code| run_with_overflow(Overflow::Wrap, code).unwrap();
+        assert_eq!(i64::MIN, run(vec![PUSH, i64::MAX, PUSH, 1, ADD, HALT]));
+        assert_eq!(i64::MAX, run(vec![PUSH, i64::MIN, PUSH, 1, SUB, HALT]));
+        assert_eq!(-2, run(vec![PUSH, i64::MAX, PUSH, 2, MUL, HALT]));
+        assert_eq!(i64::MIN, run(vec![PUSH, i64::MIN, PUSH, -1, DIV, HALT]));
     }
 
     #[test]
-    fn or() {
-        let program = vec![PUSH, 1, PUSH, 0, OR, HALT];
-        let mut cpu = Cpu::new();
-        cpu.load_program(program);
-        cpu.run().unwrap();
-        let val = cpu.pop_stack().unwrap();
-        assert_eq!(1, val);
+    fn overflow_saturates() {
+        let run = |code| run_with_overflow(Overflow::Saturate, code).unwrap();
+        assert_eq!(i64::MAX, run(vec![PUSH, i64::MAX, PUSH, 1, ADD, HALT]));
+        assert_eq!(i64::MIN, run(vec![PUSH, i64::MIN, PUSH, 1, SUB, HALT]));
+        assert_eq!(i64::MIN, run(vec![PUSH, i64::MAX, PUSH, -2, MUL, HALT]));
+        assert_eq!(i64::MAX, run(vec![PUSH, i64::MIN, PUSH, -1, DIV, HALT]));
     }
 
     #[test]
-    fn dup() {
-        let program = vec![PUSH, 1, DUP, ADD, HALT];
-        let mut cpu = Cpu::new();
-        cpu.load_program(program);
-        cpu.run().unwrap();
-        let val = cpu.pop_stack().unwrap();
-        assert_eq!(2, val);
+    fn fixed_point() {
+        let one = 1 << FIXED_POINT_BITS;
+        let half = one / 2;
+        let run = |code| run_with_overflow(Overflow::Trap, code).unwrap();
+        // 1.5 * -2.5 = -3.75
+        assert_eq!(
+            -3 * one - 3 * half / 2,
+            run(vec![PUSH, one + half, PUSH, -2 * one - half, FXMUL, HALT])
+        );
+        // 3 / 0.5 = 6
+        assert_eq!(6 * one, run(vec![PUSH, 3 * one, PUSH, half, FXDIV, HALT]));
+        // values this big would overflow a plain MUL before the shift.
+        assert_eq!(
+            1_000_000 * one,
+            run(vec![PUSH, 1000 * one, PUSH, 1000 * one, FXMUL, HALT])
+        );
+        assert!(run_with_overflow(Overflow::Trap, vec![PUSH, one, PUSH, 0, FXDIV, HALT]).is_err());
+        assert!(run_with_overflow(
+            Overflow::Trap,
+            vec![PUSH, i64::MAX, PUSH, 2 * one, FXMUL, HALT]
+        )
+        .is_err());
     }
 
     #[test]
-    fn is_eq() {
-        let program = vec![PUSH, 1, PUSH, 1, ISEQ, HALT];
-        let mut cpu = Cpu::new();
-        cpu.load_program(program);
-        cpu.run().unwrap();
-        let val = cpu.pop_stack().unwrap();
-        assert_eq!(1, val);
+    fn core_dump_after_a_failure() {
+        let mut cpu = Cpu::builder().trace_length(2).build();
+        cpu.load_program(
+            Program::from_code(vec![PUSH, 5, STORE, 0, PUSH, 1, PUSH, 0, DIV, HALT]).unwrap(),
+        );
+        let err = cpu.run().unwrap_err();
+        let dump = cpu.core_dump(&err);
+        assert!(dump.error.contains("Division by zero"));
+        assert_eq!(8, dump.address);
+        assert_eq!(vec![6, 8], dump.trace);
+        assert_eq!(1, dump.frames.len());
+        assert_eq!(Some(&5), dump.frames[0].variables.get(&0));
+        assert_eq!(*cpu.program, dump.program);
     }
 
     #[test]
-    fn is_gt() {
-        let program = vec![PUSH, 2, PUSH, 1, ISGT, HALT];
+    fn invalid_jump_targets() {
+        for program in [vec![JMP, -1], vec![PUSH, 1, JIF, 99], vec![CALL, 2]] {
+            let mut cpu = Cpu::new();
+            cpu.load_program(Program::from_code(program.clone()).unwrap());
+            let err = cpu.run().unwrap_err();
+            let invalid = err.downcast_ref::<InvalidJumpTarget>().unwrap();
+            assert_eq!(program[program.len() - 1], invalid.to);
+            assert_eq!(program.len() - 2, invalid.from);
+        }
+    }
+
+    #[test]
+    fn trap_handler_substitutes() {
         let mut cpu = Cpu::new();
-        cpu.load_program(program);
+        cpu.set_trap_handler(|trap, _| match trap {
+            Trap::DivisionByZero => TrapAction::Substitute(i64::MAX),
+            _ => TrapAction::Abort,
+        });
+        cpu.load_program(Program::from_code(vec![PUSH, 1, PUSH, 0, DIV, HALT]).unwrap());
         cpu.run().unwrap();
-        let val = cpu.pop_stack().unwrap();
-        assert_eq!(1, val);
+        assert_eq!(i64::MAX, cpu.pop_stack().unwrap());
     }
 
     #[test]
-    fn is_gte() {
-        let program = vec![PUSH, 2, PUSH, 1, ISGE, HALT];
+    fn trap_handler_skips() {
         let mut cpu = Cpu::new();
-        cpu.load_program(program);
+        cpu.set_trap_handler(|_, _| TrapAction::Skip);
+        // the ADD underflows and is skipped, the bad JMP is ignored.
+        cpu.load_program(Program::from_code(vec![ADD, JMP, -4, PUSH, 3, HALT]).unwrap());
         cpu.run().unwrap();
-        let val = cpu.pop_stack().unwrap();
-        assert_eq!(1, val);
+        assert_eq!(vec![3], cpu.stack);
 
-        let program = vec![PUSH, 1, PUSH, 1, ISGE, HALT];
+        // what a skipped instruction popped goes back.
         let mut cpu = Cpu::new();
-        cpu.load_program(program);
+        cpu.set_trap_handler(|_, _| TrapAction::Skip);
+        let program = vec![PUSH, 5, ADD, PUSH, i64::MAX, PUSH, 1, ADD, HALT];
+        cpu.load_program(Program::from_code(program).unwrap());
         cpu.run().unwrap();
-        let val = cpu.pop_stack().unwrap();
-        assert_eq!(1, val);
-    }
+        assert_eq!(vec![5, i64::MAX, 1], cpu.stack);
 
-    #[test]
-    fn jmp() {
-        let program = vec![JMP, 5, PUSH, 420, HALT, JMP, 2];
+        // and the host popping an empty stack isn't the program's fault.
         let mut cpu = Cpu::new();
-        cpu.load_program(program);
-        cpu.run().unwrap();
+        cpu.set_trap_handler(|_, _| panic!("trap handler called"));
+        let err = cpu.get_latest_return_value().unwrap_err();
+        assert_eq!("Tried to pop empty stack", err.to_string());
     }
 
     #[test]
-    fn jif() {
-        let program = vec![PUSH, 1, JIF, 5, POP, PUSH, 0, JIF, 4, PUSH, 420, HALT];
+    fn trap_handler_redirects_jumps() {
         let mut cpu = Cpu::new();
-        cpu.load_program(program);
+        cpu.set_trap_handler(|trap, address| {
+            assert_eq!((Trap::InvalidJump { target: 100 }, 0), (trap, address));
+            TrapAction::Substitute(4)
+        });
+        cpu.load_program(Program::from_code(vec![JMP, 100, PUSH, 1, HALT]).unwrap());
         cpu.run().unwrap();
-        let val = cpu.pop_stack().unwrap();
-        assert_eq!(420, val)
+        assert!(cpu.stack.is_empty());
     }
 
     #[test]
-    fn load() {
-        let program = vec![LOAD, 0, HALT];
+    fn trap_handler_substitutes_one_bad_jump() {
         let mut cpu = Cpu::new();
-        cpu.load_program(program);
-        cpu.run().unwrap();
-        let val = cpu.pop_stack().unwrap();
-        assert_eq!(0, val)
+        cpu.set_trap_handler(|_, _| TrapAction::Substitute(-1));
+        cpu.load_program(Program::from_code(vec![PUSH, 1, JMP, 100, HALT]).unwrap());
+        let err = cpu.run().unwrap_err();
+        let invalid = err.downcast_ref::<InvalidJumpTarget>().unwrap();
+        assert_eq!((2, -1), (invalid.from, invalid.to));
     }
 
     #[test]
-    fn store() {
-        let program = vec![PUSH, 42, STORE, 0, HALT];
+    fn trap_handler_aborts() {
         let mut cpu = Cpu::new();
-        cpu.load_program(program);
-        cpu.run().unwrap();
-        let val = cpu.get_current_frame().get(0);
-        assert_eq!(42, val)
+        cpu.set_trap_handler(|_, _| TrapAction::Abort);
+        cpu.load_program(Program::from_code(vec![POP, HALT]).unwrap());
+        let err = cpu.run().unwrap_err();
+        assert!(format!("{err:#}").contains("Tried to pop empty stack at address 0"));
     }
 
     #[test]
-    fn load_and_store() {
-        let program = vec![PUSH, 42, STORE, 0, LOAD, 0, HALT];
+    fn profiles_functions() {
+        let program = Program::from_code(vec![CALL, 3, HALT, CALL, 6, RET, PUSH, 1, RET]).unwrap();
         let mut cpu = Cpu::new();
-        cpu.load_program(program);
+        cpu.load_program(program.clone());
+        cpu.enable_profiling();
         cpu.run().unwrap();
-        let val = cpu.pop_stack().unwrap();
-        assert_eq!(42, val)
+
+        let profiler = cpu.profiler().unwrap();
+        let report = profiler.report(&program);
+        let counts: Vec<(usize, u64, u64)> = report
+            .iter()
+            .map(|p| (p.address, p.self_instructions, p.total_instructions))
+            .collect();
+        assert_eq!(vec![(0, 2, 6), (3, 2, 4), (6, 2, 2)], counts);
+        assert_eq!(
+            "<entry> 2\n<entry>;<3> 2\n<entry>;<3>;<6> 2\n",
+            profiler.folded_stacks(&program)
+        );
     }
 
     #[test]
-    fn bigger_program() {
-        // we're just really checking if the program halts.
-        let program = vec![
-            // Init a with "6"
-            PUSH, 6, STORE, 0, // Init b with "4"
-            PUSH, 4, STORE, 1, // Load a and b into the stack
-            LOAD, 0, // Stack contains a
-            LOAD, 1,    // Stack contains a, b
-            ISGT, // Stack contains a > b
-            JIF, 21, // This is the "else" path
-            LOAD, 1, // Stack contains b
-            STORE, 2, // Set c to the stack head, meaning c = b
-            JMP, 25, // This is the "if" path, and this is the address 21
-            LOAD, 0, // Stack contains a
-            STORE, 2, // Set c to the stack head, meaning c = a
-            // Done; this is address 25
-            HALT,
-        ];
-
+    fn cancelling() {
         let mut cpu = Cpu::new();
-        cpu.load_program(program);
-        cpu.run().unwrap();
-        let val = cpu.get_current_frame().get(2);
-        assert_eq!(val, 6);
+        cpu.load_program(Program::from_code(vec![JMP, 0]).unwrap());
+        let handle = cpu.cancel_handle();
+        // loops forever until it's told to stop.
+        let running = std::thread::spawn(move || (cpu.run(), cpu));
+        handle.cancel();
+        let (result, mut cpu) = running.join().unwrap();
+        assert_eq!(
+            Some(&Cancelled { address: 0 }),
+            result.unwrap_err().downcast_ref()
+        );
+
+        // it stays stopped until it's reset.
+        let steps = cpu.steps();
+        assert_eq!(Outcome::Cancelled, cpu.run_to_break().unwrap());
+        handle.reset();
+        assert_eq!(Outcome::Yielded, cpu.run_slice(10).unwrap());
+        assert_eq!(steps + 10, cpu.steps());
     }
 
     #[test]
-    fn add_multiply_without_multiplying() {
-        let program = vec![
-            // Init a with "6"
-            PUSH, 6, STORE, 0, // Init b with "4"
-            PUSH, 4, STORE, 1, // Init total to 0
-            PUSH, 0, STORE, 2, // While part
-            // Here is address 12
-            LOAD, 1, // Stack contains b
-            PUSH, 1,    // Stack contains b, 1
-            ISGE, // Stack contains b >= 1
-            NOT,  // Stack contains b < 1
-            JIF, 36, // 36 is the address of the HALT label
-            // Inner loop part
-            LOAD, 0, // Stack contains a
-            LOAD, 2,   // Stack contains a, total
-            ADD, // Stack contains a + total
-            STORE, 2, // Save in total, meaning total = a + total
-            LOAD, 1, // Stack contains b
-            PUSH, 1,   // Stack contains b, 1
-            SUB, // Stack contains b - 1
-            STORE, 1, // Save in b, meaning b = b - 1
-            JMP, 12, // Go back to the start of the loop
-            HALT,
-        ];
+    fn profiles_allocations() {
+        let program = Program::from_code(vec![
+            MNEW, CALL, 4, HALT, // main
+            MNEW, DUP, PUSH, 1, PUSH, 2, MSET, RET, // a map with an entry
+        ])
+        .unwrap();
         let mut cpu = Cpu::new();
-        cpu.load_program(program);
+        cpu.load_program(program.clone());
+        cpu.enable_profiling();
         cpu.run().unwrap();
-        let val = cpu.get_current_frame().get(2);
-        assert_eq!(24, val);
+
+        let report = cpu.profiler().unwrap().report(&program);
+        let allocations: Vec<(usize, u64, u64)> = report
+            .iter()
+            .map(|p| (p.address, p.allocations, p.allocated_words))
+            .collect();
+        assert_eq!(vec![(4, 2, 4), (0, 1, 2)], allocations);
     }
 
     #[test]
-    fn funcall_no_args_return() {
-        let program = vec![CALL, 3, HALT, RET];
+    fn closure_captures_values() {
+        let program = vec![PUSH, 7, PUSH, 5, PUSH, 6, MKCLOS, 2, HALT];
         let mut cpu = Cpu::new();
-        cpu.load_program(program);
+        cpu.load_program(Program::from_code(program).unwrap());
         cpu.run().unwrap();
-        assert!(cpu.stack.is_empty());
+        let closure = cpu.pop_stack().unwrap();
+        assert_eq!((7, vec![5, 6]), cpu.get_closure(closure).unwrap());
     }
 
     #[test]
-    fn funcall_returns_no_arguments_int_return() {
-        let program = vec![CALL, 3, HALT, PUSH, 7, RET];
-        let mut cpu = Cpu::new();
-        cpu.load_program(program);
-        cpu.run().unwrap();
-        let val = cpu.pop_stack().unwrap();
-        assert_eq!(7, val)
+    fn corrupt_closure_is_an_error() {
+        // a capture count past the end of the heap, or below zero.
+        for count in [99, -1, i64::MAX] {
+            let program = vec![
+                PUSH, 0, MKCLOS, 0, DUP, PUSH, 2, ADD, PUSH, count, HSTORE, CALLCLOS, HALT,
+            ];
+            let mut cpu = Cpu::new();
+            cpu.load_program(Program::from_code(program).unwrap());
+            let err = format!("{:#}", cpu.run().unwrap_err());
+            assert!(err.contains("Value 0 is not a closure"), "{err}");
+        }
     }
 
     #[test]
-    fn doubles_given_argument() {
-        let program = vec![PUSH, 3, CALL, 5, HALT, PUSH, 2, MUL, RET];
+    fn call_closure() {
+        let program = vec![
+            PUSH, 10, // The argument
+            PUSH, 10, // Function address of "add_captured"
+            PUSH, 5, // Captured value, lands in slot 0
+            MKCLOS, 1, // Stack contains 10, closure
+            CALLCLOS, HALT, // Here is address 10, the start of "add_captured"
+            LOAD, 0, // Captured value
+            ADD, RET,
+        ];
         let mut cpu = Cpu::new();
-        cpu.load_program(program);
+        cpu.load_program(Program::from_code(program).unwrap());
         cpu.run().unwrap();
         let val = cpu.pop_stack().unwrap();
-        assert_eq!(6, val)
+        assert_eq!(15, val)
     }
 
     #[test]
-    fn maximum() {
-        let program = vec![
-            PUSH, 6, // Push the first argument
-            PUSH, 4, // Push the second argument
-            CALL, 7,    // Call "max"
-            HALT, // Here is address 7, the start of "max" function
-            STORE, 1, // Store b in local variable 1; the stack now contains [a]
-            STORE, 0, // Store a in local variable 0; the stack is now empty
-            LOAD, 0, // The stack now contains [a]
-            LOAD, 1,    // The stack now contains [a, b]
-            ISGE, // The stack now contains [a > b]
-            JIF, 21, // If the top of the stack is true (a > b), jump to the "if" path
-            LOAD, 1,   // "else" path: load b on the stack
-            RET, // Here is address 23
-            LOAD, 0, // "if" path: load a on the stack
-            RET,
-        ];
+    fn call_non_closure() {
+        let program = vec![PUSH, 0, CALLCLOS, HALT];
         let mut cpu = Cpu::new();
-        cpu.load_program(program);
-        cpu.run().unwrap();
-        let val = cpu.pop_stack().unwrap();
-        assert_eq!(6, val)
+        cpu.load_program(Program::from_code(program).unwrap());
+        assert!(cpu.run().is_err());
     }
 }
@@ -0,0 +1,221 @@
+// drives a loaded Cpu a command at a time, typed in or read from a script
+// so a session can be replayed as a test:
+//
+//     break :loop if slot 0 == 3   stop before an address or label, see breakpoint.rs
+//     delete :loop                 drop the breakpoint there
+//     continue                     run to the next breakpoint or the end
+//     step 5                       run that many instructions, 1 if it's left out
+//     print peek(0) * 2            show an expression's value, see watch.rs
+//     assert depth() == 1          fail unless the expression isn't 0
+//
+// blank lines and lines starting with `#` are skipped.
+
+use std::io::{BufRead, Write};
+
+use anyhow::{bail, Context, Result};
+
+use crate::breakpoint::Condition;
+use crate::cpu::{Cpu, Outcome};
+use crate::watch::Watch;
+
+pub struct Debugger {
+    cpu: Cpu,
+}
+
+impl Debugger {
+    // `cpu` should have its program loaded already.
+    pub fn new(cpu: Cpu) -> Self {
+        Self { cpu }
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    // carry out one command and say what happened, if anything.
+    pub fn execute(&mut self, command: &str) -> Result<Option<String>> {
+        let command = command.trim();
+        let (word, rest) = command.split_once(' ').unwrap_or((command, ""));
+        let rest = rest.trim();
+        match word {
+            "" => Ok(None),
+            _ if word.starts_with('#') => Ok(None),
+            "break" => {
+                let (location, condition) = match rest.split_once(" if ") {
+                    Some((location, condition)) => {
+                        (location, Some(condition.parse::<Condition>()?))
+                    }
+                    None => (rest, None),
+                };
+                let address = self.address(location)?;
+                self.cpu.add_breakpoint(address, condition);
+                Ok(Some(format!("breakpoint at {address}")))
+            }
+            "delete" => {
+                let address = self.address(rest)?;
+                if !self.cpu.remove_breakpoint(address) {
+                    bail!("No breakpoint at {address}")
+                }
+                Ok(None)
+            }
+            "continue" => {
+                let outcome = self.cpu.run_to_break()?;
+                Ok(Some(self.describe(outcome)))
+            }
+            "step" => {
+                let steps = match rest {
+                    "" => 1,
+                    steps => steps
+                        .parse()
+                        .with_context(|| format!("{steps:?} isn't a number of steps"))?,
+                };
+                let outcome = self.cpu.run_slice(steps)?;
+                Ok(Some(self.describe(outcome)))
+            }
+            "print" => {
+                let value = rest.parse::<Watch>()?.evaluate(&self.cpu)?;
+                Ok(Some(format!("{rest} = {value}")))
+            }
+            "assert" => {
+                let watch = rest.parse::<Watch>()?;
+                if watch.evaluate(&self.cpu)? == 0 {
+                    bail!("Assertion {watch} failed")
+                }
+                Ok(None)
+            }
+            other => bail!(
+                "Unknown command {other:?}, expected break, delete, continue, step, print or assert"
+            ),
+        }
+    }
+
+    // every line of `script` in turn, giving up at the first that fails.
+    pub fn run_script(&mut self, script: &str, mut output: impl Write) -> Result<()> {
+        for (number, line) in script.lines().enumerate() {
+            let said = self
+                .execute(line)
+                .with_context(|| format!("Line {}: {}", number + 1, line.trim()))?;
+            if let Some(said) = said {
+                writeln!(output, "{said}")?;
+            }
+        }
+        Ok(())
+    }
+
+    // read commands until the input runs out or says `quit`. A command that
+    // fails is reported and the session carries on.
+    pub fn run(&mut self, input: impl BufRead, mut output: impl Write) -> Result<()> {
+        write!(output, "(debug) ")?;
+        output.flush()?;
+        for line in input.lines() {
+            let line = line.context("Could not read input")?;
+            match line.trim() {
+                "quit" | "exit" => break,
+                line => match self.execute(line) {
+                    Ok(Some(said)) => writeln!(output, "{said}")?,
+                    Ok(None) => {}
+                    Err(err) => writeln!(output, "error: {err:#}")?,
+                },
+            }
+            write!(output, "(debug) ")?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    // a label from the program's symbols, or a plain address.
+    fn address(&self, location: &str) -> Result<usize> {
+        if location.starts_with(':') {
+            return match self
+                .cpu
+                .program()
+                .symbols()
+                .iter()
+                .find(|symbol| symbol.name == location)
+            {
+                Some(symbol) => Ok(symbol.address as usize),
+                None => bail!("No label {location}"),
+            };
+        }
+        location
+            .parse()
+            .with_context(|| format!("Expected a label or an address, got {location:?}"))
+    }
+
+    fn describe(&self, outcome: Outcome) -> String {
+        let address = self.cpu.ip();
+        let label = match self.cpu.program().symbol_at(address as i64) {
+            Some(symbol) => format!(" ({})", symbol.name),
+            None => String::new(),
+        };
+        match outcome {
+            Outcome::Halted => "halted".to_string(),
+            Outcome::Breakpoint(address) => format!("stopped at {address}{label}"),
+            Outcome::Yielded => format!("at {address}{label}"),
+            Outcome::Cancelled => format!("cancelled at {address}{label}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::{parse_program, AssemblerOptions};
+
+    fn debugger() -> Debugger {
+        // counts slot 0 down from 3.
+        let source = "push 3\nstore 0\n:loop\nload 0\npush 1\nsub\ndup\nstore 0\njif :loop\nhalt";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(program);
+        Debugger::new(cpu)
+    }
+
+    #[test]
+    fn script() {
+        let script = "# second time round\n\
+                      break :loop if hits == 2\n\
+                      continue\n\
+                      assert slot(0) == 2\n\
+                      print slot(0) * 10\n\
+                      delete :loop\n\
+                      step 2\n\
+                      print peek(0)\n\
+                      continue\n\
+                      assert depth() == 0";
+        let mut output = vec![];
+        debugger().run_script(script, &mut output).unwrap();
+        assert_eq!(
+            "breakpoint at 4\nstopped at 4 (:loop)\nslot(0) * 10 = 20\nat 8\npeek(0) = 1\nhalted\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn script_failures() {
+        let error = |script: &str| {
+            let err = debugger().run_script(script, std::io::sink()).unwrap_err();
+            format!("{err:#}")
+        };
+        assert_eq!(
+            "Line 2: assert slot(0) == 1: Assertion slot(0) == 1 failed",
+            error("continue\nassert slot(0) == 1")
+        );
+        assert_eq!(
+            "Line 1: break :nowhere: No label :nowhere",
+            error("break :nowhere")
+        );
+        assert!(error("\nfrobnicate").starts_with("Line 2: frobnicate: Unknown command"));
+    }
+
+    #[test]
+    fn session() {
+        let mut output = vec![];
+        let input = "step\nbogus\nprint ip()\nquit\nstep\n";
+        debugger().run(input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("(debug) at 2\n"));
+        assert!(output.contains("error: Unknown command \"bogus\""));
+        assert!(output.ends_with("ip() = 2\n(debug) "));
+    }
+}
@@ -0,0 +1,298 @@
+// turns a stream of words back into instructions.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{self, Write};
+
+use anyhow::{bail, Result};
+
+use crate::cpu::{describe_instruction, has_code_operand, Opcode};
+use crate::program::Program;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub address: usize,
+    pub opcode: Opcode,
+    // LOOP's variable, the one instruction with an operand before `operand`.
+    pub slot: Option<i64>,
+    pub operand: Option<i64>,
+}
+
+impl Instruction {
+    pub fn mnemonic(&self) -> &'static str {
+        self.opcode.name()
+    }
+
+    pub fn width(&self) -> usize {
+        1 + self.slot.iter().count() + self.operand.iter().count()
+    }
+
+    pub fn next_address(&self) -> usize {
+        self.address + self.width()
+    }
+
+    // written with `label` in place of the address it goes to.
+    pub fn with_target(&self, label: &str) -> String {
+        match self.slot {
+            Some(slot) => format!("{} {slot} {label}", self.mnemonic()),
+            None => format!("{} {label}", self.mnemonic()),
+        }
+    }
+
+    // what it does in words, naming what it pops a, b and c, deepest first.
+    pub fn summary(&self) -> String {
+        let operands: Vec<String> = self
+            .slot
+            .iter()
+            .chain(&self.operand)
+            .map(i64::to_string)
+            .collect();
+        let names = ["a", "b", "c"].map(String::from);
+        describe_instruction(self.opcode, &operands, &names)
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.mnemonic())?;
+        for operand in self.slot.iter().chain(&self.operand) {
+            write!(f, " {operand}")?;
+        }
+        Ok(())
+    }
+}
+
+pub fn decode(code: &[i64]) -> Result<Vec<Instruction>> {
+    let mut instructions = vec![];
+    let mut address = 0;
+    while address < code.len() {
+        let instruction = decode_at(code, address)?;
+        address = instruction.next_address();
+        instructions.push(instruction);
+    }
+    Ok(instructions)
+}
+
+pub fn decode_at(code: &[i64], address: usize) -> Result<Instruction> {
+    let Some(opcode) = code.get(address).copied() else {
+        bail!("Address {address} is past the end of the program")
+    };
+    let Ok(opcode) = Opcode::try_from(opcode) else {
+        bail!("Unknown opcode {opcode} at address {address}")
+    };
+    let mnemonic = opcode.name();
+    let count = opcode.operand_count();
+    let Some(operands) = code.get(address + 1..address + 1 + count) else {
+        bail!("{mnemonic} at address {address} is missing its operand")
+    };
+    let (slot, operand) = match operands {
+        [] => (None, None),
+        [operand] => (None, Some(*operand)),
+        [slot, operand, ..] => (Some(*slot), Some(*operand)),
+    };
+    Ok(Instruction {
+        address,
+        opcode,
+        slot,
+        operand,
+    })
+}
+
+// assembly source that reassembles to the same code. Jump targets get a label
+// from the symbol table, or a made up `:L<address>` one if there isn't one.
+pub fn disassemble(program: &Program) -> Result<String> {
+    render(program, false)
+}
+
+// the same, with a comment on every instruction saying what it does.
+pub fn disassemble_explained(program: &Program) -> Result<String> {
+    render(program, true)
+}
+
+fn render(program: &Program, explain: bool) -> Result<String> {
+    let instructions = decode(program.code())?;
+    let boundaries: HashSet<i64> = instructions.iter().map(|i| i.address as i64).collect();
+
+    let mut labels: BTreeMap<i64, String> = BTreeMap::new();
+    for symbol in program.symbols().iter() {
+        labels
+            .entry(symbol.address)
+            .or_insert_with(|| symbol.name.clone());
+    }
+    for instruction in instructions.iter() {
+        if let (true, Some(target)) = (has_code_operand(instruction.opcode), instruction.operand) {
+            if boundaries.contains(&target) {
+                labels
+                    .entry(target)
+                    .or_insert_with(|| format!(":L{target}"));
+            }
+        }
+    }
+
+    let mut out = String::new();
+    // data directives don't take up code addresses, so all of it can go first
+    // as one block and keep its layout.
+    if !program.data().is_empty() {
+        let words: Vec<String> = program.data().iter().map(i64::to_string).collect();
+        let _ = writeln!(out, ".words :D0 {}", words.join(" "));
+    }
+    // record shapes get rebuilt along with the pool, so each one RNEW uses is
+    // declared again, with made up names if there's no debug info.
+    let mut records: BTreeMap<i64, String> = BTreeMap::new();
+    for instruction in instructions.iter() {
+        let (Opcode::Rnew, Some(shape)) = (instruction.opcode, instruction.operand) else {
+            continue;
+        };
+        if records.contains_key(&shape) {
+            continue;
+        }
+        let info = program
+            .debug_info()
+            .records
+            .iter()
+            .find(|record| record.shape == shape);
+        let (name, fields) = match info {
+            Some(record) => (record.name.clone(), record.fields.clone()),
+            None => {
+                let count = program.constants()[shape as usize];
+                (
+                    format!(":R{shape}"),
+                    (0..count).map(|f| format!("f{f}")).collect(),
+                )
+            }
+        };
+        let _ = writeln!(out, ".record {name} {}", fields.join(" "));
+        records.insert(shape, name);
+    }
+    // functions with an arity go back to being `.fn` blocks.
+    let mut in_function = false;
+    for instruction in instructions.iter() {
+        let address = instruction.address as i64;
+        if let Some(label) = labels.get(&address) {
+            let arity = program
+                .symbol_at(address)
+                .filter(|symbol| symbol.name == *label)
+                .and_then(|symbol| symbol.arity);
+            let _ = match arity {
+                Some(arity) => {
+                    if in_function {
+                        out.push_str(".endfn\n");
+                    }
+                    in_function = true;
+                    writeln!(out, ".fn {label} args={} rets={}", arity.args, arity.rets)
+                }
+                None => writeln!(out, "{label}"),
+            };
+        }
+        let line = match (instruction.opcode, instruction.operand) {
+            (opcode, Some(target)) if has_code_operand(opcode) && labels.contains_key(&target) => {
+                format!("    {}", instruction.with_target(&labels[&target]))
+            }
+            (Opcode::Rnew, Some(shape)) => format!("    rnew {}", records[&shape]),
+            // the pool gets rebuilt when this is reassembled.
+            (Opcode::Pushc, Some(index)) => match program.constants().get(index as usize) {
+                Some(value) => format!("    push {value}"),
+                None => format!("    {instruction}"),
+            },
+            _ => format!("    {instruction}"),
+        };
+        let _ = match explain {
+            true => writeln!(out, "{line:<28};; {}", instruction.summary()),
+            false => writeln!(out, "{line}"),
+        };
+    }
+    // labels pointing just past the last instruction.
+    if let Some(label) = labels.get(&(program.code().len() as i64)) {
+        let _ = writeln!(out, "{label}");
+    }
+    if in_function {
+        out.push_str(".endfn\n");
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::{parse_program, AssemblerOptions};
+    use crate::cpu::{ADD, HALT, ISGT, PUSH};
+
+    #[test]
+    fn decodes_operands() {
+        let instructions = decode(&[PUSH, 1, PUSH, 2, ADD, HALT]).unwrap();
+        let rendered: Vec<String> = instructions.iter().map(|i| i.to_string()).collect();
+        assert_eq!(vec!["push 1", "push 2", "add", "halt"], rendered);
+        assert_eq!(4, instructions[2].address);
+    }
+
+    #[test]
+    fn missing_operand() {
+        assert!(decode(&[PUSH]).is_err());
+    }
+
+    #[test]
+    fn disassembly_reassembles() {
+        let source = "push 9999999999\ncall :max\nhalt\n:max\njif :yes\njmp 10\n:yes\npop\nret";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        let text = disassemble(&program).unwrap();
+        assert!(text.contains("    call :max\n"));
+        assert!(text.contains("    jmp :L10\n"));
+
+        let reassembled = parse_program(text, &AssemblerOptions::default()).unwrap();
+        assert_eq!(program.code(), reassembled.code());
+        assert_eq!(program.constants(), reassembled.constants());
+    }
+
+    #[test]
+    fn data_reassembles() {
+        let source = ".string :s \"ok\"\npush :s\ndload\nhalt";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        let text = disassemble(&program).unwrap();
+        assert!(text.starts_with(".words :D0 111 107\n"));
+        let reassembled = parse_program(text, &AssemblerOptions::default()).unwrap();
+        assert_eq!(program.data(), reassembled.data());
+    }
+
+    #[test]
+    fn records_reassemble() {
+        let source = ".record :point x y\nrnew :point\nrget :point.y\nhalt";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        let text = disassemble(&program).unwrap();
+        assert!(text.starts_with(".record :point x y\n"));
+        assert!(text.contains("    rnew :point\n"));
+        let reassembled = parse_program(text, &AssemblerOptions::default()).unwrap();
+        assert_eq!(program, reassembled);
+    }
+
+    #[test]
+    fn explained() {
+        let program = Program::from_code(vec![PUSH, 6, PUSH, 4, ISGT, HALT]).unwrap();
+        let text = disassemble_explained(&program).unwrap();
+        assert!(text.contains("    push 6                  ;; push 6\n"));
+        assert!(text.contains("    isgt                    ;; a > b\n"));
+        // the comments don't get in the way of reassembling.
+        let reassembled = parse_program(text, &AssemblerOptions::default()).unwrap();
+        assert_eq!(program.code(), reassembled.code());
+    }
+
+    #[test]
+    fn loop_reassembles() {
+        let source = "push 3\nstore 0\n:top\nloop 0 :top\nhalt";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        let text = disassemble_explained(&program).unwrap();
+        assert!(text.contains(
+            "    loop 0 :top             ;; count variable 0 down and jump to 4 while it's above 0\n"
+        ));
+        let reassembled = parse_program(text, &AssemblerOptions::default()).unwrap();
+        assert_eq!(program.code(), reassembled.code());
+    }
+
+    #[test]
+    fn functions_reassemble() {
+        let source = "call :f\nhalt\n.fn :f args=1 rets=1\nret\n.endfn";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        let text = disassemble(&program).unwrap();
+        assert!(text.contains(".fn :f args=1 rets=1\n    ret\n.endfn\n"));
+        let reassembled = parse_program(text, &AssemblerOptions::default()).unwrap();
+        assert_eq!(program.symbols(), reassembled.symbols());
+    }
+}
@@ -0,0 +1,249 @@
+// compiles infix expressions like `1 + 2 * (x - 3)` or `a >= 0 && !done` into
+// a program that leaves the value on top of the stack. Each variable gets a
+// slot in the root frame, in the order they first show up.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use anyhow::{bail, Context, Result};
+
+use crate::cpu::{ADD, AND, DIV, HALT, ISEQ, ISGE, ISGT, LOAD, MUL, NOT, OR, PUSH, STORE, SUB};
+use crate::program::Program;
+
+#[derive(Debug)]
+pub struct Compiled {
+    pub program: Program,
+    // variable `n` is read from slot `n`.
+    pub variables: Vec<String>,
+}
+
+// bound variables are stored before the expression runs, the rest read
+// whatever is in their slot, 0 unless something stored there first.
+pub fn compile(source: &str, bindings: &BTreeMap<String, i64>) -> Result<Compiled> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+        code: vec![],
+        variables: vec![],
+    };
+    parser.expression()?;
+    if let Some((token, column)) = parser.tokens.get(parser.position) {
+        bail!("Unexpected {token} at column {column}")
+    }
+
+    let mut code = vec![];
+    for (slot, name) in parser.variables.iter().enumerate() {
+        if let Some(value) = bindings.get(name) {
+            code.extend([PUSH, *value, STORE, slot as i64]);
+        }
+    }
+    // jumps aren't used, so the expression's code can move.
+    code.extend(parser.code);
+    code.push(HALT);
+    Ok(Compiled {
+        program: Program::from_code(code)?,
+        variables: parser.variables,
+    })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Token {
+    Number(i64),
+    Name(String),
+    // operators and parentheses.
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Number(number) => write!(f, "{number}"),
+            Self::Name(name) => write!(f, "{name}"),
+            Self::Symbol(symbol) => write!(f, "{symbol}"),
+        }
+    }
+}
+
+// longest first, so `<=` isn't read as `<` then `=`.
+const SYMBOLS: &[&str] = &[
+    "&&", "||", "==", "!=", "<=", ">=", "<", ">", "+", "-", "*", "/", "!", "(", ")",
+];
+
+// tokens and the 1-based column they start at.
+pub(crate) fn tokenize(source: &str) -> Result<Vec<(Token, usize)>> {
+    let mut tokens = vec![];
+    let mut rest = source;
+    while let Some(c) = rest.chars().next() {
+        let column = source.len() - rest.len() + 1;
+        if c.is_whitespace() {
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        let length = if c.is_ascii_digit() {
+            let length = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let number = rest[..length]
+                .parse()
+                .with_context(|| format!("Number at column {column} is too big"))?;
+            tokens.push((Token::Number(number), column));
+            length
+        } else if c.is_alphabetic() || c == '_' {
+            let length = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push((Token::Name(rest[..length].to_string()), column));
+            length
+        } else if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            tokens.push((Token::Symbol(symbol), column));
+            symbol.len()
+        } else {
+            bail!("Unexpected {c:?} at column {column}")
+        };
+        rest = &rest[length..];
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    code: Vec<i64>,
+    variables: Vec<String>,
+}
+
+// binary operators from loosest to tightest binding, and the code each
+// compiles to once both sides are on the stack.
+const LEVELS: &[&[(&str, &[i64])]] = &[
+    &[("||", &[OR])],
+    &[("&&", &[AND])],
+    &[("==", &[ISEQ]), ("!=", &[ISEQ, NOT])],
+    &[
+        (">", &[ISGT]),
+        (">=", &[ISGE]),
+        // the same comparisons, with the operands the other way round.
+        ("<", &[ISGE, NOT]),
+        ("<=", &[ISGT, NOT]),
+    ],
+    &[("+", &[ADD]), ("-", &[SUB])],
+    &[("*", &[MUL]), ("/", &[DIV])],
+];
+
+impl Parser {
+    fn expression(&mut self) -> Result<()> {
+        self.binary(0)
+    }
+
+    // left associative, so `8 - 2 - 1` is `(8 - 2) - 1`.
+    fn binary(&mut self, level: usize) -> Result<()> {
+        let Some(operators) = LEVELS.get(level) else {
+            return self.unary();
+        };
+        self.binary(level + 1)?;
+        while let Some((_, code)) = operators
+            .iter()
+            .find(|(symbol, _)| self.peek() == Some(&Token::Symbol(symbol)))
+        {
+            self.position += 1;
+            self.binary(level + 1)?;
+            self.code.extend_from_slice(code);
+        }
+        Ok(())
+    }
+
+    fn unary(&mut self) -> Result<()> {
+        match self.peek() {
+            Some(Token::Symbol("-")) => {
+                self.position += 1;
+                self.code.extend([PUSH, 0]);
+                self.unary()?;
+                self.code.push(SUB);
+            }
+            Some(Token::Symbol("!")) => {
+                self.position += 1;
+                self.unary()?;
+                self.code.push(NOT);
+            }
+            _ => self.primary()?,
+        }
+        Ok(())
+    }
+
+    fn primary(&mut self) -> Result<()> {
+        let Some((token, column)) = self.tokens.get(self.position).cloned() else {
+            bail!("Expression ended early")
+        };
+        self.position += 1;
+        match token {
+            Token::Number(number) => self.code.extend([PUSH, number]),
+            Token::Name(name) if name == "true" => self.code.extend([PUSH, 1]),
+            Token::Name(name) if name == "false" => self.code.extend([PUSH, 0]),
+            Token::Name(name) => {
+                let slot = match self.variables.iter().position(|seen| *seen == name) {
+                    Some(slot) => slot,
+                    None => {
+                        self.variables.push(name);
+                        self.variables.len() - 1
+                    }
+                };
+                self.code.extend([LOAD, slot as i64]);
+            }
+            Token::Symbol("(") => {
+                self.expression()?;
+                if self.peek() != Some(&Token::Symbol(")")) {
+                    bail!("Missing ) for the ( at column {column}")
+                }
+                self.position += 1;
+            }
+            token => bail!("Unexpected {token} at column {column}"),
+        }
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    fn evaluate(source: &str, bindings: &[(&str, i64)]) -> i64 {
+        let bindings = bindings
+            .iter()
+            .map(|(name, value)| (name.to_string(), *value))
+            .collect();
+        let compiled = compile(source, &bindings).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(compiled.program);
+        cpu.run().unwrap().exit_value.unwrap()
+    }
+
+    #[test]
+    fn arithmetic_and_precedence() {
+        assert_eq!(-3, evaluate("1 + 2 * (x - 3)", &[("x", 1)]));
+        assert_eq!(5, evaluate("8 - 2 - 1", &[]));
+        assert_eq!(-7, evaluate("-(3 + 4)", &[]));
+        assert_eq!(3, evaluate("7 / 2", &[]));
+    }
+
+    #[test]
+    fn comparisons_and_logic() {
+        assert_eq!(1, evaluate("a < b && b <= 3", &[("a", 1), ("b", 3)]));
+        assert_eq!(0, evaluate("a > b || !(a != 1)", &[("a", 2), ("b", 3)]));
+        assert_eq!(1, evaluate("2 >= 2 == true", &[]));
+    }
+
+    #[test]
+    fn variables_and_errors() {
+        let compiled = compile("y * x + y", &BTreeMap::new()).unwrap();
+        assert_eq!(vec!["y", "x"], compiled.variables);
+        let error = |source| compile(source, &BTreeMap::new()).unwrap_err().to_string();
+        assert_eq!("Missing ) for the ( at column 1", error("(1 + 2"));
+        assert_eq!("Unexpected ) at column 3", error("1 ) 2"));
+        assert_eq!("Unexpected '$' at column 3", error("1 $ 2"));
+        assert_eq!("Expression ended early", error("1 +"));
+    }
+}
@@ -0,0 +1,338 @@
+// a Forth flavoured way to write programs, translated to assembly:
+//
+//     : double 2 * ;
+//     : countdown begin dup . 1 - dup 0= until drop ;
+//     21 double .  5 countdown cr
+//
+// Numbers are pushed, words run, and `: name ... ;` defines a word. Words
+// are case insensitive. There's `if ... else ... then` and
+// `begin ... until`, `\` comments out the rest of a line and `( ... )`
+// anything in between. True is 1 like everywhere else in the machine. The
+// stack shuffling words the vm has no instruction for go through variables
+// below 0, which nothing else uses.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use anyhow::{bail, Context, Result};
+
+use crate::assembler::{parse_program, AssemblerOptions};
+use crate::program::Program;
+
+// `.`, printing a number in decimal and then a space.
+const RUNTIME: &str = "
+    jmp :forth.start
+.fn :forth.dot
+    call :forth.digits
+    push 32
+    prnchr
+    ret
+.endfn
+.fn :forth.digits
+    store 0
+    load 0
+    push 0
+    isge
+    jif :.positive
+    push 45
+    prnchr
+    push 0
+    load 0
+    sub
+    store 0
+:.positive
+    load 0
+    push 10
+    div
+    dup
+    jif :.more
+    pop
+    jmp :.last
+:.more
+    call :forth.digits
+:.last
+    load 0
+    load 0
+    push 10
+    div
+    push 10
+    mul
+    sub
+    push 48
+    add
+    prnchr
+    ret
+.endfn
+:forth.start
+    nop
+";
+
+// words that are a fixed run of instructions.
+const PRIMITIVES: &[(&str, &[&str])] = &[
+    ("+", &["add"]),
+    ("-", &["sub"]),
+    ("*", &["mul"]),
+    ("/", &["div"]),
+    (
+        "mod",
+        &[
+            "store -1", "store -2", "load -2", "load -2", "load -1", "div", "load -1", "mul", "sub",
+        ],
+    ),
+    ("negate", &["store -1", "push 0", "load -1", "sub"]),
+    ("=", &["iseq"]),
+    (">", &["isgt"]),
+    (">=", &["isge"]),
+    ("<", &["isge", "not"]),
+    ("<=", &["isgt", "not"]),
+    ("0=", &["iszero"]),
+    ("and", &["and"]),
+    ("or", &["or"]),
+    ("invert", &["not"]),
+    ("dup", &["dup"]),
+    ("drop", &["pop"]),
+    ("swap", &["store -1", "store -2", "load -1", "load -2"]),
+    (
+        "over",
+        &["store -1", "store -2", "load -2", "load -1", "load -2"],
+    ),
+    (
+        "rot",
+        &[
+            "store -1", "store -2", "store -3", "load -2", "load -1", "load -3",
+        ],
+    ),
+    (".", &["call :forth.dot"]),
+    ("emit", &["prnchr"]),
+    ("cr", &["push 10", "prnchr"]),
+    (".s", &["prnstk"]),
+];
+
+// the source as assembly, ready for the assembler. It runs off the end of
+// the code when it's done.
+pub fn to_assembly(source: &str) -> Result<String> {
+    let mut compiler = Compiler {
+        out: RUNTIME.trim_start().to_string(),
+        ..Default::default()
+    };
+    for (index, line) in source.lines().enumerate() {
+        compiler
+            .line(line)
+            .with_context(|| format!("Line {}", index + 1))?;
+    }
+    if let Some((name, _, _)) = compiler.definition {
+        bail!("Definition of {name} has no ;")
+    }
+    if let Some(control) = compiler.control.last() {
+        bail!("{} is never closed", control.word())
+    }
+    Ok(compiler.out)
+}
+
+pub fn compile(source: &str, options: &AssemblerOptions) -> Result<Program> {
+    let mut assembly = to_assembly(source)?;
+    assembly.push_str("    halt\n");
+    parse_program(assembly, options).context("Generated assembly didn't assemble")
+}
+
+enum Control {
+    // the label to go to when the condition is false.
+    If(String),
+    // the label after `then`.
+    Else(String),
+    // the label at `begin`.
+    Begin(String),
+}
+
+impl Control {
+    fn word(&self) -> &'static str {
+        match self {
+            Self::If(_) => "if",
+            Self::Else(_) => "else",
+            Self::Begin(_) => "begin",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Compiler {
+    out: String,
+    // the function label for each word defined so far.
+    words: HashMap<String, String>,
+    // the word being defined, its label, and the label that jumps past its
+    // body.
+    definition: Option<(String, String, String)>,
+    control: Vec<Control>,
+    labels: usize,
+}
+
+impl Compiler {
+    fn emit(&mut self, line: &str) {
+        writeln!(self.out, "    {line}").unwrap();
+    }
+
+    fn place(&mut self, label: &str) {
+        writeln!(self.out, "{label}").unwrap();
+    }
+
+    // a label jumped forward to, which might end up at the end of the code
+    // and needs an instruction to point at. The REPL appends lines to the
+    // code, so it can't go at the very end.
+    fn land(&mut self, label: &str) {
+        self.place(label);
+        self.emit("nop");
+    }
+
+    fn label(&mut self) -> String {
+        self.labels += 1;
+        format!(":forth.l{}", self.labels)
+    }
+
+    fn line(&mut self, line: &str) -> Result<()> {
+        let line = match line.split_once('\\') {
+            Some((code, _)) if code.is_empty() || code.ends_with(char::is_whitespace) => code,
+            _ => line,
+        };
+        let mut words = line.split_whitespace();
+        while let Some(word) = words.next() {
+            let word = word.to_lowercase();
+            match word.as_str() {
+                "(" => {
+                    if !words.any(|word| word.ends_with(')')) {
+                        bail!("( without a closing )")
+                    }
+                }
+                ":" => {
+                    if self.definition.is_some() {
+                        bail!("Definitions can't be nested")
+                    }
+                    let Some(name) = words.next() else {
+                        bail!(": needs a name")
+                    };
+                    let skip = self.label();
+                    self.emit(&format!("jmp {skip}"));
+                    // names can hold anything, so labels are numbered instead.
+                    let label = self.label();
+                    writeln!(self.out, ".fn {label} ;; {name}").unwrap();
+                    // it's only in the dictionary once it's finished.
+                    self.definition = Some((name.to_lowercase(), label, skip));
+                }
+                ";" => {
+                    let Some((name, label, skip)) = self.definition.take() else {
+                        bail!("; outside a definition")
+                    };
+                    if let Some(control) = self.control.last() {
+                        bail!("{} in {name} is never closed", control.word())
+                    }
+                    self.emit("ret");
+                    writeln!(self.out, ".endfn").unwrap();
+                    self.land(&skip);
+                    self.words.insert(name, label);
+                }
+                "recurse" => {
+                    let Some((_, label, _)) = &self.definition else {
+                        bail!("recurse outside a definition")
+                    };
+                    let call = format!("call {label}");
+                    self.emit(&call);
+                }
+                "if" => {
+                    let otherwise = self.label();
+                    self.emit(&format!("jz {otherwise}"));
+                    self.control.push(Control::If(otherwise));
+                }
+                "else" => {
+                    let Some(Control::If(otherwise)) = self.control.pop() else {
+                        bail!("else without if")
+                    };
+                    let end = self.label();
+                    self.emit(&format!("jmp {end}"));
+                    self.place(&otherwise);
+                    self.control.push(Control::Else(end));
+                }
+                "then" => match self.control.pop() {
+                    Some(Control::If(label) | Control::Else(label)) => self.land(&label),
+                    _ => bail!("then without if"),
+                },
+                "begin" => {
+                    let top = self.label();
+                    self.place(&top);
+                    self.control.push(Control::Begin(top));
+                }
+                "until" => {
+                    let Some(Control::Begin(top)) = self.control.pop() else {
+                        bail!("until without begin")
+                    };
+                    self.emit(&format!("jz {top}"));
+                }
+                word => {
+                    if let Some(label) = self.words.get(word).cloned() {
+                        self.emit(&format!("call {label}"));
+                    } else if let Some((_, instructions)) =
+                        PRIMITIVES.iter().find(|(name, _)| *name == word)
+                    {
+                        for instruction in instructions.iter() {
+                            self.emit(instruction);
+                        }
+                    } else if let Ok(number) = word.parse::<i64>() {
+                        self.emit(&format!("push {number}"));
+                    } else {
+                        bail!("Unknown word {word}")
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::{Cpu, SharedOutput};
+
+    fn run(source: &str) -> (String, Vec<i64>) {
+        let program = compile(source, &AssemblerOptions::default()).unwrap();
+        let output = SharedOutput::default();
+        let mut cpu = Cpu::new();
+        cpu.set_output(output.clone());
+        cpu.load_program(program);
+        cpu.run().unwrap();
+        let printed = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        (printed, cpu.stack().to_vec())
+    }
+
+    #[test]
+    fn words_and_definitions() {
+        assert_eq!(
+            ("42 ".to_string(), vec![]),
+            run(": double 2 * ;\n21 double .")
+        );
+        assert_eq!(
+            ("-7 1 ".to_string(), vec![2, 3, 1]),
+            run("7 NEGATE . 10 3 mod . 1 2 3 rot")
+        );
+        let (printed, _) = run(": countdown begin dup . 1 - dup 0= until drop ;\n3 countdown cr");
+        assert_eq!("3 2 1 \n", printed);
+    }
+
+    #[test]
+    fn conditionals_and_recursion() {
+        let source = "
+            \\ n -- n!
+            : fact ( n ) dup 1 > if dup 1 - recurse * then ;
+            : sign dup 0 < if drop 45 else 0 > if 43 else 48 then then emit ;
+            5 fact .  -3 sign 0 sign 9 sign
+        ";
+        assert_eq!("120 -0+", run(source).0);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source| to_assembly(source).unwrap_err().to_string();
+        assert_eq!("Line 2", error("1\nfrob"));
+        assert_eq!("Definition of f has no ;", error(": f 1"));
+        assert_eq!("if is never closed", error("1 if 2"));
+        assert!(format!("{:#}", to_assembly("then").unwrap_err()).contains("then without if"));
+    }
+}
@@ -0,0 +1,201 @@
+// the .hexbc format: the same sections as the binary container, but one word
+// per line in hex so it can be diffed and patched by hand.
+//
+//   ;; anything after ;; is a comment
+//   .code
+//   0000000000000001 ;; push
+//   0000000000000006
+//   .constants
+//   7fffffffffffffff
+//   .data
+//   0000000000000068
+//   .symbols
+//   0000000000000007 :max args=2 rets=1
+//   .file max.bc
+//   .lines
+//   0000000000000000 3
+//   .records
+//   0000000000000000 :point x y
+//   .features
+//   closures
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use anyhow::{bail, Context, Result};
+
+use crate::disassembler;
+use crate::program::{Arity, Program, ProgramParts, RecordInfo, Symbol};
+
+pub const EXTENSION: &str = "hexbc";
+
+pub fn encode(program: &Program) -> String {
+    // label the opcode words so a reviewer can follow along. Code that doesn't
+    // decode just goes out bare.
+    let mut annotations = HashMap::new();
+    if let Ok(instructions) = disassembler::decode(program.code()) {
+        for instruction in instructions {
+            annotations.insert(instruction.address, instruction.to_string());
+        }
+    }
+
+    let mut out = String::new();
+    out.push_str(";; biteycode hex bytecode\n");
+    out.push_str(".code\n");
+    for (address, word) in program.code().iter().enumerate() {
+        match annotations.get(&address) {
+            Some(annotation) => {
+                let _ = writeln!(out, "{:016x} ;; {address}: {annotation}", word);
+            }
+            None => {
+                let _ = writeln!(out, "{:016x}", word);
+            }
+        }
+    }
+    out.push_str(".constants\n");
+    for (index, word) in program.constants().iter().enumerate() {
+        let _ = writeln!(out, "{:016x} ;; #{index} = {word}", word);
+    }
+    out.push_str(".data\n");
+    for word in program.data().iter() {
+        let _ = writeln!(out, "{:016x}", word);
+    }
+    out.push_str(".symbols\n");
+    for symbol in program.symbols().iter() {
+        let _ = match symbol.arity {
+            Some(arity) => writeln!(
+                out,
+                "{:016x} {} args={} rets={}",
+                symbol.address, symbol.name, arity.args, arity.rets
+            ),
+            None => writeln!(out, "{:016x} {}", symbol.address, symbol.name),
+        };
+    }
+    let debug_info = program.debug_info();
+    if let Some(file) = debug_info.file.as_ref() {
+        let _ = writeln!(out, ".file {file}");
+    }
+    out.push_str(".lines\n");
+    for (address, line) in debug_info.lines.iter() {
+        let _ = writeln!(out, "{:016x} {line}", address);
+    }
+    out.push_str(".records\n");
+    for record in debug_info.records.iter() {
+        let _ = writeln!(
+            out,
+            "{:016x} {} {}",
+            record.shape,
+            record.name,
+            record.fields.join(" ")
+        );
+    }
+    out.push_str(".features\n");
+    for feature in program.features().iter() {
+        let _ = writeln!(out, "{feature}");
+    }
+    out
+}
+
+pub fn decode(source: &str) -> Result<Program> {
+    let mut parts = ProgramParts::default();
+    let mut section = None;
+    for (number, line) in source.lines().enumerate() {
+        let line = match line.find(";;") {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        let mut words = line.split_whitespace();
+        let Some(first) = words.next() else {
+            continue;
+        };
+        if first == ".file" {
+            parts.debug_info.file = words.next().map(str::to_string);
+            continue;
+        }
+        if first.starts_with('.') {
+            section = Some(first);
+            continue;
+        }
+        if section == Some(".features") {
+            parts.features.push(first.to_string());
+            continue;
+        }
+
+        let word = u64::from_str_radix(first, 16)
+            .with_context(|| format!("Line {}: {first} is not a hex word", number + 1))?
+            as i64;
+        match section {
+            Some(".code") => parts.code.push(word),
+            Some(".constants") => parts.constants.push(word),
+            Some(".data") => parts.data.push(word),
+            Some(".symbols") => {
+                let Some(name) = words.next() else {
+                    bail!("Line {}: symbol is missing its name", number + 1)
+                };
+                let mut arity = None;
+                for attribute in words {
+                    let (key, value) = attribute
+                        .split_once('=')
+                        .and_then(|(key, value)| Some((key, value.parse().ok()?)))
+                        .with_context(|| format!("Line {}: bad arity {attribute}", number + 1))?;
+                    let arity: &mut Arity = arity.get_or_insert_with(Arity::default);
+                    match key {
+                        "args" => arity.args = value,
+                        "rets" => arity.rets = value,
+                        _ => bail!("Line {}: unknown symbol attribute {key}", number + 1),
+                    }
+                }
+                parts.symbols.push(Symbol {
+                    name: name.to_string(),
+                    address: word,
+                    arity,
+                });
+            }
+            Some(".lines") => {
+                let line = words
+                    .next()
+                    .and_then(|line| line.parse().ok())
+                    .with_context(|| format!("Line {}: bad source line", number + 1))?;
+                parts.debug_info.lines.insert(word, line);
+            }
+            Some(".records") => {
+                let Some(name) = words.next() else {
+                    bail!("Line {}: record is missing its name", number + 1)
+                };
+                parts.debug_info.records.push(RecordInfo {
+                    name: name.to_string(),
+                    shape: word,
+                    fields: words.map(str::to_string).collect(),
+                });
+            }
+            Some(other) => bail!("Line {}: unknown section {other}", number + 1),
+            None => bail!("Line {}: word before any section", number + 1),
+        }
+    }
+    Program::new(parts)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::{parse_program, AssemblerOptions};
+
+    #[test]
+    fn round_trip() {
+        let source = "push -1\npush 9999999999\ncall :f\nhalt\n:f\nret";
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        let text = encode(&program);
+        assert!(text.contains("ffffffffffffffff\n"));
+        assert!(text.contains(";; 4: call 7\n"));
+        assert_eq!(program, decode(&text).unwrap());
+    }
+
+    #[test]
+    fn hand_written() {
+        let text = ".code\n1 ;; push\n2a\n3\n";
+        let program = decode(text).unwrap();
+        assert_eq!(vec![1, 42, 3], program.code());
+        assert!(decode("1\n").is_err());
+        assert!(decode(".code\nzz\n").is_err());
+    }
+}
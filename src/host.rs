@@ -0,0 +1,227 @@
+// rust functions scripts can call with SYSCALL, bound with Cpu::bind():
+//
+//     let sqrt = cpu.bind("sqrt", |x: i64| -> i64 { (x as f64).sqrt() as i64 });
+//
+// and then `push 16` `syscall :sqrt` leaves 4 on the stack. Arguments are
+// popped deepest first, so they're pushed in the order they're written, and
+// whatever the function returns is pushed back. Words convert to `i64` as
+// they are and to `bool` as not 0. A function can return nothing, a word, a
+// bool, a pair of words, or a Result of any of those to fail the instruction.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+
+// a word from the stack as an argument.
+pub trait FromWord {
+    fn from_word(word: i64) -> Self;
+}
+
+impl FromWord for i64 {
+    fn from_word(word: i64) -> Self {
+        word
+    }
+}
+
+impl FromWord for bool {
+    fn from_word(word: i64) -> Self {
+        word != 0
+    }
+}
+
+// what a host function hands back, as the words to push.
+pub trait IntoWords {
+    // how many words it always is.
+    const COUNT: usize;
+
+    fn into_words(self) -> Result<Vec<i64>>;
+}
+
+impl IntoWords for () {
+    const COUNT: usize = 0;
+
+    fn into_words(self) -> Result<Vec<i64>> {
+        Ok(vec![])
+    }
+}
+
+impl IntoWords for i64 {
+    const COUNT: usize = 1;
+
+    fn into_words(self) -> Result<Vec<i64>> {
+        Ok(vec![self])
+    }
+}
+
+impl IntoWords for bool {
+    const COUNT: usize = 1;
+
+    fn into_words(self) -> Result<Vec<i64>> {
+        Ok(vec![self as i64])
+    }
+}
+
+impl IntoWords for (i64, i64) {
+    const COUNT: usize = 2;
+
+    fn into_words(self) -> Result<Vec<i64>> {
+        Ok(vec![self.0, self.1])
+    }
+}
+
+impl<T: IntoWords> IntoWords for Result<T> {
+    const COUNT: usize = T::COUNT;
+
+    fn into_words(self) -> Result<Vec<i64>> {
+        self?.into_words()
+    }
+}
+
+// a closure taking up to four words. `Args` is the tuple of argument types,
+// it's only there so each arity gets its own impl.
+pub trait HostFunction<Args>: Send + 'static {
+    const ARGS: usize;
+    const RETS: usize;
+
+    // `args` is exactly ARGS long, deepest first.
+    fn call(&mut self, args: &[i64]) -> Result<Vec<i64>>;
+}
+
+macro_rules! host_functions {
+    ($($count:literal: ($($arg:ident),*);)*) => {$(
+        impl<F, R, $($arg),*> HostFunction<($($arg,)*)> for F
+        where
+            F: FnMut($($arg),*) -> R + Send + 'static,
+            R: IntoWords,
+            $($arg: FromWord,)*
+        {
+            const ARGS: usize = $count;
+            const RETS: usize = R::COUNT;
+
+            #[allow(non_snake_case, unused_variables, unused_mut)]
+            fn call(&mut self, args: &[i64]) -> Result<Vec<i64>> {
+                let mut words = args.iter();
+                $(let $arg = $arg::from_word(*words.next().unwrap());)*
+                self($($arg),*).into_words()
+            }
+        }
+    )*};
+}
+
+host_functions! {
+    0: ();
+    1: (A);
+    2: (A, B);
+    3: (A, B, C);
+    4: (A, B, C, D);
+}
+
+// takes the arguments, deepest first, and gives back the words to push.
+// Shared, so a forked Cpu calls the same functions.
+pub(crate) type Call = Arc<Mutex<dyn FnMut(&[i64]) -> Result<Vec<i64>> + Send>>;
+
+// a bound function with its types forgotten, as the Cpu keeps it.
+#[derive(Clone)]
+pub(crate) struct Binding {
+    pub name: String,
+    pub args: usize,
+    pub call: Call,
+}
+
+impl Binding {
+    pub fn new<Args, F: HostFunction<Args>>(name: &str, mut function: F) -> Self {
+        Self {
+            name: name.to_string(),
+            args: F::ARGS,
+            call: Arc::new(Mutex::new(move |args: &[i64]| function.call(args))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use anyhow::bail;
+
+    use crate::assembler::{parse_program, AssemblerOptions};
+    use crate::cpu::Cpu;
+
+    // a fresh machine each time, since a loaded program carries on from
+    // wherever the last one stopped.
+    fn run(bind: impl Fn(&mut Cpu), source: &str) -> Result<Vec<i64>, String> {
+        let mut cpu = Cpu::new();
+        bind(&mut cpu);
+        let options = AssemblerOptions {
+            defines: cpu.host_function_defines(),
+            ..Default::default()
+        };
+        cpu.load_program(parse_program(source.to_string(), &options).unwrap());
+        match cpu.run() {
+            Ok(_) => Ok(cpu.stack().to_vec()),
+            Err(error) => Err(error.root_cause().to_string()),
+        }
+    }
+
+    #[test]
+    fn typed_bindings() {
+        let bind = |cpu: &mut Cpu| {
+            cpu.bind("sqrt", |x: i64| -> i64 { (x as f64).sqrt() as i64 });
+            cpu.bind("clamp", |x: i64, low: i64, high: i64| x.clamp(low, high));
+            cpu.bind("divmod", |a: i64, b: i64| (a / b, a % b));
+            cpu.bind("either", |a: bool, b: bool| a || b);
+        };
+        assert_eq!(
+            Ok(vec![4, 10, 3, 2, 1]),
+            run(
+                bind,
+                "push 16\nsyscall :sqrt\npush 50\npush 0\npush 10\nsyscall :clamp\n\
+                 push 17\npush 5\nsyscall :divmod\npush 0\npush 7\nsyscall :either\nhalt"
+            )
+        );
+    }
+
+    #[test]
+    fn side_effects_and_rebinding() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let bind = |cpu: &mut Cpu| {
+            let log = seen.clone();
+            cpu.bind("log", move |x: i64| log.lock().unwrap().push(x));
+        };
+        let source = "push 1\nsyscall :log\npush 2\nsyscall :log\nhalt";
+        assert_eq!(Ok(vec![]), run(bind, source));
+        assert_eq!(vec![1, 2], *seen.lock().unwrap());
+
+        // the same name keeps its number.
+        let mut cpu = Cpu::new();
+        let number = cpu.bind("log", |_: i64| {});
+        cpu.bind("other", || 1);
+        assert_eq!(number, cpu.bind("log", |_: i64| 7));
+        assert_eq!(2, cpu.host_function_defines().len());
+    }
+
+    #[test]
+    fn errors() {
+        let bind = |cpu: &mut Cpu| {
+            cpu.bind("max", |a: i64, b: i64| a.max(b));
+            cpu.bind("checked", |x: i64| -> anyhow::Result<i64> {
+                if x < 0 {
+                    bail!("{x} is negative")
+                }
+                Ok(x)
+            });
+        };
+        assert_eq!(
+            Err("max takes 2 arguments but the stack only has 1".to_string()),
+            run(bind, "push 1\nsyscall :max\nhalt")
+        );
+        assert_eq!(
+            Err("-1 is negative".to_string()),
+            run(bind, "push -1\nsyscall :checked\nhalt")
+        );
+        assert_eq!(
+            Err("No host function bound as 5".to_string()),
+            run(bind, "syscall 5\nhalt")
+        );
+    }
+}
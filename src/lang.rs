@@ -0,0 +1,531 @@
+// a toy language that compiles to assembly source, and from there to a
+// program:
+//
+//     fn main() {
+//         let total = 0;
+//         let i = 1;
+//         while i <= 10 {
+//             total = total + square(i);
+//             i = i + 1;
+//         }
+//         return total;
+//     }
+//
+//     fn square(x) { return x * x; }
+//
+// Everything is a word. Variables live in the function's frame, parameters
+// first, and are visible from their `let` to the end of the function.
+// Arguments are pushed left to right and every function leaves exactly one
+// value behind, 0 if it falls off the end. The program runs `main` and halts
+// with what it returns.
+
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+
+use anyhow::{bail, Context, Result};
+
+use crate::assembler::{parse_program, AssemblerOptions};
+use crate::program::Program;
+
+pub fn compile(source: &str, options: &AssemblerOptions) -> Result<Program> {
+    let assembly = compile_to_assembly(source)?;
+    parse_program(assembly, options).context("Generated assembly didn't assemble")
+}
+
+pub fn compile_to_assembly(source: &str) -> Result<String> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+        depth: 0,
+    };
+    let mut generator = Generator::default();
+    writeln!(generator.out, "    call :main\n    halt").unwrap();
+    while parser.peek().is_some() {
+        parser.function(&mut generator)?;
+    }
+
+    match generator.functions.get("main") {
+        Some((0, _)) => {}
+        Some(_) => bail!("main can't take parameters"),
+        None => bail!("There's no main function"),
+    }
+    for (name, arguments, position) in generator.calls.iter() {
+        let Some((parameters, _)) = generator.functions.get(name) else {
+            bail!("{position}: call to undefined function {name}")
+        };
+        if parameters != arguments {
+            bail!("{position}: {name} takes {parameters} argument(s), not {arguments}")
+        }
+    }
+    Ok(generator.out)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Position {
+    line: usize,
+    column: usize,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Line {}, column {}", self.line, self.column)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Number(number) => write!(f, "{number}"),
+            Self::Name(name) => write!(f, "{name}"),
+            Self::Symbol(symbol) => write!(f, "{symbol}"),
+        }
+    }
+}
+
+// longest first, so `<=` isn't read as `<` then `=`.
+const SYMBOLS: &[&str] = &[
+    "&&", "||", "==", "!=", "<=", ">=", "<", ">", "+", "-", "*", "/", "!", "(", ")", "{", "}", ",",
+    ";", "=",
+];
+
+const KEYWORDS: &[&str] = &["fn", "let", "if", "else", "while", "return"];
+
+// `//` comments run to the end of the line.
+fn tokenize(source: &str) -> Result<Vec<(Token, Position)>> {
+    let mut tokens = vec![];
+    for (index, line) in source.lines().enumerate() {
+        let line = match line.find("//") {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        let mut rest = line;
+        while let Some(c) = rest.chars().next() {
+            let position = Position {
+                line: index + 1,
+                column: line.len() - rest.len() + 1,
+            };
+            if c.is_whitespace() {
+                rest = &rest[c.len_utf8()..];
+                continue;
+            }
+            let (token, length) = if c.is_ascii_digit() {
+                let length = rest
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len());
+                let number = rest[..length]
+                    .parse()
+                    .with_context(|| format!("{position}: number is too big"))?;
+                (Token::Number(number), length)
+            } else if c.is_alphabetic() || c == '_' {
+                let length = rest
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                (Token::Name(rest[..length].to_string()), length)
+            } else if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+                (Token::Symbol(symbol), symbol.len())
+            } else {
+                bail!("{position}: unexpected {c:?}")
+            };
+            tokens.push((token, position));
+            rest = &rest[length..];
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Default)]
+struct Generator {
+    out: String,
+    // parameter count and where each function was defined.
+    functions: HashMap<String, (usize, Position)>,
+    // every call, with its argument count, checked once all functions are known.
+    calls: Vec<(String, usize, Position)>,
+    // the current function's variables and their slots.
+    variables: HashMap<String, usize>,
+    labels: usize,
+}
+
+impl Generator {
+    fn emit(&mut self, line: impl fmt::Display) {
+        writeln!(self.out, "    {line}").unwrap();
+    }
+
+    fn label(&mut self) -> String {
+        self.labels += 1;
+        format!(":.l{}", self.labels)
+    }
+
+    fn slot(&self, name: &str, position: Position) -> Result<usize> {
+        match self.variables.get(name) {
+            Some(slot) => Ok(*slot),
+            None => bail!("{position}: {name} isn't declared"),
+        }
+    }
+}
+
+// binary operators from loosest to tightest binding, and the instructions
+// each compiles to once both sides are on the stack.
+const LEVELS: &[&[(&str, &[&str])]] = &[
+    &[("||", &["or"])],
+    &[("&&", &["and"])],
+    &[("==", &["iseq"]), ("!=", &["iseq", "not"])],
+    &[
+        (">", &["isgt"]),
+        (">=", &["isge"]),
+        ("<", &["isge", "not"]),
+        ("<=", &["isgt", "not"]),
+    ],
+    &[("+", &["add"]), ("-", &["sub"])],
+    &[("*", &["mul"]), ("/", &["div"])],
+];
+
+// how deep expressions and blocks can nest, so deeply nested source is an
+// error rather than a stack overflow.
+const MAX_NESTING: usize = 256;
+
+struct Parser {
+    tokens: Vec<(Token, Position)>,
+    position: usize,
+    // expressions and blocks being parsed, one inside the other.
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn at(&self, symbol: &str) -> bool {
+        matches!(self.peek(), Some(Token::Symbol(found)) if *found == symbol)
+    }
+
+    fn next(&mut self) -> Result<(Token, Position)> {
+        let Some(token) = self.tokens.get(self.position).cloned() else {
+            bail!("Source ended early")
+        };
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<Position> {
+        let (token, position) = self.next()?;
+        if !matches!(token, Token::Symbol(found) if found == symbol) {
+            bail!("{position}: expected {symbol}, got {token}")
+        }
+        Ok(position)
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Name(name)) if name == keyword) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn name(&mut self) -> Result<(String, Position)> {
+        match self.next()? {
+            (Token::Name(name), position) if !KEYWORDS.contains(&name.as_str()) => {
+                Ok((name, position))
+            }
+            (token, position) => bail!("{position}: expected a name, got {token}"),
+        }
+    }
+
+    fn function(&mut self, generator: &mut Generator) -> Result<()> {
+        if !self.keyword("fn") {
+            let (token, position) = self.next()?;
+            bail!("{position}: expected fn, got {token}")
+        }
+        let (name, position) = self.name()?;
+        self.expect("(")?;
+        let mut parameters = vec![];
+        while !self.at(")") {
+            if !parameters.is_empty() {
+                self.expect(",")?;
+            }
+            parameters.push(self.name()?);
+        }
+        self.expect(")")?;
+
+        if let Some((_, first)) = generator.functions.get(&name) {
+            bail!("{position}: {name} is already defined at {first}")
+        }
+        generator
+            .functions
+            .insert(name.clone(), (parameters.len(), position));
+        generator.variables.clear();
+        writeln!(
+            generator.out,
+            ".fn :{name} args={} rets=1",
+            parameters.len()
+        )
+        .unwrap();
+        for (slot, (parameter, position)) in parameters.iter().enumerate() {
+            if generator
+                .variables
+                .insert(parameter.clone(), slot)
+                .is_some()
+            {
+                bail!("{position}: {parameter} is already a parameter")
+            }
+        }
+        // the last argument is on top.
+        for slot in (0..parameters.len()).rev() {
+            generator.emit(format_args!("store {slot}"));
+        }
+        self.block(generator)?;
+        generator.emit("push 0");
+        generator.emit("ret");
+        writeln!(generator.out, ".endfn").unwrap();
+        Ok(())
+    }
+
+    // run `parse` one level deeper.
+    fn nested(&mut self, parse: impl FnOnce(&mut Self) -> Result<()>) -> Result<()> {
+        if self.depth >= MAX_NESTING {
+            match self.tokens.get(self.position) {
+                Some((_, position)) => bail!("{position}: nested more than {MAX_NESTING} deep"),
+                None => bail!("Nested more than {MAX_NESTING} deep"),
+            }
+        }
+        self.depth += 1;
+        let parsed = parse(self);
+        self.depth -= 1;
+        parsed
+    }
+
+    fn block(&mut self, generator: &mut Generator) -> Result<()> {
+        self.expect("{")?;
+        self.nested(|parser| {
+            while !parser.at("}") {
+                parser.statement(generator)?;
+            }
+            Ok(())
+        })?;
+        self.expect("}")?;
+        Ok(())
+    }
+
+    fn statement(&mut self, generator: &mut Generator) -> Result<()> {
+        if self.keyword("let") {
+            let (name, _) = self.name()?;
+            self.expect("=")?;
+            self.expression(generator)?;
+            self.expect(";")?;
+            // declaring a name again reuses its slot.
+            let next = generator.variables.len();
+            let slot = *generator.variables.entry(name).or_insert(next);
+            generator.emit(format_args!("store {slot}"));
+        } else if self.keyword("if") {
+            self.expression(generator)?;
+            let otherwise = generator.label();
+            let end = generator.label();
+            generator.emit(format_args!("jz {otherwise}"));
+            self.block(generator)?;
+            generator.emit(format_args!("jmp {end}"));
+            writeln!(generator.out, "{otherwise}").unwrap();
+            if self.keyword("else") {
+                match self.keyword("if") {
+                    // `else if` is an if statement on its own in the else.
+                    true => {
+                        self.position -= 1;
+                        self.statement(generator)?;
+                    }
+                    false => self.block(generator)?,
+                }
+            }
+            writeln!(generator.out, "{end}").unwrap();
+        } else if self.keyword("while") {
+            let top = generator.label();
+            let end = generator.label();
+            writeln!(generator.out, "{top}").unwrap();
+            self.expression(generator)?;
+            generator.emit(format_args!("jz {end}"));
+            self.block(generator)?;
+            generator.emit(format_args!("jmp {top}"));
+            writeln!(generator.out, "{end}").unwrap();
+        } else if self.keyword("return") {
+            match self.at(";") {
+                true => generator.emit("push 0"),
+                false => self.expression(generator)?,
+            }
+            self.expect(";")?;
+            generator.emit("ret");
+        } else if matches!(
+            self.tokens.get(self.position + 1),
+            Some((Token::Symbol("="), _))
+        ) {
+            let (name, position) = self.name()?;
+            self.expect("=")?;
+            self.expression(generator)?;
+            self.expect(";")?;
+            let slot = generator.slot(&name, position)?;
+            generator.emit(format_args!("store {slot}"));
+        } else {
+            self.expression(generator)?;
+            self.expect(";")?;
+            generator.emit("pop");
+        }
+        Ok(())
+    }
+
+    fn expression(&mut self, generator: &mut Generator) -> Result<()> {
+        self.nested(|parser| parser.binary(generator, 0))
+    }
+
+    // left associative, so `8 - 2 - 1` is `(8 - 2) - 1`.
+    fn binary(&mut self, generator: &mut Generator, level: usize) -> Result<()> {
+        let Some(operators) = LEVELS.get(level) else {
+            return self.unary(generator);
+        };
+        self.binary(generator, level + 1)?;
+        while let Some((_, instructions)) = operators.iter().find(|(symbol, _)| self.at(symbol)) {
+            self.position += 1;
+            self.binary(generator, level + 1)?;
+            for instruction in instructions.iter() {
+                generator.emit(instruction);
+            }
+        }
+        Ok(())
+    }
+
+    fn unary(&mut self, generator: &mut Generator) -> Result<()> {
+        if self.at("-") {
+            self.position += 1;
+            generator.emit("push 0");
+            self.nested(|parser| parser.unary(generator))?;
+            generator.emit("sub");
+        } else if self.at("!") {
+            self.position += 1;
+            self.nested(|parser| parser.unary(generator))?;
+            generator.emit("not");
+        } else {
+            self.primary(generator)?;
+        }
+        Ok(())
+    }
+
+    fn primary(&mut self, generator: &mut Generator) -> Result<()> {
+        let (token, position) = self.next()?;
+        match token {
+            Token::Number(number) => generator.emit(format_args!("push {number}")),
+            Token::Name(name) if name == "true" => generator.emit("push 1"),
+            Token::Name(name) if name == "false" => generator.emit("push 0"),
+            Token::Name(name) if KEYWORDS.contains(&name.as_str()) => {
+                bail!("{position}: unexpected {name}")
+            }
+            Token::Name(name) if self.at("(") => {
+                self.position += 1;
+                let mut arguments = 0;
+                while !self.at(")") {
+                    if arguments > 0 {
+                        self.expect(",")?;
+                    }
+                    self.expression(generator)?;
+                    arguments += 1;
+                }
+                self.expect(")")?;
+                generator.emit(format_args!("call :{name}"));
+                generator.calls.push((name, arguments, position));
+            }
+            Token::Name(name) => {
+                let slot = generator.slot(&name, position)?;
+                generator.emit(format_args!("load {slot}"));
+            }
+            Token::Symbol("(") => {
+                self.expression(generator)?;
+                self.expect(")")?;
+            }
+            token => bail!("{position}: unexpected {token}"),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::Cpu;
+    use crate::verifier;
+
+    fn run(source: &str) -> i64 {
+        let program = compile(source, &AssemblerOptions::default()).unwrap();
+        let diagnostics = verifier::verify(&program).unwrap();
+        assert!(!verifier::has_errors(&diagnostics), "{diagnostics:?}");
+        let mut cpu = Cpu::new();
+        cpu.load_program(program);
+        cpu.run().unwrap().exit_value.unwrap()
+    }
+
+    #[test]
+    fn loops_and_calls() {
+        let source = "
+            fn main() {
+                let total = 0;
+                let i = 1;
+                while i <= 10 {
+                    total = total + square(i);
+                    i = i + 1;
+                }
+                return total;
+            }
+
+            // called before it's defined.
+            fn square(x) { return x * x; }
+        ";
+        assert_eq!(385, run(source));
+    }
+
+    #[test]
+    fn branches_and_recursion() {
+        let source = "
+            fn main() { return fib(10) - sign(0 - 4) + sign(0); }
+            fn fib(n) {
+                if n < 2 { return n; }
+                return fib(n - 1) + fib(n - 2);
+            }
+            fn sign(x) {
+                if x > 0 { return 1; } else if x < 0 { return -1; } else { return 0; }
+            }
+            fn nothing(a, b) { a; }
+        ";
+        assert_eq!(56, run(source));
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source| compile_to_assembly(source).unwrap_err().to_string();
+        assert_eq!("There's no main function", error("fn f() {}"));
+        assert_eq!(
+            "Line 1, column 15: y isn't declared",
+            error("fn main() { x(y); }")
+        );
+        assert_eq!(
+            "Line 1, column 13: f takes 1 argument(s), not 2",
+            error("fn main() { f(1, 2); }\nfn f(a) {}")
+        );
+        assert_eq!(
+            "Line 1, column 13: call to undefined function g",
+            error("fn main() { g(); }")
+        );
+        assert_eq!(
+            "Line 1, column 19: expected ;, got }",
+            error("fn main() { 1 + 2 }")
+        );
+
+        // too deep for a recursive descent parser to follow.
+        for (start, nested) in [("return", "("), ("return", "-"), ("", "if 1 {")] {
+            let source = format!("fn main() {{ {start} {}", nested.repeat(50_000));
+            let error = compile_to_assembly(&source).unwrap_err().to_string();
+            assert!(error.contains("nested more than 256 deep"), "{error}");
+        }
+    }
+}
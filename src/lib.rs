@@ -0,0 +1,27 @@
+pub mod assembler;
+pub mod breakpoint;
+pub mod bytecode;
+pub mod callgraph;
+pub mod cfg;
+pub mod coredump;
+pub mod cost;
+pub mod cpu;
+pub mod debugger;
+pub mod disassembler;
+pub mod exprc;
+pub mod forth;
+pub mod hexbc;
+pub mod host;
+pub mod lang;
+pub mod lint;
+pub mod plugin;
+pub mod pool;
+pub mod profiler;
+pub mod program;
+pub mod repl;
+pub mod replay;
+pub mod sexpr;
+pub mod stackdepth;
+pub mod testgen;
+pub mod verifier;
+pub mod watch;
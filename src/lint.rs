@@ -0,0 +1,281 @@
+// likely mistakes that still assemble and verify. Nothing here stops a
+// program from running, these are for `biteycode lint`.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+
+use anyhow::Result;
+
+use crate::callgraph::call_graph;
+use crate::cpu::{has_code_operand, Opcode};
+use crate::disassembler::{decode, Instruction};
+use crate::program::Program;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lint {
+    // short name of the check, e.g. `push-pop`.
+    pub rule: &'static str,
+    pub address: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} (at address {})",
+            self.rule, self.message, self.address
+        )
+    }
+}
+
+// every lint for the program, in address order.
+pub fn lint(program: &Program) -> Result<Vec<Lint>> {
+    let instructions = decode(program.code())?;
+    let mut lints = vec![];
+    jumps_into_operands(&instructions, &mut lints);
+    fallthrough_into_functions(program, &instructions, &mut lints)?;
+    discarded_values(program, &instructions, &mut lints);
+    fusable_tests(program, &instructions, &mut lints);
+    stores_never_loaded(program, &instructions, &mut lints)?;
+    lints.sort_by_key(|lint| lint.address);
+    Ok(lints)
+}
+
+fn jumps_into_operands(instructions: &[Instruction], lints: &mut Vec<Lint>) {
+    let mut owner = HashMap::new();
+    for instruction in instructions.iter() {
+        for address in instruction.address + 1..instruction.next_address() {
+            owner.insert(address as i64, instruction.address);
+        }
+    }
+    for instruction in instructions.iter() {
+        if !has_code_operand(instruction.opcode) {
+            continue;
+        }
+        let Some(start) = instruction.operand.and_then(|target| owner.get(&target)) else {
+            continue;
+        };
+        lints.push(Lint {
+            rule: "jump-into-operand",
+            address: instruction.address,
+            message: format!(
+                "{} lands on the operand of the instruction at {start}",
+                instruction.mnemonic()
+            ),
+        });
+    }
+}
+
+// a function is the entry point, anything called, or anything declared with
+// `.fn`. Running into one from above is almost always a missing RET.
+fn fallthrough_into_functions(
+    program: &Program,
+    instructions: &[Instruction],
+    lints: &mut Vec<Lint>,
+) -> Result<()> {
+    let mut starts: BTreeSet<usize> = call_graph(program)?.iter().map(|f| f.start).collect();
+    for symbol in program.symbols().iter().filter(|s| s.arity.is_some()) {
+        starts.insert(symbol.address as usize);
+    }
+    for pair in instructions.windows(2) {
+        let (last, next) = (&pair[0], &pair[1]);
+        if !starts.contains(&next.address)
+            || matches!(
+                last.opcode,
+                Opcode::Jmp | Opcode::Ret | Opcode::Iret | Opcode::Halt
+            )
+        {
+            continue;
+        }
+        lints.push(Lint {
+            rule: "fallthrough",
+            address: last.address,
+            message: format!(
+                "execution falls through into {}",
+                program.function_name(next.address)
+            ),
+        });
+    }
+    Ok(())
+}
+
+// a value pushed or compared and then thrown straight away. A POP with a
+// label on it can be reached some other way, so that's left alone.
+fn discarded_values(program: &Program, instructions: &[Instruction], lints: &mut Vec<Lint>) {
+    let targets = jump_targets(program, instructions);
+    for pair in instructions.windows(2) {
+        let (first, pop) = (&pair[0], &pair[1]);
+        if pop.opcode != Opcode::Pop || targets.contains(&pop.address) {
+            continue;
+        }
+        let (rule, message) = match first.opcode {
+            Opcode::Push | Opcode::Pushc => ("push-pop", "pushed value is popped straight away"),
+            Opcode::Iseq | Opcode::Isgt | Opcode::Isge | Opcode::Iszero => (
+                "unused-comparison",
+                "comparison result is popped without being used",
+            ),
+            _ => continue,
+        };
+        lints.push(Lint {
+            rule,
+            address: first.address,
+            message: message.to_string(),
+        });
+    }
+}
+
+// tests against 0 spelled out the long way, when there's an instruction that
+// does the lot. Anything after the first instruction with a label on it can
+// be reached some other way, so that's left alone.
+fn fusable_tests(program: &Program, instructions: &[Instruction], lints: &mut Vec<Lint>) {
+    let targets = jump_targets(program, instructions);
+    let mut index = 0;
+    while index < instructions.len() {
+        let window = &instructions[index..];
+        let unlabelled = |count: usize| {
+            window[1..count]
+                .iter()
+                .all(|instruction| !targets.contains(&instruction.address))
+        };
+        let opcodes: Vec<(Opcode, Option<i64>)> = window
+            .iter()
+            .take(3)
+            .map(|instruction| (instruction.opcode, instruction.operand))
+            .collect();
+        let (count, message) = match opcodes.as_slice() {
+            [(Opcode::Push, Some(0)), (Opcode::Iseq, _), (Opcode::Jif, _), ..] if unlabelled(3) => {
+                (3, "push 0, iseq, jif can be jz")
+            }
+            [(Opcode::Push, Some(0)), (Opcode::Iseq, _), ..] if unlabelled(2) => {
+                (2, "push 0, iseq can be iszero")
+            }
+            [(Opcode::Not | Opcode::Iszero, _), (Opcode::Jif, _), ..] if unlabelled(2) => {
+                (2, "a test and jif can be jz")
+            }
+            _ => {
+                index += 1;
+                continue;
+            }
+        };
+        lints.push(Lint {
+            rule: "fusable-test",
+            address: window[0].address,
+            message: message.to_string(),
+        });
+        index += count;
+    }
+}
+
+// variables belong to a frame, so a STORE only matters to LOADs in the same
+// function.
+fn stores_never_loaded(
+    program: &Program,
+    instructions: &[Instruction],
+    lints: &mut Vec<Lint>,
+) -> Result<()> {
+    let functions = call_graph(program)?;
+    let function_of =
+        |address: usize| functions.partition_point(|function| function.start <= address) - 1;
+    let loaded: HashSet<(usize, i64)> = instructions
+        .iter()
+        .filter_map(|instruction| {
+            match instruction.opcode {
+                Opcode::Load => instruction.operand,
+                // counts down what was stored to it.
+                Opcode::Loop => instruction.slot,
+                _ => None,
+            }
+            .map(|variable| (function_of(instruction.address), variable))
+        })
+        .collect();
+    for instruction in instructions.iter() {
+        let (Opcode::Store, Some(variable)) = (instruction.opcode, instruction.operand) else {
+            continue;
+        };
+        if loaded.contains(&(function_of(instruction.address), variable)) {
+            continue;
+        }
+        lints.push(Lint {
+            rule: "dead-store",
+            address: instruction.address,
+            message: format!("variable {variable} is stored but never loaded"),
+        });
+    }
+    Ok(())
+}
+
+// addresses something other than falling through can reach.
+fn jump_targets(program: &Program, instructions: &[Instruction]) -> HashSet<usize> {
+    let mut targets: HashSet<usize> = program
+        .symbols()
+        .iter()
+        .filter_map(|symbol| usize::try_from(symbol.address).ok())
+        .collect();
+    for instruction in instructions.iter() {
+        if let (true, Some(target)) = (has_code_operand(instruction.opcode), instruction.operand) {
+            if let Ok(target) = usize::try_from(target) {
+                targets.insert(target);
+            }
+        }
+    }
+    targets
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::{parse_program, AssemblerOptions};
+    use crate::cpu::{JMP, PUSH};
+
+    fn rules(source: &str) -> Vec<(&'static str, usize)> {
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        lint(&program)
+            .unwrap()
+            .iter()
+            .map(|lint| (lint.rule, lint.address))
+            .collect()
+    }
+
+    #[test]
+    fn clean_program() {
+        let source = "push 2\nstore 0\nload 0\ncall :f\nhalt\n:f\npush 1\niseq\nret";
+        assert!(rules(source).is_empty());
+    }
+
+    #[test]
+    fn finds_each_mistake() {
+        let source = "push 1\npop\nstore 3\npush 1\npush 2\nisgt\npop\ncall :f\n:f\nret";
+        assert_eq!(
+            vec![
+                ("push-pop", 0),
+                ("dead-store", 3),
+                ("unused-comparison", 9),
+                ("fallthrough", 11),
+            ],
+            rules(source)
+        );
+    }
+
+    #[test]
+    fn fusable_tests() {
+        let source = "push 1\npush 0\niseq\njif :end\npush 0\niseq\nnot\njif :end\n\
+                      push 0\n:end\niseq\nhalt";
+        assert_eq!(
+            vec![
+                ("fusable-test", 2),
+                ("fusable-test", 7),
+                ("fusable-test", 10),
+            ],
+            rules(source)
+        );
+    }
+
+    #[test]
+    fn jump_into_operand() {
+        let program = Program::from_code(vec![PUSH, 1, JMP, 1]).unwrap();
+        let lints = lint(&program).unwrap();
+        assert_eq!("jump-into-operand", lints[0].rule);
+        assert_eq!(2, lints[0].address);
+    }
+}
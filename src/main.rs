@@ -1,244 +1,872 @@
-// let's implement an assembler real fast.
-
-use std::{collections::HashMap, io::Write, vec};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
 
 use anyhow::{bail, Context, Result};
-use cpu::{
-    Cpu, ADD, AND, CALL, DIV, DUP, HALT, ISEQ, ISGE, ISGT, JIF, JMP, LOAD, MUL, NOT, OR, POP,
-    PRNSTK, PUSH, RET, STORE, SUB,
-};
-mod cpu;
-
-#[derive(Clone, Debug)]
-enum ProgramValue {
-    Instruction(i64),
-    Value(i64),
-    Constant(String, i64),
-    FunctionLabel(String),
-    Label(String),
-}
-
-fn parse_line(line: String) -> Result<Vec<ProgramValue>> {
-    // it's a label
-    // we'll outline our grammar here.
-    let mut split_lines = line.trim().split(' ').filter(|v| !v.is_empty());
-    // we'll skip empty lines
-    let Some(mut word) = split_lines.next() else {
-        return Ok(vec![]);
-    };
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::EnvFilter;
 
-    word = word.trim();
+use stackvm::{
+    assembler::{self, parse_file_ir, AssemblerOptions},
+    bytecode::{self, emit_bytecode, load_bytecode, EncodeOptions, Endian},
+    callgraph, cfg,
+    coredump::{self, CoreDump},
+    cost::CostModel,
+    cpu::{CancelHandle, Cancelled, Cpu, MemoryLimits, Nondeterminism, Overflow, StackDumpFormat},
+    debugger::Debugger,
+    disassembler, exprc, lang, lint, plugin, profiler,
+    program::Program,
+    repl::Repl,
+    replay, sexpr, stackdepth, testgen, verifier,
+};
 
-    // we can define constants
-    if is_label(word) {
-        match split_lines.next() {
-            Some(argument) => {
-                let constant = argument
-                    .parse::<i64>()
-                    .context("Label argument was not number")?;
-                return Ok(vec![ProgramValue::Constant(word.to_string(), constant)]);
-            }
-            // A label with no value will demarcate the next instruction address.
-            None => return Ok(vec![ProgramValue::FunctionLabel(word.to_string())]),
-        }
-    }
+// how many instructions to show when a run is cut short.
+const TRACE_LENGTH: usize = 16;
 
-    if is_comment(word) {
-        return Ok(vec![]);
-    }
+// how often watch mode checks the source files for changes.
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
 
-    match word.to_lowercase().as_str() {
-        "push" => {
-            let argument = get_labeled_or_unlabled_argument(&mut split_lines)?;
-            Ok(vec![ProgramValue::Instruction(PUSH), argument])
-        }
-        "add" => Ok(vec![ProgramValue::Instruction(ADD)]),
-        "halt" => Ok(vec![ProgramValue::Instruction(HALT)]),
-        "sub" => Ok(vec![ProgramValue::Instruction(SUB)]),
-        "mul" => Ok(vec![ProgramValue::Instruction(MUL)]),
-        "div" => Ok(vec![ProgramValue::Instruction(DIV)]),
-        "not" => Ok(vec![ProgramValue::Instruction(NOT)]),
-        "and" => Ok(vec![ProgramValue::Instruction(AND)]),
-        "or" => Ok(vec![ProgramValue::Instruction(OR)]),
-        "pop" => Ok(vec![ProgramValue::Instruction(POP)]),
-        "dup" => Ok(vec![ProgramValue::Instruction(DUP)]),
-        "iseq" => Ok(vec![ProgramValue::Instruction(ISEQ)]),
-        "isgt" => Ok(vec![ProgramValue::Instruction(ISGT)]),
-        "isge" => Ok(vec![ProgramValue::Instruction(ISGE)]),
-        "load" => {
-            let argument = get_labeled_or_unlabled_argument(&mut split_lines)?;
-            Ok(vec![ProgramValue::Instruction(LOAD), argument])
-        }
-        "jmp" => {
-            let argument = get_labeled_or_unlabled_argument(&mut split_lines)?;
-            Ok(vec![ProgramValue::Instruction(JMP), argument])
-        }
-        "jif" => {
-            let argument = get_labeled_or_unlabled_argument(&mut split_lines)?;
-            Ok(vec![ProgramValue::Instruction(JIF), argument])
-        }
-        "store" => {
-            let argument = get_labeled_or_unlabled_argument(&mut split_lines)?;
-            Ok(vec![ProgramValue::Instruction(STORE), argument])
-        }
-        "call" => {
-            let argument = get_labeled_or_unlabled_argument(&mut split_lines)?;
-            Ok(vec![ProgramValue::Instruction(CALL), argument])
-        }
-        "ret" => Ok(vec![ProgramValue::Instruction(RET)]),
-        "prnstk" => Ok(vec![ProgramValue::Instruction(PRNSTK)]),
-        other => bail!("Received invalid instruction {other}"),
-    }
+#[derive(Parser)]
+#[command(name = "biteycode", about = "A little stack vm and its assembler")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    /// Log more, `-vv` traces every instruction. `RUST_LOG` overrides this.
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Only log warnings and errors.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
 }
 
-fn get_labeled_or_unlabled_argument<'a, Iter>(iterator: &mut Iter) -> Result<ProgramValue>
-where
-    Iter: Iterator<Item = &'a str>,
-{
-    let token = get_token(iterator)?;
-    if is_label(token.clone()) {
-        Ok(ProgramValue::Label(token))
-    } else {
-        Ok(ProgramValue::Value(
-            token.parse::<i64>().context("Not number")?,
-        ))
-    }
+#[derive(Clone, Copy, ValueEnum)]
+enum Emit {
+    Bytecode,
+    /// The assembler's parsed program, for other tools to consume.
+    Json,
 }
 
-fn is_label<T: Into<String>>(string: T) -> bool {
-    string.into().starts_with(':')
+#[derive(Clone, Copy, ValueEnum)]
+enum OverflowMode {
+    /// Stop with an error.
+    Trap,
+    /// Two's complement wraparound.
+    Wrap,
+    /// Clamp to the largest or smallest word.
+    Saturate,
 }
 
-fn is_comment<T: Into<String>>(string: T) -> bool {
-    string.into().starts_with(";;")
+#[derive(Clone, Copy, ValueEnum)]
+enum NondeterminismMode {
+    /// Let syscalls and device reads through.
+    Allow,
+    /// Print every word syscalls and device reads returned once the program halts.
+    Record,
+    /// Stop with an error at the first syscall or device read.
+    Forbid,
 }
 
-fn get_token<'a, Iter>(iterator: &mut Iter) -> Result<String>
-where
-    Iter: Iterator<Item = &'a str>,
-{
-    match iterator.next() {
-        Some(token) => Ok(token.to_string()),
-        None => {
-            bail!("No token present when required")
-        }
-    }
+#[derive(Clone, Copy, ValueEnum)]
+enum StackDump {
+    Text,
+    /// One JSON object per line.
+    Json,
+    /// The instruction pointer, stack and variables laid out to read.
+    Pretty,
 }
 
-fn parse_program(program: String) -> Result<Vec<i64>> {
-    let mut value_stream = vec![];
-    // first grab the lines
-    for line in program.lines() {
-        let parsed = parse_line(line.to_string())?;
-        value_stream.extend(parsed);
-    }
+#[derive(Subcommand)]
+enum Command {
+    /// Assemble a source file into bytecode.
+    Assemble {
+        /// `-` reads the source from stdin.
+        source: PathBuf,
+        /// Defaults to `bytecode`, or stdout for `--emit=json`.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Drop labeled blocks unreachable from the entry point or an `.export`.
+        #[arg(long)]
+        strip_dead_code: bool,
+        /// Replace compare-then-`jif` sequences with single compare-and-jump instructions.
+        #[arg(long)]
+        fuse_branches: bool,
+        /// Compress the bytecode with zstd. Needs the `zstd` feature.
+        #[arg(long)]
+        compress: bool,
+        /// Write the sections little endian instead of big endian.
+        #[arg(long)]
+        little_endian: bool,
+        /// Sign the bytecode with this ed25519 private key, in PEM. Needs the
+        /// `crypto` feature.
+        #[arg(long, value_name = "KEYFILE")]
+        sign: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t = Emit::Bytecode)]
+        emit: Emit,
+        /// Define a constant before assembling, e.g. `-D size=64`. Repeatable.
+        #[arg(short = 'D', long = "define", value_parser = parse_define)]
+        defines: Vec<(String, i64)>,
+    },
+    /// Run a bytecode file, or assemble and run a source file.
+    Run {
+        file: PathBuf,
+        /// Print a per-function profile once the program halts.
+        #[arg(long)]
+        profile: bool,
+        /// Write flamegraph-compatible folded stacks to this file.
+        #[arg(long)]
+        folded: Option<PathBuf>,
+        /// TOML table of per-instruction cycle costs.
+        #[arg(long)]
+        costs: Option<PathBuf>,
+        /// Print the total cycle count once the program halts.
+        #[arg(long)]
+        cycles: bool,
+        /// Give up after executing this many instructions.
+        #[arg(long)]
+        max_steps: Option<u64>,
+        /// Give up after this long, e.g. `5s` or `500ms`.
+        #[arg(long, value_parser = humantime::parse_duration)]
+        timeout: Option<Duration>,
+        /// Maximum number of words on the data stack.
+        #[arg(long)]
+        max_stack: Option<usize>,
+        /// Maximum number of call frames.
+        #[arg(long)]
+        max_frames: Option<usize>,
+        /// Maximum number of heap words.
+        #[arg(long)]
+        max_heap: Option<usize>,
+        /// Print peak memory usage once the program halts.
+        #[arg(long)]
+        memory_stats: bool,
+        /// Print instructions executed, peak stack and frame depth, calls
+        /// made and time taken once the program halts.
+        #[arg(long)]
+        stats: bool,
+        /// Halt quietly when execution runs off the end of the program.
+        #[arg(long)]
+        implicit_halt: bool,
+        /// Fail on loading a variable that was never stored to, instead of reading 0.
+        #[arg(long)]
+        strict_loads: bool,
+        /// How PRNSTK prints the machine state.
+        #[arg(long, value_enum, default_value_t = StackDump::Text)]
+        stack_dump: StackDump,
+        /// What arithmetic does when a result doesn't fit in a word.
+        #[arg(long, value_enum, default_value_t = OverflowMode::Trap)]
+        overflow: OverflowMode,
+        /// What to do about results that come from the host rather than the
+        /// program, for runs that have to be reproducible.
+        #[arg(long, value_enum, default_value_t = NondeterminismMode::Allow)]
+        nondeterminism: NondeterminismMode,
+        /// If the program fails, save the machine state here, e.g. `crash.bcore`.
+        #[arg(long)]
+        core_dump: Option<PathBuf>,
+        /// Print a line saying what each instruction did as it runs.
+        #[arg(long)]
+        explain: bool,
+        /// Stream JSON lines of execution events to a file, or to `tcp://HOST:PORT`.
+        #[arg(long)]
+        events: Option<String>,
+        /// Load host functions for `syscall` from a plugin library, or every
+        /// library in a directory. Repeatable.
+        #[arg(long = "plugin")]
+        plugins: Vec<PathBuf>,
+        /// Refuse to run bytecode that wasn't signed by the private half of
+        /// this ed25519 public key, in PEM. Needs the `crypto` feature.
+        #[arg(long, value_name = "PUBKEY")]
+        verify: Option<PathBuf>,
+    },
+    /// Assemble and run a source file every time it or a module it imports changes.
+    Watch {
+        source: PathBuf,
+        /// Give up on a run after this long, so a stuck program doesn't stall the loop.
+        #[arg(long, value_parser = humantime::parse_duration, default_value = "5s")]
+        timeout: Duration,
+    },
+    /// Show what a program was doing when it failed, from a `run --core-dump` file.
+    Inspect { dump: PathBuf },
+    /// Show what changed between two core dumps: stack entries, variables, heap
+    /// words and map entries.
+    Diff { before: PathBuf, after: PathBuf },
+    /// Print a program as assembly source, with labels where we can find them.
+    Disasm {
+        file: PathBuf,
+        /// Comment every instruction with what it does.
+        #[arg(long)]
+        explain: bool,
+    },
+    /// Write the control flow graph of a program as Graphviz DOT.
+    Cfg {
+        file: PathBuf,
+        /// Where to write the graph, stdout if not given.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Print which functions call which, with instruction counts.
+    Calls {
+        file: PathBuf,
+        /// Emit Graphviz DOT instead of a text listing.
+        #[arg(long)]
+        dot: bool,
+    },
+    /// Work out the worst-case data stack depth of each function and the whole program.
+    Analyze { file: PathBuf },
+    /// Point out likely mistakes, like dead stores and falling into the next function.
+    Lint { file: PathBuf },
+    /// Run a program again, checking it does just what a `run --events` trace
+    /// of it recorded, and stop where it doesn't.
+    Replay {
+        file: PathBuf,
+        trace: PathBuf,
+        /// Load host functions for `syscall` the run used. Repeatable.
+        #[arg(long = "plugin")]
+        plugins: Vec<PathBuf>,
+    },
+    /// Write random programs that pass the verifier and always halt, as
+    /// `SEED.bc` in a directory, e.g. to seed a fuzzer.
+    Testgen {
+        output: PathBuf,
+        /// How many programs to write.
+        #[arg(long, default_value_t = 100)]
+        count: u64,
+        /// The seed of the first program, the rest count up from it.
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+        /// About how many instructions each program has.
+        #[arg(long, default_value_t = 60)]
+        size: usize,
+    },
+    /// Step through a program with breakpoints, see `debugger.rs` for the commands.
+    Debug {
+        file: PathBuf,
+        /// Run the commands in this file instead of reading them from stdin,
+        /// failing at the first one that fails.
+        #[arg(long)]
+        script: Option<PathBuf>,
+    },
+    /// Enter instructions one at a time and watch the stack and variables change.
+    Repl {
+        /// Take Forth, like `: double 2 * ; 21 double .`, instead of assembly.
+        #[arg(long)]
+        forth: bool,
+    },
+    /// Compile a program in the toy language, see `lang.rs`, to bytecode.
+    ///
+    /// `.scm` and `.lisp` files are s-expressions instead, see `sexpr.rs`.
+    Compile {
+        source: PathBuf,
+        /// Defaults to `bytecode`, or stdout for `--asm`.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Write the generated assembly instead of bytecode.
+        #[arg(long)]
+        asm: bool,
+    },
+    /// Compile an expression like `1 + 2 * (x - 3)` and print its value.
+    Exprc {
+        expression: String,
+        /// Give a variable a value, e.g. `--set x=4`. Repeatable.
+        #[arg(long = "set", value_parser = parse_binding)]
+        bindings: Vec<(String, i64)>,
+        /// Write the compiled bytecode here instead of running it.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Print a source file laid out the canonical way.
+    Fmt {
+        source: PathBuf,
+        /// Rewrite the file in place instead of printing it.
+        #[arg(short, long)]
+        write: bool,
+        /// Print nothing, and fail if the file isn't already formatted.
+        #[arg(long, conflicts_with = "write")]
+        check: bool,
+    },
+}
 
-    // gather all our constants.
-    let mut constants = HashMap::new();
-    let mut after_constant_remapping = vec![];
-    for value in value_stream.into_iter() {
-        if let ProgramValue::Constant(name, value) = value {
-            constants.insert(name, value);
-        } else {
-            after_constant_remapping.push(value);
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    init_logging(cli.verbose, cli.quiet);
+    match cli.command {
+        Command::Assemble {
+            source,
+            output,
+            strip_dead_code,
+            fuse_branches,
+            compress,
+            little_endian,
+            sign,
+            emit,
+            defines,
+        } => {
+            let options = AssemblerOptions {
+                strip_dead_code,
+                fuse_branches,
+                source_name: Some(source.display().to_string()),
+                defines: defines.into_iter().collect(),
+            };
+            match emit {
+                Emit::Bytecode => {
+                    let output = output.unwrap_or_else(|| PathBuf::from("bytecode"));
+                    let program = assemble_file(&source, &options)?;
+                    report_diagnostics(&program)?;
+                    let signing_key = match sign {
+                        Some(keyfile) => {
+                            Some(std::fs::read_to_string(&keyfile).with_context(|| {
+                                format!("Could not read signing key {}", keyfile.display())
+                            })?)
+                        }
+                        None => None,
+                    };
+                    let encode_options = EncodeOptions {
+                        compress,
+                        endian: match little_endian {
+                            true => Endian::Little,
+                            false => Endian::Big,
+                        },
+                        signing_key,
+                    };
+                    emit_bytecode(&output, &program, &encode_options)
+                        .context("Could not emit bytecode")?;
+                    tracing::info!("Emitted bytecode to {}", output.display());
+                }
+                Emit::Json => {
+                    let ir = parse_file_ir(&source, &options).context("Could not parse program")?;
+                    let json = serde_json::to_string_pretty(&ir)?;
+                    match output {
+                        Some(output) => {
+                            std::fs::write(output, json).context("Could not write ir")?
+                        }
+                        None => println!("{json}"),
+                    }
+                }
+            }
         }
-    }
-
-    // now we convert our function labels into constants
-    let mut after_function_labels = vec![];
-    let mut instruction_number = 0;
-    for value in after_constant_remapping.iter() {
-        match value {
-            ProgramValue::FunctionLabel(label) => {
-                constants.insert(label.to_string(), instruction_number);
+        Command::Run {
+            file,
+            profile,
+            folded,
+            costs,
+            cycles,
+            max_steps,
+            timeout,
+            max_stack,
+            max_frames,
+            max_heap,
+            memory_stats,
+            stats,
+            implicit_halt,
+            strict_loads,
+            stack_dump,
+            overflow,
+            nondeterminism,
+            core_dump,
+            explain,
+            events,
+            plugins,
+            verify,
+        } => {
+            let mut builder = Cpu::builder()
+                .implicit_halt(implicit_halt)
+                .strict_loads(strict_loads)
+                .explain(explain)
+                .stack_dump_format(match stack_dump {
+                    StackDump::Text => StackDumpFormat::Text,
+                    StackDump::Json => StackDumpFormat::JsonLines,
+                    StackDump::Pretty => StackDumpFormat::Pretty,
+                })
+                .overflow(match overflow {
+                    OverflowMode::Trap => Overflow::Trap,
+                    OverflowMode::Wrap => Overflow::Wrap,
+                    OverflowMode::Saturate => Overflow::Saturate,
+                })
+                .nondeterminism(match nondeterminism {
+                    NondeterminismMode::Allow => Nondeterminism::Allow,
+                    NondeterminismMode::Record => Nondeterminism::Record,
+                    NondeterminismMode::Forbid => Nondeterminism::Forbid,
+                });
+            if let Some(costs) = costs {
+                builder = builder.cost_model(CostModel::from_toml_file(costs)?);
+            }
+            if let Some(max_steps) = max_steps {
+                builder = builder.max_steps(max_steps);
+            }
+            if let Some(timeout) = timeout {
+                builder = builder.timeout(timeout);
+            }
+            if max_steps.is_some() || timeout.is_some() || core_dump.is_some() {
+                builder = builder.trace_length(TRACE_LENGTH);
+            }
+            builder = builder.memory_limits(MemoryLimits {
+                stack_words: max_stack,
+                frames: max_frames,
+                heap_words: max_heap,
+            });
+            let mut cpu = builder.build();
+            for plugin in plugins {
+                let names = plugin::load_plugins(&mut cpu, &plugin)?;
+                tracing::info!("Loaded {} from {}", names.join(", "), plugin.display());
+            }
+            let program = match verify {
+                Some(pubkey) => {
+                    let public_key = std::fs::read_to_string(&pubkey).with_context(|| {
+                        format!("Could not read public key {}", pubkey.display())
+                    })?;
+                    if !bytecode::is_hex_path(&file) && !bytecode::has_magic(&file)? {
+                        bail!("Only bytecode can be signed, {} is source", file.display())
+                    }
+                    bytecode::load_signed_bytecode(&file, &public_key)?
+                }
+                // sources can call plugin functions by name.
+                None => load_or_assemble_with(&file, cpu.host_function_defines())?,
+            };
+            report_diagnostics(&program)?;
+            cpu.load_program(program.clone());
+            if profile || folded.is_some() {
+                cpu.enable_profiling();
+            }
+            if let Some(events) = events {
+                cpu.set_event_sink(open_event_sink(&events)?);
+            }
+            cancel_on_ctrl_c(cpu.cancel_handle())?;
+            let outcome = match cpu.run() {
+                Ok(outcome) => outcome,
+                Err(err) => {
+                    if err.is::<Cancelled>() {
+                        eprintln!("Interrupted");
+                        eprint!("{cpu}");
+                        // where it was about to go, not what it last did.
+                        let mut dump = cpu.core_dump(&err);
+                        dump.address = cpu.ip();
+                        eprint!(
+                            "call stack, innermost first:\n{}",
+                            coredump::backtrace(&dump)?
+                        );
+                    }
+                    if let Some(core_dump) = core_dump {
+                        cpu.core_dump(&err).save(&core_dump)?;
+                        tracing::info!("Wrote core dump to {}", core_dump.display());
+                    }
+                    return Err(err.context("Could not run program"));
+                }
+            };
+            if let Some(profiler) = cpu.profiler() {
+                if profile {
+                    print!("{}", profiler::to_text(&profiler.report(&program)));
+                }
+                if let Some(folded) = folded {
+                    std::fs::write(folded, profiler.folded_stacks(&program))
+                        .context("Could not write folded stacks")?;
+                }
+            }
+            let last_value = outcome
+                .exit_value
+                .context("Could not get last return value")?;
+            println!("we ran our dumb program and all we got was {last_value}");
+            if cycles {
+                println!("took {} cycles", cpu.cycles());
+            }
+            for input in cpu.host_inputs() {
+                println!(
+                    "step {} at {}: {} gave {:?}",
+                    input.step, input.address, input.source, input.words
+                );
+            }
+            if memory_stats {
+                let stats = cpu.memory_stats();
+                println!(
+                    "peak memory: {} stack words, {} frames, {} heap words",
+                    stats.peak_stack_words, stats.peak_frames, stats.peak_heap_words
+                );
+            }
+            if stats {
+                println!(
+                    "instructions: {}, calls: {}, max stack depth: {}, max frame depth: {}, elapsed: {:?}",
+                    outcome.steps,
+                    outcome.calls,
+                    outcome.max_stack_depth,
+                    outcome.max_frame_depth,
+                    outcome.elapsed
+                );
+            }
+        }
+        Command::Watch { source, timeout } => watch(&source, timeout)?,
+        Command::Inspect { dump } => {
+            print!("{}", coredump::to_text(&CoreDump::load(&dump)?)?);
+        }
+        Command::Diff { before, after } => {
+            let (before, after) = (CoreDump::load(&before)?, CoreDump::load(&after)?);
+            print!("{}", coredump::diff_to_text(&before, &after));
+        }
+        Command::Disasm { file, explain } => {
+            let program = load_or_assemble(&file)?;
+            match explain {
+                true => print!("{}", disassembler::disassemble_explained(&program)?),
+                false => print!("{}", disassembler::disassemble(&program)?),
+            }
+        }
+        Command::Cfg { file, output } => {
+            let program = load_or_assemble(&file)?;
+            let dot = cfg::to_dot(&program)?;
+            match output {
+                Some(output) => std::fs::write(output, dot).context("Could not write graph")?,
+                None => print!("{dot}"),
+            }
+        }
+        Command::Calls { file, dot } => {
+            let program = load_or_assemble(&file)?;
+            let functions = callgraph::call_graph(&program)?;
+            if dot {
+                print!("{}", callgraph::to_dot(&functions));
+            } else {
+                print!("{}", callgraph::to_text(&functions));
+            }
+        }
+        Command::Analyze { file } => {
+            let program = load_or_assemble(&file)?;
+            print!(
+                "{}",
+                stackdepth::to_text(&stackdepth::stack_depths(&program)?)
+            );
+        }
+        Command::Lint { file } => {
+            let program = load_or_assemble(&file)?;
+            let lints = lint::lint(&program)?;
+            let debug_info = program.debug_info();
+            for lint in lints.iter() {
+                match debug_info.lines.get(&(lint.address as i64)) {
+                    Some(line) => {
+                        let file = debug_info.file.as_deref().unwrap_or("<source>");
+                        println!("{file}:{line}: {lint}");
+                    }
+                    None => println!("{lint}"),
+                }
+            }
+            if !lints.is_empty() {
+                bail!("{} problem(s) found", lints.len())
+            }
+        }
+        Command::Replay {
+            file,
+            trace,
+            plugins,
+        } => {
+            let mut cpu = Cpu::new();
+            for plugin in plugins {
+                plugin::load_plugins(&mut cpu, &plugin)?;
+            }
+            let program = load_or_assemble_with(&file, cpu.host_function_defines())?;
+            cpu.load_program(program);
+            let events = File::open(&trace)
+                .with_context(|| format!("Could not open trace {}", trace.display()))?;
+            let steps = replay::replay(&mut cpu, std::io::BufReader::new(events))?;
+            println!("all {steps} steps matched");
+        }
+        Command::Testgen {
+            output,
+            count,
+            seed,
+            size,
+        } => {
+            std::fs::create_dir_all(&output).context("Could not create output directory")?;
+            for seed in seed..seed + count {
+                let program = testgen::generate(seed, size);
+                emit_bytecode(
+                    output.join(format!("{seed}.bc")),
+                    &program,
+                    &EncodeOptions::default(),
+                )
+                .context("Could not emit bytecode")?;
+            }
+            tracing::info!("Wrote {count} programs to {}", output.display());
+        }
+        Command::Debug { file, script } => {
+            let program = load_or_assemble(&file)?;
+            report_diagnostics(&program)?;
+            let mut cpu = Cpu::new();
+            cpu.load_program(program);
+            let mut debugger = Debugger::new(cpu);
+            match script {
+                Some(script) => {
+                    let commands = std::fs::read_to_string(&script)
+                        .with_context(|| format!("Could not read {}", script.display()))?;
+                    debugger.run_script(&commands, std::io::stdout())?
+                }
+                None => debugger.run(std::io::stdin().lock(), std::io::stdout())?,
+            }
+        }
+        Command::Repl { forth } => {
+            let mut repl = match forth {
+                true => Repl::forth(),
+                false => Repl::new(),
+            };
+            repl.run(std::io::stdin().lock(), std::io::stdout())?
+        }
+        Command::Compile {
+            source,
+            output,
+            asm,
+        } => {
+            let text = std::fs::read_to_string(&source).context("Could not open file")?;
+            let lisp = matches!(
+                source.extension().and_then(|extension| extension.to_str()),
+                Some("scm" | "lisp")
+            );
+            if lisp {
+                let program = sexpr::compile(&text, Some(source.display().to_string()))
+                    .context("Could not compile")?;
+                match (asm, output) {
+                    // there's no assembly in between, so show what came out.
+                    (true, Some(output)) => {
+                        std::fs::write(output, disassembler::disassemble(&program)?)
+                            .context("Could not write assembly")?
+                    }
+                    (true, None) => print!("{}", disassembler::disassemble(&program)?),
+                    (false, output) => {
+                        let output = output.unwrap_or_else(|| PathBuf::from("bytecode"));
+                        emit_bytecode(&output, &program, &EncodeOptions::default())
+                            .context("Could not emit bytecode")?;
+                        tracing::info!("Emitted bytecode to {}", output.display());
+                    }
+                }
+            } else if asm {
+                let assembly = lang::compile_to_assembly(&text).context("Could not compile")?;
+                match output {
+                    Some(output) => {
+                        std::fs::write(output, assembly).context("Could not write assembly")?
+                    }
+                    None => print!("{assembly}"),
+                }
+            } else {
+                let options = AssemblerOptions {
+                    source_name: Some(source.display().to_string()),
+                    ..Default::default()
+                };
+                let program = lang::compile(&text, &options).context("Could not compile")?;
+                let output = output.unwrap_or_else(|| PathBuf::from("bytecode"));
+                emit_bytecode(&output, &program, &EncodeOptions::default())
+                    .context("Could not emit bytecode")?;
+                tracing::info!("Emitted bytecode to {}", output.display());
+            }
+        }
+        Command::Exprc {
+            expression,
+            bindings,
+            output,
+        } => {
+            let compiled = exprc::compile(&expression, &bindings.into_iter().collect())
+                .context("Could not compile expression")?;
+            match output {
+                Some(output) => {
+                    emit_bytecode(&output, &compiled.program, &EncodeOptions::default())
+                        .context("Could not emit bytecode")?;
+                    for (slot, name) in compiled.variables.iter().enumerate() {
+                        tracing::info!("{name} is variable {slot}");
+                    }
+                }
+                None => {
+                    let mut cpu = Cpu::new();
+                    cpu.load_program(compiled.program);
+                    let outcome = cpu.run().context("Could not run expression")?;
+                    if let Some(value) = outcome.exit_value {
+                        println!("{value}");
+                    }
+                }
             }
-            program_value => {
-                instruction_number += 1;
-                after_function_labels.push(program_value);
+        }
+        Command::Fmt {
+            source,
+            write,
+            check,
+        } => {
+            let text = std::fs::read_to_string(&source).context("Could not open file")?;
+            let formatted = assembler::format_source(&text).context("Could not parse program")?;
+            if check {
+                if formatted != text {
+                    bail!("{} is not formatted", source.display())
+                }
+            } else if write {
+                std::fs::write(&source, formatted).context("Could not write file")?;
+            } else {
+                print!("{formatted}");
             }
         }
     }
+    Ok(())
+}
 
-    // now rename our constants
-    let mut after_renaming = vec![];
-    let mut after_label_iter = after_function_labels.into_iter();
+// rebuild and rerun forever. When the source doesn't assemble we keep watching
+// the files from the last good build, so fixing a module brings us back.
+fn watch(source: &Path, timeout: Duration) -> Result<()> {
+    let mut watched = vec![source.to_path_buf()];
     loop {
-        let Some(value) = after_label_iter.next() else {
-            // token stream complete.
-            break;
-        };
-
-        // now destructure the labels
-        match value {
-            ProgramValue::Label(name) => {
-                let Some(constant) = constants.get(name) else {
-                    bail!("Used undeclared constant {name}")
-                };
-                after_renaming.push(ProgramValue::Value(*constant));
-            }
-            program_value => after_renaming.push(program_value.clone()),
+        tracing::info!("Assembling {}", source.display());
+        match assemble_and_run(source, timeout, &mut watched) {
+            Ok(value) => println!("result: {value}"),
+            Err(err) => eprintln!("error: {err:#}"),
         }
+        wait_for_change(&watched);
     }
+}
 
-    // now everything should be just a stream of instructions and values
-    // we can convert to just numbers
-    let mut out = vec![];
-    for value in after_renaming.into_iter() {
-        match value {
-            ProgramValue::Instruction(inst) => out.push(inst),
-            ProgramValue::Value(val) => out.push(val),
-            value => {
-                bail!("Invalid value leaked through {value:?}")
-            }
+fn assemble_and_run(source: &Path, timeout: Duration, watched: &mut Vec<PathBuf>) -> Result<i64> {
+    let options = AssemblerOptions {
+        source_name: Some(source.display().to_string()),
+        ..Default::default()
+    };
+    let ir = parse_file_ir(source, &options).context("Could not parse program")?;
+    *watched = std::iter::once(source.to_path_buf())
+        .chain(ir.modules.iter().map(|module| PathBuf::from(&module.file)))
+        .collect();
+    let program = assembler::lower(&ir).context("Could not parse program")?;
+    report_diagnostics(&program)?;
+
+    let mut cpu = Cpu::builder()
+        .timeout(timeout)
+        .trace_length(TRACE_LENGTH)
+        .build();
+    cpu.load_program(program);
+    cpu.run()
+        .context("Could not run program")?
+        .exit_value
+        .context("Could not get last return value")
+}
+
+// block until any of the files is modified, created or deleted.
+fn wait_for_change(files: &[PathBuf]) {
+    let modified = || -> Vec<Option<SystemTime>> {
+        files
+            .iter()
+            .map(|file| std::fs::metadata(file).and_then(|m| m.modified()).ok())
+            .collect()
+    };
+    let before = modified();
+    loop {
+        std::thread::sleep(WATCH_INTERVAL);
+        if modified() != before {
+            return;
         }
     }
-    Ok(out)
 }
 
-fn emit_bytecode(filename: String, instructions: Vec<i64>) -> Result<()> {
-    let mut file = std::fs::File::create(filename).context("Unable to create outfile")?;
-    for instruction in instructions.into_iter() {
-        file.write(&instruction.to_be_bytes())
-            .context("Could not write instruction")?;
+// logs go to stderr so they never mix with the program's output.
+fn init_logging(verbose: u8, quiet: bool) {
+    let level = match (quiet, verbose) {
+        (true, _) => LevelFilter::WARN,
+        (false, 0) => LevelFilter::INFO,
+        (false, 1) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    };
+    let filter = EnvFilter::builder()
+        .with_default_directive(level.into())
+        .from_env_lossy();
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+// print verifier findings, and refuse to go on if any of them are errors.
+fn report_diagnostics(program: &Program) -> Result<()> {
+    let diagnostics = verifier::verify(program)?;
+    for diagnostic in diagnostics.iter() {
+        eprintln!("{diagnostic}");
+    }
+    if verifier::has_errors(&diagnostics) {
+        bail!("Program failed verification")
     }
-    file.flush().context("Could not flush file")?;
     Ok(())
 }
 
-fn load_bytecode(filename: String) -> Result<Vec<i64>> {
-    let file = std::fs::read(filename).context("Could not open file")?;
+// `name=value`, the name gets the label colon if it doesn't have one.
+fn parse_binding(binding: &str) -> Result<(String, i64)> {
+    let Some((name, value)) = binding.split_once('=') else {
+        bail!("Expected name=value, got {binding:?}")
+    };
+    let value = value
+        .parse()
+        .with_context(|| format!("{value:?} isn't a number"))?;
+    Ok((name.to_string(), value))
+}
 
-    let mut instructions = vec![];
-    for chunk in file.as_slice().chunks(8) {
-        let buf: [u8; 8] = chunk.try_into().unwrap();
-        instructions.push(i64::from_be_bytes(buf));
+fn parse_define(define: &str) -> Result<(String, i64)> {
+    let Some((name, value)) = define.split_once('=') else {
+        bail!("Expected name=value, got {define:?}")
+    };
+    if name.is_empty() {
+        bail!("Missing a name in {define:?}")
     }
-    Ok(instructions)
+    let name = match name.starts_with(':') {
+        true => name.to_string(),
+        false => format!(":{name}"),
+    };
+    let value = value
+        .parse()
+        .with_context(|| format!("{value:?} isn't a number"))?;
+    Ok((name, value))
 }
 
-fn main() {
-    let incoming_program =
-        std::fs::read_to_string("/Users/patrickcrawford/dev/projects/stackvm/progn")
-            .expect("Could not load program");
-    println!("loaded program from disk");
-
-    let parsed = match parse_program(incoming_program) {
-        Ok(parsed) => parsed,
-        Err(err) => panic!("Could not parse program {err:#}"),
+// a file, or a socket something is already listening on.
+fn open_event_sink(target: &str) -> Result<BufWriter<Box<dyn Write + Send>>> {
+    let sink: Box<dyn Write + Send> = match target.strip_prefix("tcp://") {
+        Some(address) => Box::new(
+            TcpStream::connect(address)
+                .with_context(|| format!("Could not connect to {address}"))?,
+        ),
+        None => Box::new(File::create(target).context("Could not create events file")?),
     };
-    println!("parsed program");
+    Ok(BufWriter::new(sink))
+}
 
-    emit_bytecode("bytecode".to_string(), parsed).expect("Could not emit bytecode");
-    println!("Emitted bytecode");
+fn assemble_file(source: &Path, options: &AssemblerOptions) -> Result<Program> {
+    let _span = tracing::info_span!("assemble", file = %source.display()).entered();
+    // `-` assembles whatever is piped in, without reading it all in first.
+    if source == Path::new("-") {
+        return assembler::assemble_reader(std::io::stdin().lock(), options)
+            .context("Could not parse program");
+    }
+    assembler::assemble_file(source, options).context("Could not parse program")
+}
 
-    let bytecode = load_bytecode("bytecode".to_string()).expect("Could not load bytecode");
-    println!("loaded bytecode");
+// the first Ctrl-C stops the program before its next instruction, a second
+// one gives up waiting and exits straight away.
+fn cancel_on_ctrl_c(handle: CancelHandle) -> Result<()> {
+    let action = move || {
+        if handle.is_cancelled() {
+            signal_hook::low_level::exit(130);
+        }
+        handle.cancel();
+    };
+    // SAFETY: it only touches an atomic and calls _exit, both of which are
+    // fine in a signal handler.
+    unsafe { signal_hook::low_level::register(signal_hook::consts::SIGINT, action) }
+        .context("Could not handle Ctrl-C")?;
+    Ok(())
+}
 
-    let mut cpu = Cpu::new();
-    cpu.load_program(bytecode);
-    cpu.run().expect("Could not run program");
-    let last_value = cpu
-        .get_latest_return_value()
-        .expect("Could not get last return value");
-    println!("we ran our dumb program and all we got was {last_value}");
+// anything that isn't .hexbc and doesn't start with the bytecode magic is
+// treated as source.
+fn load_or_assemble(file: &Path) -> Result<Program> {
+    load_or_assemble_with(file, BTreeMap::new())
+}
+
+// with `defines` for the assembler if it's source.
+fn load_or_assemble_with(file: &Path, defines: BTreeMap<String, i64>) -> Result<Program> {
+    if bytecode::is_hex_path(file) || bytecode::has_magic(file)? {
+        load_bytecode(file)
+    } else {
+        let options = AssemblerOptions {
+            source_name: Some(file.display().to_string()),
+            defines,
+            ..Default::default()
+        };
+        assemble_file(file, &options)
+    }
 }
@@ -0,0 +1,237 @@
+// host functions from dynamic libraries, so `run --plugin` can give programs
+// more syscalls without rebuilding biteycode. Loading them needs the
+// `plugins` feature. A plugin exports `biteycode_plugin`, which hands back a
+// description of its functions:
+//
+//     unsafe extern "C" fn double(args: *const i64, results: *mut i64) -> i32 {
+//         *results = *args * 2;
+//         0
+//     }
+//
+//     static FUNCTIONS: [PluginFunction; 1] = [PluginFunction {
+//         name: c"double".as_ptr(),
+//         args: 1,
+//         rets: 1,
+//         call: double,
+//     }];
+//
+//     #[no_mangle]
+//     pub extern "C" fn biteycode_plugin() -> *const PluginDescriptor {
+//         static PLUGIN: PluginDescriptor = PluginDescriptor {
+//             abi_version: PLUGIN_ABI_VERSION,
+//             functions: FUNCTIONS.as_ptr(),
+//             function_count: 1,
+//         };
+//         &PLUGIN
+//     }
+//
+// Everything it points at has to live as long as the library is loaded.
+
+use std::any::Any;
+use std::ffi::{c_char, CStr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+
+use crate::cpu::Cpu;
+use crate::host::Binding;
+
+// bumped whenever the structs below change shape. A plugin built against a
+// different version is refused rather than called wrongly.
+pub const PLUGIN_ABI_VERSION: u32 = 1;
+
+// the symbol every plugin exports, a PluginEntry.
+pub const PLUGIN_ENTRY: &str = "biteycode_plugin";
+
+pub type PluginEntry = unsafe extern "C" fn() -> *const PluginDescriptor;
+
+#[repr(C)]
+pub struct PluginDescriptor {
+    pub abi_version: u32,
+    pub functions: *const PluginFunction,
+    pub function_count: usize,
+}
+
+#[repr(C)]
+pub struct PluginFunction {
+    // nul terminated UTF-8, what `syscall :name` calls it.
+    pub name: *const c_char,
+    pub args: usize,
+    pub rets: usize,
+    // reads `args` words, deepest first, and writes `rets` words to push.
+    // Anything but 0 fails the instruction.
+    pub call: unsafe extern "C" fn(args: *const i64, results: *mut i64) -> i32,
+}
+
+// so plugins can keep them in statics. They only point at things that are
+// never written to.
+unsafe impl Sync for PluginDescriptor {}
+unsafe impl Sync for PluginFunction {}
+
+// bind every function in the plugin at `path`, or in every library in
+// `path` if it's a directory. Returns the names bound.
+pub fn load_plugins(cpu: &mut Cpu, path: &Path) -> Result<Vec<String>> {
+    let mut libraries = vec![];
+    if path.is_dir() {
+        for entry in std::fs::read_dir(path).context("Could not read plugin directory")? {
+            let library = entry?.path();
+            if library.extension().and_then(|e| e.to_str()) == Some(std::env::consts::DLL_EXTENSION)
+            {
+                libraries.push(library);
+            }
+        }
+        // so later plugins override earlier ones the same way every time.
+        libraries.sort();
+    } else {
+        libraries.push(path.to_path_buf());
+    }
+
+    let mut names = vec![];
+    for library in libraries {
+        let bindings = open(&library)
+            .with_context(|| format!("Could not load plugin {}", library.display()))?;
+        for binding in bindings {
+            names.push(binding.name.clone());
+            cpu.bind_dynamic(binding);
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(feature = "plugins")]
+fn open(path: &Path) -> Result<Vec<Binding>> {
+    // there's no checking what a library does when it's loaded or called,
+    // plugins are trusted like the binary itself.
+    unsafe {
+        let library = libloading::Library::new(path)?;
+        let entry: libloading::Symbol<PluginEntry> = library.get(PLUGIN_ENTRY.as_bytes())?;
+        let descriptor = entry();
+        if descriptor.is_null() {
+            bail!("{PLUGIN_ENTRY} returned nothing")
+        }
+        bindings(&*descriptor, Arc::new(library))
+    }
+}
+
+#[cfg(not(feature = "plugins"))]
+fn open(_path: &Path) -> Result<Vec<Binding>> {
+    bail!("Plugins need biteycode built with the plugins feature")
+}
+
+// `library` is whatever has to stay loaded for the functions to be called.
+//
+// SAFETY: the descriptor and everything it points at must be valid while
+// `library` is.
+#[cfg_attr(not(feature = "plugins"), allow(dead_code))]
+unsafe fn bindings(
+    descriptor: &PluginDescriptor,
+    library: Arc<dyn Any + Send + Sync>,
+) -> Result<Vec<Binding>> {
+    if descriptor.abi_version != PLUGIN_ABI_VERSION {
+        bail!(
+            "Plugin ABI version is {}, but this biteycode only supports {PLUGIN_ABI_VERSION}",
+            descriptor.abi_version
+        )
+    }
+    let functions = match descriptor.function_count {
+        0 => &[][..],
+        count => std::slice::from_raw_parts(descriptor.functions, count),
+    };
+    let mut bindings = vec![];
+    for function in functions {
+        let name = CStr::from_ptr(function.name)
+            .to_str()
+            .context("Plugin function name isn't UTF-8")?
+            .to_string();
+        let (args, rets, call) = (function.args, function.rets, function.call);
+        let library = library.clone();
+        let error_name = name.clone();
+        bindings.push(Binding {
+            name,
+            args,
+            call: Arc::new(Mutex::new(move |words: &[i64]| {
+                // the library stays loaded for as long as this is bound.
+                let _loaded = &library;
+                let mut results = vec![0; rets];
+                let code = call(words.as_ptr(), results.as_mut_ptr());
+                if code != 0 {
+                    bail!("{error_name} returned error {code}")
+                }
+                Ok(results)
+            })),
+        });
+    }
+    Ok(bindings)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::{parse_program, AssemblerOptions};
+
+    unsafe extern "C" fn add(args: *const i64, results: *mut i64) -> i32 {
+        let args = std::slice::from_raw_parts(args, 2);
+        *results = args[0] + args[1];
+        0
+    }
+
+    unsafe extern "C" fn fail(_: *const i64, _: *mut i64) -> i32 {
+        3
+    }
+
+    static FUNCTIONS: [PluginFunction; 2] = [
+        PluginFunction {
+            name: c"add".as_ptr(),
+            args: 2,
+            rets: 1,
+            call: add,
+        },
+        PluginFunction {
+            name: c"fail".as_ptr(),
+            args: 0,
+            rets: 0,
+            call: fail,
+        },
+    ];
+
+    fn descriptor(abi_version: u32) -> PluginDescriptor {
+        PluginDescriptor {
+            abi_version,
+            functions: FUNCTIONS.as_ptr(),
+            function_count: FUNCTIONS.len(),
+        }
+    }
+
+    fn run(source: &str) -> Result<Vec<i64>> {
+        let mut cpu = Cpu::new();
+        for binding in unsafe { bindings(&descriptor(PLUGIN_ABI_VERSION), Arc::new(()))? } {
+            cpu.bind_dynamic(binding);
+        }
+        let options = AssemblerOptions {
+            defines: cpu.host_function_defines(),
+            ..Default::default()
+        };
+        cpu.load_program(parse_program(source.to_string(), &options)?);
+        cpu.run()?;
+        Ok(cpu.stack().to_vec())
+    }
+
+    #[test]
+    fn plugin_functions() {
+        assert_eq!(vec![5], run("push 2\npush 3\nsyscall :add\nhalt").unwrap());
+        let error = run("syscall :fail\nhalt").unwrap_err();
+        assert_eq!("fail returned error 3", error.root_cause().to_string());
+    }
+
+    #[test]
+    fn abi_version_is_checked() {
+        let error = unsafe { bindings(&descriptor(2), Arc::new(())) }
+            .err()
+            .unwrap();
+        assert_eq!(
+            "Plugin ABI version is 2, but this biteycode only supports 1",
+            error.to_string()
+        );
+    }
+}
@@ -0,0 +1,280 @@
+// runs lots of programs nobody vouched for side by side. Each gets its own
+// machine with its own limits, and they take turns a fixed number of steps
+// at a time, so a program that loops forever only slows the others down
+// until its fuel runs out. Whatever one program does, failing or panicking
+// the vm included, ends up in its own report and nobody else's.
+//
+// run_batch() is for the other case, lots of programs that are trusted to
+// finish, run start to end across every core with the `parallel` feature.
+
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+
+use crate::cpu::{Cpu, MemoryLimits, MemoryStats, Outcome, RunOutcome, SharedOutput};
+use crate::program::Program;
+
+// steps each program gets per turn unless told otherwise.
+pub const DEFAULT_SLICE: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TenantLimits {
+    // instructions the program may execute in total.
+    pub fuel: u64,
+    pub memory: MemoryLimits,
+}
+
+// how one program got on.
+#[derive(Debug)]
+pub struct TenantReport {
+    pub name: String,
+    // how it ended, or why it was stopped.
+    pub result: Result<RunOutcome, String>,
+    // what it printed.
+    pub output: Vec<u8>,
+    pub steps: u64,
+    // how many times it was scheduled.
+    pub turns: u64,
+    pub memory: MemoryStats,
+    // time spent running it, not waiting for its turn.
+    pub elapsed: Duration,
+}
+
+struct Tenant {
+    name: String,
+    cpu: Cpu,
+    output: SharedOutput,
+    turns: u64,
+    elapsed: Duration,
+    // set once it's done, one way or another.
+    result: Option<Result<RunOutcome, String>>,
+}
+
+impl Tenant {
+    fn new(name: String, program: Program, limits: TenantLimits) -> Self {
+        let mut cpu = Cpu::builder()
+            .max_steps(limits.fuel)
+            .memory_limits(limits.memory)
+            .write_protect_code(true)
+            .build();
+        let output = SharedOutput::default();
+        cpu.set_output(output.clone());
+        cpu.load_program(program);
+        Self {
+            name,
+            cpu,
+            output,
+            turns: 0,
+            elapsed: Duration::ZERO,
+            result: None,
+        }
+    }
+
+    // run for up to `steps`, or to the end with None. False if it needs
+    // another turn.
+    fn turn(&mut self, steps: Option<u64>) -> bool {
+        self.turns += 1;
+        let started = Instant::now();
+        let cpu = &mut self.cpu;
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| match steps {
+            Some(steps) => cpu.run_slice(steps),
+            None => cpu.run().map(|_| Outcome::Halted),
+        }));
+        self.elapsed += started.elapsed();
+        self.result = Some(match outcome {
+            // a BRK has nobody to stop for here.
+            Ok(Ok(Outcome::Yielded | Outcome::Breakpoint(_))) => return false,
+            Ok(Ok(Outcome::Halted)) => Ok(cpu.outcome()),
+            Ok(Ok(Outcome::Cancelled)) => Err("Cancelled".to_string()),
+            Ok(Err(error)) => Err(format!("{error:#}")),
+            Err(panic) => Err(match panic.downcast_ref::<&str>() {
+                Some(message) => format!("The vm panicked: {message}"),
+                None => match panic.downcast_ref::<String>() {
+                    Some(message) => format!("The vm panicked: {message}"),
+                    None => "The vm panicked".to_string(),
+                },
+            }),
+        });
+        true
+    }
+
+    fn report(self) -> TenantReport {
+        let output = std::mem::take(&mut *self.output.0.lock().unwrap());
+        TenantReport {
+            name: self.name,
+            result: self.result.unwrap(),
+            output,
+            steps: self.cpu.steps(),
+            turns: self.turns,
+            memory: self.cpu.memory_stats(),
+            elapsed: self.elapsed,
+        }
+    }
+}
+
+pub struct VmPool {
+    slice: u64,
+    tenants: Vec<Tenant>,
+}
+
+impl Default for VmPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_SLICE)
+    }
+}
+
+impl VmPool {
+    // each turn is `slice` steps, at least 1.
+    pub fn new(slice: u64) -> Self {
+        Self {
+            slice: slice.max(1),
+            tenants: vec![],
+        }
+    }
+
+    // queue `program` up to run. Returns its index in the reports.
+    pub fn add(&mut self, name: &str, program: Program, limits: TenantLimits) -> usize {
+        self.tenants
+            .push(Tenant::new(name.to_string(), program, limits));
+        self.tenants.len() - 1
+    }
+
+    // take turns until every program has halted or failed, then say how
+    // each went, in the order they were added.
+    pub fn run(mut self) -> Vec<TenantReport> {
+        let mut queue: VecDeque<usize> = (0..self.tenants.len()).collect();
+        while let Some(index) = queue.pop_front() {
+            if !self.tenants[index].turn(Some(self.slice)) {
+                queue.push_back(index);
+            }
+        }
+        self.tenants.into_iter().map(Tenant::report).collect()
+    }
+}
+
+// run each program with its input pushed first, deepest first, each on its
+// own machine. With the `parallel` feature they're spread over a thread
+// pool, otherwise they run one after another. Reports are named by index and
+// come back in the same order.
+pub fn run_batch(
+    programs: Vec<Program>,
+    inputs: Vec<Vec<i64>>,
+    limits: TenantLimits,
+) -> Result<Vec<TenantReport>> {
+    if programs.len() != inputs.len() {
+        bail!(
+            "{} programs but {} inputs, there should be one each",
+            programs.len(),
+            inputs.len()
+        )
+    }
+    let jobs: Vec<(usize, (Program, Vec<i64>))> =
+        programs.into_iter().zip(inputs).enumerate().collect();
+    let run = |(index, (program, input)): (usize, (Program, Vec<i64>))| {
+        let mut tenant = Tenant::new(index.to_string(), program, limits);
+        match input.iter().try_for_each(|value| tenant.cpu.push(*value)) {
+            Ok(()) => {
+                tenant.turn(None);
+            }
+            Err(error) => tenant.result = Some(Err(format!("{error:#}"))),
+        }
+        tenant.report()
+    };
+
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        Ok(jobs.into_par_iter().map(run).collect())
+    }
+    #[cfg(not(feature = "parallel"))]
+    Ok(jobs.into_iter().map(run).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::{parse_program, AssemblerOptions};
+
+    fn program(source: &str) -> Program {
+        parse_program(source.to_string(), &AssemblerOptions::default()).unwrap()
+    }
+
+    const LIMITS: TenantLimits = TenantLimits {
+        fuel: 1000,
+        memory: MemoryLimits {
+            stack_words: Some(16),
+            frames: None,
+            heap_words: None,
+        },
+    };
+
+    #[test]
+    fn failures_stay_with_their_tenant() {
+        let mut pool = VmPool::new(10);
+        pool.add("spin", program(":top\njmp :top"), LIMITS);
+        pool.add(
+            "hello",
+            program("push 104\nprnchr\npush 105\nprnchr\npush 7\nhalt"),
+            LIMITS,
+        );
+        pool.add("divide", program("push 1\npush 0\ndiv\nhalt"), LIMITS);
+        pool.add("grow", program(":top\npush 1\njmp :top"), LIMITS);
+
+        let reports = pool.run();
+        let names: Vec<&str> = reports.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(vec!["spin", "hello", "divide", "grow"], names);
+
+        let error = |index: usize| reports[index].result.clone().unwrap_err();
+        assert!(error(0).contains("Step limit of 1000 reached"));
+        assert_eq!(1000, reports[0].steps);
+        assert_eq!(Some(7), reports[1].result.as_ref().unwrap().exit_value);
+        assert_eq!(b"hi".to_vec(), reports[1].output);
+        assert!(error(2).contains("Division by zero"));
+        assert!(error(3).contains("Stack limit of 16 words exceeded"));
+        assert_eq!(16, reports[3].memory.peak_stack_words);
+    }
+
+    #[test]
+    fn turns_are_shared_fairly() {
+        let mut pool = VmPool::new(5);
+        // 3 instructions a time round the loop, 10 times.
+        let count = program("push 10\n:top\npush 1\nsub\ndup\njif :top\nhalt");
+        pool.add("long", program(":top\njmp :top"), LIMITS);
+        pool.add("short", count, LIMITS);
+        let reports = pool.run();
+        // the loop never gets more than its share while the other is running.
+        assert_eq!(Some(0), reports[1].result.as_ref().unwrap().exit_value);
+        assert_eq!(42, reports[1].steps);
+        assert_eq!(9, reports[1].turns);
+        // and one more to find its fuel has run out.
+        assert_eq!(201, reports[0].turns);
+    }
+
+    #[test]
+    fn batches() {
+        let square = || program("dup\nmul\nhalt");
+        let programs = vec![square(), square(), program(":top\njmp :top")];
+        let inputs = vec![vec![3], vec![-5], vec![]];
+        let reports = run_batch(programs, inputs, LIMITS).unwrap();
+        let values: Vec<_> = reports
+            .iter()
+            .map(|report| report.result.as_ref().map(|outcome| outcome.exit_value))
+            .collect();
+        assert_eq!(Ok(Some(9)), values[0]);
+        assert_eq!(Ok(Some(25)), values[1]);
+        assert!(values[2].unwrap_err().contains("Step limit"));
+        assert_eq!("1", reports[1].name);
+        assert_eq!(1, reports[1].turns);
+
+        let too_much = vec![vec![0; 20]];
+        let reports = run_batch(vec![square()], too_much, LIMITS).unwrap();
+        assert!(reports[0]
+            .result
+            .clone()
+            .unwrap_err()
+            .contains("Stack limit"));
+        assert!(run_batch(vec![square()], vec![], LIMITS).is_err());
+    }
+}
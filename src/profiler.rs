@@ -0,0 +1,185 @@
+// per-function profiling. the cpu tells us about every instruction, heap
+// allocation and call/return, and we attribute instruction counts,
+// allocations and wall-clock time to whatever function is on top of the
+// call stack.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use crate::program::Program;
+
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    // function start addresses, the entry point is always at the bottom.
+    stack: Vec<usize>,
+    pending_instructions: u64,
+    since: Instant,
+    folded: HashMap<Vec<usize>, u64>,
+    self_time: HashMap<usize, Duration>,
+    calls: HashMap<usize, u64>,
+    // allocations and the words they took, by the function that made them.
+    allocations: HashMap<usize, (u64, u64)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionProfile {
+    pub name: String,
+    pub address: usize,
+    pub calls: u64,
+    pub self_instructions: u64,
+    pub total_instructions: u64,
+    pub self_time: Duration,
+    // heap allocations made by the function itself, and how many words.
+    pub allocations: u64,
+    pub allocated_words: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            stack: vec![0],
+            pending_instructions: 0,
+            since: Instant::now(),
+            folded: HashMap::new(),
+            self_time: HashMap::new(),
+            calls: HashMap::from([(0, 1)]),
+            allocations: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn instruction(&mut self) {
+        self.pending_instructions += 1;
+    }
+
+    pub(crate) fn allocation(&mut self, words: usize) {
+        let top = *self.stack.last().unwrap();
+        let (count, total) = self.allocations.entry(top).or_default();
+        *count += 1;
+        *total += words as u64;
+    }
+
+    pub(crate) fn enter(&mut self, address: usize) {
+        self.flush();
+        self.stack.push(address);
+        *self.calls.entry(address).or_default() += 1;
+    }
+
+    pub(crate) fn exit(&mut self) {
+        self.flush();
+        if self.stack.len() > 1 {
+            self.stack.pop();
+        }
+    }
+
+    // hand everything since the last call/return to the current stack.
+    fn flush(&mut self) {
+        let now = Instant::now();
+        let top = *self.stack.last().unwrap();
+        *self.self_time.entry(top).or_default() += now - self.since;
+        self.since = now;
+
+        if self.pending_instructions > 0 {
+            *self.folded.entry(self.stack.clone()).or_default() += self.pending_instructions;
+            self.pending_instructions = 0;
+        }
+    }
+
+    // functions ranked by the instructions they executed themselves.
+    pub fn report(&mut self, program: &Program) -> Vec<FunctionProfile> {
+        self.flush();
+        let mut profiles: HashMap<usize, FunctionProfile> = HashMap::new();
+        let profile_for = |address: usize| {
+            let (allocations, allocated_words) =
+                self.allocations.get(&address).copied().unwrap_or_default();
+            FunctionProfile {
+                name: program.function_name(address),
+                address,
+                calls: self.calls.get(&address).copied().unwrap_or_default(),
+                self_instructions: 0,
+                total_instructions: 0,
+                self_time: self.self_time.get(&address).copied().unwrap_or_default(),
+                allocations,
+                allocated_words,
+            }
+        };
+
+        for (stack, count) in self.folded.iter() {
+            let top = *stack.last().unwrap();
+            profiles
+                .entry(top)
+                .or_insert_with(|| profile_for(top))
+                .self_instructions += count;
+
+            // recursive functions only count once per stack.
+            let mut seen = vec![];
+            for address in stack.iter() {
+                if seen.contains(address) {
+                    continue;
+                }
+                seen.push(*address);
+                profiles
+                    .entry(*address)
+                    .or_insert_with(|| profile_for(*address))
+                    .total_instructions += count;
+            }
+        }
+
+        let mut profiles: Vec<FunctionProfile> = profiles.into_values().collect();
+        profiles.sort_by(|a, b| {
+            b.self_instructions
+                .cmp(&a.self_instructions)
+                .then(a.address.cmp(&b.address))
+        });
+        profiles
+    }
+
+    // one line per distinct stack, `outer;inner count`, for flamegraph.pl and friends.
+    pub fn folded_stacks(&mut self, program: &Program) -> String {
+        self.flush();
+        let mut lines: Vec<String> = self
+            .folded
+            .iter()
+            .map(|(stack, count)| {
+                let names: Vec<String> = stack
+                    .iter()
+                    .map(|address| program.function_name(*address))
+                    .collect();
+                format!("{} {count}", names.join(";"))
+            })
+            .collect();
+        lines.sort();
+        let mut out = lines.join("\n");
+        out.push('\n');
+        out
+    }
+}
+
+pub fn to_text(profiles: &[FunctionProfile]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{:<20} {:>8} {:>12} {:>12} {:>8} {:>12} {:>12}",
+        "function", "calls", "self instrs", "total instrs", "allocs", "alloc words", "self time"
+    );
+    for profile in profiles.iter() {
+        let _ = writeln!(
+            out,
+            "{:<20} {:>8} {:>12} {:>12} {:>8} {:>12} {:>12?}",
+            profile.name,
+            profile.calls,
+            profile.self_instructions,
+            profile.total_instructions,
+            profile.allocations,
+            profile.allocated_words,
+            profile.self_time
+        );
+    }
+    out
+}
@@ -0,0 +1,411 @@
+// a program as it moves between the assembler, the container formats and the
+// cpu. Programs are checked when they're built so everything downstream can
+// assume the code decodes and the metadata points at real addresses.
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::cpu::Opcode;
+use crate::disassembler::decode;
+
+// names for the optional bits of the vm a program can depend on.
+pub const FEATURE_CLOSURES: &str = "closures";
+pub const FEATURE_CONSTANT_POOL: &str = "constant-pool";
+pub const FEATURE_DATA: &str = "data";
+pub const FEATURE_FIXED_POINT: &str = "fixed-point";
+pub const FEATURE_STRINGS: &str = "strings";
+pub const FEATURE_MAPS: &str = "maps";
+pub const FEATURE_RECORDS: &str = "records";
+pub const FEATURE_MEMORY: &str = "memory";
+
+// everything this build of the vm knows how to run.
+pub const SUPPORTED_FEATURES: &[&str] = &[
+    FEATURE_CLOSURES,
+    FEATURE_CONSTANT_POOL,
+    FEATURE_DATA,
+    FEATURE_FIXED_POINT,
+    FEATURE_STRINGS,
+    FEATURE_MAPS,
+    FEATURE_RECORDS,
+    FEATURE_MEMORY,
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "ProgramParts", into = "ProgramParts")]
+pub struct Program {
+    code: Vec<i64>,
+    constants: Vec<i64>,
+    // words laid down by data directives like `.string`, read with DLOAD.
+    data: Vec<i64>,
+    symbols: Vec<Symbol>,
+    debug_info: DebugInfo,
+    features: Vec<String>,
+}
+
+// the unchecked pieces of a program. Fill these in and hand them to
+// `Program::new`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProgramParts {
+    pub code: Vec<i64>,
+    pub constants: Vec<i64>,
+    pub data: Vec<i64>,
+    pub symbols: Vec<Symbol>,
+    pub debug_info: DebugInfo,
+    pub features: Vec<String>,
+}
+
+// a named code address, as written by the assembler for each label.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Symbol {
+    pub name: String,
+    pub address: i64,
+    // what a function declared with `.fn` takes off the stack and leaves on it.
+    #[serde(default)]
+    pub arity: Option<Arity>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Arity {
+    pub args: usize,
+    pub rets: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DebugInfo {
+    pub file: Option<String>,
+    // instruction address to 1-based source line.
+    pub lines: BTreeMap<i64, usize>,
+    #[serde(default)]
+    pub records: Vec<RecordInfo>,
+    #[serde(default)]
+    pub variables: Vec<VariableInfo>,
+}
+
+// the names behind a record shape, so tools can show `:point.x` instead of
+// field 0.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordInfo {
+    pub name: String,
+    // constant pool index of the shape.
+    pub shape: i64,
+    pub fields: Vec<String>,
+}
+
+// a name given to a frame slot with `.var`, for the code addresses
+// start..end where it means that.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VariableInfo {
+    pub name: String,
+    pub slot: i64,
+    pub start: i64,
+    pub end: i64,
+}
+
+impl Program {
+    pub fn new(parts: ProgramParts) -> Result<Self> {
+        // a newer assembler may rely on things we've never heard of, and
+        // running the code anyway would just produce garbage.
+        for feature in parts.features.iter() {
+            if !SUPPORTED_FEATURES.contains(&feature.as_str()) {
+                bail!("Program requires VM feature {feature}, which this VM doesn't support")
+            }
+        }
+
+        let instructions = decode(&parts.code)?;
+        for instruction in instructions.iter() {
+            if let (Opcode::Pushc | Opcode::Rnew, Some(index)) =
+                (instruction.opcode, instruction.operand)
+            {
+                if index < 0 || index as usize >= parts.constants.len() {
+                    bail!(
+                        "{} at address {} uses constant {index}, but the pool has {}",
+                        instruction.mnemonic(),
+                        instruction.address,
+                        parts.constants.len()
+                    )
+                }
+            }
+        }
+
+        // labels can sit just past the last instruction.
+        let in_code = |address: i64| address >= 0 && address as usize <= parts.code.len();
+        for symbol in parts.symbols.iter() {
+            if !in_code(symbol.address) {
+                bail!(
+                    "Symbol {} points outside the program at {}",
+                    symbol.name,
+                    symbol.address
+                )
+            }
+        }
+        for address in parts.debug_info.lines.keys() {
+            if !in_code(*address) {
+                bail!("Debug info points outside the program at {address}")
+            }
+        }
+        for record in parts.debug_info.records.iter() {
+            if record.shape < 0 || record.shape as usize >= parts.constants.len() {
+                bail!(
+                    "Record {} has shape {}, which isn't in the constant pool",
+                    record.name,
+                    record.shape
+                )
+            }
+        }
+
+        Ok(Self {
+            code: parts.code,
+            constants: parts.constants,
+            data: parts.data,
+            symbols: parts.symbols,
+            debug_info: parts.debug_info,
+            features: parts.features,
+        })
+    }
+
+    // just code, no metadata.
+    pub fn from_code(code: Vec<i64>) -> Result<Self> {
+        Self::new(ProgramParts {
+            code,
+            ..Default::default()
+        })
+    }
+
+    pub fn into_parts(self) -> ProgramParts {
+        ProgramParts {
+            code: self.code,
+            constants: self.constants,
+            data: self.data,
+            symbols: self.symbols,
+            debug_info: self.debug_info,
+            features: self.features,
+        }
+    }
+
+    pub fn code(&self) -> &[i64] {
+        &self.code
+    }
+
+    pub fn constants(&self) -> &[i64] {
+        &self.constants
+    }
+
+    pub fn data(&self) -> &[i64] {
+        &self.data
+    }
+
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    pub fn debug_info(&self) -> &DebugInfo {
+        &self.debug_info
+    }
+
+    pub fn features(&self) -> &[String] {
+        &self.features
+    }
+
+    pub fn symbol_at(&self, address: i64) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.address == address)
+    }
+
+    // the closest label at or before an address, the one it's "in".
+    pub fn symbol_before(&self, address: i64) -> Option<&Symbol> {
+        self.symbols
+            .iter()
+            .filter(|symbol| symbol.address <= address)
+            .max_by_key(|symbol| symbol.address)
+    }
+
+    // what the code at `address` calls variable `slot`, if anything.
+    pub fn variable_name(&self, address: i64, slot: i64) -> Option<&str> {
+        self.debug_info
+            .variables
+            .iter()
+            .find(|v| v.slot == slot && (v.start..v.end).contains(&address))
+            .map(|v| v.name.as_str())
+    }
+
+    // `name=value (slot n)` for named variables, `n = value` for the rest.
+    pub fn describe_variables(&self, address: i64, variables: &BTreeMap<i64, i64>) -> Vec<String> {
+        variables
+            .iter()
+            .map(|(slot, value)| match self.variable_name(address, *slot) {
+                Some(name) => format!("{name}={value} (slot {slot})"),
+                None => format!("{slot} = {value}"),
+            })
+            .collect()
+    }
+
+    // something printable for a function starting at this address, even if it has no label.
+    pub fn function_name(&self, address: usize) -> String {
+        match self.symbol_at(address as i64) {
+            Some(symbol) => symbol.name.clone(),
+            None if address == 0 => "<entry>".to_string(),
+            None => format!("<{address}>"),
+        }
+    }
+}
+
+impl TryFrom<ProgramParts> for Program {
+    type Error = anyhow::Error;
+
+    fn try_from(parts: ProgramParts) -> Result<Self> {
+        Self::new(parts)
+    }
+}
+
+impl From<Program> for ProgramParts {
+    fn from(program: Program) -> Self {
+        program.into_parts()
+    }
+}
+
+// whole instructions rather than raw words, with jumps that land on one and
+// constant indexes inside the pool, so a fuzzer's input gets past
+// Program::new and into the interpreter.
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Program {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        use crate::cpu::has_code_operand;
+
+        // small, so the records RNEW makes from them fit in memory.
+        let constants = (0..u.int_in_range(1..=8)?)
+            .map(|_| u.int_in_range(0..=64))
+            .collect::<arbitrary::Result<Vec<i64>>>()?;
+        let data: Vec<i64> = u.arbitrary()?;
+        let opcodes: Vec<Opcode> = u.arbitrary()?;
+        let mut addresses = vec![];
+        let mut address = 0;
+        for opcode in opcodes.iter() {
+            addresses.push(address);
+            address += 1 + opcode.operand_count() as i64;
+        }
+
+        let mut code = vec![];
+        for opcode in opcodes {
+            code.push(opcode.value());
+            for operand in 0..opcode.operand_count() {
+                let last = operand + 1 == opcode.operand_count();
+                code.push(match opcode {
+                    Opcode::Push => u.arbitrary()?,
+                    Opcode::Pushc | Opcode::Rnew => u.choose_index(constants.len())? as i64,
+                    opcode if last && has_code_operand(opcode) => *u.choose(&addresses)?,
+                    // slots, counts and syscall numbers, mostly in range.
+                    _ => u.int_in_range(-1..=16)?,
+                });
+            }
+        }
+
+        let mut parts = ProgramParts {
+            code,
+            constants,
+            data,
+            ..Default::default()
+        };
+        parts.features = required_features(&parts);
+        Program::new(parts).map_err(|_| arbitrary::Error::IncorrectFormat)
+    }
+}
+
+// the features a program needs from the vm, going by the opcodes and
+// segments it uses.
+pub fn required_features(parts: &ProgramParts) -> Vec<String> {
+    let mut features = vec![];
+    if let Ok(instructions) = decode(&parts.code) {
+        let used: Vec<&str> = instructions
+            .iter()
+            .filter_map(|i| i.opcode.info().category.feature())
+            .collect();
+        // in the order they're listed in SUPPORTED_FEATURES.
+        for feature in SUPPORTED_FEATURES {
+            if used.contains(feature) {
+                features.push(feature.to_string());
+            }
+        }
+    }
+    if !parts.constants.is_empty() {
+        features.push(FEATURE_CONSTANT_POOL.to_string());
+    }
+    if !parts.data.is_empty() {
+        features.push(FEATURE_DATA.to_string());
+    }
+    features
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::{HALT, PUSH, PUSHC, RNEW};
+
+    #[test]
+    fn validates_on_construction() {
+        assert!(Program::from_code(vec![PUSH, 1, HALT]).is_ok());
+        assert!(Program::from_code(vec![PUSH]).is_err());
+        assert!(Program::from_code(vec![-7]).is_err());
+        assert!(Program::from_code(vec![PUSHC, 0]).is_err());
+        assert!(Program::from_code(vec![RNEW, 0]).is_err());
+
+        let parts = ProgramParts {
+            code: vec![HALT],
+            symbols: vec![Symbol {
+                name: ":far".to_string(),
+                address: 2,
+                arity: None,
+            }],
+            ..Default::default()
+        };
+        assert!(Program::new(parts).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_features() {
+        let parts = ProgramParts {
+            code: vec![HALT],
+            features: vec![FEATURE_CLOSURES.to_string(), "teleport".to_string()],
+            ..Default::default()
+        };
+        let err = Program::new(parts).unwrap_err();
+        assert!(err.to_string().contains("requires VM feature teleport"));
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn arbitrary_programs() {
+        use crate::cpu::has_code_operand;
+        use arbitrary::{Arbitrary, Unstructured};
+
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        for _ in 0..100 {
+            let bytes: Vec<u8> = (0..512)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect();
+            let program = Program::arbitrary(&mut Unstructured::new(&bytes)).unwrap();
+            let instructions = decode(&program.code).unwrap();
+            for instruction in instructions.iter() {
+                if let (true, Some(target)) =
+                    (has_code_operand(instruction.opcode), instruction.operand)
+                {
+                    assert!(instructions.iter().any(|i| i.address as i64 == target));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn deserializing_validates() {
+        let program = Program::from_code(vec![PUSH, 1, HALT]).unwrap();
+        let json = serde_json::to_string(&program).unwrap();
+        assert_eq!(program, serde_json::from_str(&json).unwrap());
+        assert!(serde_json::from_str::<Program>(r#"{"code":[1],"constants":[],"symbols":[],"debug_info":{"file":null,"lines":{}},"features":[]}"#).is_err());
+    }
+}
@@ -0,0 +1,260 @@
+// type assembly a line at a time and watch the machine change. Every line is
+// added to the program so far, which is reassembled and run up to its new
+// end. After each line the instruction pointer, the stack (top first) and the
+// current frame's variables are drawn, and `undo` goes back a line. In Forth
+// mode lines are Forth instead, see `forth.rs`. `watch slot(0) * 2` adds an
+// expression to draw the value of each time too, see `watch.rs`, and
+// `unwatch 1` drops the first.
+
+use std::io::{BufRead, Write};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+use crate::assembler::{parse_program, AssemblerOptions};
+use crate::cpu::{Cpu, Snapshot};
+use crate::forth;
+use crate::watch::Watch;
+
+// a line that loops forever shouldn't take the session with it.
+const LINE_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct Repl {
+    cpu: Cpu,
+    lines: Vec<String>,
+    // the state before each line that's been entered, newest last.
+    history: Vec<Snapshot>,
+    watches: Vec<Watch>,
+    forth: bool,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Self {
+            cpu: Cpu::builder()
+                .implicit_halt(true)
+                .timeout(LINE_TIMEOUT)
+                .build(),
+            lines: vec![],
+            history: vec![],
+            watches: vec![],
+            forth: false,
+        }
+    }
+
+    // a session that takes Forth instead of assembly.
+    pub fn forth() -> Self {
+        Self {
+            forth: true,
+            ..Self::new()
+        }
+    }
+
+    // read lines until the input runs out or says `quit`.
+    pub fn run(&mut self, input: impl BufRead, mut output: impl Write) -> Result<()> {
+        write!(output, "> ")?;
+        output.flush()?;
+        for line in input.lines() {
+            let line = line.context("Could not read input")?;
+            match line.trim() {
+                "quit" | "exit" => break,
+                "undo" => match self.undo() {
+                    true => write!(output, "{}", self.render())?,
+                    false => writeln!(output, "nothing to undo")?,
+                },
+                "" => {}
+                line if line.starts_with("watch ") => match line["watch ".len()..].parse() {
+                    Ok(watch) => {
+                        self.watches.push(watch);
+                        write!(output, "{}", self.render())?
+                    }
+                    Err(err) => writeln!(output, "error: {err:#}")?,
+                },
+                line if line.starts_with("unwatch ") => {
+                    match self.unwatch(&line["unwatch ".len()..]) {
+                        Ok(()) => write!(output, "{}", self.render())?,
+                        Err(err) => writeln!(output, "error: {err:#}")?,
+                    }
+                }
+                line => match self.enter(line) {
+                    Ok(()) => write!(output, "{}", self.render())?,
+                    Err(err) => writeln!(output, "error: {err:#}")?,
+                },
+            }
+            write!(output, "> ")?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    // add a line to the program and run it. A line that doesn't assemble or
+    // fails to run leaves everything as it was.
+    pub fn enter(&mut self, line: &str) -> Result<()> {
+        let snapshot = self.cpu.snapshot();
+        self.lines.push(line.to_string());
+        let result = self.reload().and_then(|words| {
+            // nothing new to run, e.g. a label.
+            if self.cpu.ip() == words {
+                return Ok(());
+            }
+            self.cpu.resume();
+            self.cpu.run().map(|_| ())
+        });
+        if let Err(err) = result {
+            self.lines.pop();
+            self.cpu.restore(snapshot);
+            // the old program always assembled.
+            self.reload()?;
+            return Err(err);
+        }
+        self.history.push(snapshot);
+        Ok(())
+    }
+
+    // false if there's nothing to go back to.
+    pub fn undo(&mut self) -> bool {
+        let Some(snapshot) = self.history.pop() else {
+            return false;
+        };
+        self.lines.pop();
+        self.cpu.restore(snapshot);
+        let _ = self.reload();
+        true
+    }
+
+    // drop a watch by its number, counting from 1 as render() does.
+    fn unwatch(&mut self, number: &str) -> Result<()> {
+        let index = number
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .and_then(|number| number.checked_sub(1))
+            .filter(|index| *index < self.watches.len());
+        let Some(index) = index else {
+            bail!("No watch {}", number.trim())
+        };
+        self.watches.remove(index);
+        Ok(())
+    }
+
+    // assemble everything entered so far and return how many words of code
+    // that came to.
+    fn reload(&mut self) -> Result<usize> {
+        let mut source = self.lines.join("\n");
+        // definitions and all, so undoing a line undoes what it defined.
+        if self.forth {
+            source = forth::to_assembly(&source)?;
+        }
+        let program = parse_program(source, &AssemblerOptions::default())?;
+        let words = program.code().len();
+        self.cpu.load_program(program);
+        Ok(words)
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = format!("ip: {}\nstack:\n", self.cpu.ip());
+        let stack = self.cpu.stack();
+        if stack.is_empty() {
+            out.push_str("    (empty)\n");
+        }
+        for (depth, value) in stack.iter().rev().enumerate() {
+            let marker = if depth == 0 { "  <- top" } else { "" };
+            out.push_str(&format!("    | {value:>20} |{marker}\n"));
+        }
+        let variables = self
+            .cpu
+            .program()
+            .describe_variables(self.cpu.ip() as i64, &self.cpu.variables());
+        match variables.is_empty() {
+            true => out.push_str("variables: none\n"),
+            false => out.push_str(&format!("variables: {}\n", variables.join(", "))),
+        }
+        for (number, watch) in self.watches.iter().enumerate() {
+            let value = match watch.evaluate(&self.cpu) {
+                Ok(value) => value.to_string(),
+                Err(err) => format!("error: {err:#}"),
+            };
+            out.push_str(&format!("watch {}: {watch} = {value}\n", number + 1));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn runs_each_line_and_undoes() {
+        let mut repl = Repl::new();
+        repl.enter(":start").unwrap();
+        repl.enter("push 6").unwrap();
+        repl.enter("push 4").unwrap();
+        repl.enter("store 0").unwrap();
+        assert_eq!(vec![6], repl.cpu.stack());
+        assert!(repl.render().contains("variables: 0 = 4\n"));
+
+        // a failing line is dropped without touching the state.
+        assert!(repl.enter("add").is_err());
+        assert!(repl.enter("bogus").is_err());
+        assert_eq!(vec![6], repl.cpu.stack());
+
+        assert!(repl.undo());
+        assert_eq!(vec![6, 4], repl.cpu.stack());
+        repl.enter("add").unwrap();
+        assert_eq!(vec![10], repl.cpu.stack());
+        assert_eq!(
+            "ip: 5\nstack:\n    |                   10 |  <- top\nvariables: none\n",
+            repl.render()
+        );
+    }
+
+    #[test]
+    fn forth_mode() {
+        let mut repl = Repl::forth();
+        repl.enter(": double 2 * ;").unwrap();
+        repl.enter("21 double").unwrap();
+        assert_eq!(vec![42], repl.cpu.stack());
+        assert!(repl.enter("nonsense").is_err());
+        repl.enter("double 1 if 1 + then").unwrap();
+        assert_eq!(vec![85], repl.cpu.stack());
+        assert!(repl.undo());
+        assert!(repl.undo());
+        assert!(repl.undo());
+        assert!(repl.enter("1 double").is_err());
+    }
+
+    #[test]
+    fn watches() {
+        let mut output = vec![];
+        let mut repl = Repl::new();
+        let input = "push 3\nwatch peek(0) * 2\nwatch peek(1)\npush 4\nunwatch 1\nunwatch 5\n";
+        repl.run(input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("watch 1: peek(0) * 2 = 6\n"));
+        assert!(output.contains("watch 2: peek(1) = error: peek(1) is past the bottom"));
+        assert!(output.contains("watch 1: peek(0) * 2 = 8\nwatch 2: peek(1) = 3\n"));
+        assert!(output.contains("error: No watch 5\n"));
+        assert!(repl
+            .render()
+            .ends_with("variables: none\nwatch 1: peek(1) = 3\n"));
+    }
+
+    #[test]
+    fn session() {
+        let mut output = vec![];
+        Repl::new()
+            .run("push 1\nundo\nundo\nquit\npush 2\n".as_bytes(), &mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("<- top"));
+        assert!(output.contains("nothing to undo"));
+        assert!(output.ends_with("> "));
+    }
+}
@@ -0,0 +1,171 @@
+// runs a program again against the events an earlier run streamed with
+// `run --events`, checking every instruction does just what it did then.
+// It stops at the first instruction that doesn't, so a change to the
+// interpreter can be checked against captured workloads.
+
+use std::io::{self, BufRead};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+use crate::cpu::Cpu;
+
+// returns how many steps matched, which is all of them.
+pub fn replay(cpu: &mut Cpu, trace: impl BufRead) -> Result<u64> {
+    let mut events = vec![];
+    for (number, line) in trace.lines().enumerate() {
+        let line = line.context("Could not read the trace")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event: Value = serde_json::from_str(&line)
+            .with_context(|| format!("Line {} of the trace isn't an event", number + 1))?;
+        events.push(event);
+    }
+    let checker = Checker::default();
+    checker.state().expected = events.into_iter();
+    cpu.set_event_sink(checker.clone());
+    let result = cpu.run();
+
+    let mut state = checker.state();
+    if let Some((expected, actual)) = state.divergence.take() {
+        match expected {
+            Some(expected) => bail!(
+                "Diverged at step {}: expected {expected} but got {actual}",
+                state.step
+            ),
+            None => bail!(
+                "Diverged at step {}: the trace ends but the program went on to {actual}",
+                state.step
+            ),
+        }
+    }
+    result.with_context(|| format!("Failed at step {}, which the trace didn't", state.step))?;
+    if let Some(next) = state.expected.next() {
+        bail!(
+            "Diverged after step {}: the program halted but the trace goes on to {next}",
+            state.step
+        )
+    }
+    Ok(state.step)
+}
+
+// the event sink, comparing each line as it's written.
+#[derive(Clone, Default)]
+struct Checker(Arc<Mutex<State>>);
+
+#[derive(Default)]
+struct State {
+    expected: std::vec::IntoIter<Value>,
+    // what's been written since the last whole line.
+    partial: Vec<u8>,
+    // the last step event that matched.
+    step: u64,
+    // what the trace had, if anything, and what the program did instead.
+    divergence: Option<(Option<Value>, Value)>,
+}
+
+impl Checker {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl io::Write for Checker {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        state.partial.extend_from_slice(bytes);
+        while let Some(end) = state.partial.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = state.partial.drain(..=end).collect();
+            let actual: Value = serde_json::from_slice(&line).map_err(io::Error::other)?;
+            let expected = state.expected.next();
+            if expected.as_ref() != Some(&actual) {
+                state.divergence = Some((expected, actual));
+                return Err(io::Error::other("diverged from the trace"));
+            }
+            if actual["event"] == "step" {
+                state.step = actual["step"].as_u64().unwrap_or_default();
+            }
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::{ADD, CALL, DUP, HALT, MUL, PUSH, RET, STORE};
+    use crate::program::Program;
+
+    fn record(code: Vec<i64>) -> String {
+        let mut cpu = Cpu::new();
+        let recorded = Arc::new(Mutex::new(vec![]));
+        cpu.set_event_sink(Recorder(recorded.clone()));
+        cpu.load_program(Program::from_code(code).unwrap());
+        cpu.run().unwrap();
+        let bytes = recorded.lock().unwrap().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[derive(Clone)]
+    struct Recorder(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Recorder {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn replay_code(code: Vec<i64>, trace: &str) -> Result<u64> {
+        let mut cpu = Cpu::new();
+        cpu.load_program(Program::from_code(code).unwrap());
+        replay(&mut cpu, trace.as_bytes())
+    }
+
+    #[test]
+    fn matches_its_own_trace() {
+        let code = vec![PUSH, 3, CALL, 5, HALT, DUP, STORE, 0, RET];
+        let trace = record(code.clone());
+        assert_eq!(6, replay_code(code, &trace).unwrap());
+    }
+
+    #[test]
+    fn stops_at_the_first_divergence() {
+        let trace = record(vec![PUSH, 3, PUSH, 4, ADD, HALT]);
+        let err = replay_code(vec![PUSH, 3, PUSH, 4, MUL, HALT], &trace).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Diverged at step 2: expected {\"address\":4,"));
+
+        let err = replay_code(vec![PUSH, 3, HALT], &trace).unwrap_err();
+        assert!(err.to_string().starts_with("Diverged at step 1: expected"));
+        let short: String = trace
+            .lines()
+            .take(2)
+            .map(|line| format!("{line}\n"))
+            .collect();
+        let err = replay_code(vec![PUSH, 3, PUSH, 4, ADD, HALT], &short).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Diverged at step 1: the trace ends but the program went on to"));
+        let long = format!("{trace}{}\n", trace.lines().last().unwrap());
+        let err = replay_code(vec![PUSH, 3, PUSH, 4, ADD, HALT], &long).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Diverged after step 4: the program halted but the trace goes on"));
+        let err = replay_code(vec![PUSH, 3, PUSH, 4, ADD, HALT], "{\"event\"").unwrap_err();
+        assert_eq!("Line 1 of the trace isn't an event", err.to_string());
+    }
+}
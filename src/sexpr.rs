@@ -0,0 +1,510 @@
+// s-expressions compiled straight to the assembler's ir, which lowers them
+// the same way it lowers assembly:
+//
+//     (define (max a b) (if (> a b) a b))
+//     (define (fact n) (if (< n 2) 1 (* n (fact (- n 1)))))
+//     (let ((x 4)) (max (fact x) 20))
+//
+// Top level forms run in order and the program halts with the value of the
+// last one. Functions can be called before they're defined. There's `if`,
+// `let`, `begin`, `+ - * /`, `= < > <= >=`, `and`, `or`, `not`, and `#t` and
+// `#f` for 1 and 0. `;` comments run to the end of the line.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+
+use crate::assembler::{lower, IrInstruction, IrLabel, IrOperand, IrVariable, ProgramIr, Span};
+use crate::cpu::{has_code_operand, Opcode};
+use crate::program::{Arity, Program};
+
+pub fn compile(source: &str, file: Option<String>) -> Result<Program> {
+    lower(&to_ir(source, file)?)
+}
+
+pub fn to_ir(source: &str, file: Option<String>) -> Result<ProgramIr> {
+    let nodes = read(source)?;
+    let mut generator = Generator {
+        ir: ProgramIr {
+            file,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    // every function's arity up front, so calls can come before definitions.
+    let mut definitions = vec![];
+    let mut expressions = vec![];
+    for node in nodes.iter() {
+        match node.list() {
+            Some([head, rest @ ..]) if head.symbol() == Some("define") => {
+                let (name, parameters, body) = definition(node, rest)?;
+                if generator.functions.contains_key(name) {
+                    bail!("{}: {name} is defined twice", position(node.span()))
+                }
+                generator
+                    .functions
+                    .insert(name.to_string(), (parameters.len(), None));
+                definitions.push((name, parameters, body, node.span()));
+            }
+            _ => expressions.push(node),
+        }
+    }
+
+    for (index, node) in expressions.iter().enumerate() {
+        if index > 0 {
+            generator.emit(Opcode::Pop, None, node.span());
+        }
+        generator.expression(node)?;
+    }
+    generator.emit(Opcode::Halt, None, Span::default());
+
+    for (name, parameters, body, span) in definitions {
+        generator.function(name, &parameters, body, span)?;
+    }
+    for (index, name) in std::mem::take(&mut generator.calls) {
+        let address = generator.functions[&name].1;
+        generator.ir.instructions[index].operand = Some(IrOperand {
+            value: address.unwrap(),
+            label: Some(format!(":{name}")),
+            code: true,
+        });
+    }
+    Ok(generator.ir)
+}
+
+// `(define (name parameter...) body...)`, split up.
+fn definition<'a>(node: &Node, rest: &'a [Node]) -> Result<(&'a str, Vec<&'a str>, &'a [Node])> {
+    let Some(([name, parameters @ ..], body)) = rest
+        .split_first()
+        .and_then(|(signature, body)| Some((signature.list()?, body)))
+    else {
+        bail!(
+            "{}: expected (define (name parameter...) body...)",
+            position(node.span())
+        )
+    };
+    let mut names = vec![];
+    for symbol in std::iter::once(name).chain(parameters) {
+        let Some(symbol) = symbol.symbol() else {
+            bail!("{}: expected a name", position(symbol.span()))
+        };
+        names.push(symbol);
+    }
+    Ok((names[0], names[1..].to_vec(), body))
+}
+
+fn position(span: Span) -> String {
+    format!("Line {}, column {}", span.line, span.start + 1)
+}
+
+#[derive(Debug, PartialEq)]
+enum Node {
+    Number(i64, Span),
+    Symbol(String, Span),
+    List(Vec<Node>, Span),
+}
+
+impl Node {
+    fn span(&self) -> Span {
+        match self {
+            Self::Number(_, span) | Self::Symbol(_, span) | Self::List(_, span) => *span,
+        }
+    }
+
+    fn symbol(&self) -> Option<&str> {
+        match self {
+            Self::Symbol(symbol, _) => Some(symbol),
+            _ => None,
+        }
+    }
+
+    fn list(&self) -> Option<&[Node]> {
+        match self {
+            Self::List(nodes, _) => Some(nodes),
+            _ => None,
+        }
+    }
+}
+
+fn read(source: &str) -> Result<Vec<Node>> {
+    // the lists still open, and where each started.
+    let mut open: Vec<(Vec<Node>, Span)> = vec![];
+    let mut top = vec![];
+    for (index, line) in source.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or_default();
+        let mut rest = line;
+        while let Some(c) = rest.chars().next() {
+            let start = line.len() - rest.len();
+            let length = match c {
+                c if c.is_whitespace() => c.len_utf8(),
+                '(' | ')' => 1,
+                _ => rest
+                    .find(|c: char| c.is_whitespace() || c == '(' || c == ')')
+                    .unwrap_or(rest.len()),
+            };
+            let span = Span {
+                line: index + 1,
+                start,
+                end: start + length,
+            };
+            let node = match c {
+                c if c.is_whitespace() => None,
+                '(' => {
+                    open.push((vec![], span));
+                    None
+                }
+                ')' => match open.pop() {
+                    Some((nodes, span)) => Some(Node::List(nodes, span)),
+                    None => bail!("{}: unexpected )", position(span)),
+                },
+                _ => {
+                    let atom = &rest[..length];
+                    Some(match atom.parse() {
+                        Ok(number) => Node::Number(number, span),
+                        Err(_) => Node::Symbol(atom.to_string(), span),
+                    })
+                }
+            };
+            if let Some(node) = node {
+                match open.last_mut() {
+                    Some((nodes, _)) => nodes.push(node),
+                    None => top.push(node),
+                }
+            }
+            rest = &rest[length..];
+        }
+    }
+    if let Some((_, span)) = open.last() {
+        bail!("{}: ( is never closed", position(*span))
+    }
+    Ok(top)
+}
+
+#[derive(Default)]
+struct Generator {
+    ir: ProgramIr,
+    address: i64,
+    // arity and, once it's been generated, address of each function.
+    functions: HashMap<String, (usize, Option<i64>)>,
+    // call instructions waiting for their function's address.
+    calls: Vec<(usize, String)>,
+    // names in scope, their slots and where they were bound, innermost last.
+    scope: Vec<(String, i64, i64)>,
+    slots: i64,
+}
+
+impl Generator {
+    // the index of the new instruction.
+    fn emit(&mut self, opcode: Opcode, operand: Option<i64>, span: Span) -> usize {
+        self.ir.instructions.push(IrInstruction {
+            address: self.address,
+            mnemonic: opcode.name().to_string(),
+            opcode,
+            slot: None,
+            operand: operand.map(|value| IrOperand {
+                value,
+                label: None,
+                code: has_code_operand(opcode),
+            }),
+            span,
+        });
+        self.address += 1 + operand.is_some() as i64;
+        self.ir.instructions.len() - 1
+    }
+
+    // point a jump emitted earlier at the next instruction.
+    fn land(&mut self, jump: usize) {
+        self.ir.instructions[jump].operand = Some(IrOperand {
+            value: self.address,
+            label: None,
+            code: true,
+        });
+    }
+
+    fn function(
+        &mut self,
+        name: &str,
+        parameters: &[&str],
+        body: &[Node],
+        span: Span,
+    ) -> Result<()> {
+        self.functions.get_mut(name).unwrap().1 = Some(self.address);
+        self.ir.labels.push(IrLabel {
+            name: format!(":{name}"),
+            address: self.address,
+            arity: Some(Arity {
+                args: parameters.len(),
+                rets: 1,
+            }),
+            span,
+        });
+        self.slots = 0;
+        for parameter in parameters.iter() {
+            self.bind(parameter);
+        }
+        // the last argument is on top.
+        for slot in (0..parameters.len()).rev() {
+            self.emit(Opcode::Store, Some(slot as i64), span);
+        }
+        self.sequence(body, span)?;
+        self.emit(Opcode::Ret, None, span);
+        self.unbind(0, span);
+        Ok(())
+    }
+
+    fn bind(&mut self, name: &str) -> i64 {
+        self.scope
+            .push((name.to_string(), self.slots, self.address));
+        self.slots += 1;
+        self.slots - 1
+    }
+
+    // drop the names bound after the first `depth`, keeping what they were
+    // called as debug info for the code they were in scope for.
+    fn unbind(&mut self, depth: usize, span: Span) {
+        for (name, slot, start) in self.scope.drain(depth..) {
+            self.ir.variables.push(IrVariable {
+                name,
+                slot,
+                start,
+                end: self.address,
+                span,
+            });
+        }
+    }
+
+    // each expression in turn, leaving only the last one's value.
+    fn sequence(&mut self, nodes: &[Node], span: Span) -> Result<()> {
+        if nodes.is_empty() {
+            self.emit(Opcode::Push, Some(0), span);
+        }
+        for (index, node) in nodes.iter().enumerate() {
+            if index > 0 {
+                self.emit(Opcode::Pop, None, node.span());
+            }
+            self.expression(node)?;
+        }
+        Ok(())
+    }
+
+    fn expression(&mut self, node: &Node) -> Result<()> {
+        let span = node.span();
+        let nodes = match node {
+            Node::Number(number, _) => {
+                self.emit(Opcode::Push, Some(*number), span);
+                return Ok(());
+            }
+            Node::Symbol(symbol, _) => {
+                let (opcode, operand) = match symbol.as_str() {
+                    "#t" => (Opcode::Push, 1),
+                    "#f" => (Opcode::Push, 0),
+                    _ => match self.scope.iter().rev().find(|(name, _, _)| name == symbol) {
+                        Some((_, slot, _)) => (Opcode::Load, *slot),
+                        None => bail!("{}: {symbol} isn't bound", position(span)),
+                    },
+                };
+                self.emit(opcode, Some(operand), span);
+                return Ok(());
+            }
+            Node::List(nodes, _) => nodes,
+        };
+        let Some((head, arguments)) = nodes.split_first() else {
+            bail!("{}: () isn't an expression", position(span))
+        };
+        let Some(head) = head.symbol() else {
+            bail!("{}: can only call functions by name", position(span))
+        };
+        let arity = |expected: usize| -> Result<()> {
+            if arguments.len() != expected {
+                bail!(
+                    "{}: {head} takes {expected} argument(s), not {}",
+                    position(span),
+                    arguments.len()
+                )
+            }
+            Ok(())
+        };
+        match head {
+            "if" => {
+                if !(2..=3).contains(&arguments.len()) {
+                    bail!("{}: expected (if condition then else)", position(span))
+                }
+                self.expression(&arguments[0])?;
+                let then = self.emit(Opcode::Jif, Some(0), span);
+                match arguments.get(2) {
+                    Some(otherwise) => self.expression(otherwise)?,
+                    None => _ = self.emit(Opcode::Push, Some(0), span),
+                }
+                let end = self.emit(Opcode::Jmp, Some(0), span);
+                self.land(then);
+                self.expression(&arguments[1])?;
+                self.land(end);
+            }
+            "let" => {
+                let Some((bindings, body)) = arguments
+                    .split_first()
+                    .and_then(|(bindings, body)| Some((bindings.list()?, body)))
+                else {
+                    bail!(
+                        "{}: expected (let ((name value)...) body...)",
+                        position(span)
+                    )
+                };
+                let depth = self.scope.len();
+                // values are worked out before any of the names are bound.
+                let mut names = vec![];
+                for binding in bindings {
+                    let Some([name, value]) = binding.list() else {
+                        bail!("{}: expected (name value)", position(binding.span()))
+                    };
+                    let Some(name) = name.symbol() else {
+                        bail!("{}: expected a name", position(name.span()))
+                    };
+                    self.expression(value)?;
+                    names.push(name);
+                }
+                // the last value is on top.
+                for slot in (self.slots..self.slots + names.len() as i64).rev() {
+                    self.emit(Opcode::Store, Some(slot), span);
+                }
+                for name in names {
+                    self.bind(name);
+                }
+                self.sequence(body, span)?;
+                self.unbind(depth, span);
+            }
+            "begin" => self.sequence(arguments, span)?,
+            "define" => bail!("{}: define only works at the top level", position(span)),
+            "not" => {
+                arity(1)?;
+                self.expression(&arguments[0])?;
+                self.emit(Opcode::Not, None, span);
+            }
+            "-" if arguments.len() == 1 => {
+                self.emit(Opcode::Push, Some(0), span);
+                self.expression(&arguments[0])?;
+                self.emit(Opcode::Sub, None, span);
+            }
+            "+" | "*" | "-" | "/" | "and" | "or" => {
+                let (opcode, identity) = match head {
+                    "+" => (Opcode::Add, Some(0)),
+                    "*" => (Opcode::Mul, Some(1)),
+                    "and" => (Opcode::And, Some(1)),
+                    "or" => (Opcode::Or, Some(0)),
+                    "-" => (Opcode::Sub, None),
+                    _ => (Opcode::Div, None),
+                };
+                match (arguments.split_first(), identity) {
+                    (Some((first, rest)), _) => {
+                        self.expression(first)?;
+                        for argument in rest {
+                            self.expression(argument)?;
+                            self.emit(opcode, None, span);
+                        }
+                    }
+                    (None, Some(identity)) => _ = self.emit(Opcode::Push, Some(identity), span),
+                    (None, None) => bail!("{}: {head} needs an argument", position(span)),
+                }
+            }
+            "=" | ">" | ">=" | "<" | "<=" => {
+                arity(2)?;
+                self.expression(&arguments[0])?;
+                self.expression(&arguments[1])?;
+                let opcodes: &[Opcode] = match head {
+                    "=" => &[Opcode::Iseq],
+                    ">" => &[Opcode::Isgt],
+                    ">=" => &[Opcode::Isge],
+                    // the other way round, and negated.
+                    "<" => &[Opcode::Isge, Opcode::Not],
+                    _ => &[Opcode::Isgt, Opcode::Not],
+                };
+                for opcode in opcodes {
+                    self.emit(*opcode, None, span);
+                }
+            }
+            name => {
+                let Some((parameters, _)) = self.functions.get(name) else {
+                    bail!("{}: {name} isn't a function", position(span))
+                };
+                arity(*parameters)?;
+                for argument in arguments {
+                    self.expression(argument)?;
+                }
+                let call = self.emit(Opcode::Call, Some(0), span);
+                self.calls.push((call, name.to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::Cpu;
+    use crate::verifier;
+
+    fn run(source: &str) -> i64 {
+        let program = compile(source, None).unwrap();
+        let diagnostics = verifier::verify(&program).unwrap();
+        assert!(!verifier::has_errors(&diagnostics), "{diagnostics:?}");
+        let mut cpu = Cpu::new();
+        cpu.load_program(program);
+        cpu.run().unwrap().exit_value.unwrap()
+    }
+
+    #[test]
+    fn functions_and_recursion() {
+        let source = "
+            (define (max a b) (if (> a b) a b))
+            ; used before it's defined
+            (let ((x 4) (y 2)) (max (fact x) (* 10 y)))
+            (define (fact n)
+              (if (< n 2) 1 (* n (fact (- n 1)))))
+        ";
+        assert_eq!(24, run(source));
+        assert_eq!(-6, run("(- 10 (+ 1 2 3) 10)"));
+        assert_eq!(1, run("(begin 5 (and #t (not #f) (<= 2 2)))"));
+        // the inner x shadows the outer one, and the values are worked out first.
+        assert_eq!(7, run("(let ((x 3)) (let ((x 4) (y x)) (+ x y)))"));
+    }
+
+    #[test]
+    fn names_slots_for_the_debugger() {
+        let program = compile("(define (f n) (let ((m (* n 2))) m))\n(f 3)", None).unwrap();
+        let variables = &program.debug_info().variables;
+        let named: Vec<(&str, i64)> = variables
+            .iter()
+            .map(|v| (v.name.as_str(), v.slot))
+            .collect();
+        assert_eq!(vec![("m", 1), ("n", 0)], named);
+        // m only once it's bound, n for the whole function.
+        assert!(variables[0].start > variables[1].start);
+        assert_eq!(variables[0].end, variables[1].end - 1);
+    }
+
+    #[test]
+    fn ir_points_back_at_the_source() {
+        let ir = to_ir("(define (one) 1)\n(one)", Some("one.scm".to_string())).unwrap();
+        assert_eq!(":one", ir.labels[0].name);
+        let call = &ir.instructions[0];
+        assert_eq!(("call", 2), (call.mnemonic.as_str(), call.span.line));
+        assert_eq!(
+            Some(":one".to_string()),
+            call.operand.as_ref().unwrap().label
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source| to_ir(source, None).unwrap_err().to_string();
+        assert_eq!("Line 1, column 4: x isn't bound", error("(+ x 1)"));
+        assert_eq!("Line 1, column 1: ( is never closed", error("(+ 1"));
+        assert_eq!("Line 1, column 1: f isn't a function", error("(f 1)"));
+        assert_eq!(
+            "Line 2, column 1: f takes 1 argument(s), not 0",
+            error("(define (f x) x)\n(f)")
+        );
+    }
+}
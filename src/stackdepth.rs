@@ -0,0 +1,254 @@
+// worst-case data stack depth, worked out without running anything, so a
+// program can be given a fixed size stack it's known to fit in.
+//
+// each function is walked from its start counting values relative to the
+// stack it was called with. A call adds the callee's worst case on top of
+// whatever the caller has pushed so far. Recursion, indirect calls and loops
+// that leave the stack a different height each time around have no static
+// bound.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Write};
+
+use anyhow::Result;
+
+use crate::callgraph::{call_graph, Function};
+use crate::cpu::{is_branch, stack_effect, Opcode};
+use crate::disassembler::{decode, Instruction};
+use crate::program::Program;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Depth {
+    Words(usize),
+    // why there's no bound.
+    Unbounded(String),
+}
+
+impl fmt::Display for Depth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Words(1) => write!(f, "1 word"),
+            Self::Words(words) => write!(f, "{words} words"),
+            Self::Unbounded(reason) => write!(f, "unbounded ({reason})"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionDepth {
+    pub name: String,
+    pub start: usize,
+    // the most values it has on top of the stack it was called with,
+    // counting everything it calls.
+    pub depth: Depth,
+    // how much taller the stack is when it returns, None if it never does.
+    pub net: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackDepths {
+    // the whole program, from the entry point with an empty stack.
+    pub program: Depth,
+    pub functions: Vec<FunctionDepth>,
+}
+
+pub fn stack_depths(program: &Program) -> Result<StackDepths> {
+    let instructions = decode(program.code())?;
+    let functions = call_graph(program)?;
+    let mut analysis = Analysis {
+        instructions: instructions.iter().map(|i| (i.address, i)).collect(),
+        functions: &functions,
+        summaries: HashMap::new(),
+        in_progress: HashSet::new(),
+    };
+    let functions: Vec<FunctionDepth> = functions
+        .iter()
+        .map(|function| {
+            let summary = analysis.summarize(function.start);
+            FunctionDepth {
+                name: function.name.clone(),
+                start: function.start,
+                depth: summary.depth,
+                net: summary.net,
+            }
+        })
+        .collect();
+    // the entry point is always the first function.
+    let program = functions
+        .first()
+        .map_or(Depth::Words(0), |entry| entry.depth.clone());
+    Ok(StackDepths { program, functions })
+}
+
+pub fn to_text(depths: &StackDepths) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "program: {}", depths.program);
+    for function in depths.functions.iter() {
+        let _ = write!(
+            out,
+            "{} @{}: {}",
+            function.name, function.start, function.depth
+        );
+        let _ = match function.net {
+            Some(net) => writeln!(out, ", returns {net:+}"),
+            None => writeln!(out),
+        };
+    }
+    out
+}
+
+#[derive(Debug, Clone)]
+struct Summary {
+    depth: Depth,
+    net: Option<i64>,
+}
+
+struct Analysis<'a> {
+    instructions: HashMap<usize, &'a Instruction>,
+    functions: &'a [Function],
+    summaries: HashMap<usize, Summary>,
+    // functions being summarized further up the call chain.
+    in_progress: HashSet<usize>,
+}
+
+impl Analysis<'_> {
+    fn summarize(&mut self, start: usize) -> Summary {
+        if let Some(summary) = self.summaries.get(&start) {
+            return summary.clone();
+        }
+        self.in_progress.insert(start);
+        let summary = self.walk(start).unwrap_or_else(|reason| Summary {
+            depth: Depth::Unbounded(reason),
+            net: None,
+        });
+        self.in_progress.remove(&start);
+        self.summaries.insert(start, summary.clone());
+        summary
+    }
+
+    fn name(&self, start: usize) -> String {
+        self.functions
+            .iter()
+            .find(|function| function.start == start)
+            .map_or_else(|| format!("<{start}>"), |function| function.name.clone())
+    }
+
+    // follow every path through the function, keeping the stack height at
+    // each instruction. Err is the reason there's no bound.
+    fn walk(&mut self, start: usize) -> Result<Summary, String> {
+        let mut heights: HashMap<usize, i64> = HashMap::new();
+        let mut worklist = vec![(start, 0)];
+        let mut peak = 0;
+        let mut net = None;
+        while let Some((address, height)) = worklist.pop() {
+            match heights.insert(address, height) {
+                Some(seen) if seen == height => continue,
+                Some(_) => {
+                    return Err(format!(
+                        "the stack height at {address} changes each time around a loop"
+                    ))
+                }
+                None => {}
+            }
+            let Some(instruction) = self.instructions.get(&address).copied() else {
+                // ran off the end, or a bad jump. The verifier reports those.
+                continue;
+            };
+            let next = instruction.next_address();
+            let target = instruction.operand.and_then(|t| usize::try_from(t).ok());
+            match instruction.opcode {
+                Opcode::Halt | Opcode::Iret => {}
+                Opcode::Ret => match net {
+                    Some(other) if other != height => {
+                        return Err(format!(
+                            "{} returns with different stack heights",
+                            self.name(start)
+                        ))
+                    }
+                    _ => net = Some(height),
+                },
+                Opcode::Call => {
+                    let Some(callee) = target else {
+                        return Err(format!("call to a bad address at {address}"));
+                    };
+                    if self.in_progress.contains(&callee) {
+                        return Err(format!("{} is recursive", self.name(callee)));
+                    }
+                    let summary = self.summarize(callee);
+                    match summary.depth {
+                        Depth::Words(words) => peak = peak.max(height + words as i64),
+                        Depth::Unbounded(reason) => return Err(reason),
+                    }
+                    // nothing after a call that never returns runs.
+                    if let Some(callee_net) = summary.net {
+                        worklist.push((next, height + callee_net));
+                    }
+                }
+                Opcode::Callclos | Opcode::Int => {
+                    return Err(format!("indirect call at {address}"))
+                }
+                opcode => {
+                    let Some((pops, pushes)) = stack_effect(opcode, instruction.operand) else {
+                        return Err(format!("unknown stack effect at {address}"));
+                    };
+                    let height = height - pops as i64 + pushes as i64;
+                    peak = peak.max(height);
+                    match (opcode, target) {
+                        (Opcode::Jmp, Some(target)) => worklist.push((target, height)),
+                        (opcode, Some(target)) if is_branch(opcode) => {
+                            worklist.push((target, height));
+                            worklist.push((next, height));
+                        }
+                        _ => worklist.push((next, height)),
+                    }
+                }
+            }
+        }
+        Ok(Summary {
+            depth: Depth::Words(peak.max(0) as usize),
+            net,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::{parse_program, AssemblerOptions};
+
+    fn depths(source: &str) -> StackDepths {
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        stack_depths(&program).unwrap()
+    }
+
+    #[test]
+    fn adds_up_calls() {
+        // :max goes one word above what it was called with when it
+        // duplicates, and the caller has 3 words down by then.
+        let depths =
+            depths("push 9\npush 1\npush 2\ncall :max\nadd\nhalt\n:max\ndup\nstore 0\nisgt\nret");
+        assert_eq!(Depth::Words(4), depths.program);
+        assert_eq!(Depth::Words(1), depths.functions[1].depth);
+        assert_eq!(Some(-1), depths.functions[1].net);
+    }
+
+    #[test]
+    fn loops_that_keep_their_height() {
+        let depths = depths(":loop\npush 1\npush 2\nadd\njif :loop\nhalt");
+        assert_eq!(Depth::Words(2), depths.program);
+    }
+
+    #[test]
+    fn no_bound() {
+        let unbounded = |source: &str| match depths(source).program {
+            Depth::Unbounded(reason) => reason,
+            depth => panic!("expected no bound, got {depth}"),
+        };
+        assert_eq!(
+            ":f is recursive",
+            unbounded("call :f\nhalt\n:f\ncall :f\nret")
+        );
+        assert!(unbounded(":loop\npush 1\njmp :loop").contains("around a loop"));
+        assert!(unbounded("push 0\nmkclos 0\ncallclos\nhalt").contains("indirect call"));
+    }
+}
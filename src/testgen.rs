@@ -0,0 +1,214 @@
+// random programs the verifier has nothing to say about, for stressing the
+// interpreter and seeding fuzzers: stack effects that add up, jumps that land
+// on instructions, and a HALT at the end that's always reached, since
+// branches only go forward and loops only ever count down. Arithmetic can
+// still overflow, so run them with Overflow::Wrap. Division is always by a
+// constant that isn't 0.
+//
+// The same seed and size always give the same program, which leaves
+// variable 0 on the stack when it halts.
+
+use crate::cpu::{
+    ADD, AND, DIV, DUP, FXDIV, FXMUL, HALT, ISEQ, ISGE, ISGT, ISZERO, JEQ, JGE, JIF, JLT, JMP, JNE,
+    JNZ, JZ, LOAD, LOOP, MUL, NOT, OR, POP, PUSH, STORE, SUB,
+};
+use crate::program::Program;
+
+// about how many instructions `size` is in.
+pub fn generate(seed: u64, size: usize) -> Program {
+    let mut generator = Generator {
+        rng: Rng::new(seed),
+        code: vec![],
+        depth: 0,
+        budget: size,
+    };
+    generator.block(0, 0);
+    // something to come out of run(), to compare between interpreters.
+    generator.code.extend([LOAD, 0, HALT]);
+    Program::from_code(generator.code).expect("generated code is always whole instructions")
+}
+
+// how deep blocks nest inside each other.
+const MAX_NESTING: usize = 3;
+// the stack never gets deeper than this above where a block started.
+const MAX_DEPTH: usize = 8;
+// variables the generated code reads and writes. Loop counters use the slots
+// after these, one per nesting level, so nothing else touches them.
+const SLOTS: i64 = 4;
+// values that tend to find edge cases, mixed in with random ones.
+const INTERESTING: &[i64] = &[0, 1, -1, 2, 7, 1 << 32, i64::MAX, i64::MIN];
+
+// xorshift64*, small and good enough for shuffling instructions.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // xorshift never leaves 0.
+        Self(seed ^ 0x9e37_79b9_7f4a_7c15)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn value(&mut self) -> i64 {
+        match self.below(3) {
+            0 => INTERESTING[self.below(INTERESTING.len())],
+            1 => self.next() as i64,
+            _ => self.below(100) as i64 - 50,
+        }
+    }
+}
+
+struct Generator {
+    rng: Rng,
+    code: Vec<i64>,
+    // values on the stack.
+    depth: usize,
+    // instructions left to spend.
+    budget: usize,
+}
+
+impl Generator {
+    // instructions that leave the stack as deep as it was, never reaching
+    // below it. The outermost block goes on until the budget is spent.
+    fn block(&mut self, nesting: usize, floor: usize) {
+        let length = match nesting {
+            0 => usize::MAX,
+            _ => 1 + self.rng.below(8),
+        };
+        for _ in 0..length {
+            if self.budget == 0 {
+                break;
+            }
+            match self.rng.below(10) {
+                0 | 1 if nesting < MAX_NESTING => self.branch(nesting),
+                2 if nesting < MAX_NESTING => self.counted_loop(nesting),
+                _ => self.straight(floor),
+            }
+        }
+        while self.depth > floor {
+            self.emit(&[POP], 1, 0);
+        }
+    }
+
+    fn straight(&mut self, floor: usize) {
+        let available = self.depth - floor;
+        if available == 0 || (available < MAX_DEPTH && self.rng.below(3) == 0) {
+            return self.straight_push();
+        }
+        match self.rng.below(6) {
+            // binary operators.
+            0 | 1 if available >= 2 => {
+                const BINARY: &[i64] = &[ADD, SUB, MUL, AND, OR, ISEQ, ISGT, ISGE, FXMUL];
+                let opcode = BINARY[self.rng.below(BINARY.len())];
+                self.emit(&[opcode], 2, 1)
+            }
+            2 => {
+                let divisor = match self.rng.value() {
+                    0 => 3,
+                    divisor => divisor,
+                };
+                let opcode = [DIV, FXDIV][self.rng.below(2)];
+                self.emit(&[PUSH, divisor, opcode], 1, 1)
+            }
+            3 => {
+                let opcode = [NOT, ISZERO][self.rng.below(2)];
+                self.emit(&[opcode], 1, 1)
+            }
+            4 if available < MAX_DEPTH => self.emit(&[DUP], 1, 2),
+            5 => {
+                let slot = self.slot();
+                self.emit(&[STORE, slot], 1, 0)
+            }
+            _ => self.emit(&[POP], 1, 0),
+        }
+    }
+
+    // a test, then a block that's skipped if the test goes one way.
+    fn branch(&mut self, nesting: usize) {
+        let floor = self.depth;
+        let (opcode, pops) = match self.rng.below(6) {
+            0..=2 => ([JIF, JZ, JNZ][self.rng.below(3)], 1),
+            3 | 4 => ([JEQ, JNE, JLT, JGE][self.rng.below(4)], 2),
+            // over a block that never runs, which still has to make sense.
+            _ => (JMP, 0),
+        };
+        for _ in 0..pops {
+            self.straight_push();
+        }
+        self.emit(&[opcode, 0], pops, 0);
+        let operand = self.code.len() - 1;
+        self.block(nesting + 1, floor);
+        self.code[operand] = self.code.len() as i64;
+    }
+
+    // a block run a few times by LOOP, with a counter only it uses.
+    fn counted_loop(&mut self, nesting: usize) {
+        let counter = SLOTS + nesting as i64;
+        let times = 1 + self.rng.below(4) as i64;
+        self.emit(&[PUSH, times, STORE, counter], 0, 0);
+        let top = self.code.len() as i64;
+        let floor = self.depth;
+        self.block(nesting + 1, floor);
+        self.emit(&[LOOP, counter, top], 0, 0);
+    }
+
+    // a variable or a constant.
+    fn straight_push(&mut self) {
+        let words = match self.rng.below(3) {
+            0 => [LOAD, self.slot()],
+            _ => [PUSH, self.rng.value()],
+        };
+        self.emit(&words, 0, 1)
+    }
+
+    fn slot(&mut self) -> i64 {
+        self.rng.below(SLOTS as usize) as i64
+    }
+
+    fn emit(&mut self, words: &[i64], pops: usize, pushes: usize) {
+        self.code.extend_from_slice(words);
+        self.depth = self.depth - pops + pushes;
+        self.budget = self.budget.saturating_sub(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::{Cpu, Overflow};
+    use crate::verifier::verify;
+
+    #[test]
+    fn programs_verify_and_halt() {
+        for seed in 0..200 {
+            let program = generate(seed, 60);
+            let diagnostics = verify(&program).unwrap();
+            assert!(diagnostics.is_empty(), "seed {seed}: {diagnostics:?}");
+            let mut cpu = Cpu::builder()
+                .overflow(Overflow::Wrap)
+                .max_steps(100_000)
+                .build();
+            cpu.load_program(program);
+            match cpu.run() {
+                Ok(outcome) => assert_eq!(1, outcome.stack_size, "seed {seed}"),
+                Err(err) => panic!("seed {seed}: {err:#}"),
+            }
+        }
+    }
+
+    #[test]
+    fn seeds_are_repeatable() {
+        assert_eq!(generate(7, 100), generate(7, 100));
+        assert_ne!(generate(7, 100), generate(8, 100));
+        assert!(generate(7, 400).code().len() > generate(7, 10).code().len());
+    }
+}
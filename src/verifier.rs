@@ -0,0 +1,393 @@
+// static checks on assembled programs, run before we hand them to the cpu.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::Result;
+
+use crate::cfg::basic_blocks;
+use crate::cpu::{has_code_operand, is_branch, stack_effect, Opcode};
+use crate::disassembler::{decode, Instruction};
+use crate::program::{Arity, Program};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub address: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        match self.address {
+            Some(address) => write!(f, "{severity}: {} (at address {address})", self.message),
+            None => write!(f, "{severity}: {}", self.message),
+        }
+    }
+}
+
+pub fn verify(program: &Program) -> Result<Vec<Diagnostic>> {
+    let mut diagnostics = vec![];
+    check_jump_targets(program, &mut diagnostics)?;
+    check_termination(program, &mut diagnostics)?;
+    check_arities(program, &mut diagnostics)?;
+    Ok(diagnostics)
+}
+
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(|d| d.severity == Severity::Error)
+}
+
+// operands live inline, so a jump into the middle of an instruction would
+// execute its operand as an opcode.
+fn check_jump_targets(program: &Program, diagnostics: &mut Vec<Diagnostic>) -> Result<()> {
+    let instructions = decode(program.code())?;
+    // every word maps to the address of the instruction it belongs to.
+    let mut owner = HashMap::new();
+    for instruction in instructions.iter() {
+        for address in instruction.address..instruction.next_address() {
+            owner.insert(address as i64, instruction.address);
+        }
+    }
+
+    for instruction in instructions.iter() {
+        if !has_code_operand(instruction.opcode) {
+            continue;
+        }
+        let Some(target) = instruction.operand else {
+            continue;
+        };
+        match owner.get(&target) {
+            None => diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                address: Some(instruction.address),
+                message: format!(
+                    "{} targets address {target}, outside the program (0..{})",
+                    instruction.mnemonic(),
+                    program.code().len()
+                ),
+            }),
+            Some(start) if *start as i64 != target => diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                address: Some(instruction.address),
+                message: format!(
+                    "{} targets address {target}, which is an operand of the instruction at {start}",
+                    instruction.mnemonic()
+                ),
+            }),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// warn if a reachable path runs off the end of the code, or if nothing
+// reachable ever stops the program.
+fn check_termination(program: &Program, diagnostics: &mut Vec<Diagnostic>) -> Result<()> {
+    let blocks = basic_blocks(program)?;
+    if blocks.is_empty() {
+        return Ok(());
+    }
+
+    let mut reachable = vec![false; blocks.len()];
+    let mut worklist = vec![0];
+    while let Some(index) = worklist.pop() {
+        if reachable[index] {
+            continue;
+        }
+        reachable[index] = true;
+        for (target, _) in blocks[index].successors.iter() {
+            // blocks come in address order.
+            if let Ok(target) = blocks.binary_search_by_key(target, |block| block.start) {
+                worklist.push(target);
+            }
+        }
+    }
+
+    // running off the end stops it too, just not nicely.
+    let mut stops = false;
+    for (block, _) in blocks.iter().zip(reachable).filter(|(_, r)| *r) {
+        stops |= block
+            .instructions
+            .iter()
+            .any(|i| matches!(i.opcode, Opcode::Halt | Opcode::Ret | Opcode::Iret));
+
+        let last = block.instructions.last().unwrap();
+        if !matches!(
+            last.opcode,
+            Opcode::Jmp | Opcode::Ret | Opcode::Iret | Opcode::Halt
+        ) && last.next_address() == program.code().len()
+        {
+            stops = true;
+            diagnostics.push(Diagnostic {
+                severity: Severity::Warning,
+                address: Some(last.address),
+                message: "execution can run off the end of the program (missing HALT?)".to_string(),
+            });
+        }
+    }
+    if !stops {
+        diagnostics.push(Diagnostic {
+            severity: Severity::Warning,
+            address: None,
+            message: "no reachable HALT or RET, the program can never stop".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// functions declared with `.fn` say how many values they take and leave.
+// Count the values on the stack through each of them, and through the entry
+// point, and check every call and return against the declarations. A path is
+// dropped once the count stops being known, at an indirect call or a call to
+// an undeclared function.
+//
+// Inside a declared function the count is exact, so anything that doesn't
+// add up is an error. The entry point's count starts at 0, but whoever runs
+// the program may push inputs first, so there it's only a warning.
+fn check_arities(program: &Program, diagnostics: &mut Vec<Diagnostic>) -> Result<()> {
+    let instructions = decode(program.code())?;
+    let by_address: HashMap<usize, &Instruction> =
+        instructions.iter().map(|i| (i.address, i)).collect();
+    let mut declarations: HashMap<i64, (&str, Arity)> = HashMap::new();
+    for symbol in program.symbols().iter() {
+        if let Some(arity) = symbol.arity {
+            declarations
+                .entry(symbol.address)
+                .or_insert((symbol.name.as_str(), arity));
+        }
+    }
+    let declared = |address: i64| declarations.get(&address).copied();
+    // where each function is called from, to say who a bad return hurts.
+    let mut callers: HashMap<i64, Vec<String>> = HashMap::new();
+    for instruction in instructions.iter().filter(|i| i.opcode == Opcode::Call) {
+        if let Some(target) = instruction.operand {
+            callers
+                .entry(target)
+                .or_default()
+                .push(instruction.address.to_string());
+        }
+    }
+
+    let mut roots: Vec<(usize, Option<(&str, Arity)>)> = vec![];
+    if declared(0).is_none() {
+        roots.push((0, None));
+    }
+    for symbol in program.symbols().iter() {
+        if let Some(arity) = symbol.arity {
+            roots.push((symbol.address as usize, Some((&symbol.name, arity))));
+        }
+    }
+
+    for (start, function) in roots {
+        let entry_depth = function.map_or(0, |(_, arity)| arity.args);
+        let severity = match function {
+            Some(_) => Severity::Error,
+            None => Severity::Warning,
+        };
+        let mut report = |address: usize, message: String| {
+            diagnostics.push(Diagnostic {
+                severity,
+                address: Some(address),
+                message,
+            })
+        };
+        let mut seen = HashMap::new();
+        let mut worklist = vec![(start, entry_depth)];
+        while let Some((address, depth)) = worklist.pop() {
+            // top level code is free to leave things behind on one branch
+            // and not another, a function has to come out the same.
+            match seen.insert(address, depth) {
+                Some(earlier) if earlier != depth && function.is_some() => {
+                    report(
+                        address,
+                        format!(
+                            "the stack holds {earlier} here on one path but {depth} on another"
+                        ),
+                    );
+                    // one report per place is plenty.
+                    seen.insert(address, earlier);
+                    continue;
+                }
+                Some(_) => continue,
+                None => {}
+            }
+            let Some(instruction) = by_address.get(&address) else {
+                continue;
+            };
+            let depth = match instruction.opcode {
+                Opcode::Call => {
+                    let Some((callee, arity)) = instruction.operand.and_then(declared) else {
+                        continue;
+                    };
+                    if depth < arity.args {
+                        report(
+                            address,
+                            format!(
+                                "call to {callee} needs args={} but the stack only holds {depth}",
+                                arity.args
+                            ),
+                        );
+                        continue;
+                    }
+                    depth - arity.args + arity.rets
+                }
+                Opcode::Ret => {
+                    if let Some((name, arity)) = function {
+                        if depth != arity.rets {
+                            let called_from = callers
+                                .get(&(start as i64))
+                                .map_or("nowhere".to_string(), |sites| sites.join(", "));
+                            report(
+                                address,
+                                format!(
+                                    "{name} returns leaving {depth} on the stack, but declares rets={}, \
+                                     called from {called_from}",
+                                    arity.rets
+                                ),
+                            );
+                        }
+                    }
+                    continue;
+                }
+                Opcode::Halt | Opcode::Iret => continue,
+                opcode => match stack_effect(opcode, instruction.operand) {
+                    Some((pops, pushes)) if pops <= depth => depth - pops + pushes,
+                    Some((pops, _)) => {
+                        // below the arguments is the caller's business.
+                        if let Some((name, arity)) = function {
+                            report(
+                                address,
+                                format!(
+                                    "{} needs {pops} on the stack but {name} only has {depth} \
+                                     of its args={} left",
+                                    instruction.mnemonic(),
+                                    arity.args
+                                ),
+                            );
+                        }
+                        continue;
+                    }
+                    None => continue,
+                },
+            };
+            let target = instruction.operand.and_then(|t| usize::try_from(t).ok());
+            match (instruction.opcode, target) {
+                (Opcode::Jmp, Some(target)) => worklist.push((target, depth)),
+                (opcode, Some(target)) if is_branch(opcode) => {
+                    worklist.push((target, depth));
+                    worklist.push((instruction.next_address(), depth));
+                }
+                _ => worklist.push((instruction.next_address(), depth)),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::{parse_program, AssemblerOptions};
+    use crate::cpu::{HALT, JIF, JMP, PUSH};
+
+    fn verify_source(source: &str) -> Vec<Diagnostic> {
+        let program = parse_program(source.to_string(), &AssemblerOptions::default()).unwrap();
+        verify(&program).unwrap()
+    }
+
+    #[test]
+    fn clean_program() {
+        assert!(verify_source("push 1\njif :end\npush 2\n:end\nhalt").is_empty());
+    }
+
+    #[test]
+    fn runs_off_the_end() {
+        let diagnostics = verify_source("push 1\njif :end\nhalt\n:end\npush 2");
+        assert_eq!(1, diagnostics.len());
+        assert_eq!(Some(5), diagnostics[0].address);
+    }
+
+    #[test]
+    fn jump_into_operand() {
+        let program = Program::from_code(vec![PUSH, 1, JMP, 1, HALT]).unwrap();
+        let diagnostics = verify(&program).unwrap();
+        assert!(has_errors(&diagnostics));
+        assert_eq!(Some(2), diagnostics[0].address);
+    }
+
+    #[test]
+    fn jump_out_of_bounds() {
+        let program = Program::from_code(vec![JMP, -3, JIF, 5, HALT]).unwrap();
+        let diagnostics = verify(&program).unwrap();
+        let addresses: Vec<Option<usize>> = diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+            .map(|d| d.address)
+            .collect();
+        assert_eq!(vec![Some(0), Some(2)], addresses);
+    }
+
+    #[test]
+    fn checks_declared_arities() {
+        let source = "push 1\npush 2\ncall :max\npush 3\ncall :max\nhalt\n.fn :max args=2 rets=1\nisgt\nret\n.endfn";
+        assert!(verify_source(source).is_empty());
+
+        let diagnostics = verify_source(
+            "push 1\ncall :max\nhalt\n.fn :max args=2 rets=1\njif :.yes\nret\n:.yes\npush 1\nret\n.endfn",
+        );
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            vec![
+                "call to :max needs args=2 but the stack only holds 1",
+                ":max returns leaving 2 on the stack, but declares rets=1, called from 2",
+            ],
+            messages
+        );
+        // the caller might have been handed inputs, the function can't have.
+        assert_eq!(Severity::Warning, diagnostics[0].severity);
+        assert_eq!(Severity::Error, diagnostics[1].severity);
+    }
+
+    #[test]
+    fn checks_balance_inside_functions() {
+        // one more left behind every time round the loop.
+        let diagnostics = verify_source(
+            "push 1\ncall :f\nhalt\n\
+             .fn :f args=1 rets=1\n:.loop\npush 1\npush 1\njif :.loop\nadd\nret\n.endfn",
+        );
+        assert_eq!(1, diagnostics.len());
+        assert_eq!(Some(5), diagnostics[0].address);
+        assert_eq!(
+            "the stack holds 1 here on one path but 2 on another",
+            diagnostics[0].message
+        );
+        assert!(has_errors(&diagnostics));
+
+        // and one reaching into its caller's values.
+        let diagnostics =
+            verify_source("push 1\npush 2\ncall :f\nhalt\n.fn :f args=1 rets=1\nadd\nret\n.endfn");
+        assert_eq!(
+            "add needs 2 on the stack but :f only has 1 of its args=1 left",
+            diagnostics[0].message
+        );
+        assert_eq!(Some(7), diagnostics[0].address);
+    }
+
+    #[test]
+    fn never_stops() {
+        let diagnostics = verify_source(":loop\npush 1\npop\njmp :loop");
+        assert_eq!(1, diagnostics.len());
+        assert_eq!(None, diagnostics[0].address);
+    }
+}
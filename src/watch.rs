@@ -0,0 +1,290 @@
+// expressions over the machine's state, e.g. `slot(2) + peek(0)` or
+// `depth() > 3`, for the debugger to show after every step. They use
+// exprc's syntax, with calls in place of variables:
+//
+//     slot(n)   variable n in the current frame, 0 if it's never been stored to
+//     peek(n)   n down from the top of the stack, so peek(0) is the top
+//     depth()   how many values are on the stack
+//     frames()  how many call frames there are, the root frame included
+//     ip()      the instruction pointer
+//     steps()   instructions executed so far
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Error, Result};
+
+use crate::cpu::Cpu;
+use crate::exprc::{tokenize, Token};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watch {
+    // as it was written, to show next to the value.
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Number(i64),
+    Call(Function, Option<Box<Expr>>),
+    Negate(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(Box<Expr>, &'static str, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Function {
+    Slot,
+    Peek,
+    Depth,
+    Frames,
+    Ip,
+    Steps,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "slot" => Some(Self::Slot),
+            "peek" => Some(Self::Peek),
+            "depth" => Some(Self::Depth),
+            "frames" => Some(Self::Frames),
+            "ip" => Some(Self::Ip),
+            "steps" => Some(Self::Steps),
+            _ => None,
+        }
+    }
+
+    fn takes_argument(self) -> bool {
+        matches!(self, Self::Slot | Self::Peek)
+    }
+}
+
+// binary operators from loosest to tightest binding, as in exprc.
+const LEVELS: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["==", "!="],
+    &[">", ">=", "<", "<="],
+    &["+", "-"],
+    &["*", "/"],
+];
+
+impl Watch {
+    pub fn evaluate(&self, cpu: &Cpu) -> Result<i64> {
+        self.expr.evaluate(cpu)
+    }
+}
+
+impl Expr {
+    fn evaluate(&self, cpu: &Cpu) -> Result<i64> {
+        Ok(match self {
+            Self::Number(number) => *number,
+            Self::Call(function, argument) => {
+                let argument = match argument {
+                    Some(argument) => argument.evaluate(cpu)?,
+                    None => 0,
+                };
+                match function {
+                    Function::Slot => cpu.variable(argument),
+                    Function::Peek => {
+                        let stack = cpu.stack();
+                        match usize::try_from(argument)
+                            .ok()
+                            .and_then(|depth| stack.len().checked_sub(depth + 1))
+                        {
+                            Some(index) => stack[index],
+                            None => bail!(
+                                "peek({argument}) is past the bottom of a stack {} deep",
+                                stack.len()
+                            ),
+                        }
+                    }
+                    Function::Depth => cpu.stack().len() as i64,
+                    Function::Frames => cpu.frames().count() as i64,
+                    Function::Ip => cpu.ip() as i64,
+                    Function::Steps => cpu.steps() as i64,
+                }
+            }
+            Self::Negate(operand) => operand.evaluate(cpu)?.wrapping_neg(),
+            Self::Not(operand) => (operand.evaluate(cpu)? == 0) as i64,
+            Self::Binary(left, operator, right) => {
+                let (left, right) = (left.evaluate(cpu)?, right.evaluate(cpu)?);
+                match *operator {
+                    "||" => (left != 0 || right != 0) as i64,
+                    "&&" => (left != 0 && right != 0) as i64,
+                    "==" => (left == right) as i64,
+                    "!=" => (left != right) as i64,
+                    ">" => (left > right) as i64,
+                    ">=" => (left >= right) as i64,
+                    "<" => (left < right) as i64,
+                    "<=" => (left <= right) as i64,
+                    "+" => left.wrapping_add(right),
+                    "-" => left.wrapping_sub(right),
+                    "*" => left.wrapping_mul(right),
+                    _ => match left.checked_div(right) {
+                        Some(quotient) => quotient,
+                        None => bail!("Division by zero"),
+                    },
+                }
+            }
+        })
+    }
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+}
+
+impl Parser {
+    // left associative, so `8 - 2 - 1` is `(8 - 2) - 1`.
+    fn binary(&mut self, level: usize) -> Result<Expr> {
+        let Some(operators) = LEVELS.get(level) else {
+            return self.unary();
+        };
+        let mut expr = self.binary(level + 1)?;
+        while let Some(operator) = operators
+            .iter()
+            .find(|symbol| self.peek() == Some(&Token::Symbol(symbol)))
+        {
+            self.position += 1;
+            let right = self.binary(level + 1)?;
+            expr = Expr::Binary(Box::new(expr), operator, Box::new(right));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        match self.peek() {
+            Some(Token::Symbol("-")) => {
+                self.position += 1;
+                Ok(Expr::Negate(Box::new(self.unary()?)))
+            }
+            Some(Token::Symbol("!")) => {
+                self.position += 1;
+                Ok(Expr::Not(Box::new(self.unary()?)))
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        let Some((token, column)) = self.tokens.get(self.position).cloned() else {
+            bail!("Expression ended early")
+        };
+        self.position += 1;
+        match token {
+            Token::Number(number) => Ok(Expr::Number(number)),
+            Token::Name(name) if name == "true" => Ok(Expr::Number(1)),
+            Token::Name(name) if name == "false" => Ok(Expr::Number(0)),
+            Token::Name(name) => {
+                let Some(function) = Function::from_name(&name) else {
+                    bail!("Unknown function {name} at column {column}, expected slot, peek, depth, frames, ip or steps")
+                };
+                self.expect("(", &name, column)?;
+                let argument = match function.takes_argument() {
+                    true => Some(Box::new(self.binary(0)?)),
+                    false => None,
+                };
+                self.expect(")", &name, column)?;
+                Ok(Expr::Call(function, argument))
+            }
+            Token::Symbol("(") => {
+                let expr = self.binary(0)?;
+                if self.peek() != Some(&Token::Symbol(")")) {
+                    bail!("Missing ) for the ( at column {column}")
+                }
+                self.position += 1;
+                Ok(expr)
+            }
+            token => bail!("Unexpected {token} at column {column}"),
+        }
+    }
+
+    fn expect(&mut self, symbol: &'static str, name: &str, column: usize) -> Result<()> {
+        if self.peek() != Some(&Token::Symbol(symbol)) {
+            let argument = match Function::from_name(name).is_some_and(Function::takes_argument) {
+                true => "an argument",
+                false => "no arguments",
+            };
+            bail!("Expected {name} at column {column} to be called with {argument}")
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+}
+
+impl FromStr for Watch {
+    type Err = Error;
+
+    fn from_str(source: &str) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            position: 0,
+        };
+        let expr = parser.binary(0)?;
+        if let Some((token, column)) = parser.tokens.get(parser.position) {
+            bail!("Unexpected {token} at column {column}")
+        }
+        Ok(Self {
+            source: source.trim().to_string(),
+            expr,
+        })
+    }
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::{CALL, HALT, PUSH, RET, STORE};
+    use crate::program::Program;
+
+    #[test]
+    fn evaluates_against_the_machine() {
+        let mut cpu = Cpu::new();
+        let code = vec![PUSH, 4, STORE, 2, PUSH, 10, PUSH, 3, CALL, 11, HALT, RET];
+        cpu.load_program(Program::from_code(code).unwrap());
+        cpu.run_slice(4).unwrap();
+        let value = |source: &str| source.parse::<Watch>().unwrap().evaluate(&cpu);
+        assert_eq!(7, value("slot(2) + peek(0)").unwrap());
+        assert_eq!(10, value("peek(depth() - 1)").unwrap());
+        assert_eq!(1, value("frames() == 1 && ip() == 8").unwrap());
+        assert_eq!(-4, value("-steps()").unwrap());
+        assert_eq!(
+            "peek(2) is past the bottom of a stack 2 deep",
+            value("peek(2)").unwrap_err().to_string()
+        );
+        assert!(value("1 / slot(0)").is_err());
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |source: &str| source.parse::<Watch>().unwrap_err().to_string();
+        assert!(error("top").starts_with("Unknown function top at column 1"));
+        assert_eq!(
+            "Expected slot at column 1 to be called with an argument",
+            error("slot 2")
+        );
+        assert_eq!(
+            "Expected depth at column 3 to be called with no arguments",
+            error("1+depth(1)")
+        );
+        assert_eq!("Unexpected ) at column 8", error("peek(0))"));
+        assert_eq!(
+            "slot(1) * 2",
+            "  slot(1) * 2 ".parse::<Watch>().unwrap().to_string()
+        );
+    }
+}